@@ -1,74 +1,829 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
 
 use anyhow::{Error, Result};
 use dashmap::DashMap;
-use tokio::sync::{mpsc::Sender, oneshot};
-use web_rwkv::{context::Context, model::Model, tokenizer::Tokenizer};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{oneshot, Mutex, OwnedMutexGuard};
+use web_rwkv::tokenizer::Tokenizer;
 
 use crate::{
-    config::ModelConfig,
+    cli::WsConfig,
+    commands::{idempotency::IdempotencyCache, resume::ResumeBuffers},
+    config::{ModelConfig, DEFAULT_MODEL},
+    error::CommandErrorKind,
     helper::{Logits, State},
     states::{
-        infer::{InferContext, InferRequest, InferResult},
-        permit::BatchRequest,
+        infer::{InferContext, InferResult},
+        merge,
+        model::AxumModel,
+        normalizer::Normalizers,
+        prefix_cache::PrefixCache,
+        sample_pipeline::GenerationRecord,
         sampler::Samplers,
-        softmax::Softmax,
+        spill::{Spill, SpillFile, SpillStats},
+        state_file::{self, StateLayout},
+        template::Templates,
+        terminal::Terminals,
         transformer::Transformers,
     },
 };
 
+#[derive(Debug, Clone)]
+struct InferState {
+    /// Name of the model the state is created against
+    model: String,
+    // Can be None to represent state not created by pipeline yet
+    state: Option<State>,
+    /// The file holding the data of the state while it's spilled, in which case `state` is
+    /// `None`
+    spilled: Option<Arc<SpillFile>>,
+    /// The connection owning the state, which deletes it on disconnect. `None` for
+    /// persistent states.
+    owner: Option<usize>,
+    /// Nothing is fed to the state yet, so it can be loaded from the prefix cache
+    fresh: bool,
+    /// The state is replaced by `set_state`, so it must be loaded into the pipeline even
+    /// if the pipeline still holds it
+    reload: bool,
+    /// Bumped by `set_state`, so the pipeline doesn't send back the replaced state
+    generation: usize,
+    /// Time the infers of the state waited for a batch slot, since `take_queued`
+    queued: Duration,
+    /// Tokens the state is allowed to see before it's rebuilt from its latest tokens, 0 for
+    /// no limit
+    max_context: usize,
+    /// Tokens fed to the state, only kept if `max_context` is set
+    history: Vec<u16>,
+    /// The latest tokens fed to the state, kept if it's created with `track_history`
+    tracked: Option<TokenHistory>,
+    /// Exempts the state from eviction by `--max-states`
+    pinned: bool,
+    /// Keeps the slot of the state in the pipeline, by `pin_state`
+    slot_pinned: bool,
+    /// Snapshots taken by `checkpoint_state`, oldest first
+    checkpoints: Vec<Checkpoint>,
+    /// Held by the command advancing the state, see `lock_states`
+    lock: StateLock,
+    /// Logits after the tokens last fed by `feed_next`, along with the count of `tokens`
+    /// then, so they are only used until anything else is fed
+    next_logits: Option<(usize, Logits)>,
+    /// Count of tokens fed to the state, which only grows once they are inferred, and
+    /// doesn't count the tokens inferred again by a rebuild
+    tokens: usize,
+    created: Instant,
+    /// Last time the state is inferred, replaced or touched
+    used: Instant,
+    /// How long the state may stay unused before it expires, `None` to never expire
+    ttl: Option<Duration>,
+}
+
+/// The lock of a state, which is cloned as a new lock since a copy of a state is advanced
+/// on its own.
+#[derive(Debug, Default)]
+struct StateLock(Arc<Mutex<()>>);
+
+impl Clone for StateLock {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+/// A named snapshot of a state, which `rollback_state` restores along with the tokens fed
+/// to the state then.
+#[derive(Debug, Clone)]
+struct Checkpoint {
+    name: String,
+    /// `None` if nothing was fed to the state yet
+    state: Option<State>,
+    history: Vec<u16>,
+    tracked: Option<TokenHistory>,
+    tokens: usize,
+}
+
+/// The latest tokens fed to a state, at most `capacity` of them, recorded once they are
+/// actually inferred into the state.
+#[derive(Debug, Clone)]
+struct TokenHistory {
+    capacity: usize,
+    tokens: VecDeque<u16>,
+}
+
+impl TokenHistory {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tokens: VecDeque::new(),
+        }
+    }
+
+    fn extend(&mut self, tokens: &[u16]) {
+        self.tokens.extend(tokens);
+        let len = self.tokens.len().saturating_sub(self.capacity);
+        self.tokens.drain(..len);
+    }
+
+    /// Drops the last tokens of this count, e.g. the draft tokens rejected by speculative
+    /// decoding.
+    fn truncate(&mut self, count: usize) {
+        let len = self.tokens.len().saturating_sub(count);
+        self.tokens.truncate(len);
+    }
+}
+
+/// Prefix of the ids of states and components which only live during a command.
+const TEMPORARY_PREFIX: &str = "#temporary-";
+
+/// How long a state deleted by the server is remembered, so using it tells why it's gone
+/// instead of never created.
+const REMOVED_RECORD_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Why the server deleted a state on its own.
+#[derive(Debug, Clone, Copy)]
+enum Removal {
+    /// The state stayed idle beyond its TTL.
+    Expired,
+    /// The state was the least recently used one when `--max-states` is reached.
+    Evicted,
+}
+
+/// Metadata of a state, given by `list_states` and `get_state`.
+#[derive(Debug, Clone, Serialize)]
+pub struct StateInfo {
+    pub id: String,
+    pub model: String,
+    /// Whether the state outlives the connection creating it.
+    pub persistent: bool,
+    /// Milliseconds since the state is created.
+    pub age_ms: u64,
+    /// Milliseconds since the state is last inferred, replaced or touched.
+    pub idle_ms: u64,
+    /// Seconds the state may stay idle before it expires, if it does.
+    pub ttl_seconds: Option<u64>,
+    /// Tokens fed to the state so far.
+    pub tokens: usize,
+    /// Approximate memory taken by the data of the state.
+    pub bytes: usize,
+    /// Whether the data of the state is spilled to disk.
+    pub spilled: bool,
+    /// Whether the state is exempt from eviction by `--max-states`.
+    pub pinned: bool,
+    /// Whether the state keeps its slot in the batch of its model, by `pin_state`.
+    pub slot_pinned: bool,
+    /// Names of the checkpoints of the state, oldest first.
+    pub checkpoints: Vec<String>,
+    /// Whether the state keeps the tokens fed to it for `get_state_history`.
+    pub track_history: bool,
+}
+
+/// The latest tokens fed to a state, given by `get_state_history`.
+#[derive(Debug, Clone, Serialize)]
+pub struct StateHistory {
+    pub id: String,
+    /// Position of the first token among all tokens fed to the state.
+    pub start: usize,
+    pub tokens: Vec<u16>,
+}
+
+/// How the tokens fed to a state change when its data is replaced by `set_state`.
+#[derive(Debug, Clone, Copy)]
+pub enum FedTokens<'a> {
+    /// The new data is the old one after these tokens are fed.
+    Extend(&'a [u16]),
+    /// The new data is the old one before its last tokens of this count are fed.
+    Truncate(usize),
+    /// The new data doesn't follow the old one.
+    Reset,
+}
+
+impl InferState {
+    /// Records tokens about to be fed. If the history grows beyond `max_context`, the
+    /// oldest tokens are dropped until half of it is left (but no less than the new
+    /// tokens), and the kept tokens are returned to rebuild the state from scratch.
+    fn feed(&mut self, tokens: &[u16]) -> Option<Vec<u16>> {
+        if self.max_context == 0 {
+            return None;
+        }
+        self.history.extend_from_slice(tokens);
+        if self.history.len() <= self.max_context {
+            return None;
+        }
+        let keep = (self.max_context / 2)
+            .max(tokens.len())
+            .min(self.max_context);
+        self.history.drain(..self.history.len() - keep);
+        Some(self.history.clone())
+    }
+
+    /// Counts tokens once they are inferred into the state, and records them if the state
+    /// tracks its history.
+    fn advance(&mut self, tokens: &[u16]) {
+        self.tokens += tokens.len();
+        if let Some(tracked) = &mut self.tracked {
+            tracked.extend(tokens);
+        }
+    }
+}
+
+/// Server-wide pipeline settings used by infer requests which omit them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InferDefaults {
+    /// Sampler id used if a request omits `sampler`.
+    #[serde(default)]
+    pub sampler: Option<String>,
+    /// Transformer ids applied to every state if a request omits `transformers`.
+    #[serde(default)]
+    pub transformers: Option<Vec<String>>,
+}
+
+/// Server-wide settings of the app state, set by the command line.
+#[derive(Debug, Clone, Default)]
+pub struct AppSettings {
+    /// Most prompts kept by the prefix cache, 0 to disable it.
+    pub prefix_cache_size: usize,
+    /// Most responses kept for idempotency keys.
+    pub idempotency_cache_size: usize,
+    /// Tokens proposed by the draft model in each round of speculative decoding.
+    pub draft_tokens: usize,
+    /// Default context limit of new states, 0 for no limit.
+    pub max_context: usize,
+    /// Most states allowed at once, 0 for no limit.
+    pub max_states: usize,
+    /// Most checkpoints kept by each state, 0 to disable them.
+    pub max_checkpoints: usize,
+    /// Most tokens kept by each state created with `track_history`.
+    pub history_size: usize,
+    /// How long a command waits for the states other commands are advancing.
+    pub state_lock_timeout: Duration,
+    /// How long the results of resumable commands are kept, 0 to disable them.
+    pub resume_window: Duration,
+    /// Where `save_state` and `load_state` keep state files, if enabled.
+    pub state_dir: Option<PathBuf>,
+}
+
+/// Options of a new state, which is deleted once the connection is closed unless
+/// `persistent`.
+#[derive(Debug, Clone, Default)]
+pub struct StateOptions {
+    /// The model the state is created against, or the default model.
+    pub model: Option<String>,
+    pub persistent: bool,
+    /// Overrides the default context limit of the server.
+    pub max_context: Option<usize>,
+    /// Overrides the default TTL, 0 to never expire.
+    pub ttl_seconds: Option<u64>,
+    /// A pinned state is never evicted.
+    pub pinned: bool,
+    /// Keeps the latest tokens fed to the state.
+    pub track_history: bool,
+}
+
 pub struct InnerState {
     pub config: ModelConfig,
+    pub ws_config: WsConfig,
     pub samplers: Arc<Samplers>,
     pub transformers: Arc<Transformers>,
-    infer_queue: Sender<Vec<InferRequest>>,
-    softmax_queue: Sender<Vec<(Vec<f32>, oneshot::Sender<Vec<f32>>)>>,
+    pub terminals: Arc<Terminals>,
+    pub normalizers: Arc<Normalizers>,
+    pub templates: Arc<Templates>,
     // State holders
-    // Can be None to represent state not created by pipeline yet
-    infer_states: Arc<DashMap<String, Option<State>>>,
+    infer_states: Arc<DashMap<String, InferState>>,
     pub tokenizer: Arc<Tokenizer>,
-    pub context: Context,
-    pub model: Arc<Model<'static>>,
-    pub batch_request: BatchRequest,
+    pub models: HashMap<String, Arc<AxumModel>>,
+    pub prefix_cache: PrefixCache,
+    /// Tokens proposed by the draft model in each round of speculative decoding.
+    pub draft_tokens: usize,
+    /// Default context limit of new states, 0 for no limit.
+    pub max_context: usize,
+    /// Where `save_state` and `load_state` keep state files, if enabled.
+    pub state_dir: Option<PathBuf>,
+    /// The last generation of each state, for `continue`.
+    generations: DashMap<String, GenerationRecord>,
+    /// Most states allowed at once before the least recently used ones are evicted, 0 for
+    /// no limit.
+    pub max_states: usize,
+    /// Most checkpoints kept by each state, beyond which the oldest one is dropped. 0
+    /// disables checkpoints.
+    pub max_checkpoints: usize,
+    /// Most tokens kept by each state created with `track_history`.
+    pub history_size: usize,
+    /// How long a command waits for the states other commands are advancing.
+    pub state_lock_timeout: Duration,
+    /// States deleted by the server on its own, with why and when.
+    removed: DashMap<String, (Removal, Instant)>,
+    /// Spills cold states to disk, if `max_resident_states` is set.
+    spill: Option<Spill>,
+    defaults: RwLock<InferDefaults>,
+    /// Whether the server reports healthy, which is false until the startup warmup is done.
+    ready: AtomicBool,
+    next_connection: AtomicUsize,
+    next_temporary: AtomicUsize,
+    /// Cancellation flags of running commands, by connection and echo_id.
+    running_commands: DashMap<(Option<usize>, String), Arc<AtomicBool>>,
+    /// Responses of recent commands sent with an idempotency key.
+    pub idempotency: IdempotencyCache,
+    /// Results of recent resumable commands, by echo_id.
+    pub resumable: ResumeBuffers,
 }
 
 #[derive(Clone)]
-/// Global state holder of the entire app.
-pub struct AppState(pub Arc<InnerState>);
+/// Global state holder of the entire app, along with the id of the connection using it.
+pub struct AppState(pub Arc<InnerState>, Option<usize>);
 
 impl AppState {
     pub async fn new(
         config: &ModelConfig,
-        infer_queue: Sender<Vec<InferRequest>>,
-        softmax_queue: Sender<Vec<(Vec<f32>, oneshot::Sender<Vec<f32>>)>>,
-        context: Context,
-        model: Arc<Model<'static>>,
-        batch_request: BatchRequest,
+        ws_config: WsConfig,
+        settings: AppSettings,
+        models: HashMap<String, Arc<AxumModel>>,
     ) -> Result<Self> {
-        Ok(AppState(Arc::new(InnerState {
-            config: config.clone(),
-            samplers: Arc::new(Samplers::new()),
-            transformers: Arc::new(Transformers::new()),
-            infer_queue,
-            softmax_queue,
-            infer_states: Arc::new(DashMap::with_capacity(128)),
-            tokenizer: Arc::new(config.tokenizer.load_tokenizer().await?),
-            context,
-            model,
-            batch_request,
-        })))
+        let AppSettings {
+            prefix_cache_size,
+            idempotency_cache_size,
+            draft_tokens,
+            max_context,
+            max_states,
+            max_checkpoints,
+            history_size,
+            state_lock_timeout,
+            resume_window,
+            state_dir,
+        } = settings;
+        Ok(AppState(
+            Arc::new(InnerState {
+                config: config.clone(),
+                ws_config,
+                samplers: Arc::new(Samplers::new()),
+                transformers: Arc::new(Transformers::new()),
+                terminals: Arc::new(Terminals::new()),
+                normalizers: Arc::new(Normalizers::new()),
+                templates: Arc::new(Templates::new()),
+                infer_states: Arc::new(DashMap::with_capacity(128)),
+                tokenizer: Arc::new(config.tokenizer.load_tokenizer().await?),
+                models,
+                prefix_cache: PrefixCache::new(prefix_cache_size),
+                draft_tokens,
+                max_context,
+                state_dir,
+                generations: DashMap::with_capacity(128),
+                max_states,
+                max_checkpoints,
+                history_size,
+                state_lock_timeout,
+                removed: DashMap::new(),
+                spill: match config.state.get_spill()? {
+                    Some((dir, max_resident)) => Some(Spill::new(dir, max_resident)?),
+                    None => None,
+                },
+                defaults: RwLock::new(InferDefaults::default()),
+                ready: AtomicBool::new(true),
+                next_connection: AtomicUsize::new(0),
+                next_temporary: AtomicUsize::new(0),
+                running_commands: DashMap::with_capacity(128),
+                idempotency: IdempotencyCache::new(idempotency_cache_size),
+                resumable: ResumeBuffers::new(resume_window),
+            }),
+            None,
+        ))
+    }
+
+    /// Creates a view of the app state for a new connection, which owns the ephemeral
+    /// states created through it.
+    pub fn connect(&self) -> Self {
+        let connection = self.0.next_connection.fetch_add(1, Ordering::Relaxed);
+        AppState(self.0.clone(), Some(connection))
+    }
+
+    /// The connection using the app state, if any.
+    pub fn connection(&self) -> Option<usize> {
+        self.1
+    }
+
+    /// Deletes all ephemeral states and scoped components owned by the connection, and
+    /// cancels its running commands, except resumable ones.
+    pub fn disconnect(&self) {
+        if let Some(connection) = self.1 {
+            self.0.infer_states.retain(|id, state| {
+                let owned = state.owner == Some(connection);
+                if owned {
+                    self.release_slot(id, state);
+                }
+                !owned
+            });
+            self.0
+                .generations
+                .retain(|id, _| self.0.infer_states.contains_key(id));
+            self.0.samplers.disconnect(connection);
+            self.0.transformers.disconnect(connection);
+            self.0
+                .running_commands
+                .iter()
+                .filter(|x| x.key().0 == Some(connection))
+                .filter(|x| !self.0.resumable.is_running(x.key().0, &x.key().1))
+                .for_each(|x| x.value().store(true, Ordering::Relaxed));
+        }
+    }
+
+    /// Registers a running command, so it can be cancelled by its echo_id from the same
+    /// connection until the returned handle is dropped.
+    pub fn register_command(&self, echo_id: String) -> CommandHandle {
+        let cancelled = Arc::new(AtomicBool::new(false));
+        self.0
+            .running_commands
+            .insert((self.1, echo_id.clone()), cancelled.clone());
+        CommandHandle {
+            state: self.clone(),
+            echo_id,
+            cancelled,
+        }
+    }
+
+    /// Cancels a running command of the connection.
+    pub fn cancel_command(&self, echo_id: &str) -> Result<()> {
+        self.0
+            .running_commands
+            .get(&(self.1, echo_id.to_string()))
+            .ok_or(CommandErrorKind::BadRequest.error(format!(
+                "No cancellable command with echo_id {} is running!",
+                echo_id
+            )))?
+            .store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.0.ready.load(Ordering::Acquire)
+    }
+
+    pub fn set_ready(&self, ready: bool) {
+        self.0.ready.store(ready, Ordering::Release);
+    }
+
+    /// Replaces the defaults of infer requests. The components must exist, though they may
+    /// be deleted later, which fails the infers relying on them.
+    pub fn set_defaults(&self, defaults: InferDefaults) -> Result<()> {
+        if let Some(sampler) = &defaults.sampler {
+            if !self.0.samplers.has_sampler(sampler) {
+                return Err(CommandErrorKind::SamplerNotFound.error("Sampler id does not exist!"));
+            }
+        }
+        if let Some(transformers) = &defaults.transformers {
+            if transformers
+                .iter()
+                .any(|x| !self.0.transformers.has_transformer(x))
+            {
+                return Err(CommandErrorKind::TransformerNotFound
+                    .error("One or more transformer ids not exist!"));
+            }
+        }
+        *self.0.defaults.write().unwrap() = defaults;
+        Ok(())
+    }
+
+    pub fn defaults(&self) -> InferDefaults {
+        self.0.defaults.read().unwrap().clone()
+    }
+
+    /// Runs a dummy token through a throwaway state of the model, so the kernels are
+    /// compiled and the buffers are allocated before any real request.
+    pub async fn warmup(&self, model: &str) -> Result<()> {
+        let axum_model = self.model(Some(model))?;
+        let _permit = axum_model.batch_request.request(1)?;
+        let state = self.create_temporary_state(model, None)?;
+        let logits = self.infer(vec![state.id.clone()], vec![vec![0]]).await?;
+        axum_model
+            .softmax(logits.into_iter().map(|x| x.0).collect())
+            .await;
+        Ok(())
+    }
+
+    /// Gets a loaded model by name, `None` for the default model.
+    pub fn model(&self, name: Option<&str>) -> Result<Arc<AxumModel>> {
+        let name = name.unwrap_or(DEFAULT_MODEL);
+        self.0
+            .models
+            .get(name)
+            .cloned()
+            .ok_or(CommandErrorKind::ModelNotFound.error(format!("Model {} is not loaded!", name)))
+    }
+
+    /// Gets the model which all the states are created against.
+    pub fn state_model(&self, state_keys: &Vec<String>) -> Result<Arc<AxumModel>> {
+        let mut model: Option<String> = None;
+        for key in state_keys {
+            let state_model = self
+                .0
+                .infer_states
+                .get(key)
+                .ok_or_else(|| self.missing_state(key))?
+                .model
+                .clone();
+            match &model {
+                Some(model) if model != &state_model => {
+                    return Err(CommandErrorKind::BadRequest.error(format!(
+                        "State {} is created against model {}, but other states are created against model {}!",
+                        key, state_model, model
+                    )))
+                }
+                Some(_) => (),
+                None => model = Some(state_model),
+            }
+        }
+        self.model(model.as_deref())
     }
 
     pub async fn update_state(&self, id: Vec<String>, tokens: Vec<Vec<u16>>) -> Result<()> {
+        // The states no longer follow their last generations
+        self.forget_generations(&id);
         let _ = self.infer(id, tokens).await?;
         Ok(())
     }
 
-    pub async fn create_state(&self, id: String) -> Result<()> {
+    /// Feeds a long prompt to a state like `update_state`, in chunks of `chunk_size` tokens
+    /// (the token chunk size of the model if omitted) per batch. Larger chunks take fewer
+    /// batches, but hold a slot longer.
+    pub async fn prefill(
+        &self,
+        id: String,
+        tokens: Vec<u16>,
+        chunk_size: Option<usize>,
+    ) -> Result<()> {
+        if chunk_size == Some(0) {
+            return Err(CommandErrorKind::BadRequest.error("chunk_size must be at least 1!"));
+        }
+        let id = vec![id];
+        self.forget_generations(&id);
+        let _ = self
+            .infer_states(id, vec![tokens], false, chunk_size)
+            .await?;
+        Ok(())
+    }
+
+    /// Creates a state with the options, see `StateOptions`.
+    pub async fn create_state(&self, id: String, options: StateOptions) -> Result<()> {
+        let StateOptions {
+            model,
+            persistent,
+            max_context,
+            ttl_seconds,
+            pinned,
+            track_history,
+        } = options;
+        if track_history && self.0.history_size == 0 {
+            return Err(CommandErrorKind::BadRequest
+                .error("Token history is disabled, set --history-size to enable it!"));
+        }
         if self.0.infer_states.contains_key(&id) {
-            return Err(Error::msg("State already exists!"));
+            return Err(CommandErrorKind::AlreadyExists.error("State already exists!"));
+        }
+        let model = self.model(model.as_deref())?.name.clone();
+        self.make_room(1, None)?;
+        self.0.removed.remove(&id);
+        let owner = if persistent { None } else { self.1 };
+        let ttl = match ttl_seconds {
+            Some(0) => None,
+            Some(seconds) => Some(Duration::from_secs(seconds)),
+            None => self.0.config.state.get_ttl(),
+        };
+        self.0.infer_states.insert(
+            id,
+            InferState {
+                model,
+                state: None,
+                spilled: None,
+                owner,
+                fresh: true,
+                reload: false,
+                generation: 0,
+                queued: Duration::ZERO,
+                max_context: max_context.unwrap_or(self.0.max_context),
+                history: Vec::new(),
+                tracked: track_history.then(|| TokenHistory::new(self.0.history_size)),
+                pinned,
+                slot_pinned: false,
+                checkpoints: Vec::new(),
+                lock: StateLock::default(),
+                next_logits: None,
+                tokens: 0,
+                created: Instant::now(),
+                used: Instant::now(),
+                ttl,
+            },
+        );
+        Ok(())
+    }
+
+    /// A unique id for states and components which only live during a command.
+    pub fn temporary_id(&self) -> String {
+        format!(
+            "{}{}",
+            TEMPORARY_PREFIX,
+            self.0.next_temporary.fetch_add(1, Ordering::Relaxed)
+        )
+    }
+
+    /// Creates a state with a unique id, which is deleted once the returned handle is
+    /// dropped.
+    pub fn create_temporary_state(
+        &self,
+        model: &str,
+        state: Option<State>,
+    ) -> Result<TemporaryState> {
+        let model = self.model(Some(model))?.name.clone();
+        let id = self.temporary_id();
+        self.0.infer_states.insert(
+            id.clone(),
+            InferState {
+                model,
+                state,
+                spilled: None,
+                owner: self.1,
+                fresh: false,
+                reload: false,
+                generation: 0,
+                queued: Duration::ZERO,
+                max_context: 0,
+                history: Vec::new(),
+                tracked: None,
+                pinned: false,
+                slot_pinned: false,
+                checkpoints: Vec::new(),
+                lock: StateLock::default(),
+                next_logits: None,
+                tokens: 0,
+                created: Instant::now(),
+                used: Instant::now(),
+                ttl: None,
+            },
+        );
+        Ok(TemporaryState {
+            state: self.clone(),
+            id,
+        })
+    }
+
+    /// Makes a shallow copy of the latest data of a state with a unique id, which is deleted
+    /// once the returned handle is dropped. Waits for commands advancing the state like
+    /// `lock_states` does, so the copy never catches it midway.
+    pub async fn copy_temporary_state(&self, src: &str) -> Result<TemporaryState> {
+        let _locks = self.lock_states(&[src.to_string()]).await?;
+        self.sync_state(src).await?;
+        self.reload_states(&[src.to_string()]).await?;
+        let mut state = self
+            .0
+            .infer_states
+            .get(src)
+            .ok_or_else(|| self.missing_state(src))?
+            .clone();
+        state.owner = self.1;
+        state.slot_pinned = false;
+        state.checkpoints.clear();
+        // It's deleted by its handle, not swept in the middle of the command
+        state.ttl = None;
+        let id = self.temporary_id();
+        self.0.infer_states.insert(id.clone(), state);
+        Ok(TemporaryState {
+            state: self.clone(),
+            id,
+        })
+    }
+
+    /// Replaces the data of a state, which is loaded on the next infer even if the
+    /// pipeline still holds the state. `fed` brings the tokens fed to the state in line
+    /// with the new data.
+    pub fn set_state(&self, id: &str, state: State, fed: FedTokens) -> Result<()> {
+        let mut infer_state = self
+            .0
+            .infer_states
+            .get_mut(id)
+            .ok_or_else(|| self.missing_state(id))?;
+        match fed {
+            FedTokens::Extend(tokens) => {
+                if infer_state.max_context > 0 {
+                    infer_state.history.extend_from_slice(tokens);
+                }
+                infer_state.advance(tokens);
+            }
+            FedTokens::Truncate(count) => {
+                let len = infer_state.history.len().saturating_sub(count);
+                infer_state.history.truncate(len);
+                if let Some(tracked) = &mut infer_state.tracked {
+                    tracked.truncate(count);
+                }
+                infer_state.tokens = infer_state.tokens.saturating_sub(count);
+            }
+            FedTokens::Reset => {
+                infer_state.history.clear();
+                if let Some(tracked) = &mut infer_state.tracked {
+                    tracked.tokens.clear();
+                }
+                infer_state.tokens = 0;
+            }
         }
-        self.0.infer_states.insert(id, None);
+        infer_state.state = Some(state);
+        infer_state.spilled = None;
+        infer_state.next_logits = None;
+        infer_state.used = Instant::now();
+        infer_state.fresh = false;
+        infer_state.reload = true;
+        infer_state.generation += 1;
+        Ok(())
+    }
+
+    /// Resets a state to the initial state, as if it's just created, while keeping its id,
+    /// settings and pinned slot. Like `set_state`, infers still running with the old data
+    /// don't write it back, and the initial state is loaded on the next infer.
+    pub fn reset_state(&self, id: &str) -> Result<()> {
+        let mut infer_state = self
+            .0
+            .infer_states
+            .get_mut(id)
+            .ok_or_else(|| self.missing_state(id))?;
+        infer_state.state = None;
+        infer_state.spilled = None;
+        infer_state.next_logits = None;
+        infer_state.history.clear();
+        if let Some(tracked) = &mut infer_state.tracked {
+            tracked.tokens.clear();
+        }
+        infer_state.tokens = 0;
+        infer_state.used = Instant::now();
+        infer_state.fresh = true;
+        infer_state.reload = true;
+        infer_state.generation += 1;
+        Ok(())
+    }
+
+    /// Takes a snapshot of the latest data of a state under a name, replacing the
+    /// checkpoint of the same name. The oldest checkpoint is dropped if the state has
+    /// `max_checkpoints` of them already.
+    pub async fn checkpoint_state(&self, id: &str, name: String) -> Result<()> {
+        if self.0.max_checkpoints == 0 {
+            return Err(CommandErrorKind::BadRequest
+                .error("Checkpoints are disabled, set --max-checkpoints to enable them!"));
+        }
+        if name.is_empty() {
+            return Err(CommandErrorKind::BadRequest.error("Checkpoint name must not be empty!"));
+        }
+        self.sync_state(id).await?;
+        self.reload_states(&[id.to_string()]).await?;
+        let mut infer_state = self
+            .0
+            .infer_states
+            .get_mut(id)
+            .ok_or_else(|| self.missing_state(id))?;
+        let checkpoint = Checkpoint {
+            name,
+            state: infer_state.state.clone(),
+            history: infer_state.history.clone(),
+            tracked: infer_state.tracked.clone(),
+            tokens: infer_state.tokens,
+        };
+        infer_state
+            .checkpoints
+            .retain(|x| x.name != checkpoint.name);
+        if infer_state.checkpoints.len() >= self.0.max_checkpoints {
+            infer_state.checkpoints.remove(0);
+        }
+        infer_state.checkpoints.push(checkpoint);
+        Ok(())
+    }
+
+    /// Restores a checkpoint of a state in place, along with the tokens fed then. The
+    /// checkpoint is kept, so the state can be rolled back to it again.
+    pub async fn rollback_state(&self, id: &str, name: &str) -> Result<()> {
+        self.sync_state(id).await?;
+        let mut infer_state = self
+            .0
+            .infer_states
+            .get_mut(id)
+            .ok_or_else(|| self.missing_state(id))?;
+        let checkpoint = infer_state
+            .checkpoints
+            .iter()
+            .find(|x| x.name == name)
+            .cloned()
+            .ok_or_else(|| {
+                CommandErrorKind::CheckpointNotFound
+                    .error(format!("State {} has no checkpoint {}!", id, name))
+            })?;
+        infer_state.fresh = checkpoint.state.is_none();
+        infer_state.state = checkpoint.state;
+        infer_state.spilled = None;
+        infer_state.next_logits = None;
+        infer_state.history = checkpoint.history;
+        infer_state.tracked = checkpoint.tracked;
+        infer_state.tokens = checkpoint.tokens;
+        infer_state.used = Instant::now();
+        infer_state.reload = true;
+        infer_state.generation += 1;
         Ok(())
     }
 
@@ -77,26 +832,647 @@ impl AppState {
         self.0.infer_states.contains_key(id)
     }
 
-    pub async fn copy_state(&self, src: String, dst: String) -> Result<()> {
+    /// Copies a state. A shallow copy shares the data with the source until either of them
+    /// is loaded into the pipeline, while a deep copy duplicates the data right away.
+    /// `persistent` decides if the copy outlives the connection, e.g. to keep an ephemeral
+    /// state, and defaults to whether the source does.
+    pub async fn copy_state(
+        &self,
+        src: String,
+        dst: String,
+        shallow: bool,
+        persistent: Option<bool>,
+    ) -> Result<()> {
         if self.0.infer_states.contains_key(&dst) {
-            return Err(Error::msg("Destination state id already exists!"));
+            return Err(
+                CommandErrorKind::AlreadyExists.error("Destination state id already exists!")
+            );
         }
-        let src = self
+        self.sync_state(&src).await?;
+        self.make_room(1, Some(src.as_str()))?;
+        let mut src = self
             .0
             .infer_states
             .get(&src)
-            .ok_or(Error::msg("State doesn't exist!"))?
+            .ok_or_else(|| self.missing_state(&src))?
             .clone();
+        src.owner = self.copy_owner(src.owner, persistent);
+        src.slot_pinned = false;
+        src.checkpoints.clear();
+        (src.created, src.used) = (Instant::now(), Instant::now());
+        if !shallow {
+            src.state = src.state.as_ref().map(State::deep_clone);
+        }
+        self.0.removed.remove(&dst);
         self.0.infer_states.insert(dst, src);
         Ok(())
     }
 
-    pub async fn delete_state(&self, id: String) -> Result<()> {
+    /// Copies a state to each of the destinations at once, e.g. for best-of-n sampling.
+    /// Nothing is copied if any destination already exists.
+    pub async fn fan_out_state(
+        &self,
+        src: String,
+        dsts: Vec<String>,
+        shallow: bool,
+        persistent: Option<bool>,
+    ) -> Result<()> {
+        let unique: HashSet<&String> = dsts.iter().collect();
+        if unique.len() != dsts.len() {
+            return Err(CommandErrorKind::BadRequest.error("Destination state ids must be unique!"));
+        }
+        if let Some(dst) = dsts.iter().find(|x| self.0.infer_states.contains_key(*x)) {
+            return Err(CommandErrorKind::AlreadyExists
+                .error(format!("Destination state id {} already exists!", dst)));
+        }
+        self.sync_state(&src).await?;
+        self.make_room(dsts.len(), Some(src.as_str()))?;
+        let mut src = self
+            .0
+            .infer_states
+            .get(&src)
+            .ok_or_else(|| self.missing_state(&src))?
+            .clone();
+        src.owner = self.copy_owner(src.owner, persistent);
+        src.slot_pinned = false;
+        src.checkpoints.clear();
+        (src.created, src.used) = (Instant::now(), Instant::now());
+        for dst in dsts {
+            let mut copy = src.clone();
+            if !shallow {
+                copy.state = copy.state.as_ref().map(State::deep_clone);
+            }
+            self.0.removed.remove(&dst);
+            self.0.infer_states.insert(dst, copy);
+        }
+        Ok(())
+    }
+
+    /// The owner of a copy of a state owned by `owner`.
+    fn copy_owner(&self, owner: Option<usize>, persistent: Option<bool>) -> Option<usize> {
+        match persistent {
+            Some(true) => None,
+            Some(false) => self.1,
+            // A copy of an ephemeral state is owned by the connection making the copy
+            None => owner.and(self.1),
+        }
+    }
+
+    /// Brings the data of a state up to date with the pipeline, which keeps the latest data
+    /// of an inferred state in its slot until another state takes the slot.
+    pub async fn sync_state(&self, id: &str) -> Result<()> {
+        let (model, generation) = {
+            let infer_state = self
+                .0
+                .infer_states
+                .get(id)
+                .ok_or_else(|| self.missing_state(id))?;
+            // The pipeline holds no newer data if the state is replaced since
+            if infer_state.fresh || infer_state.reload {
+                return Ok(());
+            }
+            (infer_state.model.clone(), infer_state.generation)
+        };
+        if let Some(state) = self.model(Some(&model))?.sync(id.to_string()).await? {
+            if let Some(mut infer_state) = self.0.infer_states.get_mut(id) {
+                if infer_state.generation == generation {
+                    infer_state.state = Some(state);
+                    infer_state.spilled = None;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The latest data of a state along with the layout of its model, which are encoded
+    /// by `state_file` to be saved or dumped.
+    pub async fn save_state(&self, id: &str) -> Result<(StateLayout, State)> {
+        self.sync_state(id).await?;
+        self.reload_states(&[id.to_string()]).await?;
+        let (model, state) = self
+            .0
+            .infer_states
+            .get(id)
+            .map(|x| (x.model.clone(), x.state.clone()))
+            .ok_or_else(|| self.missing_state(id))?;
+        let state = state.ok_or(
+            CommandErrorKind::BadRequest.error("State is not inferred yet, nothing to save!"),
+        )?;
+        Ok((StateLayout::of(&self.model(Some(&model))?), state))
+    }
+
+    /// Creates a state blending the latest data of the sources by weights, see
+    /// `merge::merge_states`. The sources must be inferred and of the same model, and are
+    /// left as they are.
+    pub async fn merge_states(
+        &self,
+        sources: Vec<(String, f32)>,
+        dst: String,
+        persistent: bool,
+    ) -> Result<()> {
+        if self.0.infer_states.contains_key(&dst) {
+            return Err(
+                CommandErrorKind::AlreadyExists.error("Destination state id already exists!")
+            );
+        }
+        let ids: Vec<String> = sources.iter().map(|(id, _)| id.clone()).collect();
+        let model = self.state_model(&ids)?;
+        for id in &ids {
+            self.sync_state(id).await?;
+        }
+        self.reload_states(&ids).await?;
+        let mut states = Vec::with_capacity(sources.len());
+        for (id, weight) in sources {
+            let state = self
+                .0
+                .infer_states
+                .get(&id)
+                .ok_or_else(|| self.missing_state(&id))?
+                .state
+                .clone()
+                .ok_or_else(|| {
+                    CommandErrorKind::BadRequest.error(format!(
+                        "State {} is not inferred yet, nothing to merge!",
+                        id
+                    ))
+                })?;
+            states.push((state, weight));
+        }
+        let merged = merge::merge_states(&StateLayout::of(&model), &states)?;
+        let options = StateOptions {
+            model: Some(model.name.clone()),
+            persistent,
+            ..Default::default()
+        };
+        self.create_state(dst.clone(), options).await?;
+        self.set_state(&dst, merged, FedTokens::Reset)
+    }
+
+    /// Creates a state from the data encoded by `state_file`, which must match the layout
+    /// of the model. Nothing is created if it doesn't.
+    pub async fn load_state(
+        &self,
+        id: String,
+        model: Option<String>,
+        persistent: bool,
+        bytes: &[u8],
+    ) -> Result<()> {
+        let layout = StateLayout::of(&self.model(model.as_deref())?);
+        let state = state_file::decode_state(bytes, &layout)?;
+        let options = StateOptions {
+            model,
+            persistent,
+            ..Default::default()
+        };
+        self.create_state(id.clone(), options).await?;
+        // Held like an infer, so a command racing to feed the new state fails with
+        // `state_busy` instead of being dropped by the loaded data
+        let _locks = self.lock_states(std::slice::from_ref(&id)).await?;
+        self.set_state(&id, state, FedTokens::Reset)
+    }
+
+    /// The path of a state file in the state directory. Names are kept to a single plain
+    /// component, so a file can't escape the directory.
+    pub fn state_path(&self, name: &str) -> Result<PathBuf> {
+        let dir = self.0.state_dir.as_ref().ok_or(
+            CommandErrorKind::BadRequest
+                .error("State files are disabled, set --state-dir to enable them!"),
+        )?;
+        let valid = !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\']);
+        if !valid {
+            return Err(CommandErrorKind::BadRequest
+                .error(format!("{:?} is not a valid state file name!", name)));
+        }
+        Ok(dir.join(name))
+    }
+
+    /// States whose ids start with `prefix`, sorted by id. Temporary states are left out.
+    pub fn list_states(&self, prefix: &str) -> Vec<StateInfo> {
+        let mut states: Vec<StateInfo> = self
+            .0
+            .infer_states
+            .iter()
+            .filter(|x| x.key().starts_with(prefix) && !x.key().starts_with(TEMPORARY_PREFIX))
+            .map(|x| self.state_info(x.key(), x.value()))
+            .collect();
+        states.sort_unstable_by(|x, y| x.id.cmp(&y.id));
+        states
+    }
+
+    /// The metadata of a state.
+    pub fn get_state(&self, id: &str) -> Result<StateInfo> {
+        let infer_state = self
+            .0
+            .infer_states
+            .get(id)
+            .ok_or_else(|| self.missing_state(id))?;
+        Ok(self.state_info(id, &infer_state))
+    }
+
+    fn state_info(&self, id: &str, infer_state: &InferState) -> StateInfo {
+        let bytes = self
+            .0
+            .models
+            .get(&infer_state.model)
+            .map(|model| StateLayout::of(model).data_len() * std::mem::size_of::<f32>())
+            .unwrap_or_default();
+        // Checkpoints sharing the data of the state, e.g. right after a rollback, take no
+        // more memory
+        let checkpoints = infer_state
+            .checkpoints
+            .iter()
+            .filter_map(|x| x.state.as_ref())
+            .filter(|x| {
+                !infer_state
+                    .state
+                    .as_ref()
+                    .is_some_and(|y| Arc::ptr_eq(&x.0, &y.0))
+            })
+            .count();
+        StateInfo {
+            id: id.to_string(),
+            model: infer_state.model.clone(),
+            persistent: infer_state.owner.is_none(),
+            age_ms: infer_state.created.elapsed().as_millis() as u64,
+            idle_ms: infer_state.used.elapsed().as_millis() as u64,
+            ttl_seconds: infer_state.ttl.map(|x| x.as_secs()),
+            tokens: infer_state.tokens,
+            bytes: bytes * (1 + checkpoints),
+            spilled: infer_state.spilled.is_some(),
+            pinned: infer_state.pinned,
+            slot_pinned: infer_state.slot_pinned,
+            checkpoints: infer_state
+                .checkpoints
+                .iter()
+                .map(|x| x.name.clone())
+                .collect(),
+            track_history: infer_state.tracked.is_some(),
+        }
+    }
+
+    /// The last `last` tokens fed to a state, or all kept ones if omitted. Fails if the
+    /// state doesn't track its history.
+    pub fn get_state_history(&self, id: &str, last: Option<usize>) -> Result<StateHistory> {
+        let infer_state = self
+            .0
+            .infer_states
+            .get(id)
+            .ok_or_else(|| self.missing_state(id))?;
+        let tracked = infer_state.tracked.as_ref().ok_or_else(|| {
+            CommandErrorKind::BadRequest.error(format!(
+                "State {} doesn't track its history, create it with track_history!",
+                id
+            ))
+        })?;
+        let skip = tracked
+            .tokens
+            .len()
+            .saturating_sub(last.unwrap_or(usize::MAX));
+        let tokens: Vec<u16> = tracked.tokens.iter().skip(skip).copied().collect();
+        Ok(StateHistory {
+            id: id.to_string(),
+            start: infer_state.tokens.saturating_sub(tokens.len()),
+            tokens,
+        })
+    }
+
+    /// Refreshes the idle time of a state, and replaces its TTL if `ttl_seconds` is given
+    /// (0 to never expire).
+    pub fn touch_state(&self, id: &str, ttl_seconds: Option<u64>) -> Result<()> {
+        let mut infer_state = self
+            .0
+            .infer_states
+            .get_mut(id)
+            .ok_or_else(|| self.missing_state(id))?;
+        infer_state.used = Instant::now();
+        if let Some(seconds) = ttl_seconds {
+            infer_state.ttl = (seconds > 0).then(|| Duration::from_secs(seconds));
+        }
+        Ok(())
+    }
+
+    /// Deletes the states which stayed idle beyond their TTL, returning their ids. They
+    /// are remembered for a while, so using them tells they expired.
+    pub fn expire_states(&self) -> Vec<String> {
+        let mut expired = Vec::new();
+        self.0.infer_states.retain(|id, state| {
+            let alive = !state.ttl.is_some_and(|ttl| state.used.elapsed() >= ttl);
+            if !alive {
+                self.release_slot(id, state);
+                expired.push(id.clone());
+            }
+            alive
+        });
+        for id in &expired {
+            self.0.generations.remove(id);
+            self.0
+                .removed
+                .insert(id.clone(), (Removal::Expired, Instant::now()));
+        }
         self.0
+            .removed
+            .retain(|_, (_, removed)| removed.elapsed() < REMOVED_RECORD_LIFETIME);
+        expired
+    }
+
+    /// Spills the least recently used states once more than `max_resident_states` are in
+    /// memory, returning how many are spilled. Temporary states are never spilled.
+    pub async fn spill_states(&self) -> Result<usize> {
+        let spill = match &self.0.spill {
+            Some(spill) => spill,
+            None => return Ok(0),
+        };
+        let mut resident: Vec<(String, Instant)> = self
+            .0
+            .infer_states
+            .iter()
+            .filter(|x| x.state.is_some() && !x.key().starts_with(TEMPORARY_PREFIX))
+            .map(|x| (x.key().clone(), x.used))
+            .collect();
+        if resident.len() <= spill.max_resident {
+            return Ok(0);
+        }
+        resident.sort_unstable_by_key(|(_, used)| *used);
+        let excess = resident.len() - spill.max_resident;
+
+        let mut spilled = 0;
+        for (id, _) in resident.into_iter().take(excess) {
+            let (model, state) = match self
+                .0
+                .infer_states
+                .get(&id)
+                .and_then(|x| Some((x.model.clone(), x.state.clone()?)))
+            {
+                Some(resident) => resident,
+                None => continue,
+            };
+            let layout = StateLayout::of(&self.model(Some(&model))?);
+            let file = spill.file();
+            tokio::fs::write(file.path(), state_file::encode_state(&layout, &state)).await?;
+            if let Some(mut infer_state) = self.0.infer_states.get_mut(&id) {
+                // Unless the state is inferred or replaced while it's written, in which
+                // case the file is deleted as it's dropped
+                if infer_state
+                    .state
+                    .as_ref()
+                    .is_some_and(|x| Arc::ptr_eq(&x.0, &state.0))
+                {
+                    infer_state.state = None;
+                    infer_state.spilled = Some(Arc::new(file));
+                    spill.record_spill();
+                    spilled += 1;
+                }
+            }
+        }
+        Ok(spilled)
+    }
+
+    /// Reads the data of spilled states back into memory.
+    async fn reload_states(&self, ids: &[String]) -> Result<()> {
+        for id in ids {
+            let (model, file) = match self
+                .0
+                .infer_states
+                .get(id)
+                .and_then(|x| Some((x.model.clone(), x.spilled.clone()?)))
+            {
+                Some(spilled) => spilled,
+                None => continue,
+            };
+            let layout = StateLayout::of(&self.model(Some(&model))?);
+            let state = state_file::decode_state(&tokio::fs::read(file.path()).await?, &layout)?;
+            if let Some(mut infer_state) = self.0.infer_states.get_mut(id) {
+                if infer_state
+                    .spilled
+                    .as_ref()
+                    .is_some_and(|x| Arc::ptr_eq(x, &file))
+                {
+                    infer_state.state = Some(state);
+                    infer_state.spilled = None;
+                    self.record_reload();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn record_reload(&self) {
+        if let Some(spill) = &self.0.spill {
+            spill.record_reload();
+        }
+    }
+
+    /// Counts of resident and spilled states, if spilling is enabled.
+    pub fn spill_stats(&self) -> Option<SpillStats> {
+        let spill = self.0.spill.as_ref()?;
+        let (mut resident, mut spilled) = (0, 0);
+        for x in self.0.infer_states.iter() {
+            resident += x.state.is_some() as usize;
+            spilled += x.spilled.is_some() as usize;
+        }
+        Some(spill.stats(resident, spilled))
+    }
+
+    /// Makes room for `count` new states under `--max-states` by evicting the least
+    /// recently used states which are not pinned, nor the `source` being copied. Temporary
+    /// states are never counted.
+    fn make_room(&self, count: usize, source: Option<&str>) -> Result<()> {
+        if self.0.max_states == 0 {
+            return Ok(());
+        }
+        if count > self.0.max_states {
+            return Err(CommandErrorKind::BadRequest.error(format!(
+                "Can't create {} states, which is more than --max-states {}!",
+                count, self.0.max_states
+            )));
+        }
+        let mut states: Vec<(String, bool, Instant)> = self
+            .0
+            .infer_states
+            .iter()
+            .filter(|x| !x.key().starts_with(TEMPORARY_PREFIX))
+            .map(|x| (x.key().clone(), x.pinned, x.used))
+            .collect();
+        let excess = (states.len() + count).saturating_sub(self.0.max_states);
+        if excess == 0 {
+            return Ok(());
+        }
+        states.retain(|(id, pinned, _)| !pinned && Some(id.as_str()) != source);
+        if states.len() < excess {
+            return Err(CommandErrorKind::BadRequest.error(format!(
+                "--max-states {} is reached, and the states are pinned!",
+                self.0.max_states
+            )));
+        }
+        states.sort_unstable_by_key(|(_, _, used)| *used);
+        for (id, _, _) in states.into_iter().take(excess) {
+            if let Some((_, state)) = self.0.infer_states.remove(&id) {
+                self.release_slot(&id, &state);
+            }
+            self.0.generations.remove(&id);
+            self.0
+                .removed
+                .insert(id.clone(), (Removal::Evicted, Instant::now()));
+            println!("State {} evicted.", id);
+        }
+        Ok(())
+    }
+
+    /// The error for a state which doesn't exist, telling if the server deleted it.
+    pub fn missing_state(&self, id: &str) -> Error {
+        match self.0.removed.get(id).map(|x| *x) {
+            Some((Removal::Expired, removed)) => CommandErrorKind::StateExpired.error(format!(
+                "State {} expired {} seconds ago!",
+                id,
+                removed.elapsed().as_secs()
+            )),
+            Some((Removal::Evicted, removed)) => CommandErrorKind::StateEvicted.error(format!(
+                "State {} was evicted {} seconds ago to stay within --max-states!",
+                id,
+                removed.elapsed().as_secs()
+            )),
+            None => CommandErrorKind::StateNotFound.error(format!("State {} doesn't exist!", id)),
+        }
+    }
+
+    pub async fn delete_state(&self, id: String) -> Result<()> {
+        self.0.generations.remove(&id);
+        let (_, state) = self
+            .0
             .infer_states
             .remove(&id)
-            .ok_or(Error::msg("State doesn't exist!"))
-            .map(|_| ())
+            .ok_or_else(|| self.missing_state(&id))?;
+        self.release_slot(&id, &state);
+        Ok(())
+    }
+
+    /// Deletes all states whose ids match, returning the deleted ids sorted. Temporary
+    /// states are left out.
+    pub fn delete_states(&self, matches: impl Fn(&str) -> bool) -> Vec<String> {
+        let mut deleted = Vec::new();
+        self.0.infer_states.retain(|id, state| {
+            let matched = !id.starts_with(TEMPORARY_PREFIX) && matches(id);
+            if matched {
+                self.release_slot(id, state);
+                deleted.push(id.clone());
+            }
+            !matched
+        });
+        self.forget_generations(&deleted);
+        deleted.sort_unstable();
+        deleted
+    }
+
+    /// Pins the slot of a state in the batch of its model, so the state stays loaded
+    /// between its infers until it's unpinned. Fails if the slots the model allows to pin
+    /// by `max_pinned_slots` are taken.
+    pub async fn pin_state(&self, id: &str, pin: bool) -> Result<()> {
+        let model = self
+            .0
+            .infer_states
+            .get(id)
+            .map(|x| x.model.clone())
+            .ok_or_else(|| self.missing_state(id))?;
+        let model = self.model(Some(&model))?;
+        if pin && model.max_pinned == 0 {
+            return Err(CommandErrorKind::BadRequest.error(format!(
+                "Model {} allows no pinned slots, which is set by max_pinned_slots!",
+                model.name
+            )));
+        }
+        model.pin(id.to_string(), pin).await?;
+        let found = match self.0.infer_states.get_mut(id) {
+            Some(mut infer_state) => {
+                infer_state.slot_pinned = pin;
+                true
+            }
+            None => false,
+        };
+        if !found {
+            // Deleted while pinning, so nothing unpins it later
+            model.pin(id.to_string(), false).await?;
+            return Err(self.missing_state(id));
+        }
+        Ok(())
+    }
+
+    /// Unpins the slot of a removed state, which the pipeline keeps for it otherwise.
+    fn release_slot(&self, id: &str, infer_state: &InferState) {
+        if !infer_state.slot_pinned {
+            return;
+        }
+        if let Some(model) = self.0.models.get(&infer_state.model).cloned() {
+            let id = id.to_string();
+            tokio::spawn(async move { model.pin(id, false).await.ok() });
+        }
+    }
+
+    /// Records the last generation for each state of its pipeline.
+    pub fn record_generation(&self, record: GenerationRecord) {
+        for id in &record.pipeline.states {
+            self.0.generations.insert(id.clone(), record.clone());
+        }
+    }
+
+    pub fn last_generation(&self, id: &str) -> Result<GenerationRecord> {
+        self.0.generations.get(id).map(|x| x.value().clone()).ok_or(
+            CommandErrorKind::BadRequest
+                .error(format!("State {} has no generation to continue!", id)),
+        )
+    }
+
+    pub fn forget_generations(&self, ids: &[String]) {
+        for id in ids {
+            self.0.generations.remove(id);
+        }
+    }
+
+    /// The longest time any of the states waited for a batch slot since the last call,
+    /// which starts over for each of them.
+    pub fn take_queued(&self, ids: &[String]) -> Duration {
+        ids.iter()
+            .filter_map(|id| self.0.infer_states.get_mut(id))
+            .map(|mut x| std::mem::take(&mut x.queued))
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Locks the states a command is about to advance until the returned guards are
+    /// dropped, so two commands never feed the same state at once. Waits up to
+    /// `state_lock_timeout` for the states other commands hold, then fails with
+    /// `state_busy`. Temporary states belong to their command and needn't be locked.
+    pub async fn lock_states(&self, ids: &[String]) -> Result<Vec<OwnedMutexGuard<()>>> {
+        let mut ids: Vec<&String> = ids.iter().collect();
+        // Always locked in the same order, so commands waiting for each other can't deadlock
+        ids.sort_unstable();
+        ids.dedup();
+        let locks = ids
+            .iter()
+            .map(|id| {
+                self.0
+                    .infer_states
+                    .get(*id)
+                    .map(|x| x.lock.0.clone())
+                    .ok_or_else(|| self.missing_state(id))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let deadline = tokio::time::Instant::now() + self.0.state_lock_timeout;
+        let mut guards = Vec::with_capacity(locks.len());
+        for (id, lock) in ids.into_iter().zip(locks) {
+            // A free lock is taken even if the timeout is 0, since it's tried before the
+            // deadline is checked
+            match tokio::time::timeout_at(deadline, lock.lock_owned()).await {
+                Ok(guard) => guards.push(guard),
+                Err(_) => {
+                    return Err(CommandErrorKind::StateBusy.error(format!(
+                        "State {} is being advanced by another command!",
+                        id
+                    )))
+                }
+            }
+        }
+        Ok(guards)
     }
 
     pub fn tokenize(&self, input: &Vec<u8>) -> Result<Vec<u16>> {
@@ -108,56 +1484,282 @@ impl AppState {
         state_keys: Vec<String>,
         token_vecs: Vec<Vec<u16>>,
     ) -> Result<Vec<Logits>> {
-        let states = state_keys
-            .iter()
-            .map(|key| {
-                self.0
-                    .infer_states
-                    .get(key)
-                    .ok_or(Error::msg(format!("State {} doesn't exist!", key)))
+        Ok(self
+            .infer_states(state_keys, token_vecs, false, None)
+            .await?
+            .into_iter()
+            .map(|(logits, _)| logits)
+            .collect())
+    }
+
+    /// Feeds tokens to a state and keeps the logits after them, which `next_logits` gives
+    /// back until anything else is fed to the state.
+    pub async fn feed_next(&self, id: &str, tokens: Vec<u16>) -> Result<Logits> {
+        let logits = self
+            .infer(vec![id.to_string()], vec![tokens])
+            .await?
+            .remove(0);
+        if let Some(mut infer_state) = self.0.infer_states.get_mut(id) {
+            infer_state.next_logits = Some((infer_state.tokens, logits.clone()));
+        }
+        Ok(logits)
+    }
+
+    /// The logits kept by `feed_next`, unless the state is fed or replaced since.
+    pub fn next_logits(&self, id: &str) -> Result<Option<Logits>> {
+        let infer_state = self
+            .0
+            .infer_states
+            .get(id)
+            .ok_or_else(|| self.missing_state(id))?;
+        Ok(match &infer_state.next_logits {
+            Some((tokens, logits)) if *tokens == infer_state.tokens => Some(logits.clone()),
+            _ => None,
+        })
+    }
+
+    /// Same as `infer`, but also returns the state after the tokens are inferred.
+    pub async fn infer_snapshot(
+        &self,
+        state_keys: Vec<String>,
+        token_vecs: Vec<Vec<u16>>,
+    ) -> Result<Vec<(Logits, State)>> {
+        self.infer_states(state_keys, token_vecs, true, None)
+            .await?
+            .into_iter()
+            .map(|(logits, state)| {
+                state
+                    .map(|state| (logits, state))
+                    .ok_or(CommandErrorKind::Internal.error("State snapshot is missing!"))
             })
-            .collect::<Result<Vec<_>>>()?;
+            .collect()
+    }
 
-        let requests = states
+    /// Checks that all token ids are in the vocab of the model, since an id out of it would
+    /// panic deep in the inference.
+    pub fn validate_tokens(&self, model: &AxumModel, token_vecs: &[Vec<u16>]) -> Result<()> {
+        let num_vocab = model.info().num_vocab;
+        match token_vecs
+            .iter()
+            .flatten()
+            .find(|&&token| token as usize >= num_vocab)
+        {
+            Some(token) => Err(CommandErrorKind::BadRequest.error(format!(
+                "Token id {} is out of the vocab of model {}, which has {} tokens!",
+                token, model.name, num_vocab
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    async fn infer_states(
+        &self,
+        state_keys: Vec<String>,
+        token_vecs: Vec<Vec<u16>>,
+        snapshot: bool,
+        chunk_size: Option<usize>,
+    ) -> Result<Vec<(Logits, Option<State>)>> {
+        let model = self.state_model(&state_keys)?;
+        self.validate_tokens(&model, &token_vecs)?;
+        self.reload_states(&state_keys).await?;
+        let cache = &self.0.prefix_cache;
+
+        // Fresh states fed with a cached prompt skip the pipeline, and those fed with a
+        // prompt starting with a cached prefix only infer the rest of it
+        let mut results: Vec<Option<(Logits, Option<State>)>> = vec![None; state_keys.len()];
+        // Counted once the tokens are inferred, so a failed infer counts nothing
+        let mut fed = vec![Vec::new(); state_keys.len()];
+        let mut requests = Vec::with_capacity(state_keys.len());
+        let mut pending = Vec::with_capacity(state_keys.len());
+        for (index, (key, tokens)) in state_keys.iter().zip(token_vecs.into_iter()).enumerate() {
+            let mut infer_state = loop {
+                match self.0.infer_states.get_mut(key) {
+                    Some(infer_state) if infer_state.spilled.is_none() => break infer_state,
+                    Some(_) => {}
+                    None => return Err(self.missing_state(key)),
+                }
+                // Spilled again since reloaded, which is rare, so it's read again without
+                // holding the entry
+                self.reload_states(std::slice::from_ref(key)).await?;
+            };
+            fed[index] = tokens.clone();
+            // A state beyond its context limit is rebuilt from scratch with the latest tokens
+            let (tokens, rebuild) = match infer_state.feed(&tokens) {
+                Some(tokens) => (tokens, true),
+                None => (tokens, false),
+            };
+            infer_state.used = Instant::now();
+            let fresh = std::mem::replace(&mut infer_state.fresh, false) && !rebuild;
+            if rebuild {
+                infer_state.state = None;
+                infer_state.spilled = None;
+                infer_state.reload = true;
+                infer_state.generation += 1;
+            }
+            let prefix = fresh.then(|| cache.get(&model, &tokens)).flatten();
+            if let Some((len, state, logits)) = &prefix {
+                if *len == tokens.len() {
+                    infer_state.state = Some(state.clone());
+                    infer_state.spilled = None;
+                    infer_state.advance(&fed[index]);
+                    results[index] = Some((logits.clone(), snapshot.then(|| state.clone())));
+                    continue;
+                }
+            }
+            let cached = fresh && cache.is_enabled();
+            let reload = std::mem::replace(&mut infer_state.reload, false);
+            let (state, offset, reload) = match prefix {
+                // Continues from the cached state, which the pipeline doesn't hold
+                Some((len, state, _)) => (Some(state), len, true),
+                None => (infer_state.state.clone(), 0, reload),
+            };
+            pending.push((
+                index,
+                key.clone(),
+                infer_state.generation,
+                cached.then(|| (tokens.clone(), offset)),
+            ));
+            requests.push(InferContext {
+                state,
+                tokens: tokens[offset..].to_vec(),
+                snapshot: snapshot || cached,
+                reload,
+            });
+        }
+
+        // Long prompts are fed in chunks, each in a separate batch, so they don't hold a
+        // slot for the whole prompt. States between chunks are sent back and loaded again
+        let chunk_size = chunk_size.unwrap_or_else(|| model.spec.get_token_chunk_size());
+        let mut requests: Vec<_> = requests
             .into_iter()
-            .zip(token_vecs.into_iter())
-            .map(|(state, tokens)| InferContext {
-                state: state.clone(),
-                tokens,
+            .map(|mut context| {
+                let chunks: VecDeque<Vec<u16>> = std::mem::take(&mut context.tokens)
+                    .chunks(chunk_size)
+                    .map(|x| x.to_vec())
+                    .collect();
+                (context, chunks)
             })
+            .zip(pending.into_iter())
             .collect();
 
-        let mut senders = Vec::with_capacity(state_keys.len());
-        for key in state_keys.clone() {
-            let cloned = self.clone();
-            let (sender, receiver) = oneshot::channel();
-            tokio::spawn(async move {
-                // When `None` is returned, a new infer callback
-                // replaces current infer callback, so no need
-                // to update state
-                if let Ok(Some(result)) = receiver.await {
-                    cloned.0.infer_states.insert(key, Some(result));
+        while !requests.is_empty() {
+            let mut senders = Vec::with_capacity(requests.len());
+            let mut contexts = Vec::with_capacity(requests.len());
+            for ((context, chunks), (_, key, generation, prompt)) in requests.iter_mut() {
+                let (key, generation) = (key.clone(), *generation);
+                let cloned = self.clone();
+                let (sender, receiver) = oneshot::channel();
+                tokio::spawn(async move {
+                    // When `None` is returned, a new infer callback
+                    // replaces current infer callback, so no need
+                    // to update state
+                    if let Ok(Some(result)) = receiver.await {
+                        if let Some(mut state) = cloned.0.infer_states.get_mut(&key) {
+                            // The state is replaced by `set_state` since
+                            if state.generation == generation {
+                                state.state = Some(result);
+                                state.spilled = None;
+                            }
+                        }
+                    }
+                });
+                senders.push(sender);
+
+                let tokens = chunks.pop_front().unwrap_or_default();
+                if let Some((_, offset)) = prompt {
+                    *offset += tokens.len();
                 }
-            });
-            senders.push(sender)
+                contexts.push(InferContext {
+                    state: context.state.clone(),
+                    tokens,
+                    snapshot: context.snapshot || !chunks.is_empty(),
+                    reload: context.reload,
+                });
+            }
+
+            let keys = requests
+                .iter()
+                .map(|(_, (_, key, _, _))| key.clone())
+                .collect();
+            let inferred = model.infer(contexts, keys, senders).await?;
+
+            let mut remaining = Vec::with_capacity(requests.len());
+            for (((mut context, chunks), (index, key, generation, prompt)), result) in
+                requests.into_iter().zip(inferred.into_iter())
+            {
+                let InferResult {
+                    logits,
+                    state,
+                    queued,
+                } = result;
+                if let Some(mut infer_state) = self.0.infer_states.get_mut(&key) {
+                    infer_state.queued += queued;
+                }
+                if !chunks.is_empty() {
+                    // Each chunk boundary of the prompt is cached too, so prompts sharing
+                    // a prefix up to it skip that part
+                    if let (Some((prompt, offset)), Some(state)) = (&prompt, &state) {
+                        let tokens = prompt[..*offset].to_vec();
+                        cache.insert(&model, tokens, state.clone(), logits.clone());
+                    }
+                    // The next chunk continues from exactly this state
+                    context.state = state;
+                    context.reload = true;
+                    remaining.push(((context, chunks), (index, key, generation, prompt)));
+                    continue;
+                }
+                if let Some(mut infer_state) = self.0.infer_states.get_mut(&key) {
+                    // Unless the state is replaced by `set_state` since
+                    if infer_state.generation == generation {
+                        infer_state.advance(&fed[index]);
+                    }
+                }
+                if let (Some((prompt, _)), Some(state)) = (prompt, &state) {
+                    cache.insert(&model, prompt, state.clone(), logits.clone());
+                }
+                results[index] = Some((logits, state.filter(|_| snapshot)));
+            }
+            requests = remaining;
         }
 
-        let results = InferRequest::send(
-            requests,
-            self.0.infer_queue.clone(),
-            state_keys.clone(),
-            senders,
-        )
-        .await?;
+        Ok(results.into_iter().flatten().collect())
+    }
+}
 
-        Ok(results
-            .into_iter()
-            .map(|InferResult { logits }| logits)
-            .collect())
+/// A running command registered by `AppState::register_command`.
+pub struct CommandHandle {
+    state: AppState,
+    echo_id: String,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CommandHandle {
+    #[inline(always)]
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for CommandHandle {
+    fn drop(&mut self) {
+        // Another command may have reused the echo_id, which must be kept
+        self.state
+            .0
+            .running_commands
+            .remove_if(&(self.state.1, self.echo_id.clone()), |_, cancelled| {
+                Arc::ptr_eq(cancelled, &self.cancelled)
+            });
     }
+}
+
+/// A state created by `AppState::create_temporary_state`.
+pub struct TemporaryState {
+    state: AppState,
+    pub id: String,
+}
 
-    /// This must not fail, or the implementation is severly bugged
-    pub async fn softmax(&self, logits: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
-        Softmax::softmax(logits, self.0.softmax_queue.clone()).await
+impl Drop for TemporaryState {
+    fn drop(&mut self) {
+        self.state.0.infer_states.remove(&self.id);
     }
 }
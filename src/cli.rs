@@ -3,11 +3,13 @@ use std::{
     io::{BufReader, Read},
     net::{SocketAddr, SocketAddrV4},
     path::PathBuf,
+    time::Duration,
 };
 
-use crate::config::ModelConfig;
+use crate::config::{ModelConfig, ModelSpec};
 use anyhow::{Ok, Result};
-use clap::Parser;
+use clap::{Parser, ValueEnum};
+use web_rwkv::wgpu::Backends;
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
@@ -27,6 +29,185 @@ pub struct LaunchArgs {
     /// The port to listen on
     #[arg(default_value_t = 5678)]
     port: u16,
+
+    /// Seconds between pings to a WebSocket client, which is closed if it doesn't respond
+    /// before the next ping. 0 to disable
+    #[arg(long, value_name = "SECONDS", default_value_t = 30)]
+    ws_ping_interval: u64,
+
+    /// Seconds before a WebSocket connection without any running command is closed. 0 to
+    /// disable
+    #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+    ws_idle_timeout: u64,
+
+    /// How many prompts fed to fresh states are cached, so fresh states fed with the same
+    /// prompt skip the inference. 0 to disable
+    #[arg(long, value_name = "COUNT", default_value_t = 0)]
+    prefix_cache_size: usize,
+
+    /// How many idempotency keys of commands are remembered with their responses, so a
+    /// retried command with the same key gets the original response. 0 to disable
+    #[arg(long, value_name = "COUNT", default_value_t = 1024)]
+    idempotency_cache_size: usize,
+
+    /// Seconds the results of a resumable command are kept after it's done, so a client
+    /// losing its connection can get them by `resume`. 0 to disable resumable commands
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    resume_window_secs: u64,
+
+    /// Max softmax requests computed in one batch. 0 to use the batch size of each model
+    #[arg(long, value_name = "COUNT", default_value_t = 0)]
+    softmax_batch_size: usize,
+
+    /// Milliseconds to wait for more softmax requests before computing a partial batch. 0
+    /// to only batch requests which are already queued
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 0)]
+    softmax_batch_timeout_ms: u64,
+
+    /// Lower bound of the batch size of each model. If set, the batch size starts here and
+    /// is adjusted at runtime: it backs off when a run fails to allocate and ramps up while
+    /// the latency is stable. Otherwise the batch size is fixed
+    #[arg(long, value_name = "COUNT")]
+    min_batch: Option<usize>,
+
+    /// Upper bound of the batch size of each model, which is how many slots are allocated.
+    /// Defaults to `max_batch_count` of the model
+    #[arg(long, value_name = "COUNT")]
+    max_batch: Option<usize>,
+
+    /// Times a failed run of the model is retried, after the batch size can't be lowered
+    /// any more. The slots in each run are backed up to restore them after a failure, which
+    /// costs a download of their states per run. 0 to fail the run right away, which resets
+    /// the states in it
+    #[arg(long, value_name = "COUNT", default_value_t = 0)]
+    run_retries: usize,
+
+    /// Milliseconds to wait before the first retry of a failed run, doubled for every
+    /// further retry
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 100)]
+    run_retry_backoff_ms: u64,
+
+    /// The path to a draft model for speculative decoding, loaded with the settings of
+    /// `[model]`
+    #[arg(long, value_name = "PATH")]
+    draft_model: Option<PathBuf>,
+
+    /// Tokens proposed by the draft model in each round of speculative decoding
+    #[arg(long, value_name = "COUNT", default_value_t = 4)]
+    draft_tokens: usize,
+
+    /// Tokens each state keeps in its context by default. Once exceeded, the state is
+    /// rebuilt from the latest half of them. 0 for no limit
+    #[arg(long, value_name = "COUNT", default_value_t = 0)]
+    max_context: usize,
+
+    /// Most states kept at once. Creating a state beyond it evicts the least recently used
+    /// state which is not pinned. 0 for no limit
+    #[arg(long, value_name = "COUNT", default_value_t = 0)]
+    max_states: usize,
+
+    /// Most checkpoints kept by each state, beyond which `checkpoint_state` drops the
+    /// oldest one. 0 to disable checkpoints
+    #[arg(long, value_name = "COUNT", default_value_t = 8)]
+    max_checkpoints: usize,
+
+    /// Most tokens kept by each state created with `track_history`, beyond which the oldest
+    /// ones are dropped. 0 to disable token history
+    #[arg(long, value_name = "COUNT", default_value_t = 4096)]
+    history_size: usize,
+
+    /// Milliseconds a command waits for a state another command is advancing (e.g. an
+    /// infer still running with it) before failing with `state_busy`. 0 to fail right away
+    #[arg(long, value_name = "MILLISECONDS", default_value_t = 0)]
+    state_lock_timeout_ms: u64,
+
+    /// The directory where `save_state` writes and `load_state` reads state files. State
+    /// files are disabled if not given, though states can still be moved by `dump_state`
+    #[arg(long, value_name = "PATH")]
+    state_dir: Option<PathBuf>,
+
+    /// Warm up every model with a dummy token at startup, and report unhealthy on /health
+    /// until it's done
+    #[arg(long)]
+    warmup: bool,
+
+    /// Index of the GPU adapter every model is loaded on, overriding `adapter` and
+    /// `preference` of the models in the config. The available adapters are listed if the
+    /// index is out of range
+    #[arg(long, value_name = "INDEX")]
+    adapter: Option<usize>,
+
+    /// Only select from the adapters of this graphics backend, which `--adapter` indexes
+    #[arg(long, value_enum)]
+    backend: Option<Backend>,
+}
+
+/// Graphics backends which adapters can be selected from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    Vulkan,
+    Dx12,
+    Metal,
+    Gl,
+}
+
+impl Backend {
+    pub fn to_wgpu(self) -> Backends {
+        match self {
+            Self::Vulkan => Backends::VULKAN,
+            Self::Dx12 => Backends::DX12,
+            Self::Metal => Backends::METAL,
+            Self::Gl => Backends::GL,
+        }
+    }
+}
+
+/// Overrides of the adapter each model is loaded on.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeviceConfig {
+    pub adapter: Option<usize>,
+    pub backend: Option<Backend>,
+}
+
+/// Settings of WebSocket connections.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WsConfig {
+    pub ping_interval: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Settings of the softmax worker of each model.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SoftmaxConfig {
+    /// Falls back to the batch size of the model if not set.
+    pub batch_size: Option<usize>,
+    pub batch_timeout: Duration,
+}
+
+/// Bounds of the batch size of each model.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BatchConfig {
+    /// The batch size is fixed to the upper bound if not set.
+    pub min: Option<usize>,
+    /// Falls back to the batch size of the model if not set.
+    pub max: Option<usize>,
+}
+
+/// Retries of a failed run of the model.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RetryConfig {
+    pub retries: usize,
+    /// Wait before the first retry, doubled for every further retry.
+    pub backoff: Duration,
+}
+
+impl BatchConfig {
+    /// The lower and upper bounds of the batch size, given the batch size of the model.
+    pub fn bounds(&self, batch_size: usize) -> (usize, usize) {
+        let max = self.max.unwrap_or(batch_size).max(1);
+        let min = self.min.unwrap_or(max).clamp(1, max);
+        (min, max)
+    }
 }
 
 impl LaunchArgs {
@@ -41,6 +222,93 @@ impl LaunchArgs {
         )))
     }
 
+    pub fn get_ws_config(&self) -> WsConfig {
+        let seconds = |x: u64| (x > 0).then(|| Duration::from_secs(x));
+        WsConfig {
+            ping_interval: seconds(self.ws_ping_interval),
+            idle_timeout: seconds(self.ws_idle_timeout),
+        }
+    }
+
+    pub fn get_prefix_cache_size(&self) -> usize {
+        self.prefix_cache_size
+    }
+
+    pub fn get_idempotency_cache_size(&self) -> usize {
+        self.idempotency_cache_size
+    }
+
+    pub fn get_resume_window(&self) -> Duration {
+        Duration::from_secs(self.resume_window_secs)
+    }
+
+    pub fn get_softmax_config(&self) -> SoftmaxConfig {
+        SoftmaxConfig {
+            batch_size: (self.softmax_batch_size > 0).then_some(self.softmax_batch_size),
+            batch_timeout: Duration::from_millis(self.softmax_batch_timeout_ms),
+        }
+    }
+
+    pub fn get_batch_config(&self) -> BatchConfig {
+        BatchConfig {
+            min: self.min_batch,
+            max: self.max_batch,
+        }
+    }
+
+    pub fn get_retry_config(&self) -> RetryConfig {
+        RetryConfig {
+            retries: self.run_retries,
+            backoff: Duration::from_millis(self.run_retry_backoff_ms),
+        }
+    }
+
+    /// The spec of the draft model, if given.
+    pub fn get_draft_model(&self, config: &ModelConfig) -> Option<ModelSpec> {
+        self.draft_model
+            .as_ref()
+            .map(|path| config.model.with_path(path.clone()))
+    }
+
+    pub fn get_draft_tokens(&self) -> usize {
+        self.draft_tokens
+    }
+
+    pub fn get_max_context(&self) -> usize {
+        self.max_context
+    }
+
+    pub fn get_max_states(&self) -> usize {
+        self.max_states
+    }
+
+    pub fn get_max_checkpoints(&self) -> usize {
+        self.max_checkpoints
+    }
+
+    pub fn get_history_size(&self) -> usize {
+        self.history_size
+    }
+
+    pub fn get_state_lock_timeout(&self) -> Duration {
+        Duration::from_millis(self.state_lock_timeout_ms)
+    }
+
+    pub fn get_state_dir(&self) -> Option<PathBuf> {
+        self.state_dir.clone()
+    }
+
+    pub fn get_warmup(&self) -> bool {
+        self.warmup
+    }
+
+    pub fn get_device_config(&self) -> DeviceConfig {
+        DeviceConfig {
+            adapter: self.adapter,
+            backend: self.backend,
+        }
+    }
+
     pub fn get_config(&self) -> Result<ModelConfig> {
         let content = {
             let file = PathBuf::from(&self.config);
@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{app::AppState, error::CommandErrorKind};
+
+use super::{registry, types::CommandContext};
+
+/// How often `resume` checks whether it's cancelled while the command makes no progress.
+const RESUME_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[inline]
+pub async fn echo(data: Option<Value>, _state: AppState) -> Result<Value> {
+    Ok(data.unwrap_or(Value::Null))
+}
+
+pub async fn list_commands(_data: Option<Value>, _state: AppState) -> Result<Value> {
+    let commands: Vec<_> = registry()
+        .list()
+        .into_iter()
+        .map(|x| x.info(false))
+        .collect();
+    Ok(serde_json::to_value(commands)?)
+}
+
+pub async fn describe_command(data: Option<Value>, _state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let name = data.as_str().ok_or(
+            CommandErrorKind::BadRequest
+                .error("data should be a string representing the command you want to describe!"),
+        )?;
+        Ok(serde_json::to_value(registry().get(name)?.info(true))?)
+    } else {
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify command name!"))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ResumeCommand {
+    echo_id: String,
+    /// Partial results the client already got, which are not sent again.
+    #[serde(default)]
+    from: usize,
+}
+
+/// Reattaches to a resumable command by its echo_id, possibly sent by a closed connection.
+/// The buffered partial results after `from` are sent, followed by new ones until the
+/// command is done, whose final response is returned.
+pub async fn resume(
+    data: Option<Value>,
+    state: AppState,
+    context: CommandContext,
+) -> Result<Value> {
+    if let Some(data) = data {
+        let ResumeCommand { echo_id, mut from } = serde_json::from_value(data)?;
+        let progress = match state.0.resumable.get(&echo_id) {
+            Some(progress) => progress,
+            None => {
+                return Err(CommandErrorKind::BadRequest.error(format!(
+                    "No resumable command with echo_id {} is running or recently done!",
+                    echo_id
+                )))
+            }
+        };
+        loop {
+            if context.handle.is_cancelled() {
+                // Only detaches, the command itself keeps running
+                return Err(CommandErrorKind::Interrupted.error("Resume is cancelled!"));
+            }
+            let (partials, outcome) =
+                match tokio::time::timeout(RESUME_POLL_INTERVAL, progress.next(from)).await {
+                    Ok(next) => next,
+                    Err(_) => continue,
+                };
+            from += partials.len();
+            for partial in partials {
+                let _ = context.partial.send(partial);
+            }
+            if let Some(outcome) = outcome {
+                return outcome.replay();
+            }
+        }
+    } else {
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify the echo_id of the command to resume!"))
+    }
+}
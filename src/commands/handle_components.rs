@@ -0,0 +1,96 @@
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::{app::AppState, error::CommandErrorKind};
+
+#[derive(Debug, Deserialize)]
+struct ImportComponents {
+    #[serde(default)]
+    transformers: Map<String, Value>,
+    #[serde(default)]
+    samplers: Map<String, Value>,
+    #[serde(default)]
+    terminals: Map<String, Value>,
+    #[serde(default)]
+    normalizers: Map<String, Value>,
+    /// Deletes existing components with the same ids instead of failing.
+    #[serde(default)]
+    replace: bool,
+}
+
+/// Creates each component from its definition, and collects the result of each one.
+fn import_each(
+    definitions: Map<String, Value>,
+    replace: bool,
+    delete: impl Fn(&str) -> Result<()>,
+    create: impl Fn(String, Value) -> Result<()>,
+) -> Value {
+    Value::Object(
+        definitions
+            .into_iter()
+            .map(|(id, definition)| {
+                if replace {
+                    // The component may not exist yet
+                    delete(&id).ok();
+                }
+                let result = match create(id.clone(), definition) {
+                    Ok(_) => json!({ "status": "success" }),
+                    Err(e) => json!({ "status": "error", "error": e.to_string() }),
+                };
+                (id, result)
+            })
+            .collect::<Map<_, _>>(),
+    )
+}
+
+pub async fn export_components(_data: Option<Value>, state: AppState) -> Result<Value> {
+    Ok(json!({
+        "transformers": state.0.transformers.definitions(),
+        "samplers": state.0.samplers.definitions(),
+        "terminals": state.0.terminals.definitions(),
+        "normalizers": state.0.normalizers.definitions(),
+    }))
+}
+
+pub async fn import_components(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let ImportComponents {
+            transformers,
+            samplers,
+            terminals,
+            normalizers,
+            replace,
+        } = serde_json::from_value::<ImportComponents>(data)?;
+        let registries = &state.0;
+        Ok(json!({
+            "transformers": import_each(
+                transformers,
+                replace,
+                |id| registries.transformers.delete_transformer(id),
+                |id, x| registries.transformers.create_transformer(id, state.clone(), Some(x), None),
+            ),
+            "samplers": import_each(
+                samplers,
+                replace,
+                |id| registries.samplers.delete_sampler(id),
+                |id, x| registries.samplers.create_sampler(id, state.clone(), x, None),
+            ),
+            "terminals": import_each(
+                terminals,
+                replace,
+                |id| registries.terminals.delete_terminal(id),
+                |id, x| registries.terminals.create_terminal(id, state.clone(), x),
+            ),
+            "normalizers": import_each(
+                normalizers,
+                replace,
+                |id| registries.normalizers.delete_normalizer(id),
+                |id, x| registries.normalizers.create_normalizer(id, state.clone(), x),
+            ),
+        }))
+    } else {
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify the components to import!"))
+    }
+}
@@ -1,239 +1,1311 @@
-use anyhow::{Error, Result};
-use rayon::prelude::*;
+use std::{borrow::Cow, time::Duration};
+
+use anyhow::Result;
+use fastrand::Rng;
+use futures_util::future::try_join_all;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
+use serde_json::{Map, Value};
+use tokio::time::Instant;
 
-use crate::{app::AppState, commands::helpers, states::InferenceInterruption};
+use crate::{
+    app::{AppState, FedTokens, InferDefaults},
+    commands::{
+        helpers,
+        types::{CommandContext, PartialSender},
+    },
+    config::DRAFT_MODEL,
+    error::CommandErrorKind,
+    helper::Utf8Decoder,
+    states::{
+        beam_search::{BeamSearch, Search, Sequence},
+        guidance::Guidance,
+        model::AxumModel,
+        sample_pipeline::{Exhaustion, GenerationRecord, PipelineInterruption, SamplePipeline},
+        sampler::types::Sampled,
+        speculative::{Drafter, Speculation},
+        terminal::{stop_string::StopMatcher, types::Termination},
+    },
+};
 
 #[derive(Debug, Deserialize)]
 struct InferPayload {
+    /// The prompt of each state, which must be omitted if `template` is given.
+    #[serde(default)]
     tokens: Vec<Value>,
+    /// A template rendered with `variables` into the prompt of every state.
+    #[serde(default)]
+    template: Option<String>,
+    #[serde(default)]
+    variables: Map<String, Value>,
     states: Vec<String>,
-    transformers: Vec<Vec<String>>,
-    sampler: String,
+    /// The transformers of each state, which default to those set by `set_defaults`.
+    #[serde(default)]
+    transformers: Option<Vec<Vec<String>>>,
+    /// The sampler, which defaults to the one set by `set_defaults`.
+    #[serde(default)]
+    sampler: Option<String>,
+    #[serde(default)]
+    terminal: Option<String>,
+    /// Stop strings checked along with the terminal, trimmed from the output.
+    #[serde(default)]
+    stop: Vec<String>,
+    #[serde(default)]
+    normalizer: Option<String>,
+    #[serde(default)]
+    model: Option<String>,
+    /// Max tokens to sample, which defaults to (and can't exceed) the limit in config.
+    #[serde(default)]
+    max_tokens: Option<usize>,
+    /// Tokens to sample before the terminal may stop the infer.
+    #[serde(default)]
+    min_tokens: usize,
+    /// Tokens masked out until `min_tokens` are sampled, e.g. the end of text token.
+    #[serde(default)]
+    suppress_tokens: Vec<u16>,
     update_prompt: bool,
     reset_on_exhaustion: bool,
+    /// Sends the decoded text as partial results while generating.
+    #[serde(default)]
+    stream: bool,
+    /// Returns the log probability of each sampled token in `tokens` of the response.
+    #[serde(default)]
+    logprobs: bool,
+    /// Alternative tokens with the highest probabilities to include for each sampled
+    /// token, in partial results and `tokens` of the response.
+    #[serde(default)]
+    top_logprobs: usize,
+    /// Returns the ids of all sampled tokens in `token_ids` of the response.
+    #[serde(default)]
+    return_tokens: bool,
+    /// A state of the draft model to generate with speculative decoding.
+    #[serde(default)]
+    draft_state: Option<String>,
+    /// Generates with speculative decoding, drafting by n-gram lookup in the context.
+    #[serde(default)]
+    speculative: Option<SpeculativeOptions>,
+    /// Independent completions to generate from the same states. The response is an array
+    /// of them if more than 1.
+    #[serde(default = "default_n")]
+    n: usize,
+    /// Decodes the sampled tokens into `value`. If false, only `token_ids` are returned.
+    #[serde(default = "default_decode")]
+    decode: bool,
+    /// Seeds the randomness of sampling, so the same request generates the same tokens.
+    /// A random seed is picked if omitted.
+    #[serde(default)]
+    seed: Option<u64>,
+    /// How the tokens are picked, by the sampler or by beam search.
+    #[serde(default)]
+    mode: InferMode,
+    /// Beams to keep in beam search.
+    #[serde(default = "default_beams")]
+    beams: usize,
+    /// Returns all finished beams in `beams` of the response, not only the best one.
+    #[serde(default)]
+    return_beams: bool,
+    /// Milliseconds the infer may take, which defaults to the timeout in config.
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+    /// Measures the timing of the generation in `profile` of the response.
+    #[serde(default)]
+    profile: bool,
+    /// Classifier-free guidance against a negative state.
+    #[serde(default)]
+    cfg: Option<Guidance>,
+    /// Scales the logits before the normalizer and sampler, on top of the temperature of
+    /// the sampler.
+    #[serde(default)]
+    temperature: Option<f32>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum InferMode {
+    #[default]
+    Sample,
+    Beam,
+}
+
+#[derive(Debug, Deserialize)]
+struct SpeculativeOptions {
+    /// Tokens to draft in each round at most.
+    draft_len: usize,
+    /// The longest n-gram to look up.
+    #[serde(default = "default_max_ngram")]
+    max_ngram: usize,
+}
+
+fn default_max_ngram() -> usize {
+    3
+}
+
+fn default_n() -> usize {
+    1
+}
+
+fn default_beams() -> usize {
+    4
+}
+
+fn default_decode() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+struct ContinuePayload {
+    /// A state of the generation to continue.
+    state: String,
+    #[serde(default)]
+    max_tokens: Option<usize>,
+    #[serde(default)]
+    min_tokens: usize,
+    #[serde(default)]
+    suppress_tokens: Vec<u16>,
+    #[serde(default)]
+    stream: bool,
+    #[serde(default)]
+    logprobs: bool,
+    #[serde(default)]
+    top_logprobs: usize,
+    #[serde(default)]
+    return_tokens: bool,
+    #[serde(default = "default_decode")]
+    decode: bool,
+    #[serde(default)]
+    seed: Option<u64>,
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+    #[serde(default)]
+    profile: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct TopLogprob {
+    token: u16,
+    logprob: f32,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct InferPartial {
+    /// Text decoded since the last partial result, if decoding.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    /// Tokens sampled since the last partial result.
+    tokens: Vec<u16>,
+    /// Top alternatives of each state, for each token in `tokens`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    top_logprobs: Vec<Vec<Vec<TopLogprob>>>,
+}
+
+#[derive(Debug, Serialize)]
+struct TokenLogprob {
+    id: u16,
+    logprob: f32,
+    /// Top alternatives of each state.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    top: Vec<Vec<TopLogprob>>,
+}
+
+#[derive(Debug, Serialize)]
+struct InferResponse {
+    /// The decoded text, if decoding.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    last_token: u16,
+    /// Tokens sampled in this infer request.
+    inferred_tokens: usize,
+    /// One of `terminal`, `stop`, `timeout`, `repetition`, `exhaustion`, `max_tokens` or
+    /// `cancelled`.
+    stop_reason: &'static str,
+    /// The component which is exhausted, if `stop_reason` is `exhaustion`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exhaustion: Option<Exhaustion>,
+    /// Each sampled token, if `logprobs` or `top_logprobs` is requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tokens: Option<Vec<TokenLogprob>>,
+    /// Ids of all sampled tokens, including those not decoded into `value`, if
+    /// `return_tokens` is requested or not decoding.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_ids: Option<Vec<u16>>,
+    /// Ids of the tokens sampled for each state, if more than one state is inferred. The
+    /// other fields follow the first state.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state_tokens: Option<Vec<Vec<u16>>>,
+    /// The seed of the infer, which replays it if given in the request.
+    seed: u64,
+    /// Tokens sampled since the generation is started by `infer`, counting those sampled
+    /// by each `continue`.
+    steps: usize,
+    /// Cumulative log probability of the tokens, in beam search.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    score: Option<f32>,
+    /// All finished beams best first, if `return_beams` is requested in beam search.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    beams: Option<Vec<BeamSequence>>,
+    usage: Usage,
+    /// Timing of the generation, if `profile` is requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<Profile>,
+}
+
+/// Token counts of an infer and where its time went, from the request arriving to the
+/// first token sampled (prefill) and to the end (decode). Time waiting for a batch slot is
+/// counted in `queue_ms` instead of the stage it's in.
+#[derive(Debug, Default, Clone, Serialize)]
+struct Usage {
+    /// Tokens fed before the first token is sampled, of all states.
+    prompt_tokens: usize,
+    completion_tokens: usize,
+    total_tokens: usize,
+    queue_ms: u64,
+    prefill_ms: u64,
+    decode_ms: u64,
 }
 
-fn transform_logits(
-    app_state: AppState,
-    mut logits: Vec<f32>,
-    transformers: &Vec<String>,
-) -> Result<Vec<f32>> {
-    for transformer in transformers {
-        logits = app_state
-            .0
-            .transformers
-            .transform_logits(transformer, logits)?
+impl Usage {
+    /// `queued` is the time waiting for a batch slot in prefill and in decode.
+    fn new(
+        prompt_tokens: usize,
+        completion_tokens: usize,
+        arrived: Instant,
+        prefilled: Instant,
+        (prefill_queued, decode_queued): (Duration, Duration),
+    ) -> Self {
+        let prefill = prefilled.duration_since(arrived);
+        let decode = prefilled.elapsed();
+        Self {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+            queue_ms: (prefill_queued + decode_queued).as_millis() as u64,
+            prefill_ms: prefill.saturating_sub(prefill_queued).as_millis() as u64,
+            decode_ms: decode.saturating_sub(decode_queued).as_millis() as u64,
+        }
     }
-    Ok(logits)
 }
 
-async fn infer_and_sample(
-    app_state: AppState,
-    state_ids: &Vec<String>,
-    transformers: &Vec<Vec<String>>,
-    tokens: Vec<Vec<u16>>,
-    sampler: &String,
-    update_prompts: bool,
-    reset_on_exhaustion: bool,
-) -> Result<u16, InferenceInterruption> {
-    if update_prompts {
-        tokio::task::block_in_place(|| -> Result<(), InferenceInterruption> {
-            // This is the last place anything can stop the infer, if you want
-            // to stop the infer in case of additional termination from
-            // transformer/sampler, you must do it from updates, or the state
-            // will be polluted by the token input.
-
-            // Transformer and sampler should be aware of the exhaustion, where
-            // it should know it will fail no matter what logits/probs are
-            // given at sample/transformation time. and if it knows, it must
-            // throw an error.
-            let transformer_update = transformers
-                .par_iter()
-                .zip(tokens.par_iter())
-                .map(|(t_ids, tokens)| {
-                    for t_id in t_ids {
-                        let result = app_state.0.transformers.update_transformer(t_id, tokens);
-                        if let Err(InferenceInterruption::Exhaustion) = result {
-                            if reset_on_exhaustion {
-                                app_state.0.transformers.reset_transformer(t_id).unwrap();
-                            }
-                        }
-                        result?
-                    }
-                    Ok(())
+/// Timing of a generation as the server sees it. Unlike `usage`, the times include waiting
+/// for a batch slot, so they add up to what a client sees without the network latency.
+#[derive(Debug, Clone, Serialize)]
+struct Profile {
+    /// From the request arriving to the end of the generation.
+    total_ms: f64,
+    /// From the request arriving to the first token sampled.
+    time_to_first_token_ms: f64,
+    /// Tokens sampled after the first one, per second since the first one.
+    tokens_per_second: f64,
+}
+
+impl Profile {
+    fn new(completion_tokens: usize, arrived: Instant, prefilled: Instant) -> Self {
+        let decode = prefilled.elapsed().as_secs_f64();
+        Self {
+            total_ms: arrived.elapsed().as_secs_f64() * 1000.0,
+            time_to_first_token_ms: prefilled.duration_since(arrived).as_secs_f64() * 1000.0,
+            tokens_per_second: match decode > 0.0 {
+                true => completion_tokens.saturating_sub(1) as f64 / decode,
+                false => 0.0,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct BeamSequence {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    token_ids: Vec<u16>,
+    score: f32,
+    stop_reason: &'static str,
+}
+
+/// Decodes a finished beam, without the bytes the terminal trims.
+fn decode_sequence(state: &AppState, sequence: &Sequence) -> Result<String> {
+    let mut bytes = state.0.tokenizer.decode(&sequence.tokens)?;
+    bytes.truncate(bytes.len().saturating_sub(sequence.trim));
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Sends the text after `emitted` along with `pending`, and moves `emitted` to the end of
+/// it. Without text, sends `pending` once there are tokens in it.
+fn emit(
+    partial: &PartialSender,
+    text: Option<&str>,
+    emitted: &mut usize,
+    pending: &mut InferPartial,
+) -> Result<()> {
+    match text {
+        Some(text) => match text.get(*emitted..).filter(|x| !x.is_empty()) {
+            Some(value) => {
+                pending.value = Some(value.to_string());
+                *emitted = text.len();
+            }
+            None => return Ok(()),
+        },
+        None if pending.tokens.is_empty() => return Ok(()),
+        None => (),
+    }
+    partial
+        .send(serde_json::to_value(std::mem::take(pending))?)
+        .ok();
+    Ok(())
+}
+
+/// The output stage which decodes the sampled tokens into text.
+#[derive(Debug, Default)]
+struct TextOutput {
+    decoder: Utf8Decoder,
+    text: String,
+}
+
+impl TextOutput {
+    fn push(&mut self, state: &AppState, token: u16) -> Result<()> {
+        // A character split across tokens is kept in the decoder until complete
+        let text = self.decoder.push(&state.0.tokenizer.decode(&[token])?);
+        self.text.push_str(&text);
+        Ok(())
+    }
+
+    /// Removes `trim` bytes from the end, counting the bytes not decoded yet as well.
+    fn trim(&mut self, trim: usize) {
+        let undecoded = self.decoder.take_pending();
+        match undecoded.len().checked_sub(trim) {
+            Some(kept) => self
+                .text
+                .push_str(&String::from_utf8_lossy(&undecoded[..kept])),
+            None => helpers::trim_end(&mut self.text, trim - undecoded.len()),
+        }
+    }
+
+    /// The text which can be streamed, holding back `holdback` bytes at the end.
+    fn streamable(&self, holdback: usize) -> &str {
+        let mut end = self.text.len().saturating_sub(holdback);
+        while !self.text.is_char_boundary(end) {
+            end -= 1;
+        }
+        &self.text[..end]
+    }
+
+    /// The decoded text, and whether an incomplete character is left at the end, which
+    /// can't be completed anymore and is replaced by U+FFFD.
+    fn finish(mut self) -> (String, bool) {
+        let incomplete = !self.decoder.pending().is_empty();
+        self.text.push_str(&self.decoder.finish());
+        (self.text, incomplete)
+    }
+}
+
+/// Picks `n` tokens with the highest log probabilities of each distribution, in
+/// descending order.
+fn top_logprobs(logprobs: &[Vec<f32>], n: usize) -> Vec<Vec<TopLogprob>> {
+    logprobs
+        .iter()
+        .map(|logprobs| {
+            let mut indices: Vec<usize> = (0..logprobs.len()).collect();
+            let descending = |a: &usize, b: &usize| logprobs[*b].total_cmp(&logprobs[*a]);
+            if n < indices.len() {
+                indices.select_nth_unstable_by(n, descending);
+                indices.truncate(n);
+            }
+            indices.sort_unstable_by(descending);
+            indices
+                .into_iter()
+                .map(|index| TopLogprob {
+                    token: index as u16,
+                    logprob: logprobs[index],
                 })
-                .collect::<Result<Vec<()>, InferenceInterruption>>();
-            let sampler_update = app_state.0.samplers.update_sampler(&sampler, &tokens);
-            if let Err(InferenceInterruption::Exhaustion) = sampler_update {
-                if reset_on_exhaustion {
-                    app_state.0.samplers.reset_sampler(&sampler).unwrap();
+                .collect()
+        })
+        .collect()
+}
+
+/// Records a sampled token for the partial results and the response.
+fn record(
+    sampled: Sampled,
+    logprobs: Option<Vec<Vec<f32>>>,
+    top_n: usize,
+    pending: &mut InferPartial,
+    tokens: &mut Option<Vec<TokenLogprob>>,
+) {
+    let top = logprobs
+        .map(|x| top_logprobs(&x, top_n))
+        .unwrap_or_default();
+    pending.tokens.push(sampled.token);
+    if top_n > 0 {
+        pending.top_logprobs.push(top.clone());
+    }
+    if let Some(tokens) = tokens {
+        tokens.push(TokenLogprob {
+            id: sampled.token,
+            logprob: sampled.logprob,
+            top,
+        });
+    }
+}
+
+/// Options of an infer which apply to every completion.
+struct Generation {
+    max_tokens: usize,
+    min_tokens: usize,
+    suppress_tokens: Vec<u16>,
+    update_prompt: bool,
+    reset_on_exhaustion: bool,
+    stream: bool,
+    logprobs: bool,
+    top_n: usize,
+    return_tokens: bool,
+    decode: bool,
+    seed: u64,
+    /// When the infer stops with `timeout`, counted from the time the request arrives.
+    deadline: Option<Instant>,
+    /// When the request arrives, for `usage` and `profile`.
+    arrived: Instant,
+    profile: bool,
+}
+
+impl Generation {
+    /// The pipeline to sample with, which masks `suppress_tokens` until `min_tokens` are
+    /// sampled.
+    fn suppress(&self, pipeline: &SamplePipeline) -> SamplePipeline {
+        let mut pipeline = pipeline.clone();
+        if self.min_tokens > 0 {
+            pipeline.suppressed = self.suppress_tokens.clone();
+        }
+        pipeline
+    }
+}
+
+/// The deadline of a request with `timeout_ms`, or the default timeout in config.
+fn check_deadline(state: &AppState, timeout_ms: Option<u64>) -> Result<Option<Instant>> {
+    if timeout_ms == Some(0) {
+        return Err(CommandErrorKind::BadRequest.error("timeout_ms must be at least 1!"));
+    }
+    let timeout = timeout_ms
+        .map(Duration::from_millis)
+        .or_else(|| state.0.config.generation.get_timeout());
+    Ok(timeout.map(|x| Instant::now() + x))
+}
+
+/// Generates a completion from the first token sampled after the prompt.
+async fn generate(
+    state: &AppState,
+    pipeline: &SamplePipeline,
+    options: &Generation,
+    context: &CommandContext,
+    mut speculation: Option<Speculation>,
+    (sampled, distributions): (Vec<Sampled>, Option<Vec<Vec<f32>>>),
+    rng: &mut Rng,
+) -> Result<InferResponse> {
+    let keep_logprobs = options.top_n > 0;
+    // Unmasks the suppressed tokens once `min_tokens` are sampled
+    let mut pipeline = Cow::Borrowed(pipeline);
+
+    // Bytes of the result already streamed, and tokens not streamed yet
+    let mut emitted = 0;
+    let mut pending = InferPartial::default();
+    let mut token_logprobs = (options.logprobs || keep_logprobs).then(Vec::new);
+
+    let mut output = options.decode.then(TextOutput::default);
+    // Each state is fed its own tokens, while the rest follows the first state
+    let mut state_tokens: Vec<Vec<u16>> = sampled.iter().map(|x| vec![x.token]).collect();
+    let (generated, stop_reason, exhaustion) = {
+        let mut last_token = sampled[0].token;
+        let mut generated = vec![last_token];
+        record(
+            sampled[0],
+            distributions,
+            options.top_n,
+            &mut pending,
+            &mut token_logprobs,
+        );
+        let mut termination = pipeline.terminate(state, &generated)?;
+
+        loop {
+            if let Some(output) = &mut output {
+                output.push(state, last_token)?;
+            }
+
+            // The terminal can't stop the infer before `min_tokens`
+            if let Some(Termination { reason, trim }) =
+                termination.filter(|_| generated.len() >= options.min_tokens)
+            {
+                if let Some(output) = &mut output {
+                    output.trim(trim);
                 }
+                break (generated, reason, None);
             }
-            transformer_update.and(sampler_update)
-        })?;
+
+            if generated.len() >= options.max_tokens {
+                break (generated, "max_tokens", None);
+            }
+
+            if context.handle.is_cancelled() {
+                break (generated, "cancelled", None);
+            }
+
+            if options.deadline.is_some_and(|x| Instant::now() >= x) {
+                break (generated, "timeout", None);
+            }
+
+            // Holds back the text which may still be trimmed by the terminal
+            if options.stream {
+                let text = match &output {
+                    Some(output) => Some(output.streamable(pipeline.holdback(state, &generated)?)),
+                    None => None,
+                };
+                emit(&context.partial, text, &mut emitted, &mut pending)?;
+            }
+
+            if !pipeline.suppressed.is_empty() && generated.len() >= options.min_tokens {
+                pipeline.to_mut().suppressed.clear();
+                if let Some(speculation) = &mut speculation {
+                    speculation.unsuppress();
+                }
+            }
+
+            // Not ready, infer next one using last token
+            let next = match &mut speculation {
+                Some(speculation) => {
+                    speculation
+                        .next(state, last_token, keep_logprobs, rng)
+                        .await
+                }
+                None => {
+                    pipeline
+                        .infer_and_inspect(
+                            state,
+                            state_tokens
+                                .iter()
+                                .map(|x| vec![*x.last().unwrap()])
+                                .collect(),
+                            options.update_prompt,
+                            options.reset_on_exhaustion,
+                            keep_logprobs,
+                            rng,
+                        )
+                        .await
+                }
+            };
+            let (sampled, distributions) = match next {
+                Ok(sampled) => sampled,
+                // Exhausted, so stop infer.
+                Err(PipelineInterruption::Exhaustion(exhaustion)) => {
+                    break (generated, "exhaustion", Some(exhaustion));
+                }
+                // A sampling/transformation error occurred, inference
+                // is terminated
+                Err(PipelineInterruption::Error(error)) => Err(error)?,
+            };
+            for (tokens, sampled) in state_tokens.iter_mut().zip(sampled.iter()) {
+                tokens.push(sampled.token);
+            }
+            last_token = sampled[0].token;
+            generated.push(last_token);
+            record(
+                sampled[0],
+                distributions,
+                options.top_n,
+                &mut pending,
+                &mut token_logprobs,
+            );
+            termination = pipeline.terminate(state, &generated)?;
+        }
+    };
+
+    // Leaves the states as if the tokens were inferred one by one
+    if let Some(speculation) = speculation {
+        speculation.finish(state).await?;
     }
 
-    let logits = app_state
-        .infer(state_ids.clone(), tokens)
-        .await
-        .map_err(|e| InferenceInterruption::Error(e))?;
-
-    // In case if transformation is needed, we block the current thread and use rayon to
-    // transform each logits
-    let logits = if transformers.iter().any(|x| !x.is_empty()) {
-        tokio::task::block_in_place(|| {
-            logits
-                .into_par_iter()
-                .map(|x| x.0)
-                .zip(transformers.par_iter())
-                .map(|(logits, t_ids)| transform_logits(app_state.clone(), logits, t_ids))
-                .collect::<Result<Vec<_>>>()
-        })
-        .map_err(|e| InferenceInterruption::Error(e))?
-    } else {
-        logits.into_iter().map(|x| x.0).collect()
+    let (value, incomplete) = match output.map(TextOutput::finish) {
+        Some((value, incomplete)) => (Some(value), incomplete),
+        None => (None, false),
     };
-    let probs = app_state.softmax(logits).await;
-    return tokio::task::block_in_place(move || app_state.0.samplers.sample_token(&sampler, probs))
-        .map_err(|e| InferenceInterruption::Error(e));
+    if options.stream {
+        emit(
+            &context.partial,
+            value.as_deref(),
+            &mut emitted,
+            &mut pending,
+        )?;
+    }
+
+    Ok(InferResponse {
+        value,
+        last_token: *generated.last().unwrap(),
+        inferred_tokens: generated.len(),
+        stop_reason,
+        exhaustion,
+        tokens: token_logprobs,
+        steps: generated.len(),
+        // The tokens of an incomplete character are only kept intact in `token_ids`
+        token_ids: (options.return_tokens || !options.decode || incomplete).then_some(generated),
+        state_tokens: (state_tokens.len() > 1).then_some(state_tokens),
+        seed: options.seed,
+        score: None,
+        beams: None,
+        usage: Usage::default(),
+        profile: None,
+    })
 }
 
-#[derive(Debug, Serialize)]
-struct InferResponse {
-    value: String,
-    last_token: u16,
-    inferred_tokens: usize,
+/// Max tokens to sample, which defaults to (and can't exceed) the limit in config.
+fn check_max_tokens(state: &AppState, max_tokens: Option<usize>) -> Result<usize> {
+    let limit = state.0.config.generation.get_max_tokens();
+    let max_tokens = max_tokens.unwrap_or(limit);
+    if max_tokens == 0 || max_tokens > limit {
+        return Err(CommandErrorKind::BadRequest
+            .error(format!("max_tokens must be between 1 and {}!", limit)));
+    }
+    Ok(max_tokens)
+}
+
+/// Checks `min_tokens` against `max_tokens`, and `suppress_tokens` against the vocab.
+fn check_min_tokens(
+    state: &AppState,
+    model: &AxumModel,
+    min_tokens: usize,
+    max_tokens: usize,
+    suppress_tokens: &[u16],
+) -> Result<()> {
+    if min_tokens > max_tokens {
+        return Err(CommandErrorKind::BadRequest.error(format!(
+            "min_tokens must not exceed max_tokens, which is {}!",
+            max_tokens
+        )));
+    }
+    state.validate_tokens(model, &[suppress_tokens.to_vec()])
+}
+
+/// Feeds the prompt and generates a single completion, speculatively if `drafter` is
+/// given, then records the generation so it can be continued. `steps` are the tokens
+/// already sampled in the generation.
+async fn complete(
+    state: &AppState,
+    pipeline: &SamplePipeline,
+    drafter: Option<Drafter>,
+    tokens: Vec<Vec<u16>>,
+    options: &Generation,
+    context: &CommandContext,
+    steps: usize,
+) -> Result<InferResponse> {
+    let keep_logprobs = options.top_n > 0;
+    let mut rng = Rng::with_seed(options.seed);
+    pipeline.arm(state)?;
+
+    // Only waits of this infer count, not those of earlier commands on the states
+    let queued_states: Vec<String> = pipeline
+        .inferred_states()
+        .iter()
+        .chain(drafter.as_ref().and_then(Drafter::draft_state))
+        .cloned()
+        .collect();
+    state.take_queued(&queued_states);
+    let prompt_tokens: usize = tokens.iter().map(Vec::len).sum();
+
+    // Locks state_size slots for the infer. A speculation locks slots of the main model
+    // round by round, since the prompt is fed alone, which callers check fit into the batch
+    let state_model = state.state_model(&pipeline.states)?;
+    let _permits = match &drafter {
+        Some(_) => None,
+        None => Some(
+            state_model
+                .batch_request
+                .request(pipeline.inferred_states().len())?,
+        ),
+    };
+    let _draft_permits = match drafter.as_ref().and_then(Drafter::draft_state) {
+        Some(_) => Some(state.model(Some(DRAFT_MODEL))?.batch_request.request(1)?),
+        None => None,
+    };
+
+    // Feed prompt first, at least the first token should be ok
+    // or there must be some problem in the infer pipeline
+    let sampling = options.suppress(pipeline);
+    let (speculation, first) = match &drafter {
+        Some(drafter) => {
+            let mut speculation = Speculation::new(
+                state,
+                &sampling,
+                drafter.clone(),
+                options.update_prompt,
+                options.reset_on_exhaustion,
+            );
+            let first = speculation
+                .start(
+                    state,
+                    tokens.into_iter().next().unwrap(),
+                    keep_logprobs,
+                    &mut rng,
+                )
+                .await
+                .map_err(PipelineInterruption::into_start_error)?;
+            (Some(speculation), first)
+        }
+        None => (
+            None,
+            sampling
+                .infer_and_inspect(
+                    state,
+                    tokens,
+                    options.update_prompt,
+                    false,
+                    keep_logprobs,
+                    &mut rng,
+                )
+                .await
+                .map_err(PipelineInterruption::into_start_error)?,
+        ),
+    };
+    let prefilled = Instant::now();
+    let prefill_queued = state.take_queued(&queued_states);
+
+    let mut response = generate(
+        state,
+        &sampling,
+        options,
+        context,
+        speculation,
+        first,
+        &mut rng,
+    )
+    .await?;
+    response.usage = Usage::new(
+        prompt_tokens,
+        response.inferred_tokens,
+        options.arrived,
+        prefilled,
+        (prefill_queued, state.take_queued(&queued_states)),
+    );
+    response.profile = options
+        .profile
+        .then(|| Profile::new(response.inferred_tokens, options.arrived, prefilled));
+    response.steps += steps;
+    state.record_generation(GenerationRecord {
+        pipeline: pipeline.clone(),
+        drafter,
+        last_tokens: match &response.state_tokens {
+            Some(tokens) => tokens.iter().map(|x| *x.last().unwrap()).collect(),
+            None => vec![response.last_token],
+        },
+        steps: response.steps,
+        update_prompt: options.update_prompt,
+        reset_on_exhaustion: options.reset_on_exhaustion,
+    });
+    Ok(response)
 }
 
-pub async fn infer(data: Option<Value>, state: AppState) -> Result<Value> {
+pub async fn infer(data: Option<Value>, state: AppState, context: CommandContext) -> Result<Value> {
+    let arrived = Instant::now();
     if let Some(data) = data {
         let InferPayload {
             tokens,
+            template,
+            variables,
             states,
             transformers,
             sampler,
+            terminal,
+            stop,
+            normalizer,
+            model,
+            max_tokens,
+            min_tokens,
+            suppress_tokens,
             update_prompt,
             reset_on_exhaustion,
+            stream,
+            logprobs,
+            top_logprobs: top_n,
+            return_tokens,
+            draft_state,
+            speculative,
+            n,
+            decode,
+            seed,
+            mode,
+            beams,
+            return_beams,
+            timeout_ms,
+            profile,
+            cfg,
+            temperature,
         } = serde_json::from_value::<InferPayload>(data)?;
+        // Waiting for the batch counts as well
+        let deadline = check_deadline(&state, timeout_ms)?;
+        if temperature.is_some_and(|x| !x.is_finite() || x <= 0.0) {
+            return Err(CommandErrorKind::BadRequest.error("temperature must be positive!"));
+        }
+
+        let tokens = match template {
+            Some(template) if tokens.is_empty() => {
+                // Rendered before tokenization, so every client formats prompts the same
+                let prompt = state.0.templates.render(&template, &variables)?;
+                vec![Value::String(prompt); states.len()]
+            }
+            Some(_) => {
+                return Err(CommandErrorKind::BadRequest
+                    .error("tokens must be omitted when a template is given!"))
+            }
+            None => tokens,
+        };
+
+        let defaults = state.defaults();
+        let transformers = match (transformers, defaults.transformers) {
+            (Some(transformers), _) => transformers,
+            (None, Some(transformers)) => vec![transformers; states.len()],
+            (None, None) => {
+                return Err(CommandErrorKind::BadRequest
+                    .error("transformers must be given when there are no default transformers!"))
+            }
+        };
+        let sampler = sampler.or(defaults.sampler).ok_or_else(|| {
+            CommandErrorKind::BadRequest
+                .error("sampler must be given when there is no default sampler!")
+        })?;
 
         if tokens.len() != states.len() || states.len() != transformers.len() {
-            return Err(Error::msg(
-                "State, token, transformer length must be matched!",
-            ));
+            return Err(CommandErrorKind::BadRequest
+                .error("State, token, transformer length must be matched!"));
         }
 
-        if states.iter().any(|x| !state.has_state(x)) {
-            return Err(Error::msg("One or more state ids not exist!"));
+        let pipeline = SamplePipeline {
+            states,
+            transformers,
+            sampler,
+            normalizer,
+            terminal,
+            stop: match stop.is_empty() {
+                true => None,
+                false => Some(StopMatcher::new(stop)?),
+            },
+            suppressed: Vec::new(),
+            guidance: cfg,
+            temperature,
+        };
+        pipeline.validate(&state)?;
+
+        let state_model = state.state_model(&pipeline.states)?;
+        if let Some(model) = &model {
+            if model != &state_model.name {
+                return Err(CommandErrorKind::BadRequest.error(format!(
+                    "States are created against model {}, they can't be inferred with model {}!",
+                    state_model.name, model
+                )));
+            }
         }
 
-        if transformers
-            .iter()
-            .flatten()
-            .any(|x| !state.0.transformers.has_transformer(x))
-        {
-            return Err(Error::msg("One or more transformer ids not exist!"));
+        let max_tokens = check_max_tokens(&state, max_tokens)?;
+        check_min_tokens(
+            &state,
+            &state_model,
+            min_tokens,
+            max_tokens,
+            &suppress_tokens,
+        )?;
+
+        let tokens = helpers::to_each_tokens(&state, tokens)?;
+
+        if tokens.is_empty() || tokens.iter().any(|x| x.is_empty()) {
+            return Err(CommandErrorKind::BadRequest.error("Empty token list!"));
         }
+        // Checked before the prompt is fed to the components as well
+        state.validate_tokens(&state_model, &tokens)?;
 
-        if !state.0.samplers.has_sampler(&sampler) {
-            return Err(Error::msg("Sampler id does not exist!"));
+        let drafter = match (draft_state, speculative) {
+            (Some(_), Some(_)) => {
+                return Err(CommandErrorKind::BadRequest
+                    .error("draft_state and speculative can't be used together!"))
+            }
+            (Some(draft_state), None) => {
+                let draft_model = state.state_model(&vec![draft_state.clone()])?;
+                if draft_model.name != DRAFT_MODEL || state_model.name == DRAFT_MODEL {
+                    return Err(CommandErrorKind::BadRequest.error(format!(
+                        "draft_state must be created against model {}, and states against another model!",
+                        DRAFT_MODEL
+                    )));
+                }
+                if state.0.draft_tokens == 0 {
+                    return Err(CommandErrorKind::BadRequest
+                        .error("Speculative decoding is disabled by --draft-tokens 0!"));
+                }
+                Some(Drafter::Model(draft_state))
+            }
+            (
+                None,
+                Some(SpeculativeOptions {
+                    draft_len,
+                    max_ngram,
+                }),
+            ) => {
+                if draft_len == 0 || max_ngram == 0 {
+                    return Err(CommandErrorKind::BadRequest
+                        .error("draft_len and max_ngram must be at least 1!"));
+                }
+                Some(Drafter::NGram {
+                    draft_len,
+                    max_ngram,
+                })
+            }
+            (None, None) => None,
+        };
+        if drafter.is_some() {
+            if pipeline.states.len() != 1 {
+                return Err(CommandErrorKind::BadRequest
+                    .error("Speculative decoding only works with a single state!"));
+            }
+            if !state.0.samplers.can_truncate(&pipeline.sampler)? {
+                return Err(CommandErrorKind::BadRequest
+                    .error("Speculative decoding needs a sampler which can truncate probs!"));
+            }
         }
 
-        let tokens = tokens
-            .into_iter()
-            .map(|v| helpers::to_tokens(&state, v))
-            .collect::<Result<Vec<_>>>()?;
+        if n == 0 {
+            return Err(CommandErrorKind::BadRequest.error("n must be at least 1!"));
+        }
+        if pipeline.guidance.is_some() && (drafter.is_some() || n > 1 || mode == InferMode::Beam) {
+            return Err(CommandErrorKind::BadRequest
+                .error("cfg can't be used with speculative decoding, beam search or n!"));
+        }
+        if n > 1 && (stream || drafter.is_some()) {
+            return Err(CommandErrorKind::BadRequest
+                .error("Multiple completions can't be streamed or speculatively decoded!"));
+        }
+        if mode == InferMode::Beam {
+            if beams == 0 {
+                return Err(CommandErrorKind::BadRequest.error("beams must be at least 1!"));
+            }
+            if pipeline.states.len() != 1 || stream || drafter.is_some() || n > 1 {
+                return Err(CommandErrorKind::BadRequest.error(
+                    "Beam search only works with a single state, and can't be streamed, speculatively decoded or used with n!",
+                ));
+            }
+            if min_tokens > 0 {
+                return Err(CommandErrorKind::BadRequest
+                    .error("min_tokens can't be used with beam search!"));
+            }
+        }
 
-        if tokens.is_empty() || tokens.iter().any(|x| x.is_empty()) {
-            return Err(Error::msg("Empty token list!"));
+        // Slots are locked bit by bit as the infer goes, but what it locks at once must fit
+        // into the batch
+        let batch_request = &state_model.batch_request;
+        match (&drafter, mode) {
+            (_, InferMode::Beam) => batch_request.check(beams)?,
+            (Some(drafter), _) => batch_request.check(drafter.draft_len(&state) + 1)?,
+            (None, _) => batch_request.check(n * pipeline.states.len())?,
         }
 
-        let (result, last_token, inferred_tokens) = {
-            let mut out_tokens = Vec::with_capacity(4);
-            let mut inferred_tokens: usize = 1usize;
-            let mut result = String::new();
+        // The payload is validated before the states are locked, so a malformed request
+        // never holds them
+        let mut advanced = pipeline.inferred_states();
+        advanced.extend(drafter.as_ref().and_then(Drafter::draft_state).cloned());
+        let _locks = state.lock_states(&advanced).await?;
+        // Whatever the infer does to the states, their last generations can't be continued
+        state.forget_generations(&pipeline.inferred_states());
 
-            // Locks state_size slots for the infer
-            let _permits = state.0.batch_request.request(states.len());
+        if mode == InferMode::Beam {
+            let seed = seed.unwrap_or_else(|| fastrand::u64(..));
+            state.take_queued(&pipeline.states);
+            let prompt_tokens = tokens[0].len();
+            let search = BeamSearch {
+                app_state: &state,
+                pipeline: &pipeline,
+                beams,
+                max_tokens,
+                update_prompt,
+                reset_on_exhaustion,
+                deadline,
+            };
+            let Search {
+                sequences,
+                prefilled,
+                prefill_queued,
+                decode_queued,
+            } = search
+                .run(tokens.into_iter().next().unwrap(), &context.handle)
+                .await?;
+            let best = sequences
+                .first()
+                .ok_or(CommandErrorKind::Internal.error("Beam search found no sequence!"))?;
 
-            // Feed prompt first, at least the first token should be ok
-            // or there must be some problem in the infer pipeline
-            out_tokens.push(
-                infer_and_sample(
-                    state.clone(),
-                    &states,
-                    &transformers,
-                    tokens,
-                    &sampler,
-                    update_prompt,
-                    false,
-                )
-                .await
-                .map_err(|e| match e {
-                    InferenceInterruption::Exhaustion => Error::msg(
-                        "Sampler/transformer is exhausted at the start, inference won't continue.",
+            // Leaves the state and components as if the best sequence were sampled
+            let fed = best.tokens[..best.tokens.len() - 1].to_vec();
+            state.set_state(
+                &pipeline.states[0],
+                best.state.clone(),
+                FedTokens::Extend(&fed),
+            )?;
+            if update_prompt {
+                match tokio::task::block_in_place(|| {
+                    pipeline.update(&state, &vec![fed], reset_on_exhaustion)
+                }) {
+                    Ok(_) | Err(PipelineInterruption::Exhaustion(_)) => (),
+                    Err(PipelineInterruption::Error(e)) => return Err(e),
+                }
+            }
+
+            let response = InferResponse {
+                value: match decode {
+                    true => Some(decode_sequence(&state, best)?),
+                    false => None,
+                },
+                last_token: *best.tokens.last().unwrap(),
+                inferred_tokens: best.tokens.len(),
+                steps: best.tokens.len(),
+                stop_reason: best.stop_reason,
+                exhaustion: best.exhaustion.clone(),
+                tokens: None,
+                token_ids: (return_tokens || !decode).then(|| best.tokens.clone()),
+                state_tokens: None,
+                seed,
+                score: Some(best.score),
+                beams: match return_beams {
+                    true => Some(
+                        sequences
+                            .iter()
+                            .map(|x| {
+                                Ok(BeamSequence {
+                                    value: match decode {
+                                        true => Some(decode_sequence(&state, x)?),
+                                        false => None,
+                                    },
+                                    token_ids: x.tokens.clone(),
+                                    score: x.score,
+                                    stop_reason: x.stop_reason,
+                                })
+                            })
+                            .collect::<Result<Vec<_>>>()?,
                     ),
-                    InferenceInterruption::Error(e) => e,
-                })?,
-            );
+                    false => None,
+                },
+                usage: Usage::new(
+                    prompt_tokens,
+                    best.tokens.len(),
+                    arrived,
+                    prefilled,
+                    (prefill_queued, decode_queued),
+                ),
+                profile: profile.then(|| Profile::new(best.tokens.len(), arrived, prefilled)),
+            };
+            return Ok(serde_json::to_value(response)?);
+        }
 
-            let mut last_token = *out_tokens.last().unwrap();
-
-            loop {
-                if let Ok(Ok(partial)) = state
-                    .0
-                    .tokenizer
-                    .decode(&out_tokens.as_slice())
-                    .map(|x| String::from_utf8(x))
-                {
-                    result.push_str(partial.as_str());
-                    inferred_tokens += out_tokens.len();
-                    out_tokens.clear()
-                }
+        let options = Generation {
+            max_tokens,
+            min_tokens,
+            suppress_tokens,
+            update_prompt,
+            reset_on_exhaustion,
+            stream,
+            logprobs,
+            top_n,
+            return_tokens,
+            decode,
+            seed: seed.unwrap_or_else(|| fastrand::u64(..)),
+            deadline,
+            arrived,
+            profile,
+        };
 
-                // TODO: implement terminal here, also we need to ensure that
-                // out token will be empty when output, or it will be extremely tricky
-                // to hand over the out token.
-                if inferred_tokens >= 10 && out_tokens.is_empty() {
-                    break (result, last_token, inferred_tokens);
-                }
+        if n > 1 {
+            let keep_logprobs = top_n > 0;
+            let mut rng = Rng::with_seed(options.seed);
+
+            // Locks state_size slots for the prompt, then for each completion until it's
+            // done, since the prompt is fed once and completions finish one by one
+            let prefill_permits = batch_request.request(pipeline.states.len())?;
 
-                // Not ready, infer next one using last token
-                out_tokens.push(
-                    match infer_and_sample(
-                        state.clone(),
-                        &states,
-                        &transformers,
-                        vec![vec![last_token]; states.len()],
-                        &sampler,
-                        update_prompt,
-                        reset_on_exhaustion,
-                    )
+            state.take_queued(&pipeline.states);
+            let prompt_tokens: usize = tokens.iter().map(Vec::len).sum();
+
+            // The prompt is fed once, then each completion continues from a copy of the
+            // states and components
+            if update_prompt {
+                tokio::task::block_in_place(|| pipeline.update(&state, &tokens, false))
+                    .map_err(PipelineInterruption::into_start_error)?;
+            }
+            let (logits, snapshots): (Vec<_>, Vec<_>) = state
+                .infer_snapshot(pipeline.states.clone(), tokens)
+                .await?
+                .into_iter()
+                .unzip();
+            drop(prefill_permits);
+            let prefilled = Instant::now();
+            let prefill_queued = state.take_queued(&pipeline.states);
+            // Each completion draws from its own random stream derived from the seed
+            let sampling = options.suppress(&pipeline);
+            let lanes = (0..n)
+                .map(|_| Ok((sampling.fork(&state, &snapshots)?, rng.fork())))
+                .collect::<Result<Vec<_>>>()?;
+
+            let (state, options, context, logits) = (&state, &options, &context, &logits);
+            let responses = try_join_all(lanes.into_iter().map(|(lane, mut rng)| async move {
+                let _permits = batch_request.request(lane.pipeline.states.len())?;
+                lane.pipeline.arm(state)?;
+                let first = lane
+                    .pipeline
+                    .sample_logits(state, logits.clone(), keep_logprobs, &mut rng)
                     .await
-                    {
-                        Ok(token) => token,
-                        // Exhausted, so stop infer.
-                        Err(InferenceInterruption::Exhaustion) => {
-                            break (result, last_token, inferred_tokens);
-                        }
-                        // A sampling/transformation error occurred, inference
-                        // is terminated
-                        Err(InferenceInterruption::Error(error)) => Err(error)?,
-                    },
+                    .map_err(PipelineInterruption::into_start_error)?;
+                let mut response = generate(
+                    state,
+                    &lane.pipeline,
+                    options,
+                    context,
+                    None,
+                    first,
+                    &mut rng,
+                )
+                .await?;
+                response.usage = Usage::new(
+                    prompt_tokens,
+                    response.inferred_tokens,
+                    options.arrived,
+                    prefilled,
+                    (prefill_queued, state.take_queued(&lane.pipeline.states)),
                 );
-                last_token = *out_tokens.last().unwrap();
+                response.profile = options
+                    .profile
+                    .then(|| Profile::new(response.inferred_tokens, options.arrived, prefilled));
+                Ok(response)
+            }))
+            .await?;
+            return Ok(serde_json::to_value(responses)?);
+        }
+
+        let response = complete(&state, &pipeline, drafter, tokens, &options, &context, 0).await?;
+        Ok(serde_json::to_value(response)?)
+    } else {
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify infer pipeline!"))
+    }
+}
+
+/// Resumes the last generation of a state from its last token, with the pipeline of the
+/// `infer` which started it. Called by the command `continue`.
+pub async fn continue_infer(
+    data: Option<Value>,
+    state: AppState,
+    context: CommandContext,
+) -> Result<Value> {
+    let arrived = Instant::now();
+    if let Some(data) = data {
+        let ContinuePayload {
+            state: id,
+            max_tokens,
+            min_tokens,
+            suppress_tokens,
+            stream,
+            logprobs,
+            top_logprobs: top_n,
+            return_tokens,
+            decode,
+            seed,
+            timeout_ms,
+            profile,
+        } = serde_json::from_value::<ContinuePayload>(data)?;
+        let deadline = check_deadline(&state, timeout_ms)?;
+
+        let GenerationRecord {
+            pipeline,
+            drafter,
+            last_tokens,
+            steps,
+            update_prompt,
+            reset_on_exhaustion,
+        } = state.last_generation(&id)?;
+        // Any of them may be deleted since the last generation
+        pipeline.validate(&state)?;
+        if let Some(draft_state) = drafter.as_ref().and_then(Drafter::draft_state) {
+            if !state.has_state(draft_state) {
+                return Err(state.missing_state(draft_state));
             }
+        }
+
+        let max_tokens = check_max_tokens(&state, max_tokens)?;
+        let state_model = state.state_model(&pipeline.states)?;
+        check_min_tokens(
+            &state,
+            &state_model,
+            min_tokens,
+            max_tokens,
+            &suppress_tokens,
+        )?;
+        if let Some(drafter) = &drafter {
+            state_model
+                .batch_request
+                .check(drafter.draft_len(&state) + 1)?;
+        }
+
+        let options = Generation {
+            max_tokens,
+            min_tokens,
+            suppress_tokens,
+            update_prompt,
+            reset_on_exhaustion,
+            stream,
+            logprobs,
+            top_n,
+            return_tokens,
+            decode,
+            seed: seed.unwrap_or_else(|| fastrand::u64(..)),
+            deadline,
+            arrived,
+            profile,
         };
 
-        Ok(serde_json::to_value(InferResponse {
-            value: result,
-            last_token,
-            inferred_tokens,
-        })?)
+        let fed = state.get_state(&id)?.tokens;
+        let mut advanced = pipeline.inferred_states();
+        advanced.extend(drafter.as_ref().and_then(Drafter::draft_state).cloned());
+        let _locks = state.lock_states(&advanced).await?;
+        // Another command may have advanced the state while this one waited for it
+        if state.get_state(&id)?.tokens != fed {
+            return Err(CommandErrorKind::StateBusy.error(format!(
+                "State {} is advanced by another command since its last generation!",
+                id
+            )));
+        }
+
+        // The last token is not fed yet, and the prompt is already fed to the transformers
+        state.forget_generations(&pipeline.inferred_states());
+        let tokens = last_tokens.into_iter().map(|x| vec![x]).collect();
+        let response = complete(
+            &state, &pipeline, drafter, tokens, &options, &context, steps,
+        )
+        .await?;
+        Ok(serde_json::to_value(response)?)
     } else {
-        Err(Error::msg(
-            "Field data is needed to specify infer pipeline!",
-        ))
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify the state to continue!"))
     }
 }
+
+/// Sets the sampler and transformers used by infers which omit them.
+pub async fn set_defaults(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        state
+            .set_defaults(serde_json::from_value::<InferDefaults>(data)?)
+            .map(|_| Value::Null)
+    } else {
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify the defaults!"))
+    }
+}
+
+/// Cancels a running `infer` of the connection by its echo_id.
+pub async fn abort(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        state
+            .cancel_command(data.as_str().ok_or(CommandErrorKind::BadRequest.error(
+                "data should be a string representing the echo_id of the infer to abort!",
+            ))?)
+            .map(|_| Value::Null)
+    } else {
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify the echo_id of the infer!"))
+    }
+}
+
+/// Same as `abort`.
+#[inline]
+pub async fn cancel(data: Option<Value>, state: AppState) -> Result<Value> {
+    abort(data, state).await
+}
@@ -0,0 +1,289 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::{
+    app::AppState, commands::types::CommandContext, error::CommandErrorKind, helper::Logits,
+};
+
+use super::helpers;
+
+#[derive(Debug, Deserialize)]
+struct LogitsPayload {
+    states: Vec<String>,
+    tokens: Value,
+    /// Returns the `top` largest logits of each state instead of all of them.
+    #[serde(default)]
+    top: Option<usize>,
+    /// Keeps the tokens fed to the states. Otherwise the states are left as they were.
+    #[serde(default)]
+    update_state: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct TopLogit {
+    token: u16,
+    logit: f32,
+}
+
+#[derive(Debug, Serialize)]
+struct LogitsResponse {
+    /// The largest logits of each state, in descending order, if `top` is requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top: Option<Vec<Vec<TopLogit>>>,
+    /// Logits of each state, which are sent in a binary result if `top` is not requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    num_vocab: Option<usize>,
+}
+
+fn top_logits(logits: &Logits, n: usize) -> Vec<TopLogit> {
+    let logits = &logits.0;
+    let mut indices: Vec<usize> = (0..logits.len()).collect();
+    let descending = |a: &usize, b: &usize| logits[*b].total_cmp(&logits[*a]);
+    if n < indices.len() {
+        indices.select_nth_unstable_by(n, descending);
+        indices.truncate(n);
+    }
+    indices.sort_unstable_by(descending);
+    indices
+        .into_iter()
+        .map(|index| TopLogit {
+            token: index as u16,
+            logit: logits[index],
+        })
+        .collect()
+}
+
+/// Infers tokens with states and returns the logits, without sampling or touching any
+/// component.
+pub async fn get_logits(
+    data: Option<Value>,
+    state: AppState,
+    context: CommandContext,
+) -> Result<Value> {
+    if let Some(data) = data {
+        let LogitsPayload {
+            states,
+            tokens,
+            top,
+            update_state,
+        } = serde_json::from_value(data)?;
+        let tokens = helpers::to_token_vec(&state, tokens)?;
+        if states.len() != tokens.len() {
+            return Err(
+                CommandErrorKind::BadRequest.error("State and token length must be matched!")
+            );
+        }
+        if tokens.is_empty() || tokens.iter().any(|x| x.is_empty()) {
+            return Err(CommandErrorKind::BadRequest.error("Empty token list!"));
+        }
+
+        let model = state.state_model(&states)?;
+        let _permits = model.batch_request.request(states.len())?;
+        let logits = if update_state {
+            let _locks = state.lock_states(&states).await?;
+            state.forget_generations(&states);
+            state.infer(states, tokens).await?
+        } else {
+            // Shallow copies share the data with the states, which stay as they are
+            let mut copies = Vec::with_capacity(states.len());
+            for id in &states {
+                copies.push(state.copy_temporary_state(id).await?);
+            }
+            state
+                .infer(copies.iter().map(|x| x.id.clone()).collect(), tokens)
+                .await?
+        };
+
+        let response = match top {
+            Some(top) => LogitsResponse {
+                top: Some(logits.iter().map(|x| top_logits(x, top)).collect()),
+                num_vocab: None,
+            },
+            None => {
+                // Little endian f32 of all states one after another
+                let bytes = logits
+                    .iter()
+                    .flat_map(|x| x.0.iter())
+                    .flat_map(|x| x.to_le_bytes())
+                    .collect();
+                context.binary.send(bytes).ok();
+                LogitsResponse {
+                    top: None,
+                    num_vocab: logits.first().map(|x| x.0.len()),
+                }
+            }
+        };
+        Ok(serde_json::to_value(response)?)
+    } else {
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify states and tokens!"))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ScorePayload {
+    state: String,
+    tokens: Value,
+    /// Keeps the tokens fed to the state. Otherwise the state is left as it was.
+    #[serde(default)]
+    update_state: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ScoreResponse {
+    /// Log probability of each token but the first one, given the tokens before it.
+    logprobs: Vec<f32>,
+    /// Sum of `logprobs`.
+    total: f32,
+    perplexity: f32,
+}
+
+/// Computes the log likelihood of tokens with a state. The first token is only used as
+/// context, since the logits which predict it are unknown.
+pub async fn score(data: Option<Value>, state: AppState, context: CommandContext) -> Result<Value> {
+    if let Some(data) = data {
+        let ScorePayload {
+            state: id,
+            tokens,
+            update_state,
+        } = serde_json::from_value(data)?;
+        let tokens = helpers::to_tokens(&state, tokens)?;
+        if tokens.len() < 2 {
+            return Err(
+                CommandErrorKind::BadRequest.error("At least 2 tokens are needed to score!")
+            );
+        }
+
+        let model = state.state_model(&vec![id.clone()])?;
+        state.validate_tokens(&model, std::slice::from_ref(&tokens))?;
+        let _permits = model.batch_request.request(1)?;
+        let (_locks, copy) = match update_state {
+            true => {
+                let locks = state.lock_states(&[id.clone()]).await?;
+                state.forget_generations(&[id.clone()]);
+                (locks, None)
+            }
+            false => (Vec::new(), Some(state.copy_temporary_state(&id).await?)),
+        };
+        let target = copy.as_ref().map(|x| x.id.clone()).unwrap_or(id);
+
+        // The model only outputs the logits of the last token, so tokens are fed one by one
+        let mut logprobs = Vec::with_capacity(tokens.len() - 1);
+        for window in tokens.windows(2) {
+            // Also cancelled once the connection is closed
+            if context.handle.is_cancelled() {
+                return Err(CommandErrorKind::Interrupted.error("Score is cancelled!"));
+            }
+            let logits = state
+                .infer(vec![target.clone()], vec![vec![window[0]]])
+                .await?
+                .remove(0);
+            let probs = model.softmax(vec![logits.0]).await.remove(0);
+            logprobs.push(probs[window[1] as usize].ln());
+        }
+        if update_state {
+            state
+                .infer(vec![target], vec![vec![*tokens.last().unwrap()]])
+                .await?;
+        }
+
+        let total: f32 = logprobs.iter().sum();
+        let perplexity = (-total / logprobs.len() as f32).exp();
+        Ok(serde_json::to_value(ScoreResponse {
+            logprobs,
+            total,
+            perplexity,
+        })?)
+    } else {
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify state and tokens!"))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SuggestPayload {
+    state: String,
+    /// Tokens fed to the state before suggesting. Otherwise the logits kept by the last
+    /// `suggest_next` or `accept_token` are used.
+    #[serde(default)]
+    tokens: Option<Value>,
+    k: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct Candidate {
+    token: u16,
+    prob: f32,
+    /// The token decoded on its own, which may be a part of a character.
+    text: String,
+}
+
+/// Suggests the `k` most likely next tokens of a state, without sampling, so the client
+/// picks one and feeds it back by `accept_token`.
+pub async fn suggest_next(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let SuggestPayload {
+            state: id,
+            tokens,
+            k,
+        } = serde_json::from_value(data)?;
+        let model = state.state_model(&vec![id.clone()])?;
+        let logits = match tokens {
+            Some(tokens) => {
+                let tokens = helpers::to_tokens(&state, tokens)?;
+                if tokens.is_empty() {
+                    return Err(CommandErrorKind::BadRequest.error("Empty token list!"));
+                }
+                let _permits = model.batch_request.request(1)?;
+                let _locks = state.lock_states(&[id.clone()]).await?;
+                state.forget_generations(&[id.clone()]);
+                state.feed_next(&id, tokens).await?
+            }
+            None => state.next_logits(&id)?.ok_or_else(|| {
+                CommandErrorKind::BadRequest.error(format!(
+                    "No logits are kept for state {}, feed tokens to suggest from!",
+                    id
+                ))
+            })?,
+        };
+
+        let probs = model.softmax(vec![logits.0.clone()]).await.remove(0);
+        let candidates = top_logits(&logits, k)
+            .into_iter()
+            .map(|TopLogit { token, .. }| {
+                let text =
+                    String::from_utf8_lossy(&state.0.tokenizer.decode(&[token])?).into_owned();
+                Ok(Candidate {
+                    token,
+                    prob: probs[token as usize],
+                    text,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(json!({ "candidates": candidates }))
+    } else {
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify state and k!"))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AcceptPayload {
+    state: String,
+    token: u16,
+}
+
+/// Feeds the token picked from `suggest_next` to the state, keeping the logits after it
+/// for the next `suggest_next`.
+pub async fn accept_token(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let AcceptPayload { state: id, token } = serde_json::from_value(data)?;
+        let model = state.state_model(&vec![id.clone()])?;
+        let _permits = model.batch_request.request(1)?;
+        let _locks = state.lock_states(&[id.clone()]).await?;
+        state.forget_generations(&[id.clone()]);
+        state.feed_next(&id, vec![token]).await?;
+        Ok(Value::Null)
+    } else {
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify state and token!"))
+    }
+}
@@ -0,0 +1,155 @@
+use std::{collections::HashMap, time::Instant};
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{app::AppState, error::CommandErrorKind, states::state_file::StateLayout};
+
+#[derive(Debug, Serialize)]
+struct ModelInfoResponse {
+    name: String,
+    version: &'static str,
+    num_layers: usize,
+    num_emb: usize,
+    num_vocab: usize,
+    max_batch_count: usize,
+    max_chunk_count: usize,
+    /// Runs of the model so far.
+    batch_runs: usize,
+    /// Slots inferred per run on average, relative to `max_batch_count`.
+    batch_occupancy: f64,
+    /// Slots reserved by pinned states, out of `max_pinned_slots`.
+    pinned_slots: usize,
+    max_pinned_slots: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct AdapterInfo {
+    name: String,
+    vendor: u32,
+    device: u32,
+    device_type: String,
+    driver: String,
+    driver_info: String,
+    backend: String,
+}
+
+/// What the device of a model reports about its memory.
+#[derive(Debug, Serialize)]
+struct MemoryInfo {
+    /// Largest buffer the device can allocate, e.g. for a matrix of the model.
+    max_buffer_size: u64,
+    /// Largest part of a buffer a shader can bind at once.
+    max_storage_buffer_binding_size: u32,
+    /// Always `None`: the graphics backend doesn't report the total or available VRAM, nor
+    /// take memory hints in the pinned wgpu version.
+    total_vram: Option<u64>,
+    available_vram: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct GpuInfoResponse {
+    model: String,
+    adapter: AdapterInfo,
+    memory: MemoryInfo,
+    /// Memory taken by the data of a state of the model.
+    state_bytes: usize,
+    /// Memory taken on the GPU by the slots of the batch, which hold the states being
+    /// inferred.
+    pool_bytes: usize,
+    /// States of the model kept by the server, and the memory taken by their data and
+    /// checkpoints.
+    states: usize,
+    states_bytes: usize,
+}
+
+/// Warms up a model, or all models if omitted. Returns the milliseconds taken by each.
+pub async fn warmup(data: Option<Value>, state: AppState) -> Result<Value> {
+    let names = match &data {
+        None | Some(Value::Null) => state.0.models.keys().cloned().collect(),
+        Some(Value::String(name)) => vec![state.model(Some(name))?.name.clone()],
+        _ => {
+            return Err(CommandErrorKind::BadRequest.error(
+                "data should be a string representing model name, or omitted for all models!",
+            ))
+        }
+    };
+    let mut durations = HashMap::new();
+    for name in names {
+        let start = Instant::now();
+        state.warmup(&name).await?;
+        durations.insert(name, start.elapsed().as_millis());
+    }
+    Ok(serde_json::to_value(durations)?)
+}
+
+#[inline]
+pub async fn model_info(data: Option<Value>, state: AppState) -> Result<Value> {
+    let name = match &data {
+        None | Some(Value::Null) => None,
+        Some(Value::String(name)) => Some(name.as_str()),
+        _ => return Err(CommandErrorKind::BadRequest.error(
+            "data should be a string representing model name, or omitted for the default model!",
+        )),
+    };
+    let model = state.model(name)?;
+    let info = model.info();
+    Ok(serde_json::to_value(ModelInfoResponse {
+        name: model.name.clone(),
+        version: model.version(),
+        num_layers: info.num_layers,
+        num_emb: info.num_emb,
+        num_vocab: info.num_vocab,
+        max_batch_count: model.max_batch,
+        max_chunk_count: model.spec.get_chunk_size(),
+        batch_runs: model.batch_stats.runs(),
+        batch_occupancy: model.batch_stats.average_batch() / model.max_batch as f64,
+        pinned_slots: model.batch_stats.pinned(),
+        max_pinned_slots: model.max_pinned,
+    })?)
+}
+
+/// Describes the adapter a model is loaded on, and estimates the memory taken by its
+/// states.
+#[inline]
+pub async fn gpu_info(data: Option<Value>, state: AppState) -> Result<Value> {
+    let name = match &data {
+        None | Some(Value::Null) => None,
+        Some(Value::String(name)) => Some(name.as_str()),
+        _ => return Err(CommandErrorKind::BadRequest.error(
+            "data should be a string representing model name, or omitted for the default model!",
+        )),
+    };
+    let model = state.model(name)?;
+    let info = model.context.adapter.get_info();
+    let limits = model.context.device.limits();
+    let state_bytes = StateLayout::of(&model).data_len() * std::mem::size_of::<f32>();
+    let states: Vec<_> = state
+        .list_states("")
+        .into_iter()
+        .filter(|x| x.model == model.name)
+        .collect();
+    Ok(serde_json::to_value(GpuInfoResponse {
+        model: model.name.clone(),
+        adapter: AdapterInfo {
+            name: info.name,
+            vendor: info.vendor,
+            device: info.device,
+            device_type: format!("{:?}", info.device_type),
+            driver: info.driver,
+            driver_info: info.driver_info,
+            backend: format!("{:?}", info.backend),
+        },
+        memory: MemoryInfo {
+            max_buffer_size: limits.max_buffer_size,
+            max_storage_buffer_binding_size: limits.max_storage_buffer_binding_size,
+            total_vram: None,
+            available_vram: None,
+        },
+        state_bytes,
+        pool_bytes: state_bytes * model.max_batch,
+        states: states.len(),
+        states_bytes: states.iter().map(|x| x.bytes).sum(),
+    })?)
+}
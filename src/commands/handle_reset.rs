@@ -0,0 +1,51 @@
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::{app::AppState, error::CommandErrorKind};
+
+#[derive(Debug, Deserialize)]
+struct ResetAll {
+    #[serde(default)]
+    transformers: Vec<String>,
+    #[serde(default)]
+    samplers: Vec<String>,
+    #[serde(default)]
+    terminals: Vec<String>,
+    #[serde(default)]
+    normalizers: Vec<String>,
+}
+
+/// Resets each id, and collects the result of each one.
+fn reset_each(ids: Vec<String>, reset: impl Fn(&str) -> Result<()>) -> Value {
+    Value::Object(
+        ids.into_iter()
+            .map(|id| {
+                let result = match reset(&id) {
+                    Ok(_) => json!({ "status": "success" }),
+                    Err(e) => json!({ "status": "error", "error": e.to_string() }),
+                };
+                (id, result)
+            })
+            .collect::<Map<_, _>>(),
+    )
+}
+
+pub async fn reset_all(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let ResetAll {
+            transformers,
+            samplers,
+            terminals,
+            normalizers,
+        } = serde_json::from_value::<ResetAll>(data)?;
+        Ok(json!({
+            "transformers": reset_each(transformers, |id| state.0.transformers.reset_transformer(id)),
+            "samplers": reset_each(samplers, |id| state.0.samplers.reset_sampler(id)),
+            "terminals": reset_each(terminals, |id| state.0.terminals.reset_terminal(id)),
+            "normalizers": reset_each(normalizers, |id| state.0.normalizers.reset_normalizer(id)),
+        }))
+    } else {
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify ids to reset!"))
+    }
+}
@@ -1,8 +1,8 @@
-use anyhow::{Error, Result};
+use anyhow::Result;
 use serde::Deserialize;
-use serde_json::Value;
+use serde_json::{json, Value};
 
-use crate::{app::AppState, states::InferenceInterruption};
+use crate::{app::AppState, error::CommandErrorKind};
 
 use super::helpers;
 
@@ -10,21 +10,24 @@ use super::helpers;
 struct SamplerArgs {
     id: String,
     data: Value,
+    /// Deletes the sampler once the connection is closed.
+    #[serde(default)]
+    scoped: bool,
 }
 
 #[inline]
 pub async fn create_sampler(data: Option<Value>, state: AppState) -> Result<Value> {
     if let Some(data) = data {
-        let SamplerArgs { id, data } = serde_json::from_value(data)?;
+        let SamplerArgs { id, data, scoped } = serde_json::from_value(data)?;
+        let owner = state.connection().filter(|_| scoped);
         state
             .0
             .samplers
-            .create_sampler(id, state.clone(), data)
+            .create_sampler(id, state.clone(), data, owner)
             .map(|_| Value::Null)
     } else {
-        Err(Error::msg(
-            "Field data is needed to specify sampler type_id and params!",
-        ))
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify sampler type_id and params!"))
     }
 }
 
@@ -47,24 +50,31 @@ pub async fn copy_sampler(data: Option<Value>, state: AppState) -> Result<Value>
             .copy_sampler(source, destination)
             .map(|_| Value::Null)
     } else {
-        Err(Error::msg(
-            "Field data is needed to specify source sampler and destination id!",
-        ))
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify source sampler and destination id!"))
     }
 }
 
+#[inline]
+pub async fn list_samplers(_data: Option<Value>, state: AppState) -> Result<Value> {
+    Ok(serde_json::to_value(state.0.samplers.ids())?)
+}
+
 #[inline]
 pub async fn delete_sampler(data: Option<Value>, state: AppState) -> Result<Value> {
     if let Some(data) = data {
-        state
-            .0
-            .samplers
-            .delete_sampler(data.as_str().ok_or(Error::msg(
-                "data should be a string representing sampler id you want to delete!",
-            ))?)
-            .map(|_| Value::Null)
+        let deleted =
+            state
+                .0
+                .samplers
+                .delete_sampler(data.as_str().ok_or(CommandErrorKind::BadRequest.error(
+                    "data should be a string representing sampler id you want to delete!",
+                ))?)
+                .is_ok();
+        // Deleting an absent sampler is not an error, so a delete can be retried safely
+        Ok(json!({ "deleted": deleted }))
     } else {
-        Err(Error::msg("Field data is needed to specify sampler id!"))
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify sampler id!"))
     }
 }
 
@@ -83,15 +93,11 @@ pub async fn update_sampler(data: Option<Value>, state: AppState) -> Result<Valu
             .0
             .samplers
             .update_sampler(&sampler, &tokens)
-            .map_err(|interruption| match interruption {
-                InferenceInterruption::Exhaustion => Error::msg("Sampler is exhausted!"),
-                InferenceInterruption::Error(e) => e,
-            })
+            .map_err(|interruption| interruption.into_error("Sampler"))
             .map(|_| Value::Null)
     } else {
-        Err(Error::msg(
-            "Field data is needed to specify transformer id and tokens!",
-        ))
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify transformer id and tokens!"))
     }
 }
 
@@ -101,11 +107,28 @@ pub async fn reset_sampler(data: Option<Value>, state: AppState) -> Result<Value
         state
             .0
             .samplers
-            .reset_sampler(data.as_str().ok_or(Error::msg(
-                "data should be a string representing sampler id you want to reset!",
-            ))?)
+            .reset_sampler(
+                data.as_str().ok_or(
+                    CommandErrorKind::BadRequest.error(
+                        "data should be a string representing sampler id you want to reset!",
+                    ),
+                )?,
+            )
             .map(|_| Value::Null)
     } else {
-        Err(Error::msg("Field data is needed to specify sampler id!"))
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify sampler id!"))
+    }
+}
+
+pub async fn describe_sampler(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        state.0.samplers.describe_sampler(
+            data.as_str()
+                .ok_or(CommandErrorKind::BadRequest.error(
+                    "data should be a string representing sampler id you want to describe!",
+                ))?,
+        )
+    } else {
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify sampler id!"))
     }
 }
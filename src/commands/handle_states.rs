@@ -1,24 +1,172 @@
-use anyhow::{Error, Result};
+use std::collections::HashSet;
+
+use anyhow::Result;
 use serde::Deserialize;
-use serde_json::Value;
+use serde_json::{json, Value};
+
+use crate::{
+    app::{AppState, StateOptions},
+    commands::{helpers, types::CommandContext},
+    error::CommandErrorKind,
+    states::state_file,
+};
 
-use crate::{app::AppState, commands::helpers};
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum StateCreate {
+    Id(String),
+    Spec {
+        id: String,
+        #[serde(default)]
+        model: Option<String>,
+        #[serde(default)]
+        persistent: bool,
+        #[serde(default)]
+        max_context: Option<usize>,
+        #[serde(default)]
+        ttl_seconds: Option<u64>,
+        #[serde(default)]
+        pinned: bool,
+        #[serde(default)]
+        track_history: bool,
+    },
+}
 
 #[inline]
 pub async fn create_state(data: Option<Value>, state: AppState) -> Result<Value> {
     if let Some(data) = data {
-        state
-            .create_state(
-                data.as_str()
-                    .ok_or(Error::msg(
-                        "data should be a string representing state id you want to create!",
-                    ))?
-                    .to_string(),
+        let (id, options) = match serde_json::from_value::<StateCreate>(data).map_err(|_| {
+            CommandErrorKind::BadRequest.error(
+                "data should be a string representing state id you want to create, or an object with id and model!",
             )
-            .await
-            .map(|_| Value::Null)
+        })? {
+            StateCreate::Id(id) => (id, StateOptions::default()),
+            StateCreate::Spec {
+                id,
+                model,
+                persistent,
+                max_context,
+                ttl_seconds,
+                pinned,
+                track_history,
+            } => (
+                id,
+                StateOptions {
+                    model,
+                    persistent,
+                    max_context,
+                    ttl_seconds,
+                    pinned,
+                    track_history,
+                },
+            ),
+        };
+        state.create_state(id, options).await.map(|_| Value::Null)
+    } else {
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify state id!"))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum StateTouch {
+    Id(String),
+    Spec {
+        id: String,
+        #[serde(default)]
+        ttl_seconds: Option<u64>,
+    },
+}
+
+#[inline]
+pub async fn touch_state(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let (id, ttl_seconds) = match serde_json::from_value::<StateTouch>(data).map_err(|_| {
+            CommandErrorKind::BadRequest.error(
+                "data should be a string representing state id you want to touch, or an object with id and ttl_seconds!",
+            )
+        })? {
+            StateTouch::Id(id) => (id, None),
+            StateTouch::Spec { id, ttl_seconds } => (id, ttl_seconds),
+        };
+        state.touch_state(&id, ttl_seconds).map(|_| Value::Null)
+    } else {
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify state id!"))
+    }
+}
+
+#[inline]
+pub async fn reset_state(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let id = data.as_str().ok_or(
+            CommandErrorKind::BadRequest
+                .error("data should be a string representing state id you want to reset!"),
+        )?;
+        let _locks = state.lock_states(&[id.to_string()]).await?;
+        state.reset_state(id)?;
+        // Nothing is left to continue
+        state.forget_generations(&[id.to_string()]);
+        Ok(Value::Null)
     } else {
-        Err(Error::msg("Field data is needed to specify state id!"))
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify state id!"))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct StateCheckpoint {
+    id: String,
+    name: String,
+}
+
+#[inline]
+pub async fn checkpoint_state(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let StateCheckpoint { id, name } = serde_json::from_value(data)?;
+        state.checkpoint_state(&id, name).await.map(|_| Value::Null)
+    } else {
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify state id and checkpoint name!"))
+    }
+}
+
+#[inline]
+pub async fn rollback_state(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let StateCheckpoint { id, name } = serde_json::from_value(data)?;
+        let _locks = state.lock_states(&[id.clone()]).await?;
+        state.rollback_state(&id, &name).await?;
+        // The last generation isn't where the state is anymore
+        state.forget_generations(&[id]);
+        Ok(Value::Null)
+    } else {
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify state id and checkpoint name!"))
+    }
+}
+
+#[inline]
+pub async fn pin_state(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let id = data.as_str().ok_or(
+            CommandErrorKind::BadRequest
+                .error("data should be a string representing state id you want to pin!"),
+        )?;
+        state.pin_state(id, true).await.map(|_| Value::Null)
+    } else {
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify state id!"))
+    }
+}
+
+#[inline]
+pub async fn unpin_state(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let id = data.as_str().ok_or(
+            CommandErrorKind::BadRequest
+                .error("data should be a string representing state id you want to unpin!"),
+        )?;
+        state.pin_state(id, false).await.map(|_| Value::Null)
+    } else {
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify state id!"))
     }
 }
 
@@ -26,6 +174,11 @@ pub async fn create_state(data: Option<Value>, state: AppState) -> Result<Value>
 struct StateCopy {
     source: String,
     destination: String,
+    #[serde(default)]
+    shallow: bool,
+    /// Whether the copy outlives the connection, which defaults to the source's.
+    #[serde(default)]
+    persistent: Option<bool>,
 }
 
 #[inline]
@@ -34,25 +187,94 @@ pub async fn copy_state(data: Option<Value>, state: AppState) -> Result<Value> {
         let StateCopy {
             source,
             destination,
+            shallow,
+            persistent,
         } = serde_json::from_value(data)?;
         state
-            .copy_state(source, destination)
+            .copy_state(source, destination, shallow, persistent)
             .await
             .map(|_| Value::Null)
     } else {
-        Err(Error::msg(
-            "Field data is needed to specify source state and destination id!",
-        ))
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify source state and destination id!"))
     }
 }
 
+#[derive(Debug, Deserialize)]
+struct StateFanOut {
+    source: String,
+    destinations: Vec<String>,
+    #[serde(default)]
+    shallow: bool,
+    #[serde(default)]
+    persistent: Option<bool>,
+}
+
+#[inline]
+pub async fn fan_out_state(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let StateFanOut {
+            source,
+            destinations,
+            shallow,
+            persistent,
+        } = serde_json::from_value(data)?;
+        state
+            .fan_out_state(source, destinations, shallow, persistent)
+            .await
+            .map(|_| Value::Null)
+    } else {
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify source state and destination ids!"))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct MergeSource {
+    id: String,
+    weight: f32,
+}
+
+#[derive(Debug, Deserialize)]
+struct StateMerge {
+    sources: Vec<MergeSource>,
+    destination: String,
+    #[serde(default)]
+    persistent: bool,
+}
+
+#[inline]
+pub async fn merge_states(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let StateMerge {
+            sources,
+            destination,
+            persistent,
+        } = serde_json::from_value(data)?;
+        let sources = sources.into_iter().map(|x| (x.id, x.weight)).collect();
+        state
+            .merge_states(sources, destination, persistent)
+            .await
+            .map(|_| Value::Null)
+    } else {
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify sources and destination!"))
+    }
+}
+
+/// Same as `fan_out_state`.
+#[inline]
+pub async fn fork_state(data: Option<Value>, state: AppState) -> Result<Value> {
+    fan_out_state(data, state).await
+}
+
 #[inline]
 pub async fn delete_state(data: Option<Value>, state: AppState) -> Result<Value> {
     if let Some(data) = data {
         state
             .delete_state(
                 data.as_str()
-                    .ok_or(Error::msg(
+                    .ok_or(CommandErrorKind::BadRequest.error(
                         "data should be a string representing state id you want to delete!",
                     ))?
                     .to_string(),
@@ -60,10 +282,316 @@ pub async fn delete_state(data: Option<Value>, state: AppState) -> Result<Value>
             .await
             .map(|_| Value::Null)
     } else {
-        Err(Error::msg("Field data is needed to specify state id!"))
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify state id!"))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct StatesDelete {
+    #[serde(default)]
+    ids: Option<Vec<String>>,
+    #[serde(default)]
+    prefix: Option<String>,
+    /// A glob pattern, where `*` matches any characters and `?` a single one.
+    #[serde(default)]
+    pattern: Option<String>,
+    /// Allows a prefix or pattern matching every state.
+    #[serde(default)]
+    confirm_all: bool,
+}
+
+/// Deletes the states listed by id, or whose ids match a prefix or a glob pattern.
+/// Returns the deleted ids, leaving out listed ids which don't exist.
+#[inline]
+pub async fn delete_states(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let StatesDelete {
+            ids,
+            prefix,
+            pattern,
+            confirm_all,
+        } = serde_json::from_value(data)?;
+        let matches_all = match (&prefix, &pattern) {
+            (Some(prefix), _) => prefix.is_empty(),
+            (_, Some(pattern)) => pattern.chars().all(|x| x == '*'),
+            _ => false,
+        };
+        if matches_all && !confirm_all {
+            return Err(CommandErrorKind::BadRequest
+                .error("This matches every state, set confirm_all to delete them all!"));
+        }
+        let deleted = match (ids, prefix, pattern) {
+            (Some(ids), None, None) => {
+                let ids: HashSet<String> = ids.into_iter().collect();
+                state.delete_states(|id| ids.contains(id))
+            }
+            (None, Some(prefix), None) => state.delete_states(|id| id.starts_with(&prefix)),
+            (None, None, Some(pattern)) => {
+                let regex = helpers::glob_regex(&pattern)?;
+                state.delete_states(|id| regex.is_match(id))
+            }
+            _ => {
+                return Err(CommandErrorKind::BadRequest
+                    .error("Exactly one of ids, prefix and pattern must be given!"))
+            }
+        };
+        Ok(json!({ "count": deleted.len(), "ids": deleted }))
+    } else {
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify ids, prefix or pattern of states!"))
+    }
+}
+
+/// Bytes of floats in each binary result of `dump_state`.
+const DUMP_CHUNK_SIZE: usize = 1 << 20;
+
+#[derive(Debug, Deserialize)]
+struct StateSave {
+    state: String,
+    /// Name of the file in the state directory.
+    file: String,
+}
+
+#[inline]
+pub async fn save_state(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let StateSave { state: id, file } = serde_json::from_value(data)?;
+        let path = state.state_path(&file)?;
+        let (layout, saved) = state.save_state(&id).await?;
+        let bytes = state_file::encode_state(&layout, &saved);
+        let size = bytes.len();
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(path, bytes).await?;
+        Ok(json!({ "bytes": size }))
+    } else {
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify state id and file name!"))
+    }
+}
+
+#[inline]
+pub async fn dump_state(
+    data: Option<Value>,
+    state: AppState,
+    context: CommandContext,
+) -> Result<Value> {
+    if let Some(data) = data {
+        let id = data.as_str().ok_or(
+            CommandErrorKind::BadRequest
+                .error("data should be a string representing state id you want to dump!"),
+        )?;
+        let (layout, saved) = state.save_state(id).await?;
+        let (mut size, mut chunks) = (0, 0);
+        for chunk in state_file::encode_state_chunks(&layout, &saved, DUMP_CHUNK_SIZE) {
+            if context.handle.is_cancelled() {
+                return Err(CommandErrorKind::Interrupted.error("Dump is cancelled!"));
+            }
+            size += chunk.len();
+            chunks += 1;
+            context.binary.send(chunk).ok();
+        }
+        Ok(json!({ "bytes": size, "chunks": chunks }))
+    } else {
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify state id!"))
     }
 }
 
+#[derive(Debug, Deserialize)]
+struct StateLoad {
+    state: String,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default)]
+    persistent: bool,
+    /// Name of the file in the state directory for `load_state`.
+    #[serde(default)]
+    file: Option<String>,
+    /// The dumped bytes for `restore_state`, as one binary or a list of binary chunks.
+    #[serde(default)]
+    bytes: Option<Value>,
+}
+
+#[inline]
+pub async fn load_state(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let StateLoad {
+            state: id,
+            model,
+            persistent,
+            file,
+            ..
+        } = serde_json::from_value(data)?;
+        let file = file.ok_or(
+            CommandErrorKind::BadRequest.error("Field file is needed to specify the state file!"),
+        )?;
+        let bytes = tokio::fs::read(state.state_path(&file)?)
+            .await
+            .map_err(|e| {
+                CommandErrorKind::BadRequest
+                    .error(format!("Failed to read state file {}: {}", file, e))
+            })?;
+        state
+            .load_state(id, model, persistent, &bytes)
+            .await
+            .map(|_| Value::Null)
+    } else {
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify state id and file name!"))
+    }
+}
+
+#[inline]
+pub async fn restore_state(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let StateLoad {
+            state: id,
+            model,
+            persistent,
+            bytes,
+            ..
+        } = serde_json::from_value(data)?;
+        let bytes = match bytes {
+            Some(Value::Array(chunks)) => chunks
+                .into_iter()
+                .map(helpers::to_bytes)
+                .collect::<Result<Vec<_>>>()?
+                .concat(),
+            Some(bytes) => helpers::to_bytes(bytes)?,
+            None => {
+                return Err(CommandErrorKind::BadRequest
+                    .error("Field bytes is needed to specify the dumped state!"))
+            }
+        };
+        state
+            .load_state(id, model, persistent, &bytes)
+            .await
+            .map(|_| Value::Null)
+    } else {
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify state id and the dumped state!"))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct StatePrefill {
+    state: String,
+    tokens: Value,
+    #[serde(default)]
+    chunk_size: Option<usize>,
+}
+
+#[inline]
+pub async fn prefill(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let StatePrefill {
+            state: id,
+            tokens,
+            chunk_size,
+        } = serde_json::from_value(data)?;
+        let tokens = helpers::to_tokens(&state, tokens)?;
+        if tokens.is_empty() {
+            return Err(CommandErrorKind::BadRequest.error("Empty token list!"));
+        }
+        let len = tokens.len();
+        let _locks = state.lock_states(&[id.clone()]).await?;
+        state.prefill(id, tokens, chunk_size).await?;
+        Ok(json!({ "tokens": len }))
+    } else {
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify the state and tokens!"))
+    }
+}
+
+#[inline]
+pub async fn get_state(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let id =
+            data.as_str()
+                .ok_or(CommandErrorKind::BadRequest.error(
+                    "data should be a string representing state id you want to describe!",
+                ))?;
+        Ok(serde_json::to_value(state.get_state(id)?)?)
+    } else {
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify state id!"))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum StateHistoryQuery {
+    Id(String),
+    Spec {
+        id: String,
+        #[serde(default)]
+        last: Option<usize>,
+    },
+}
+
+/// Gets the latest tokens fed to a state which tracks its history, along with their text.
+#[inline]
+pub async fn get_state_history(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let (id, last) = match serde_json::from_value::<StateHistoryQuery>(data).map_err(|_| {
+            CommandErrorKind::BadRequest.error(
+                "data should be a string representing state id, or an object with id and last!",
+            )
+        })? {
+            StateHistoryQuery::Id(id) => (id, None),
+            StateHistoryQuery::Spec { id, last } => (id, last),
+        };
+        let history = state.get_state_history(&id, last)?;
+        // Offsets are in the bytes of the text, where a token may split a character
+        let mut bytes = Vec::new();
+        let mut offsets = Vec::with_capacity(history.tokens.len());
+        for &token in &history.tokens {
+            offsets.push(bytes.len());
+            bytes.extend(state.0.tokenizer.decode(&[token])?);
+        }
+        Ok(json!({
+            "id": history.id,
+            "start": history.start,
+            "token_ids": history.tokens,
+            "offsets": offsets,
+            "text": String::from_utf8_lossy(&bytes),
+        }))
+    } else {
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify state id!"))
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct StateList {
+    #[serde(default)]
+    prefix: String,
+    #[serde(default)]
+    offset: usize,
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+/// Lists the states whose ids start with `prefix`, sorted by id, a page at a time.
+#[inline]
+pub async fn list_states(data: Option<Value>, state: AppState) -> Result<Value> {
+    let StateList {
+        prefix,
+        offset,
+        limit,
+    } = match data {
+        None | Some(Value::Null) => StateList::default(),
+        Some(data) => serde_json::from_value(data)?,
+    };
+    let states = state.list_states(&prefix);
+    let total = states.len();
+    let states: Vec<_> = states
+        .into_iter()
+        .skip(offset)
+        .take(limit.unwrap_or(usize::MAX))
+        .collect();
+    Ok(json!({ "states": states, "total": total, "spill": state.spill_stats() }))
+}
+
 #[derive(Debug, Deserialize)]
 struct StateUpdate {
     states: Vec<String>,
@@ -75,10 +603,13 @@ pub async fn update_state(data: Option<Value>, state: AppState) -> Result<Value>
     if let Some(data) = data {
         let StateUpdate { states, tokens } = serde_json::from_value(data)?;
         let tokens = helpers::to_token_vec(&state, tokens)?;
-        state.update_state(states, tokens).await.map(|_| Value::Null)
+        let _locks = state.lock_states(&states).await?;
+        state
+            .update_state(states, tokens)
+            .await
+            .map(|_| Value::Null)
     } else {
-        Err(Error::msg(
-            "Field data is needed to specify state id and tokens!",
-        ))
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify state id and tokens!"))
     }
 }
@@ -0,0 +1,57 @@
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{app::AppState, error::CommandErrorKind};
+
+#[derive(Debug, Deserialize)]
+struct TemplateArgs {
+    id: String,
+    template: String,
+}
+
+#[inline]
+pub async fn create_template(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let TemplateArgs { id, template } = serde_json::from_value(data)?;
+        state
+            .0
+            .templates
+            .create_template(id, &template)
+            .map(|_| Value::Null)
+    } else {
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify template id and text!"))
+    }
+}
+
+#[inline]
+pub async fn delete_template(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        state
+            .0
+            .templates
+            .delete_template(
+                data.as_str().ok_or(CommandErrorKind::BadRequest.error(
+                    "data should be a string representing template id you want to delete!",
+                ))?,
+            )
+            .map(|_| Value::Null)
+    } else {
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify template id!"))
+    }
+}
+
+pub async fn describe_template(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let (template, variables) =
+            state.0.templates.describe_template(data.as_str().ok_or(
+                CommandErrorKind::BadRequest.error(
+                    "data should be a string representing template id you want to describe!",
+                ),
+            )?)?;
+        Ok(json!({ "template": template, "variables": variables }))
+    } else {
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify template id!"))
+    }
+}
@@ -0,0 +1,115 @@
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{app::AppState, error::CommandErrorKind};
+
+use super::helpers;
+
+#[derive(Debug, Deserialize)]
+struct TerminalArgs {
+    id: String,
+    data: Value,
+}
+
+#[inline]
+pub async fn create_terminal(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let TerminalArgs { id, data } = serde_json::from_value(data)?;
+        state
+            .0
+            .terminals
+            .create_terminal(id, state.clone(), data)
+            .map(|_| Value::Null)
+    } else {
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify terminal type_id and params!"))
+    }
+}
+
+#[inline]
+pub async fn list_terminals(_data: Option<Value>, state: AppState) -> Result<Value> {
+    Ok(serde_json::to_value(state.0.terminals.ids())?)
+}
+
+#[inline]
+pub async fn delete_terminal(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let deleted =
+            state
+                .0
+                .terminals
+                .delete_terminal(data.as_str().ok_or(CommandErrorKind::BadRequest.error(
+                    "data should be a string representing terminal id you want to delete!",
+                ))?)
+                .is_ok();
+        // Deleting an absent terminal is not an error, so a delete can be retried safely
+        Ok(json!({ "deleted": deleted }))
+    } else {
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify terminal id!"))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TerminalCopy {
+    source: String,
+    destination: String,
+}
+
+#[inline]
+pub async fn copy_terminal(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let TerminalCopy {
+            source,
+            destination,
+        } = serde_json::from_value(data)?;
+        state
+            .0
+            .terminals
+            .copy_terminal(source, destination)
+            .map(|_| Value::Null)
+    } else {
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify source terminal and destination id!"))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TerminalUpdate {
+    terminal: String,
+    tokens: Value,
+}
+
+/// Feeds tokens to the terminal as if they are generated, and returns if it fires.
+#[inline]
+pub async fn update_terminal(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        let TerminalUpdate { terminal, tokens } = serde_json::from_value(data)?;
+        let tokens = helpers::to_tokens(&state, tokens)?;
+        state
+            .0
+            .terminals
+            .terminate(&terminal, &tokens)
+            .map(|termination| Value::Bool(termination.is_some()))
+    } else {
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify terminal id and tokens!"))
+    }
+}
+
+#[inline]
+pub async fn reset_terminal(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        state
+            .0
+            .terminals
+            .reset_terminal(
+                data.as_str().ok_or(CommandErrorKind::BadRequest.error(
+                    "data should be a string representing terminal id you want to reset!",
+                ))?,
+            )
+            .map(|_| Value::Null)
+    } else {
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify terminal id!"))
+    }
+}
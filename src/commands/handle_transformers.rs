@@ -1,8 +1,8 @@
-use anyhow::{Error, Result};
+use anyhow::Result;
 use serde::Deserialize;
-use serde_json::Value;
+use serde_json::{json, Value};
 
-use crate::{app::AppState, states::InferenceInterruption};
+use crate::{app::AppState, error::CommandErrorKind};
 
 use super::helpers;
 
@@ -10,21 +10,24 @@ use super::helpers;
 struct TransformerArgs {
     id: String,
     data: Option<Value>,
+    /// Deletes the transformer once the connection is closed.
+    #[serde(default)]
+    scoped: bool,
 }
 
 #[inline]
 pub async fn create_transformer(data: Option<Value>, state: AppState) -> Result<Value> {
     if let Some(data) = data {
-        let TransformerArgs { id, data } = serde_json::from_value(data)?;
+        let TransformerArgs { id, data, scoped } = serde_json::from_value(data)?;
+        let owner = state.connection().filter(|_| scoped);
         state
             .0
             .transformers
-            .create_transformer(id, state.clone(), data)
+            .create_transformer(id, state.clone(), data, owner)
             .map(|_| Value::Null)
     } else {
-        Err(Error::msg(
-            "Field data is needed to specify transformer type_id and params!",
-        ))
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify transformer type_id and params!"))
     }
 }
 
@@ -47,26 +50,30 @@ pub async fn copy_transformer(data: Option<Value>, state: AppState) -> Result<Va
             .copy_transformer(source, destination)
             .map(|_| Value::Null)
     } else {
-        Err(Error::msg(
-            "Field data is needed to specify source transformer and destination id!",
-        ))
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify source transformer and destination id!"))
     }
 }
 
+#[inline]
+pub async fn list_transformers(_data: Option<Value>, state: AppState) -> Result<Value> {
+    Ok(serde_json::to_value(state.0.transformers.ids())?)
+}
+
 #[inline]
 pub async fn delete_transformer(data: Option<Value>, state: AppState) -> Result<Value> {
     if let Some(data) = data {
-        state
+        let deleted = state
             .0
             .transformers
-            .delete_transformer(data.as_str().ok_or(Error::msg(
+            .delete_transformer(data.as_str().ok_or(CommandErrorKind::BadRequest.error(
                 "data should be a string representing transformer id you want to delete!",
             ))?)
-            .map(|_| Value::Null)
+            .is_ok();
+        // Deleting an absent transformer is not an error, so a delete can be retried safely
+        Ok(json!({ "deleted": deleted }))
     } else {
-        Err(Error::msg(
-            "Field data is needed to specify transformer id!",
-        ))
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify transformer id!"))
     }
 }
 
@@ -85,15 +92,11 @@ pub async fn update_transformer(data: Option<Value>, state: AppState) -> Result<
             .0
             .transformers
             .update_transformer(&id, &tokens)
-            .map_err(|interrupt| match interrupt {
-                InferenceInterruption::Exhaustion => Error::msg("Transformer is exhausted!"),
-                InferenceInterruption::Error(e) => e,
-            })
+            .map_err(|interrupt| interrupt.into_error("Transformer"))
             .map(|_| Value::Null)
     } else {
-        Err(Error::msg(
-            "Field data is needed to specify transformer id and tokens!",
-        ))
+        Err(CommandErrorKind::BadRequest
+            .error("Field data is needed to specify transformer id and tokens!"))
     }
 }
 
@@ -103,13 +106,25 @@ pub async fn reset_transformer(data: Option<Value>, state: AppState) -> Result<V
         state
             .0
             .transformers
-            .reset_transformer(data.as_str().ok_or(Error::msg(
-                "data should be a string representing transformer id you want to reset!",
-            ))?)
+            .reset_transformer(data.as_str().ok_or(
+                CommandErrorKind::BadRequest.error(
+                    "data should be a string representing transformer id you want to reset!",
+                ),
+            )?)
             .map(|_| Value::Null)
     } else {
-        Err(Error::msg(
-            "Field data is needed to specify transformer id!",
-        ))
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify transformer id!"))
+    }
+}
+
+pub async fn describe_transformer(data: Option<Value>, state: AppState) -> Result<Value> {
+    if let Some(data) = data {
+        state.0.transformers.describe_transformer(
+            data.as_str().ok_or(CommandErrorKind::BadRequest.error(
+                "data should be a string representing transformer id you want to describe!",
+            ))?,
+        )
+    } else {
+        Err(CommandErrorKind::BadRequest.error("Field data is needed to specify transformer id!"))
     }
 }
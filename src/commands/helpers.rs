@@ -1,19 +1,112 @@
-use crate::app::AppState;
-use anyhow::{Error, Ok, Result};
+use crate::{app::AppState, error::CommandErrorKind};
+use anyhow::{Ok, Result};
+use bson::{spec::BinarySubtype, Binary, Bson};
+use regex::Regex;
 use serde_json::Value;
 
+/// The BSON binary subtype of tokens packed as little-endian `u16`s.
+const PACKED_TOKENS: u8 = 0x80;
+
+/// Converts tokens given as a string, an object with raw UTF-8 `bytes`, a list of token
+/// ids, or BSON binary (as an extended JSON `$binary` object). Strings and bytes are
+/// tokenized with the vocab of the server.
+///
+/// The subtype of BSON binary tells its encoding: `0x80` is packed token ids, and any
+/// other subtype is raw UTF-8 bytes.
 pub fn to_tokens(state: &AppState, data: Value) -> Result<Vec<u16>> {
     Ok(match data {
         Value::String(s) => state.tokenize(&s.into_bytes())?,
         Value::Array(v) => serde_json::from_value(Value::Array(v))?,
-        _ => return Err(Error::msg("Must be a string or a list of integers!")),
+        Value::Object(mut object) if object.len() == 1 && object.contains_key("bytes") => {
+            // Bytes may split a character, which a string can't carry
+            let bytes: Vec<u8> = serde_json::from_value(object.remove("bytes").unwrap())?;
+            state.tokenize(&bytes)?
+        }
+        Value::Object(object) if object.len() == 1 && object.contains_key("$binary") => {
+            match Bson::try_from(Value::Object(object))? {
+                Bson::Binary(Binary {
+                    subtype: BinarySubtype::UserDefined(PACKED_TOKENS),
+                    bytes,
+                }) => unpack_tokens(&bytes)?,
+                Bson::Binary(Binary { bytes, .. }) => state.tokenize(&bytes)?,
+                _ => return Err(CommandErrorKind::BadRequest.error("Malformed binary tokens!")),
+            }
+        }
+        _ => {
+            return Err(CommandErrorKind::BadRequest
+                .error("Must be a string, a list of integers, an object with bytes or binary!"))
+        }
     })
 }
 
+/// Reads token ids packed as little-endian `u16`s.
+fn unpack_tokens(bytes: &[u8]) -> Result<Vec<u16>> {
+    if bytes.len() % 2 != 0 {
+        return Err(
+            CommandErrorKind::BadRequest.error("Packed tokens must have an even number of bytes!")
+        );
+    }
+    Ok(bytes
+        .chunks_exact(2)
+        .map(|x| u16::from_le_bytes([x[0], x[1]]))
+        .collect())
+}
+
+/// Reads BSON binary of any subtype, given as an extended JSON `$binary` object.
+pub fn to_bytes(data: Value) -> Result<Vec<u8>> {
+    match Bson::try_from(data).ok() {
+        Some(Bson::Binary(Binary { bytes, .. })) => Ok(bytes),
+        _ => Err(CommandErrorKind::BadRequest.error("Must be BSON binary!")),
+    }
+}
+
+/// Converts tokens for each state, each of which is converted by `to_tokens` on its own,
+/// so strings and token ids can be mixed. A single string, object or list of token ids is
+/// the tokens of a single state.
 pub fn to_token_vec(state: &AppState, data: Value) -> Result<Vec<Vec<u16>>> {
-    if let Value::Array(data) = data {
-        data.into_iter().map(|x| to_tokens(state, x)).collect()
-    } else {
-        Ok(vec![to_tokens(state, data)?])
+    match data {
+        Value::Array(data) if !data.is_empty() && data.iter().all(Value::is_number) => {
+            Ok(vec![to_tokens(state, Value::Array(data))?])
+        }
+        Value::Array(data) => to_each_tokens(state, data),
+        data => Ok(vec![to_tokens(state, data)?]),
     }
 }
+
+/// Converts the tokens of each state, naming the state whose tokens are invalid.
+pub fn to_each_tokens(state: &AppState, data: Vec<Value>) -> Result<Vec<Vec<u16>>> {
+    data.into_iter()
+        .enumerate()
+        .map(|(index, x)| {
+            to_tokens(state, x).map_err(|e| {
+                CommandErrorKind::BadRequest
+                    .error(format!("Invalid tokens at index {}: {}", index, e))
+            })
+        })
+        .collect()
+}
+
+/// Trims `len` bytes from the end of `text`, and the broken character left if there is one.
+pub fn trim_end(text: &mut String, len: usize) {
+    let mut end = text.len().saturating_sub(len);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+}
+
+/// Compiles a glob pattern into a regex matching whole ids, where `*` matches any
+/// characters and `?` matches a single one.
+pub fn glob_regex(pattern: &str) -> Result<Regex> {
+    let body: String = pattern
+        .split('*')
+        .map(|part| {
+            part.split('?')
+                .map(regex::escape)
+                .collect::<Vec<_>>()
+                .join(".")
+        })
+        .collect::<Vec<_>>()
+        .join(".*");
+    Ok(Regex::new(&format!("^(?s:{})$", body))?)
+}
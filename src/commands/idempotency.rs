@@ -0,0 +1,102 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use serde_json::Value;
+
+use crate::error::CommandErrorKind;
+
+/// The response of a command, kept to be replayed. Errors keep their code and message.
+#[derive(Debug, Clone)]
+pub struct Outcome(std::result::Result<Value, (CommandErrorKind, String)>);
+
+impl Outcome {
+    pub fn of(result: &Result<Value>) -> Self {
+        Self(match result {
+            Ok(value) => Ok(value.clone()),
+            Err(error) => Err((CommandErrorKind::of(error), error.to_string())),
+        })
+    }
+
+    pub fn replay(&self) -> Result<Value> {
+        match &self.0 {
+            Ok(value) => Ok(value.clone()),
+            Err((kind, message)) => Err(kind.error(message.clone())),
+        }
+    }
+}
+
+/// The outcome of the command with a key, which is locked while the command runs so a
+/// duplicate waits for it instead of running again.
+pub type OutcomeSlot = Arc<tokio::sync::Mutex<Option<Outcome>>>;
+
+#[derive(Debug)]
+struct IdempotencyEntry {
+    command: String,
+    outcome: OutcomeSlot,
+}
+
+#[derive(Debug, Default)]
+struct IdempotencyMap {
+    entries: HashMap<String, IdempotencyEntry>,
+    /// Keys in the order they are first seen, the oldest is forgotten first.
+    order: VecDeque<String>,
+}
+
+/// Remembers the responses of commands sent with an idempotency key, so a client retrying
+/// a command whose response is lost gets the original response instead of running the
+/// command again.
+///
+/// Keys are shared by all connections, since a retry usually comes from a new connection.
+/// The oldest key is forgotten once the cache is full.
+#[derive(Debug)]
+pub struct IdempotencyCache {
+    capacity: usize,
+    map: Mutex<IdempotencyMap>,
+}
+
+impl IdempotencyCache {
+    /// Creates a cache remembering at most `capacity` keys, 0 to disable it.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            map: Mutex::new(IdempotencyMap::default()),
+        }
+    }
+
+    /// The outcome slot of a key, which is created empty for a new key. `None` if the
+    /// cache is disabled. Fails if the key is taken by another command.
+    pub fn slot(&self, key: &str, command: &str) -> Result<Option<OutcomeSlot>> {
+        if self.capacity == 0 {
+            return Ok(None);
+        }
+        let mut map = self.map.lock().unwrap();
+        if let Some(entry) = map.entries.get(key) {
+            return match entry.command == command {
+                true => Ok(Some(entry.outcome.clone())),
+                false => Err(CommandErrorKind::BadRequest.error(format!(
+                    "Idempotency key {} is already used by command {}!",
+                    key, entry.command
+                ))),
+            };
+        }
+        while map.entries.len() >= self.capacity {
+            match map.order.pop_front() {
+                Some(oldest) => map.entries.remove(&oldest),
+                None => break,
+            };
+        }
+        let outcome = OutcomeSlot::default();
+        map.entries.insert(
+            key.to_string(),
+            IdempotencyEntry {
+                command: command.to_string(),
+                outcome: outcome.clone(),
+            },
+        );
+        map.order.push_back(key.to_string());
+        Ok(Some(outcome))
+    }
+}
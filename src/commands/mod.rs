@@ -1,15 +1,35 @@
-use anyhow::{Error, Ok, Result};
+use anyhow::Result;
+use lazy_static::lazy_static;
 use serde::Deserialize;
 use serde_json::Value;
+use tokio::sync::mpsc;
 
-use crate::{app::AppState, register_handlers};
+use crate::{app::AppState, error::CommandErrorKind, register_handlers};
 
+use self::{
+    idempotency::Outcome,
+    registry::{Command, CommandRegistry, Handler},
+    types::{BinarySender, PartialSender},
+};
+
+mod handle_commands;
+mod handle_components;
 mod handle_infer;
+mod handle_logits;
+mod handle_models;
+mod handle_normalizers;
+mod handle_reset;
 mod handle_samplers;
 mod handle_states;
+mod handle_templates;
+mod handle_terminals;
 mod handle_transformers;
 mod helpers;
+mod schemas;
 
+pub mod idempotency;
+pub mod registry;
+pub mod resume;
 pub mod types;
 
 #[derive(Debug, Deserialize)]
@@ -17,34 +37,178 @@ pub struct TextCommand {
     pub echo_id: String,
     command: String,
     data: Option<Value>,
+    /// Replays the response of the first command with the same key instead of running
+    /// the command again.
+    #[serde(default)]
+    idempotency_key: Option<String>,
+    /// Keeps the command running after the connection is closed, and buffers its results
+    /// so they can be got by `resume` from another connection.
+    #[serde(default)]
+    resumable: bool,
+}
+
+lazy_static! {
+    static ref REGISTRY: CommandRegistry = register_handlers!(
+        [
+            // Commands
+            handle_commands::echo,
+            handle_commands::list_commands,
+            handle_commands::describe_command,
+            // States
+            handle_states::create_state,
+            handle_states::copy_state,
+            handle_states::fan_out_state,
+            handle_states::fork_state,
+            handle_states::merge_states,
+            handle_states::update_state,
+            handle_states::prefill,
+            handle_states::touch_state,
+            handle_states::reset_state,
+            handle_states::checkpoint_state,
+            handle_states::rollback_state,
+            handle_states::pin_state,
+            handle_states::unpin_state,
+            handle_states::delete_state,
+            handle_states::delete_states,
+            handle_states::list_states,
+            handle_states::get_state,
+            handle_states::get_state_history,
+            handle_states::save_state,
+            handle_states::load_state,
+            handle_states::restore_state,
+            //Transformers
+            handle_transformers::create_transformer,
+            handle_transformers::copy_transformer,
+            handle_transformers::update_transformer,
+            handle_transformers::delete_transformer,
+            handle_transformers::list_transformers,
+            handle_transformers::reset_transformer,
+            handle_transformers::describe_transformer,
+            //Samplers
+            handle_samplers::create_sampler,
+            handle_samplers::copy_sampler,
+            handle_samplers::update_sampler,
+            handle_samplers::delete_sampler,
+            handle_samplers::list_samplers,
+            handle_samplers::reset_sampler,
+            handle_samplers::describe_sampler,
+            //Terminals
+            handle_terminals::create_terminal,
+            handle_terminals::copy_terminal,
+            handle_terminals::update_terminal,
+            handle_terminals::delete_terminal,
+            handle_terminals::list_terminals,
+            handle_terminals::reset_terminal,
+            //Normalizers
+            handle_normalizers::create_normalizer,
+            handle_normalizers::copy_normalizer,
+            handle_normalizers::update_normalizer,
+            handle_normalizers::delete_normalizer,
+            handle_normalizers::list_normalizers,
+            handle_normalizers::reset_normalizer,
+            //Templates
+            handle_templates::create_template,
+            handle_templates::delete_template,
+            handle_templates::describe_template,
+            //Reset
+            handle_reset::reset_all,
+            //Components
+            handle_components::export_components,
+            handle_components::import_components,
+            //Infer
+            handle_infer::set_defaults,
+            handle_infer::abort,
+            handle_infer::cancel,
+            handle_logits::suggest_next,
+            handle_logits::accept_token,
+            //Models
+            handle_models::model_info,
+            handle_models::gpu_info,
+            handle_models::warmup,
+        ],
+        [
+            // Commands
+            handle_commands::resume,
+            //Infer
+            handle_infer::infer,
+            handle_infer::continue_infer as "continue",
+            handle_logits::get_logits,
+            handle_logits::score,
+            //States
+            handle_states::dump_state,
+        ]
+    );
+}
+
+/// All commands the server handles.
+pub fn registry() -> &'static CommandRegistry {
+    &REGISTRY
 }
 
 impl TextCommand {
-    pub async fn handle(&self, state: AppState) -> Result<Value> {
-        register_handlers!(
-            self,
-            state,
-            [
-                // States
-                handle_states::create_state,
-                handle_states::copy_state,
-                handle_states::update_state,
-                handle_states::delete_state,
-                //Transformers
-                handle_transformers::create_transformer,
-                handle_transformers::copy_transformer,
-                handle_transformers::update_transformer,
-                handle_transformers::delete_transformer,
-                handle_transformers::reset_transformer,
-                //Samplers
-                handle_samplers::create_sampler,
-                handle_samplers::copy_sampler,
-                handle_samplers::update_sampler,
-                handle_samplers::delete_sampler,
-                handle_samplers::reset_sampler,
-                //Infer
-                handle_infer::infer,
-            ]
-        )
+    pub async fn handle(
+        &self,
+        state: AppState,
+        partial: PartialSender,
+        binary: BinarySender,
+    ) -> Result<Value> {
+        let command = registry().get(&self.command)?;
+        let slot = match &self.idempotency_key {
+            Some(key) => state.0.idempotency.slot(key, &self.command)?,
+            None => None,
+        };
+        let slot = match slot {
+            Some(slot) => slot,
+            None => return self.run(command, state, partial, binary).await,
+        };
+
+        // Duplicates wait here until the first command with the key is done.
+        let mut outcome = slot.lock().await;
+        if let Some(outcome) = outcome.as_ref() {
+            return outcome.replay();
+        }
+        let result = self.run(command, state, partial, binary).await;
+        // A command cancelled by a disconnect didn't finish, so its retry runs again.
+        match &result {
+            Err(error) if CommandErrorKind::of(error) == CommandErrorKind::Interrupted => {}
+            _ => *outcome = Some(Outcome::of(&result)),
+        }
+        result
+    }
+
+    /// Runs the command, buffering its results for `resume` if it's resumable.
+    async fn run(
+        &self,
+        command: &Command,
+        state: AppState,
+        partial: PartialSender,
+        binary: BinarySender,
+    ) -> Result<Value> {
+        if !self.resumable {
+            return command
+                .call(&self.echo_id, self.data.clone(), state, partial, binary)
+                .await;
+        }
+        let resumable = &state.0.resumable;
+        let progress = resumable.start(&self.echo_id, state.connection())?;
+
+        // Partial results are still sent to the connection while it's open.
+        let (sender, mut receiver) = mpsc::unbounded_channel::<Value>();
+        let forward = async {
+            while let Some(value) = receiver.recv().await {
+                progress.push(value.clone());
+                let _ = partial.send(value);
+            }
+        };
+        let call = command.call(
+            &self.echo_id,
+            self.data.clone(),
+            state.clone(),
+            sender,
+            binary,
+        );
+        let (result, _) = tokio::join!(call, forward);
+        resumable.finish(&self.echo_id, &progress, Outcome::of(&result));
+        result
     }
 }
@@ -0,0 +1,123 @@
+use std::{collections::HashMap, future::Future, pin::Pin};
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{app::AppState, error::CommandErrorKind};
+
+use super::types::{BinarySender, CommandContext, PartialSender};
+
+pub type HandlerFuture = Pin<Box<dyn Future<Output = Result<Value>> + Send>>;
+
+/// A command handler. Only streaming handlers take a `CommandContext`, which registers the
+/// command so it can be cancelled.
+pub enum Handler {
+    Simple(fn(Option<Value>, AppState) -> HandlerFuture),
+    Streaming(fn(Option<Value>, AppState, CommandContext) -> HandlerFuture),
+}
+
+pub struct Command {
+    pub name: &'static str,
+    /// JSON schema of the data of the command, whose description describes the command.
+    pub schema: Value,
+    pub handler: Handler,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CommandInfo {
+    pub name: &'static str,
+    pub description: Option<String>,
+    pub streaming: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schema: Option<Value>,
+}
+
+impl Command {
+    pub fn info(&self, with_schema: bool) -> CommandInfo {
+        CommandInfo {
+            name: self.name,
+            description: self.schema["description"].as_str().map(str::to_string),
+            streaming: matches!(self.handler, Handler::Streaming(_)),
+            schema: with_schema.then(|| self.schema.clone()),
+        }
+    }
+
+    pub async fn call(
+        &self,
+        echo_id: &str,
+        data: Option<Value>,
+        state: AppState,
+        partial: PartialSender,
+        binary: BinarySender,
+    ) -> Result<Value> {
+        match self.handler {
+            Handler::Simple(handler) => handler(data, state).await,
+            Handler::Streaming(handler) => {
+                let context = CommandContext::new(echo_id, &state, partial, binary);
+                handler(data, state, context).await
+            }
+        }
+    }
+}
+
+/// All commands by name.
+pub struct CommandRegistry {
+    commands: HashMap<&'static str, Command>,
+}
+
+impl CommandRegistry {
+    pub fn new(commands: Vec<Command>) -> Self {
+        Self {
+            commands: commands.into_iter().map(|x| (x.name, x)).collect(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Command> {
+        self.commands
+            .get(name)
+            .ok_or_else(|| match self.suggest(name) {
+                Some(suggestion) => CommandErrorKind::CommandNotFound.error(format!(
+                    "Unknown command {}, did you mean {}?",
+                    name, suggestion
+                )),
+                None => CommandErrorKind::CommandNotFound.error(format!(
+                    "Unknown command {}! Use list_commands to see all commands.",
+                    name
+                )),
+            })
+    }
+
+    /// All commands, sorted by name.
+    pub fn list(&self) -> Vec<&Command> {
+        let mut commands: Vec<_> = self.commands.values().collect();
+        commands.sort_unstable_by_key(|x| x.name);
+        commands
+    }
+
+    /// The closest command name to a misspelled one, if any is close enough.
+    fn suggest(&self, name: &str) -> Option<&'static str> {
+        self.commands
+            .keys()
+            .map(|x| (edit_distance(name, x), *x))
+            .filter(|(distance, x)| *distance <= (x.len() / 3).max(1))
+            .min()
+            .map(|(_, x)| x)
+    }
+}
+
+/// Levenshtein distance between two strings.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, x) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, y) in b.iter().enumerate() {
+            let substitution = diagonal + (x != *y) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
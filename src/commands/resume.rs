@@ -0,0 +1,134 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use dashmap::{mapref::entry::Entry, DashMap};
+use serde_json::Value;
+use tokio::sync::Notify;
+
+use crate::error::CommandErrorKind;
+
+use super::idempotency::Outcome;
+
+#[derive(Debug, Default)]
+struct ProgressData {
+    partials: Vec<Value>,
+    /// The final response, once the command is done.
+    outcome: Option<Outcome>,
+}
+
+/// The results of a resumable command so far, which `resume` replays and follows.
+#[derive(Debug)]
+pub struct Progress {
+    /// The connection which sent the command.
+    connection: Option<usize>,
+    data: Mutex<ProgressData>,
+    notify: Notify,
+}
+
+impl Progress {
+    pub fn push(&self, partial: Value) {
+        self.data.lock().unwrap().partials.push(partial);
+        self.notify.notify_waiters();
+    }
+
+    fn finish(&self, outcome: Outcome) {
+        self.data.lock().unwrap().outcome = Some(outcome);
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.data.lock().unwrap().outcome.is_none()
+    }
+
+    /// Waits until there are partial results after the first `from` ones, or the command
+    /// is done. Returns those partial results, and the final response if done.
+    pub async fn next(&self, from: usize) -> (Vec<Value>, Option<Outcome>) {
+        loop {
+            // Created before checking, so a result pushed in between still wakes it
+            let notified = self.notify.notified();
+            {
+                let data = self.data.lock().unwrap();
+                if data.partials.len() > from || data.outcome.is_some() {
+                    let partials = data.partials.get(from..).unwrap_or_default().to_vec();
+                    return (partials, data.outcome.clone());
+                }
+            }
+            notified.await;
+        }
+    }
+}
+
+/// Keeps the results of resumable commands by their echo_ids, so a client losing its
+/// connection can get them on another one.
+///
+/// A resumable command keeps running after its connection is closed, and its results are
+/// kept for `window` after it's done. A `window` of 0 disables resumable commands.
+#[derive(Debug)]
+pub struct ResumeBuffers {
+    window: Duration,
+    map: Arc<DashMap<String, Arc<Progress>>>,
+}
+
+impl ResumeBuffers {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            map: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Starts buffering the results of a command. Fails if a resumable command with the
+    /// same echo_id is still running.
+    pub fn start(&self, echo_id: &str, connection: Option<usize>) -> Result<Arc<Progress>> {
+        if self.window.is_zero() {
+            return Err(CommandErrorKind::BadRequest.error(
+                "Resumable commands are disabled, set --resume-window-secs to enable them!",
+            ));
+        }
+        let progress = Arc::new(Progress {
+            connection,
+            data: Mutex::new(ProgressData::default()),
+            notify: Notify::new(),
+        });
+        match self.map.entry(echo_id.to_string()) {
+            Entry::Occupied(entry) if entry.get().is_running() => {
+                Err(CommandErrorKind::AlreadyExists.error(format!(
+                    "A resumable command with echo_id {} is still running!",
+                    echo_id
+                )))
+            }
+            entry => {
+                entry.insert(progress.clone());
+                Ok(progress)
+            }
+        }
+    }
+
+    /// Records the final response of a command, whose results are forgotten after the
+    /// window.
+    pub fn finish(&self, echo_id: &str, progress: &Arc<Progress>, outcome: Outcome) {
+        progress.finish(outcome);
+        let (map, echo_id, progress) = (self.map.clone(), echo_id.to_string(), progress.clone());
+        let window = self.window;
+        tokio::spawn(async move {
+            tokio::time::sleep(window).await;
+            // Unless another command reused the echo_id since
+            map.remove_if(&echo_id, |_, x| Arc::ptr_eq(x, &progress));
+        });
+    }
+
+    pub fn get(&self, echo_id: &str) -> Option<Arc<Progress>> {
+        self.map.get(echo_id).map(|x| x.value().clone())
+    }
+
+    /// Whether the command is a running resumable command sent by the connection, which
+    /// isn't cancelled when the connection is closed.
+    pub fn is_running(&self, connection: Option<usize>, echo_id: &str) -> bool {
+        self.map
+            .get(echo_id)
+            .is_some_and(|x| x.connection == connection && x.is_running())
+    }
+}
@@ -0,0 +1,714 @@
+//! JSON schemas of the `data` of each command, named after the handlers. The description
+//! of a schema is also the description of its command.
+
+use serde_json::{json, Value};
+
+/// Tokens given either as a string to tokenize or as token ids.
+fn tokens() -> Value {
+    json!({
+        "oneOf": [
+            { "type": "string" },
+            { "type": "array", "items": { "type": "integer", "minimum": 0, "maximum": 65535 } }
+        ]
+    })
+}
+
+/// Tokens for each state, or the same tokens for a single one.
+fn token_vec() -> Value {
+    json!({
+        "oneOf": [tokens(), { "type": "array", "items": tokens() }]
+    })
+}
+
+fn id(description: &str) -> Value {
+    json!({ "description": description, "type": "string" })
+}
+
+fn optional_model(description: &str) -> Value {
+    json!({ "description": description, "type": ["string", "null"] })
+}
+
+fn create(description: &str) -> Value {
+    json!({
+        "description": description,
+        "type": "object",
+        "properties": {
+            "id": { "type": "string" },
+            "data": {
+                "type": "object",
+                "properties": {
+                    "type_id": { "type": "string" },
+                    "params": {}
+                },
+                "required": ["type_id"]
+            }
+        },
+        "required": ["id", "data"]
+    })
+}
+
+/// Adds the `scoped` flag of components deleted with the connection.
+fn scoped(mut schema: Value) -> Value {
+    schema["properties"]["scoped"] = json!({ "type": "boolean", "default": false });
+    schema
+}
+
+fn copy(description: &str) -> Value {
+    json!({
+        "description": description,
+        "type": "object",
+        "properties": {
+            "source": { "type": "string" },
+            "destination": { "type": "string" }
+        },
+        "required": ["source", "destination"]
+    })
+}
+
+fn checkpoint(description: &str) -> Value {
+    json!({
+        "description": description,
+        "type": "object",
+        "properties": {
+            "id": { "type": "string" },
+            "name": { "type": "string" }
+        },
+        "required": ["id", "name"]
+    })
+}
+
+fn update(description: &str, key: &str, tokens: Value) -> Value {
+    json!({
+        "description": description,
+        "type": "object",
+        "properties": {
+            key: { "type": "string" },
+            "tokens": tokens
+        },
+        "required": [key, "tokens"]
+    })
+}
+
+pub fn echo() -> Value {
+    json!({ "description": "Responds with the data as is." })
+}
+
+pub fn list_commands() -> Value {
+    json!({ "description": "Lists the name and description of all commands.", "type": "null" })
+}
+
+pub fn describe_command() -> Value {
+    id("Describes a command with the JSON schema of its data.")
+}
+
+pub fn resume() -> Value {
+    json!({
+        "description": "Reattaches to a resumable command by its echo_id, sending its partial results after the first `from` ones and then its final response.",
+        "type": "object",
+        "properties": {
+            "echo_id": { "type": "string" },
+            "from": { "type": "integer", "minimum": 0 }
+        },
+        "required": ["echo_id"]
+    })
+}
+
+pub fn create_state() -> Value {
+    json!({
+        "description": "Creates a state against a model.",
+        "oneOf": [
+            { "type": "string" },
+            {
+                "type": "object",
+                "properties": {
+                    "id": { "type": "string" },
+                    "model": { "type": "string" },
+                    "persistent": { "type": "boolean", "default": false },
+                    "max_context": { "type": "integer", "minimum": 0 },
+                    "ttl_seconds": { "type": "integer", "minimum": 0 },
+                    "pinned": { "type": "boolean", "default": false },
+                    "track_history": { "type": "boolean", "default": false }
+                },
+                "required": ["id"]
+            }
+        ]
+    })
+}
+
+pub fn touch_state() -> Value {
+    json!({
+        "description": "Marks a state as used, optionally replacing its TTL.",
+        "oneOf": [
+            { "type": "string" },
+            {
+                "type": "object",
+                "properties": {
+                    "id": { "type": "string" },
+                    "ttl_seconds": { "type": "integer", "minimum": 0 }
+                },
+                "required": ["id"]
+            }
+        ]
+    })
+}
+
+pub fn reset_state() -> Value {
+    id("Resets a state to the initial state in place, keeping its id and settings.")
+}
+
+pub fn checkpoint_state() -> Value {
+    checkpoint("Takes a named snapshot of a state, which rollback_state restores.")
+}
+
+pub fn rollback_state() -> Value {
+    checkpoint("Restores a checkpoint of a state in place, along with its token count.")
+}
+
+pub fn pin_state() -> Value {
+    id("Keeps a state loaded in a slot of the batch of its model until it's unpinned.")
+}
+
+pub fn unpin_state() -> Value {
+    id("Releases the slot pinned by a state.")
+}
+
+pub fn copy_state() -> Value {
+    let mut schema = copy("Copies a state to a new id.");
+    schema["properties"]["shallow"] = json!({ "type": "boolean", "default": false });
+    schema["properties"]["persistent"] = json!({ "type": ["boolean", "null"] });
+    schema
+}
+
+pub fn fan_out_state() -> Value {
+    json!({
+        "description": "Copies a state to each of the new ids at once, or to none if any of them exists.",
+        "type": "object",
+        "properties": {
+            "source": { "type": "string" },
+            "destinations": { "type": "array", "items": { "type": "string" } },
+            "shallow": { "type": "boolean", "default": false },
+            "persistent": { "type": ["boolean", "null"] }
+        },
+        "required": ["source", "destinations"]
+    })
+}
+
+pub fn fork_state() -> Value {
+    let mut schema = fan_out_state();
+    schema["description"] = json!("Same as fan_out_state.");
+    schema
+}
+
+pub fn save_state() -> Value {
+    json!({
+        "description": "Saves the latest data of a state to a file in the state directory.",
+        "type": "object",
+        "properties": {
+            "state": { "type": "string" },
+            "file": { "type": "string" }
+        },
+        "required": ["state", "file"]
+    })
+}
+
+pub fn load_state() -> Value {
+    json!({
+        "description": "Creates a state from a file saved by save_state, which must match the model.",
+        "type": "object",
+        "properties": {
+            "state": { "type": "string" },
+            "model": { "type": ["string", "null"] },
+            "persistent": { "type": "boolean", "default": false },
+            "file": { "type": "string" }
+        },
+        "required": ["state", "file"]
+    })
+}
+
+pub fn dump_state() -> Value {
+    json!({
+        "description": "Sends the latest data of a state in binary results, in the format of save_state.",
+        "type": "string"
+    })
+}
+
+pub fn restore_state() -> Value {
+    json!({
+        "description": "Creates a state from the binary results of dump_state, which must match the model.",
+        "type": "object",
+        "properties": {
+            "state": { "type": "string" },
+            "model": { "type": ["string", "null"] },
+            "persistent": { "type": "boolean", "default": false },
+            "bytes": {
+                "oneOf": [
+                    { "type": "object" },
+                    { "type": "array", "items": { "type": "object" } }
+                ]
+            }
+        },
+        "required": ["state", "bytes"]
+    })
+}
+
+pub fn list_states() -> Value {
+    json!({
+        "description": "Lists the states with their metadata, sorted by id, a page at a time.",
+        "type": ["object", "null"],
+        "properties": {
+            "prefix": { "type": "string", "default": "" },
+            "offset": { "type": "integer", "minimum": 0, "default": 0 },
+            "limit": { "type": ["integer", "null"], "minimum": 0 }
+        }
+    })
+}
+
+pub fn get_state() -> Value {
+    id("Describes a state with its metadata, like an entry of list_states.")
+}
+
+pub fn get_state_history() -> Value {
+    json!({
+        "description": "Gets the latest tokens fed to a state created with track_history, with their decoded text and offsets.",
+        "oneOf": [
+            { "type": "string" },
+            {
+                "type": "object",
+                "properties": {
+                    "id": { "type": "string" },
+                    "last": { "type": "integer", "minimum": 0 }
+                },
+                "required": ["id"]
+            }
+        ]
+    })
+}
+
+pub fn merge_states() -> Value {
+    json!({
+        "description": "Creates a state blending the data of states of the same model by weights.",
+        "type": "object",
+        "properties": {
+            "sources": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "id": { "type": "string" },
+                        "weight": { "type": "number", "minimum": 0 }
+                    },
+                    "required": ["id", "weight"]
+                },
+                "minItems": 1
+            },
+            "destination": { "type": "string" },
+            "persistent": { "type": "boolean", "default": false }
+        },
+        "required": ["sources", "destination"]
+    })
+}
+
+pub fn update_state() -> Value {
+    json!({
+        "description": "Feeds tokens to states.",
+        "type": "object",
+        "properties": {
+            "states": { "type": "array", "items": { "type": "string" } },
+            "tokens": token_vec()
+        },
+        "required": ["states", "tokens"]
+    })
+}
+
+pub fn prefill() -> Value {
+    json!({
+        "description": "Feeds a long prompt to a state in large chunks, without sampling.",
+        "type": "object",
+        "properties": {
+            "state": { "type": "string" },
+            "tokens": tokens(),
+            "chunk_size": { "type": ["integer", "null"], "minimum": 1 }
+        },
+        "required": ["state", "tokens"]
+    })
+}
+
+pub fn delete_state() -> Value {
+    id("Deletes a state.")
+}
+
+pub fn delete_states() -> Value {
+    json!({
+        "description": "Deletes the states listed by id, or whose ids match a prefix or a glob pattern.",
+        "type": "object",
+        "properties": {
+            "ids": { "type": "array", "items": { "type": "string" } },
+            "prefix": { "type": "string" },
+            "pattern": { "type": "string" },
+            "confirm_all": { "type": "boolean", "default": false }
+        },
+        "oneOf": [
+            { "required": ["ids"] },
+            { "required": ["prefix"] },
+            { "required": ["pattern"] }
+        ]
+    })
+}
+
+pub fn create_transformer() -> Value {
+    scoped(create("Creates a transformer of a type with its params."))
+}
+
+pub fn copy_transformer() -> Value {
+    copy("Copies a transformer to a new id.")
+}
+
+pub fn update_transformer() -> Value {
+    update("Feeds tokens to a transformer.", "id", tokens())
+}
+
+pub fn delete_transformer() -> Value {
+    id("Deletes a transformer, and reports whether it existed.")
+}
+
+pub fn list_transformers() -> Value {
+    json!({ "description": "Lists the ids of all transformers.", "type": "null" })
+}
+
+pub fn reset_transformer() -> Value {
+    id("Resets a transformer to its initial state.")
+}
+
+pub fn describe_transformer() -> Value {
+    id("Describes the type and params of a transformer.")
+}
+
+pub fn create_sampler() -> Value {
+    scoped(create("Creates a sampler of a type with its params."))
+}
+
+pub fn copy_sampler() -> Value {
+    copy("Copies a sampler to a new id.")
+}
+
+pub fn update_sampler() -> Value {
+    update(
+        "Feeds tokens of each state to a sampler.",
+        "sampler",
+        token_vec(),
+    )
+}
+
+pub fn delete_sampler() -> Value {
+    id("Deletes a sampler, and reports whether it existed.")
+}
+
+pub fn list_samplers() -> Value {
+    json!({ "description": "Lists the ids of all samplers.", "type": "null" })
+}
+
+pub fn reset_sampler() -> Value {
+    id("Resets a sampler to its initial state.")
+}
+
+pub fn describe_sampler() -> Value {
+    id("Describes the type and params of a sampler.")
+}
+
+pub fn create_terminal() -> Value {
+    create("Creates a terminal of a type with its params.")
+}
+
+pub fn copy_terminal() -> Value {
+    copy("Copies a terminal to a new id.")
+}
+
+pub fn update_terminal() -> Value {
+    update(
+        "Feeds tokens to a terminal as if they are generated, and returns if it fires.",
+        "terminal",
+        tokens(),
+    )
+}
+
+pub fn delete_terminal() -> Value {
+    id("Deletes a terminal, and reports whether it existed.")
+}
+
+pub fn list_terminals() -> Value {
+    json!({ "description": "Lists the ids of all terminals.", "type": "null" })
+}
+
+pub fn reset_terminal() -> Value {
+    id("Resets a terminal to its initial state.")
+}
+
+pub fn create_normalizer() -> Value {
+    create("Creates a normalizer of a type with its params.")
+}
+
+pub fn copy_normalizer() -> Value {
+    copy("Copies a normalizer to a new id.")
+}
+
+pub fn update_normalizer() -> Value {
+    update(
+        "Feeds tokens of each state to a normalizer.",
+        "normalizer",
+        token_vec(),
+    )
+}
+
+pub fn delete_normalizer() -> Value {
+    id("Deletes a normalizer, and reports whether it existed.")
+}
+
+pub fn list_normalizers() -> Value {
+    json!({ "description": "Lists the ids of all normalizers.", "type": "null" })
+}
+
+pub fn reset_normalizer() -> Value {
+    id("Resets a normalizer to its initial state.")
+}
+
+pub fn create_template() -> Value {
+    json!({
+        "description": "Creates a prompt template, where each {{name}} is replaced by a variable.",
+        "type": "object",
+        "properties": {
+            "id": { "type": "string" },
+            "template": { "type": "string" }
+        },
+        "required": ["id", "template"]
+    })
+}
+
+pub fn delete_template() -> Value {
+    id("Deletes a template.")
+}
+
+pub fn describe_template() -> Value {
+    id("Describes the text and variables of a template.")
+}
+
+pub fn reset_all() -> Value {
+    let ids = json!({ "type": "array", "items": { "type": "string" }, "default": [] });
+    json!({
+        "description": "Resets many components at once, and reports the result of each.",
+        "type": "object",
+        "properties": {
+            "transformers": ids,
+            "samplers": ids,
+            "terminals": ids,
+            "normalizers": ids
+        }
+    })
+}
+
+pub fn export_components() -> Value {
+    json!({
+        "description": "Exports the definitions of all transformers, samplers, terminals and normalizers.",
+        "type": "null"
+    })
+}
+
+pub fn import_components() -> Value {
+    let definitions = json!({
+        "type": "object",
+        "additionalProperties": {
+            "type": "object",
+            "properties": {
+                "type_id": { "type": "string" },
+                "params": {}
+            },
+            "required": ["type_id"]
+        },
+        "default": {}
+    });
+    json!({
+        "description": "Creates components from definitions given by export_components, and reports the result of each.",
+        "type": "object",
+        "properties": {
+            "transformers": definitions,
+            "samplers": definitions,
+            "terminals": definitions,
+            "normalizers": definitions,
+            "replace": { "type": "boolean", "default": false }
+        }
+    })
+}
+
+pub fn infer() -> Value {
+    let ids = json!({ "type": "array", "items": { "type": "string" } });
+    json!({
+        "description": "Feeds the prompt to states, and generates from them with the sampler.",
+        "type": "object",
+        "properties": {
+            "tokens": { "type": "array", "items": tokens(), "default": [] },
+            "template": { "type": ["string", "null"] },
+            "variables": { "type": "object", "default": {} },
+            "states": ids,
+            "transformers": { "type": "array", "items": ids },
+            "sampler": { "type": "string" },
+            "terminal": { "type": ["string", "null"] },
+            "stop": { "type": "array", "items": { "type": "string", "minLength": 1 } },
+            "normalizer": { "type": ["string", "null"] },
+            "model": { "type": ["string", "null"] },
+            "max_tokens": { "type": ["integer", "null"], "minimum": 1 },
+            "min_tokens": { "type": "integer", "minimum": 0, "default": 0 },
+            "suppress_tokens": {
+                "type": "array",
+                "items": { "type": "integer", "minimum": 0, "maximum": 65535 },
+                "default": []
+            },
+            "update_prompt": { "type": "boolean" },
+            "reset_on_exhaustion": { "type": "boolean" },
+            "stream": { "type": "boolean", "default": false },
+            "logprobs": { "type": "boolean", "default": false },
+            "top_logprobs": { "type": "integer", "minimum": 0, "default": 0 },
+            "return_tokens": { "type": "boolean", "default": false },
+            "draft_state": { "type": ["string", "null"] },
+            "speculative": {
+                "type": ["object", "null"],
+                "properties": {
+                    "draft_len": { "type": "integer", "minimum": 1 },
+                    "max_ngram": { "type": "integer", "minimum": 1, "default": 3 }
+                },
+                "required": ["draft_len"]
+            },
+            "n": { "type": "integer", "minimum": 1, "default": 1 },
+            "decode": { "type": "boolean", "default": true },
+            "seed": { "type": ["integer", "null"], "minimum": 0 },
+            "mode": { "enum": ["sample", "beam"], "default": "sample" },
+            "beams": { "type": "integer", "minimum": 1, "default": 4 },
+            "return_beams": { "type": "boolean", "default": false },
+            "timeout_ms": { "type": ["integer", "null"], "minimum": 1 },
+            "profile": { "type": "boolean", "default": false },
+            "cfg": {
+                "type": ["object", "null"],
+                "properties": {
+                    "negative_state": { "type": "string" },
+                    "scale": { "type": "number" }
+                },
+                "required": ["negative_state", "scale"]
+            },
+            "temperature": { "type": ["number", "null"], "exclusiveMinimum": 0 }
+        },
+        "required": [
+            "states",
+            "update_prompt",
+            "reset_on_exhaustion"
+        ]
+    })
+}
+
+pub fn continue_infer() -> Value {
+    json!({
+        "description": "Resumes the last generation of a state, with the pipeline of the infer which started it.",
+        "type": "object",
+        "properties": {
+            "state": { "type": "string" },
+            "max_tokens": { "type": ["integer", "null"], "minimum": 1 },
+            "min_tokens": { "type": "integer", "minimum": 0, "default": 0 },
+            "suppress_tokens": {
+                "type": "array",
+                "items": { "type": "integer", "minimum": 0, "maximum": 65535 },
+                "default": []
+            },
+            "stream": { "type": "boolean", "default": false },
+            "logprobs": { "type": "boolean", "default": false },
+            "top_logprobs": { "type": "integer", "minimum": 0, "default": 0 },
+            "return_tokens": { "type": "boolean", "default": false },
+            "decode": { "type": "boolean", "default": true },
+            "seed": { "type": ["integer", "null"], "minimum": 0 },
+            "timeout_ms": { "type": ["integer", "null"], "minimum": 1 },
+            "profile": { "type": "boolean", "default": false }
+        },
+        "required": ["state"]
+    })
+}
+
+pub fn get_logits() -> Value {
+    json!({
+        "description": "Infers tokens with states and returns the logits, without sampling.",
+        "type": "object",
+        "properties": {
+            "states": { "type": "array", "items": { "type": "string" } },
+            "tokens": token_vec(),
+            "top": { "type": ["integer", "null"], "minimum": 0 },
+            "update_state": { "type": "boolean", "default": false }
+        },
+        "required": ["states", "tokens"]
+    })
+}
+
+pub fn score() -> Value {
+    json!({
+        "description": "Computes the log likelihood and perplexity of tokens with a state.",
+        "type": "object",
+        "properties": {
+            "state": { "type": "string" },
+            "tokens": tokens(),
+            "update_state": { "type": "boolean", "default": false }
+        },
+        "required": ["state", "tokens"]
+    })
+}
+
+pub fn suggest_next() -> Value {
+    json!({
+        "description": "Suggests the most likely next tokens of a state without sampling, optionally feeding tokens first.",
+        "type": "object",
+        "properties": {
+            "state": { "type": "string" },
+            "tokens": tokens(),
+            "k": { "type": "integer", "minimum": 1 }
+        },
+        "required": ["state", "k"]
+    })
+}
+
+pub fn accept_token() -> Value {
+    json!({
+        "description": "Feeds a token picked from suggest_next to a state.",
+        "type": "object",
+        "properties": {
+            "state": { "type": "string" },
+            "token": { "type": "integer", "minimum": 0, "maximum": 65535 }
+        },
+        "required": ["state", "token"]
+    })
+}
+
+pub fn set_defaults() -> Value {
+    json!({
+        "description": "Sets the sampler and transformers used by infers which omit them, replacing the previous defaults.",
+        "type": "object",
+        "properties": {
+            "sampler": { "type": ["string", "null"] },
+            "transformers": { "type": ["array", "null"], "items": { "type": "string" } }
+        }
+    })
+}
+
+pub fn abort() -> Value {
+    id("Cancels a running infer of the connection by its echo_id.")
+}
+
+pub fn cancel() -> Value {
+    id("Same as abort.")
+}
+
+pub fn model_info() -> Value {
+    optional_model("Describes a model, or the default model if omitted.")
+}
+
+pub fn gpu_info() -> Value {
+    optional_model(
+        "Describes the adapter of a model, or the default model if omitted, its memory limits and the memory taken by its states. Total and available VRAM are null, since the backend doesn't report them.",
+    )
+}
+
+pub fn warmup() -> Value {
+    optional_model("Warms up a model, or all models if omitted.")
+}
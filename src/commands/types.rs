@@ -1,12 +1,50 @@
 use anyhow::Error;
+use bson::{spec::BinarySubtype, Binary};
 use serde::Serialize;
 use serde_json::Value;
-use tokio::time::Instant;
+use tokio::{sync::mpsc::UnboundedSender, time::Instant};
+
+use crate::{
+    app::{AppState, CommandHandle},
+    error::CommandErrorKind,
+};
+
+/// Sends partial results of a command, each of which is forwarded to the client as a
+/// `CommandPartial` before the final response.
+pub type PartialSender = UnboundedSender<Value>;
+
+/// Sends binary results of a command, each of which is forwarded to the client as a
+/// `CommandBinary` in a binary frame before the final response.
+pub type BinarySender = UnboundedSender<Vec<u8>>;
+
+/// Extra context passed to streaming commands.
+pub struct CommandContext {
+    pub partial: PartialSender,
+    pub binary: BinarySender,
+    /// Set once the command is cancelled by `abort`.
+    pub handle: CommandHandle,
+}
+
+impl CommandContext {
+    pub fn new(
+        echo_id: &str,
+        state: &AppState,
+        partial: PartialSender,
+        binary: BinarySender,
+    ) -> Self {
+        Self {
+            partial,
+            binary,
+            handle: state.register_command(echo_id.to_string()),
+        }
+    }
+}
 
 #[derive(Debug, Serialize)]
 pub struct CommandError {
     echo_id: Option<String>,
     status: &'static str,
+    code: CommandErrorKind,
     error: String,
 }
 
@@ -15,6 +53,7 @@ impl CommandError {
         Self {
             echo_id: Some(id),
             status: "error",
+            code: CommandErrorKind::of(&error),
             error: error.to_string(),
         }
     }
@@ -23,6 +62,7 @@ impl CommandError {
         Self {
             echo_id: None,
             status: "error",
+            code: CommandErrorKind::of(&error),
             error: format!("{}", error.to_string()),
         }
     }
@@ -46,3 +86,41 @@ impl CommandSuccess {
         }
     }
 }
+
+#[derive(Debug, Serialize)]
+pub struct CommandPartial {
+    echo_id: String,
+    status: &'static str,
+    result: Value,
+}
+
+impl CommandPartial {
+    pub fn new(id: String, result: Value) -> Self {
+        Self {
+            echo_id: id,
+            status: "partial",
+            result,
+        }
+    }
+}
+
+/// A binary result, which is always encoded as BSON with the bytes in `result`.
+#[derive(Debug, Serialize)]
+pub struct CommandBinary {
+    echo_id: String,
+    status: &'static str,
+    result: Binary,
+}
+
+impl CommandBinary {
+    pub fn new(id: String, bytes: Vec<u8>) -> Self {
+        Self {
+            echo_id: id,
+            status: "binary",
+            result: Binary {
+                subtype: BinarySubtype::Generic,
+                bytes,
+            },
+        }
+    }
+}
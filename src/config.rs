@@ -1,6 +1,6 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::Duration};
 
-use anyhow::{Ok, Result};
+use anyhow::{Error, Ok, Result};
 use memmap2::Mmap;
 use tokio::{
     fs::File,
@@ -12,9 +12,11 @@ use web_rwkv::{
     context::{Context, ContextBuilder, Instance},
     model::{LayerFlags, Model, ModelBuilder, Quantization},
     tokenizer::Tokenizer,
-    wgpu::Adapter,
+    wgpu::{self, Adapter, Backends},
 };
 
+use crate::cli::DeviceConfig;
+
 mod props {
     use serde::Deserialize;
     use web_rwkv::wgpu::PowerPreference;
@@ -47,6 +49,34 @@ mod props {
         }
     }
 
+    #[derive(Debug, Deserialize, Clone)]
+    pub struct TokenChunkSize(usize);
+    impl Default for TokenChunkSize {
+        fn default() -> Self {
+            TokenChunkSize(1024)
+        }
+    }
+
+    impl TokenChunkSize {
+        pub fn get(&self) -> usize {
+            self.0.max(1)
+        }
+    }
+
+    #[derive(Debug, Deserialize, Clone)]
+    pub struct MaxTokens(usize);
+    impl Default for MaxTokens {
+        fn default() -> Self {
+            MaxTokens(4096)
+        }
+    }
+
+    impl MaxTokens {
+        pub fn get(&self) -> usize {
+            self.0
+        }
+    }
+
     #[derive(Debug, Deserialize, Clone)]
     pub enum Preference {
         HighPerformance = 0,
@@ -70,12 +100,29 @@ pub struct ModelSpec {
     max_batch_count: props::BatchSize,
     #[serde(default)]
     max_chunk_count: props::ChunkSize,
+    /// Prompts longer than this are fed in chunks of this many tokens.
+    #[serde(default)]
+    token_chunk_size: props::TokenChunkSize,
+    /// Milliseconds a partial batch waits for more requests before it's inferred.
+    #[serde(default)]
+    batch_window_ms: Option<u64>,
+    /// States which may pin their slots by `pin_state` at once.
+    #[serde(default)]
+    max_pinned_slots: usize,
     preference: Option<props::Preference>,
     adapter: Option<usize>,
     quantization: Option<u64>,
 }
 
 impl ModelSpec {
+    /// The same spec, loading the model at another path.
+    pub fn with_path(&self, path: PathBuf) -> Self {
+        Self {
+            path,
+            ..self.clone()
+        }
+    }
+
     pub fn get_batch_size(&self) -> usize {
         self.max_batch_count.get()
     }
@@ -84,6 +131,20 @@ impl ModelSpec {
         self.max_chunk_count.get()
     }
 
+    pub fn get_token_chunk_size(&self) -> usize {
+        self.token_chunk_size.get()
+    }
+
+    pub fn get_batch_window(&self) -> Option<Duration> {
+        self.batch_window_ms
+            .filter(|&x| x > 0)
+            .map(Duration::from_millis)
+    }
+
+    pub fn get_max_pinned_slots(&self) -> usize {
+        self.max_pinned_slots
+    }
+
     pub async fn select_adapter(&self, instance: &Instance) -> Result<Adapter> {
         if let Some(preference) = &self.preference {
             Ok(instance.adapter(preference.to_web_rwkv()).await?)
@@ -94,8 +155,49 @@ impl ModelSpec {
         }
     }
 
-    pub async fn create_context(&self) -> Result<Context> {
-        let adapter = self.select_adapter(&Instance::new()).await?;
+    /// Selects the adapter by `--adapter` and `--backend` if either is given, which index
+    /// the adapters of the backend (or of all backends) in the order they are enumerated.
+    /// `adapter` in the spec is used if only the backend is given.
+    fn select_device_adapter(&self, device: DeviceConfig) -> Result<Adapter> {
+        let backends = device
+            .backend
+            .map(|x| x.to_wgpu())
+            .unwrap_or(Backends::all());
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends,
+            ..Default::default()
+        });
+        let mut adapters: Vec<Adapter> = instance.enumerate_adapters(backends).collect();
+        let index = device.adapter.or(self.adapter).unwrap_or(0);
+        if index < adapters.len() {
+            return Ok(adapters.swap_remove(index));
+        }
+        let available: Vec<String> = adapters
+            .iter()
+            .enumerate()
+            .map(|(index, adapter)| {
+                let info = adapter.get_info();
+                format!(
+                    "  {}: {} ({:?}, {:?})",
+                    index, info.name, info.backend, info.device_type
+                )
+            })
+            .collect();
+        Err(Error::msg(match available.is_empty() {
+            true => format!("Adapter {} is not found, no adapter is available!", index),
+            false => format!(
+                "Adapter {} is not found, available adapters are:\n{}",
+                index,
+                available.join("\n")
+            ),
+        }))
+    }
+
+    pub async fn create_context(&self, device: DeviceConfig) -> Result<Context> {
+        let adapter = match (device.adapter, device.backend) {
+            (None, None) => self.select_adapter(&Instance::new()).await?,
+            _ => self.select_device_adapter(device)?,
+        };
         println!("{:?}", adapter.get_info());
         let mut context = ContextBuilder::new(adapter).with_default_pipelines();
         if self.quantization.is_some() {
@@ -138,8 +240,98 @@ impl TokenizerSpec {
     }
 }
 
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct GenerationSpec {
+    #[serde(default)]
+    max_tokens: props::MaxTokens,
+    /// Milliseconds an infer request may take, if the request doesn't set its own.
+    #[serde(default)]
+    timeout_ms: Option<u64>,
+}
+
+impl GenerationSpec {
+    /// The limit of tokens sampled in one infer request.
+    pub fn get_max_tokens(&self) -> usize {
+        self.max_tokens.get()
+    }
+
+    /// The default time limit of an infer request, if any.
+    pub fn get_timeout(&self) -> Option<Duration> {
+        self.timeout_ms.map(Duration::from_millis)
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct StateSpec {
+    /// Seconds a state may stay unused before it's deleted, if it doesn't set its own.
+    #[serde(default)]
+    ttl_seconds: u64,
+    /// States kept in memory before the least recently used ones are spilled, 0 for no
+    /// limit.
+    #[serde(default)]
+    max_resident_states: usize,
+    /// Where spilled states are kept.
+    #[serde(default)]
+    spill_dir: Option<PathBuf>,
+}
+
+impl StateSpec {
+    /// The default TTL of a state, `None` if states never expire.
+    pub fn get_ttl(&self) -> Option<Duration> {
+        (self.ttl_seconds > 0).then(|| Duration::from_secs(self.ttl_seconds))
+    }
+
+    /// The spill directory and the limit of states in memory, if spilling is enabled.
+    pub fn get_spill(&self) -> Result<Option<(PathBuf, usize)>> {
+        match (self.max_resident_states, &self.spill_dir) {
+            (0, _) => Ok(None),
+            (max, Some(dir)) => Ok(Some((dir.clone(), max))),
+            (_, None) => Err(Error::msg(
+                "spill_dir must be set along with max_resident_states!",
+            )),
+        }
+    }
+}
+
+/// The name of the model specified in `[model]`.
+pub const DEFAULT_MODEL: &str = "default";
+
+/// The name of the draft model for speculative decoding, given by `--draft-model`.
+pub const DRAFT_MODEL: &str = "draft";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct NamedModelSpec {
+    pub name: String,
+    #[serde(flatten)]
+    pub spec: ModelSpec,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct ModelConfig {
     pub model: ModelSpec,
+    /// Extra models to load, selected by name per request.
+    #[serde(default)]
+    pub models: Vec<NamedModelSpec>,
     pub tokenizer: TokenizerSpec,
+    #[serde(default)]
+    pub generation: GenerationSpec,
+    #[serde(default)]
+    pub state: StateSpec,
+}
+
+impl ModelConfig {
+    /// All models to load, with `[model]` named `default`.
+    pub fn model_specs(&self) -> Result<Vec<(String, ModelSpec)>> {
+        let mut specs = vec![(DEFAULT_MODEL.to_string(), self.model.clone())];
+        for NamedModelSpec { name, spec } in &self.models {
+            if specs.iter().any(|(x, _)| x == name) {
+                return Err(Error::msg(format!(
+                    "Model name {} is used more than once!",
+                    name
+                )));
+            }
+            specs.push((name.clone(), spec.clone()));
+        }
+        Ok(specs)
+    }
 }
@@ -0,0 +1,64 @@
+use std::fmt::Display;
+
+use anyhow::Error;
+use serde::Serialize;
+
+/// A stable, machine-readable code sent to clients along with the error message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandErrorKind {
+    StateNotFound,
+    StateExpired,
+    StateEvicted,
+    StateBusy,
+    SamplerNotFound,
+    TransformerNotFound,
+    TerminalNotFound,
+    NormalizerNotFound,
+    TemplateNotFound,
+    CheckpointNotFound,
+    ModelNotFound,
+    CommandNotFound,
+    AlreadyExists,
+    BadRequest,
+    Interrupted,
+    Internal,
+}
+
+impl CommandErrorKind {
+    /// Creates an error of this kind, which can be used like `Error::msg`.
+    pub fn error(self, message: impl Into<String>) -> Error {
+        Error::new(AppError {
+            kind: self,
+            message: message.into(),
+        })
+    }
+
+    /// Finds the kind of an error. Errors not created by [`CommandErrorKind::error`] are
+    /// internal, except malformed payloads which are bad requests.
+    pub fn of(error: &Error) -> Self {
+        for cause in error.chain() {
+            if let Some(error) = cause.downcast_ref::<AppError>() {
+                return error.kind;
+            }
+            if cause.is::<serde_json::Error>() {
+                return Self::BadRequest;
+            }
+        }
+        Self::Internal
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AppError {
+    pub kind: CommandErrorKind,
+    pub message: String,
+}
+
+impl Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for AppError {}
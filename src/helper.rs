@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 #[derive(Debug, Clone)]
 pub struct Logits(pub Vec<f32>);
 
@@ -7,15 +9,89 @@ impl Logits {
     }
 }
 
+/// A state backed on CPU.
+///
+/// Cloning a `State` is shallow, the data is shared until the state is loaded into the
+/// pipeline, where it is copied if still shared (copy-on-write).
 #[derive(Debug, Clone)]
-pub struct State(pub Vec<f32>);
+pub struct State(pub Arc<Vec<f32>>);
 
 impl State {
     pub fn len(&self) -> usize {
         self.0.len()
     }
 
+    /// Copies the data so nothing is shared with `self`.
+    pub fn deep_clone(&self) -> Self {
+        State(Arc::new(self.0.as_ref().clone()))
+    }
+
+    /// Takes the data out, copying it only if it is still shared.
+    pub fn into_data(self) -> Vec<f32> {
+        Arc::try_unwrap(self.0).unwrap_or_else(|data| data.as_ref().clone())
+    }
+
     pub fn to_state(self) -> ! {
         todo!()
     }
 }
+
+/// Decodes UTF-8 text from bytes which arrive in pieces (e.g. token by token), where a
+/// character may be split across pieces.
+#[derive(Debug, Default, Clone)]
+pub struct Utf8Decoder {
+    pending: Vec<u8>,
+}
+
+impl Utf8Decoder {
+    /// Appends bytes and returns the text completed by them.
+    ///
+    /// Invalid bytes are replaced by U+FFFD, and an incomplete character at the end is
+    /// kept until more bytes arrive.
+    pub fn push(&mut self, bytes: &[u8]) -> String {
+        self.pending.extend_from_slice(bytes);
+        let mut text = String::new();
+        let mut rest = self.pending.as_slice();
+        loop {
+            match std::str::from_utf8(rest) {
+                Ok(valid) => {
+                    text.push_str(valid);
+                    rest = &[];
+                    break;
+                }
+                Err(e) => {
+                    let (valid, invalid) = rest.split_at(e.valid_up_to());
+                    text.push_str(std::str::from_utf8(valid).unwrap());
+                    match e.error_len() {
+                        Some(len) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            rest = &invalid[len..];
+                        }
+                        None => {
+                            rest = invalid;
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        let consumed = self.pending.len() - rest.len();
+        self.pending.drain(..consumed);
+        text
+    }
+
+    /// Bytes of the incomplete character at the end.
+    pub fn pending(&self) -> &[u8] {
+        &self.pending
+    }
+
+    /// Takes out the bytes of the incomplete character at the end.
+    pub fn take_pending(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Takes out the incomplete character at the end, replaced by U+FFFD.
+    pub fn finish(&mut self) -> String {
+        String::from_utf8_lossy(&self.take_pending()).into_owned()
+    }
+}
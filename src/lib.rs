@@ -1,8 +1,9 @@
-pub mod commands;
-pub mod routes;
-pub mod states;
 pub mod app;
 pub mod cli;
+pub mod commands;
 pub mod config;
+pub mod error;
 pub mod helper;
-pub mod macros;
\ No newline at end of file
+pub mod macros;
+pub mod routes;
+pub mod states;
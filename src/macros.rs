@@ -44,12 +44,27 @@ macro_rules! hashmap_ex {
 }
 
 #[macro_export]
+/// Builds a `CommandRegistry` from lists of simple and streaming handlers. Each command is
+/// named after its handler unless renamed with `as`, e.g. for names which are Rust
+/// keywords, and its schema is the function of the same name as the handler in `schemas`.
 macro_rules! register_handlers {
-    ($self:ident, $state:ident, [$($crate_name:ident :: $handler_name:ident), *,]) => {
-        match $self.command.as_str(){
-            "echo" => Ok($self.data.clone().unwrap_or(Value::Null)),
-            $(stringify!($handler_name) => $crate_name::$handler_name($self.data.clone(), $state).await,)*
-            _ => Err(Error::msg("Unknown command!"))
-        }
+    (@name $handler_name:ident) => { stringify!($handler_name) };
+    (@name $handler_name:ident $alias:literal) => { $alias };
+    ([$($crate_name:ident :: $handler_name:ident $(as $alias:literal)?), *,], [$($stream_crate_name:ident :: $stream_handler_name:ident $(as $stream_alias:literal)?), *,]) => {
+        CommandRegistry::new(vec![
+            $(Command {
+                name: $crate::register_handlers!(@name $handler_name $($alias)?),
+                schema: schemas::$handler_name(),
+                handler: Handler::Simple(|data, state| Box::pin($crate_name::$handler_name(data, state))),
+            },)*
+            // Streaming handlers additionally take a `CommandContext`, which is only created for them
+            $(Command {
+                name: $crate::register_handlers!(@name $stream_handler_name $($stream_alias)?),
+                schema: schemas::$stream_handler_name(),
+                handler: Handler::Streaming(|data, state, context| {
+                    Box::pin($stream_crate_name::$stream_handler_name(data, state, context))
+                }),
+            },)*
+        ])
     };
 }
@@ -1,45 +1,108 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use anyhow::{Ok, Result};
+use anyhow::{Error, Ok, Result};
 use axum::{routing::get, Router};
 use clap::Parser;
 use tokio::runtime::Builder;
 use web_rwkv_axum::{
-    app::AppState,
+    app::{AppSettings, AppState},
     cli::LaunchArgs,
-    routes::{hello_world, ws},
-    states::{permit::BatchRequest, pipeline::Pipeline, softmax::Softmax},
+    config::DRAFT_MODEL,
+    routes::{health, hello_world, ws},
+    states::model::AxumModel,
 };
 
+/// How often states idle beyond their TTL are deleted, and cold states are spilled.
+const STATE_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
 async fn app(args: LaunchArgs) -> Result<()> {
     let model_config = args.get_config()?;
+    let softmax_config = args.get_softmax_config();
+    let batch_config = args.get_batch_config();
+    let retry_config = args.get_retry_config();
+    let device_config = args.get_device_config();
+
+    let mut models = HashMap::new();
+    let mut handles = Vec::new();
+    for (name, spec) in model_config.model_specs()? {
+        let (model, model_handles) = AxumModel::load(
+            name.clone(),
+            spec,
+            softmax_config,
+            batch_config,
+            retry_config,
+            device_config,
+        )
+        .await?;
+        models.insert(name, Arc::new(model));
+        handles.extend(model_handles);
+    }
+    if let Some(spec) = args.get_draft_model(&model_config) {
+        if models.contains_key(DRAFT_MODEL) {
+            return Err(Error::msg(format!(
+                "Model name {} is reserved for the draft model!",
+                DRAFT_MODEL
+            )));
+        }
+        let (model, model_handles) = AxumModel::load(
+            DRAFT_MODEL.to_string(),
+            spec,
+            softmax_config,
+            batch_config,
+            retry_config,
+            device_config,
+        )
+        .await?;
+        models.insert(DRAFT_MODEL.to_string(), Arc::new(model));
+        handles.extend(model_handles);
+    }
 
-    let context = model_config.model.create_context().await?;
-    let model = Arc::new(model_config.model.load_model(&context).await?);
-    let softmax = Softmax::new(model.clone(), model_config.model.get_batch_size()).await;
-    let batch_lock = BatchRequest::new();
+    let settings = AppSettings {
+        prefix_cache_size: args.get_prefix_cache_size(),
+        idempotency_cache_size: args.get_idempotency_cache_size(),
+        draft_tokens: args.get_draft_tokens(),
+        max_context: args.get_max_context(),
+        max_states: args.get_max_states(),
+        max_checkpoints: args.get_max_checkpoints(),
+        history_size: args.get_history_size(),
+        state_lock_timeout: args.get_state_lock_timeout(),
+        resume_window: args.get_resume_window(),
+        state_dir: args.get_state_dir(),
+    };
+    let shared_state = AppState::new(&model_config, args.get_ws_config(), settings, models).await?;
 
-    let (softmax_sender, softmax_handle) = softmax.run().await;
-    let (infer_sender, model_handle) = Pipeline::start(
-        model_config.model.get_batch_size(),
-        context.clone(),
-        model.clone(),
-        batch_lock.clone(),
-    )
-    .await;
+    if args.get_warmup() {
+        shared_state.set_ready(false);
+        let state = shared_state.clone();
+        tokio::spawn(async move {
+            for name in state.0.models.keys() {
+                if let Err(error) = state.warmup(name).await {
+                    println!("Failed to warm up model {}: {}", name, error);
+                    return;
+                }
+            }
+            println!("Models are warmed up.");
+            state.set_ready(true);
+        });
+    }
 
-    let shared_state = AppState::new(
-        &model_config,
-        infer_sender.clone(),
-        softmax_sender.clone(),
-        context.clone(),
-        model.clone(),
-        batch_lock.clone(),
-    )
-    .await?;
+    let state = shared_state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(STATE_SWEEP_INTERVAL);
+        loop {
+            interval.tick().await;
+            for id in state.expire_states() {
+                println!("State {} expired.", id);
+            }
+            if let Err(error) = state.spill_states().await {
+                println!("Failed to spill states: {}", error);
+            }
+        }
+    });
 
     let app = Router::new()
         .route("/", get(hello_world::handler))
+        .route("/health", get(health::handler))
         .route("/ws", get(ws::handler))
         .with_state(shared_state);
 
@@ -47,10 +110,10 @@ async fn app(args: LaunchArgs) -> Result<()> {
         .serve(app.into_make_service())
         .await?;
 
-    drop(infer_sender);
-    drop(softmax_sender);
-    model_handle.await?;
-    softmax_handle.await?;
+    // Pipelines stop once all `AxumModel`s are dropped with the app state
+    for handle in handles {
+        handle.await?;
+    }
     Ok(())
 }
 
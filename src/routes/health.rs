@@ -0,0 +1,12 @@
+use axum::{extract::State, http::StatusCode};
+
+use crate::app::AppState;
+
+/// Reports whether the server is ready to serve requests.
+pub async fn handler(State(state): State<AppState>) -> (StatusCode, &'static str) {
+    if state.is_ready() {
+        (StatusCode::OK, "ok")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "warming up")
+    }
+}
@@ -1,2 +1,3 @@
+pub mod health;
 pub mod hello_world;
 pub mod ws;
\ No newline at end of file
@@ -1,6 +1,6 @@
-use std::sync::Arc;
+use std::{future::Future, sync::Arc};
 
-use anyhow::Error;
+use anyhow::Result;
 use axum::{
     extract::{
         ws::{Message, WebSocket},
@@ -8,37 +8,157 @@ use axum::{
     },
     response::IntoResponse,
 };
+use bson::{Bson, Document};
 use futures_util::{stream::SplitSink, SinkExt, StreamExt};
-use tokio::{sync::Mutex, time::Instant};
+use serde_json::Value;
+use tokio::{
+    sync::{mpsc, Mutex},
+    time::{Instant, Interval},
+};
 
 use crate::{
     app::AppState,
     commands::{
-        types::{CommandError, CommandSuccess},
+        types::{
+            BinarySender, CommandBinary, CommandError, CommandPartial, CommandSuccess,
+            PartialSender,
+        },
         TextCommand,
     },
+    error::CommandErrorKind,
 };
 
 pub async fn handler(ws: WebSocketUpgrade, State(state): State<AppState>) -> impl IntoResponse {
     ws.on_upgrade(move |socket: WebSocket| handle_socket(socket, state))
 }
 
+async fn tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
 async fn handle_socket(socket: WebSocket, state: AppState) {
+    let state = state.connect();
     let (sender, mut receiver) = socket.split();
     let sender = Arc::new(Mutex::new(sender));
+    let ws_config = state.0.ws_config;
+
+    // Commands report back once done, so a long running command doesn't count as idle
+    let (done_sender, mut done_receiver) = mpsc::unbounded_channel::<()>();
+    let mut running = 0usize;
+    let mut last_active = Instant::now();
 
-    while let Some(Ok(msg)) = receiver.next().await {
-        match msg {
-            Message::Text(text) => {
-                tokio::spawn(handle_command_text(state.clone(), sender.clone(), text));
+    let mut ping = ws_config
+        .ping_interval
+        .map(|period| tokio::time::interval_at(Instant::now() + period, period));
+    let mut awaiting_pong = false;
+
+    loop {
+        let idle_deadline = ws_config
+            .idle_timeout
+            .filter(|_| running == 0)
+            .map(|timeout| last_active + timeout);
+
+        tokio::select! {
+            msg = receiver.next() => match msg {
+                Some(Ok(Message::Text(text))) => {
+                    running += 1;
+                    let (state, sender, done) = (state.clone(), sender.clone(), done_sender.clone());
+                    tokio::spawn(async move {
+                        handle_command_text(state, sender, text).await;
+                        done.send(()).ok();
+                    });
+                }
+                Some(Ok(Message::Binary(bytes))) => {
+                    running += 1;
+                    let (state, sender, done) = (state.clone(), sender.clone(), done_sender.clone());
+                    tokio::spawn(async move {
+                        handle_command_bytes(state, sender, bytes).await;
+                        done.send(()).ok();
+                    });
+                }
+                Some(Ok(Message::Pong(_))) => awaiting_pong = false,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => (),
+            },
+            Some(()) = done_receiver.recv() => {
+                running -= 1;
+                last_active = Instant::now();
             }
-            Message::Binary(bytes) => {
-                tokio::spawn(handle_command_bytes(state.clone(), sender.clone(), bytes));
+            _ = tick(&mut ping) => {
+                // No pong since the last ping, the client is gone
+                if awaiting_pong {
+                    break;
+                }
+                awaiting_pong = true;
+                if sender.lock().await.send(Message::Ping(Vec::new())).await.is_err() {
+                    break;
+                }
             }
-            Message::Close(_) => break,
-            _ => (),
+            _ = sleep_until(idle_deadline) => break,
         }
     }
+
+    // Running commands are cancelled right away, so they release their batch slots
+    // without waiting for the socket to close
+    state.disconnect();
+    sender.lock().await.close().await.ok();
+}
+
+fn encode_text(partial: &CommandPartial) -> Message {
+    Message::Text(serde_json::to_string(partial).unwrap())
+}
+
+fn encode_bytes(partial: &CommandPartial) -> Message {
+    Message::Binary(bson::to_vec(partial).unwrap())
+}
+
+/// Runs the command while forwarding its partial and binary results, the final response
+/// must only be sent after this returns so it always comes after all of them.
+async fn run_command<F, Fut>(
+    sender: &Arc<Mutex<SplitSink<WebSocket, Message>>>,
+    echo_id: &str,
+    encode: fn(&CommandPartial) -> Message,
+    handle: F,
+) -> anyhow::Result<Value>
+where
+    F: FnOnce(PartialSender, BinarySender) -> Fut,
+    Fut: Future<Output = anyhow::Result<Value>>,
+{
+    let (partial_sender, mut partial_receiver) = mpsc::unbounded_channel();
+    let (binary_sender, mut binary_receiver) = mpsc::unbounded_channel();
+    let forward = async {
+        loop {
+            let message = tokio::select! {
+                biased;
+                Some(result) = partial_receiver.recv() => {
+                    encode(&CommandPartial::new(echo_id.to_string(), result))
+                }
+                // Binary results are always sent in binary frames, even for text commands
+                Some(bytes) = binary_receiver.recv() => Message::Binary(
+                    bson::to_vec(&CommandBinary::new(echo_id.to_string(), bytes)).unwrap(),
+                ),
+                else => break,
+            };
+            if sender.lock().await.send(message).await.is_err() {
+                break;
+            }
+        }
+    };
+    // The senders are dropped once the command is done, which ends the forwarding
+    let (result, _) = tokio::join!(handle(partial_sender, binary_sender), forward);
+    result
 }
 
 async fn handle_command_text(
@@ -48,34 +168,41 @@ async fn handle_command_text(
 ) {
     let start = Instant::now();
     match serde_json::from_str::<TextCommand>(payload.as_str()) {
-        Ok(command) => match command.handle(state).await {
-            Ok(v) => {
-                sender
-                    .lock()
-                    .await
-                    .send(Message::Text(
-                        serde_json::to_string(&CommandSuccess::new(command.echo_id, v, start)).unwrap(),
-                    ))
-                    .await
-                    .ok();
-            }
-            Err(e) => {
-                sender
-                    .lock()
-                    .await
-                    .send(Message::Text(
-                        serde_json::to_string(&CommandError::new(command.echo_id, e)).unwrap(),
-                    ))
-                    .await
-                    .ok();
+        Ok(command) => {
+            match run_command(&sender, &command.echo_id, encode_text, |partial, binary| {
+                command.handle(state, partial, binary)
+            })
+            .await
+            {
+                Ok(v) => {
+                    sender
+                        .lock()
+                        .await
+                        .send(Message::Text(
+                            serde_json::to_string(&CommandSuccess::new(command.echo_id, v, start))
+                                .unwrap(),
+                        ))
+                        .await
+                        .ok();
+                }
+                Err(e) => {
+                    sender
+                        .lock()
+                        .await
+                        .send(Message::Text(
+                            serde_json::to_string(&CommandError::new(command.echo_id, e)).unwrap(),
+                        ))
+                        .await
+                        .ok();
+                }
             }
-        },
+        }
         Err(_) => {
             sender
                 .lock()
                 .await
                 .send(Message::Text(
-                    serde_json::to_string(&CommandError::new_raw(Error::msg(
+                    serde_json::to_string(&CommandError::new_raw(CommandErrorKind::BadRequest.error(
                         "Malformed JSON payload. A payload must include echo_id, command and data!",
                     )))
                     .unwrap(),
@@ -86,14 +213,30 @@ async fn handle_command_text(
     }
 }
 
+/// Decodes a BSON command. Binary values in the data become extended JSON `$binary`
+/// objects, so tokens can be sent as binary, see `helpers::to_tokens`.
+fn decode_bytes(payload: &[u8]) -> Result<TextCommand> {
+    let document = bson::from_slice::<Document>(payload)?;
+    Ok(serde_json::from_value(
+        Bson::Document(document).into_relaxed_extjson(),
+    )?)
+}
+
 async fn handle_command_bytes(
     state: AppState,
     sender: Arc<Mutex<SplitSink<WebSocket, Message>>>,
     payload: Vec<u8>,
 ) {
     let start = Instant::now();
-    match bson::from_slice::<TextCommand>(&payload) {
-        Ok(command) => match command.handle(state).await {
+    match decode_bytes(&payload) {
+        Ok(command) => match run_command(
+            &sender,
+            &command.echo_id,
+            encode_bytes,
+            |partial, binary| command.handle(state, partial, binary),
+        )
+        .await
+        {
             Ok(v) => {
                 sender
                     .lock()
@@ -120,7 +263,7 @@ async fn handle_command_bytes(
                 .lock()
                 .await
                 .send(Message::Binary(
-                    bson::to_vec(&CommandError::new_raw(Error::msg(
+                    bson::to_vec(&CommandError::new_raw(CommandErrorKind::BadRequest.error(
                         "Malformed JSON payload. A payload must include echo_id, command and data!",
                     )))
                     .unwrap(),
@@ -0,0 +1,153 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Runs in a row which must use every allowed slot with a stable latency before the batch
+/// size grows.
+const STABLE_RUNS: usize = 16;
+/// A run this many times slower per slot than the average is considered congested.
+const SLOW_FACTOR: f64 = 2.0;
+/// A run at most this many times slower per slot than the average is considered stable.
+const STABLE_FACTOR: f64 = 1.25;
+/// Weight of the latest run in the moving average.
+const SMOOTHING: f64 = 0.1;
+
+/// Decides how many slots of the batch are inferred together, within `[min, max]`.
+///
+/// The batch size starts at `min`. A failed run, e.g. if web-rwkv fails to allocate
+/// buffers, halves it, and a run which is much slower per slot than the average lowers it
+/// by one. After `STABLE_RUNS` runs in a row which use every allowed slot with a stable
+/// latency, it grows by one. With `min == max` the batch size is fixed.
+///
+/// With a window, a run which would start with fewer slots than the batch size waits up
+/// to the window for the requests of the other running infers.
+#[derive(Debug, Clone)]
+pub struct BatchController {
+    min: usize,
+    max: usize,
+    current: usize,
+    /// Moving average of the seconds a run takes per inferred slot.
+    average: Option<f64>,
+    stable_runs: usize,
+    window: Option<Duration>,
+    stats: Arc<BatchStats>,
+}
+
+/// Runs of a pipeline and the slots inferred in them, shared with the model for reporting.
+#[derive(Debug, Default)]
+pub struct BatchStats {
+    runs: AtomicUsize,
+    slots: AtomicUsize,
+    pinned: AtomicUsize,
+}
+
+impl BatchStats {
+    fn record(&self, slots: usize) {
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        self.slots.fetch_add(slots, Ordering::Relaxed);
+    }
+
+    pub fn runs(&self) -> usize {
+        self.runs.load(Ordering::Relaxed)
+    }
+
+    pub fn set_pinned(&self, pinned: usize) {
+        self.pinned.store(pinned, Ordering::Relaxed);
+    }
+
+    /// Slots reserved by pinned states.
+    pub fn pinned(&self) -> usize {
+        self.pinned.load(Ordering::Relaxed)
+    }
+
+    /// Slots inferred per run on average, 0 before the first run.
+    pub fn average_batch(&self) -> f64 {
+        match self.runs() {
+            0 => 0.0,
+            runs => self.slots.load(Ordering::Relaxed) as f64 / runs as f64,
+        }
+    }
+}
+
+impl BatchController {
+    pub fn new(min: usize, max: usize) -> Self {
+        let max = max.max(1);
+        let min = min.clamp(1, max);
+        Self {
+            min,
+            max,
+            current: min,
+            average: None,
+            stable_runs: 0,
+            window: None,
+            stats: Default::default(),
+        }
+    }
+
+    /// Sets how long a partial batch waits for more requests.
+    pub fn with_window(self, window: Option<Duration>) -> Self {
+        Self { window, ..self }
+    }
+
+    #[inline(always)]
+    pub fn window(&self) -> Option<Duration> {
+        self.window
+    }
+
+    /// The stats of the runs, which keep counting after the controller is moved into the
+    /// pipeline.
+    pub fn stats(&self) -> Arc<BatchStats> {
+        self.stats.clone()
+    }
+
+    /// The number of slots which can be inferred together now.
+    #[inline(always)]
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    /// Backs off after a failed run. Returns false if the batch size is already `min`, so
+    /// retrying won't help.
+    pub fn on_failure(&mut self) -> bool {
+        self.stable_runs = 0;
+        if self.current == self.min {
+            return false;
+        }
+        self.current = (self.current / 2).max(self.min);
+        true
+    }
+
+    /// Records a run which took `latency` to infer `slots` slots.
+    pub fn on_success(&mut self, latency: Duration, slots: usize) {
+        self.stats.record(slots);
+        if slots == 0 {
+            return;
+        }
+        let per_slot = latency.as_secs_f64() / slots as f64;
+        let average = match self.average {
+            Some(average) => average,
+            None => {
+                self.average = Some(per_slot);
+                return;
+            }
+        };
+
+        if per_slot > average * SLOW_FACTOR {
+            self.current = (self.current - 1).max(self.min);
+            self.stable_runs = 0;
+        } else if slots >= self.current && per_slot <= average * STABLE_FACTOR {
+            self.stable_runs += 1;
+            if self.stable_runs >= STABLE_RUNS && self.current < self.max {
+                self.current += 1;
+                self.stable_runs = 0;
+            }
+        } else {
+            self.stable_runs = 0;
+        }
+        self.average = Some(average * (1.0 - SMOOTHING) + per_slot * SMOOTHING);
+    }
+}
@@ -0,0 +1,261 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::time::Instant;
+
+use crate::{
+    app::{AppState, CommandHandle},
+    error::CommandErrorKind,
+    helper::{Logits, State},
+};
+
+use super::{
+    sample_pipeline::{Exhaustion, ForkedPipeline, PipelineInterruption, SamplePipeline},
+    terminal::types::Termination,
+};
+
+/// A finished beam.
+#[derive(Debug)]
+pub struct Sequence {
+    pub tokens: Vec<u16>,
+    /// Sum of the log probabilities of the tokens.
+    pub score: f32,
+    pub stop_reason: &'static str,
+    pub exhaustion: Option<Exhaustion>,
+    /// Bytes to trim from the end of the decoded tokens, given by the terminal.
+    pub trim: usize,
+    /// The state after all tokens but the last one are fed.
+    pub state: State,
+}
+
+/// The finished sequences of a search, best first, and where its time went.
+pub struct Search {
+    pub sequences: Vec<Sequence>,
+    /// When the prompt is fed.
+    pub prefilled: Instant,
+    /// Time the prompt waited for a batch slot.
+    pub prefill_queued: Duration,
+    /// Time the beams waited for batch slots after the prompt.
+    pub decode_queued: Duration,
+}
+
+/// A beam still being searched, which owns copies of the states and components.
+struct Beam {
+    forked: ForkedPipeline,
+    tokens: Vec<u16>,
+    score: f32,
+    /// Logits and state after all tokens are fed.
+    logits: Logits,
+    state: State,
+    /// The state before the last token is fed.
+    base: State,
+}
+
+impl Beam {
+    fn finish(
+        self,
+        stop_reason: &'static str,
+        exhaustion: Option<Exhaustion>,
+        trim: usize,
+    ) -> Sequence {
+        Sequence {
+            tokens: self.tokens,
+            score: self.score,
+            stop_reason,
+            exhaustion,
+            trim,
+            state: self.base,
+        }
+    }
+}
+
+/// Beam search over a pipeline with a single state.
+///
+/// Each step, every beam is extended by its most probable tokens after transformers and
+/// the normalizer, and only `beams` extensions with the highest cumulative log
+/// probability survive. The search stops once the best finished sequence can't be beaten
+/// by any beam, since scores only go down.
+pub struct BeamSearch<'a> {
+    pub app_state: &'a AppState,
+    pub pipeline: &'a SamplePipeline,
+    pub beams: usize,
+    pub max_tokens: usize,
+    pub update_prompt: bool,
+    pub reset_on_exhaustion: bool,
+    /// Finishes the live beams with `timeout` once it's passed.
+    pub deadline: Option<Instant>,
+}
+
+impl<'a> BeamSearch<'a> {
+    /// Feeds the prompt to the state, and returns the finished sequences.
+    ///
+    /// The state and components of the pipeline are only fed the prompt, beams work on
+    /// copies of them which are deleted as soon as the beams are pruned.
+    pub async fn run(&self, prompt: Vec<u16>, handle: &CommandHandle) -> Result<Search> {
+        let app_state = self.app_state;
+        if self.update_prompt {
+            tokio::task::block_in_place(|| {
+                self.pipeline
+                    .update(app_state, &vec![prompt.clone()], false)
+            })
+            .map_err(PipelineInterruption::into_start_error)?;
+        }
+        // Slots are locked for what is in flight, a single state for the prompt and then
+        // the live beams, whose count changes every step
+        let model = app_state.state_model(&self.pipeline.states)?;
+        let permits = model.batch_request.request(1)?;
+        let (logits, state) = app_state
+            .infer_snapshot(self.pipeline.states.clone(), vec![prompt])
+            .await?
+            .pop()
+            .ok_or(CommandErrorKind::Internal.error("State is not inferred!"))?;
+        drop(permits);
+        let prefilled = Instant::now();
+        let prefill_queued = app_state.take_queued(&self.pipeline.states);
+        let mut decode_queued = Duration::ZERO;
+
+        let forked = self.pipeline.fork(app_state, &[state.clone()])?;
+        forked.pipeline.arm(app_state)?;
+        let mut live = vec![Beam {
+            forked,
+            tokens: Vec::new(),
+            score: 0.0,
+            logits,
+            state: state.clone(),
+            base: state,
+        }];
+        let mut finished: Vec<Sequence> = Vec::new();
+
+        while !live.is_empty() {
+            // The best extensions of all beams
+            let mut candidates = Vec::with_capacity(live.len() * self.beams);
+            for (index, beam) in live.iter().enumerate() {
+                let logprobs = beam
+                    .forked
+                    .pipeline
+                    .logprobs(app_state, vec![beam.logits.clone()])
+                    .await?
+                    .remove(0);
+                candidates.extend(
+                    top_tokens(&logprobs, self.beams)
+                        .into_iter()
+                        .map(|token| (beam.score + logprobs[token], index, token as u16)),
+                );
+            }
+            candidates.sort_unstable_by(|a, b| b.0.total_cmp(&a.0));
+            candidates.truncate(self.beams);
+
+            let mut extended = Vec::with_capacity(candidates.len());
+            for (score, index, token) in candidates {
+                let parent = &live[index];
+                let forked = parent
+                    .forked
+                    .pipeline
+                    .fork(app_state, &[parent.state.clone()])?;
+                let mut tokens = parent.tokens.clone();
+                tokens.push(token);
+                let beam = Beam {
+                    forked,
+                    tokens,
+                    score,
+                    logits: Logits(Vec::new()),
+                    state: parent.state.clone(),
+                    base: parent.state.clone(),
+                };
+
+                if let Some(Termination { reason, trim }) =
+                    beam.forked.pipeline.terminate(app_state, &beam.tokens)?
+                {
+                    finished.push(beam.finish(reason, None, trim));
+                    continue;
+                }
+                if beam.tokens.len() >= self.max_tokens {
+                    finished.push(beam.finish("max_tokens", None, 0));
+                    continue;
+                }
+                if self.update_prompt {
+                    let update = tokio::task::block_in_place(|| {
+                        beam.forked.pipeline.update(
+                            app_state,
+                            &vec![vec![token]],
+                            self.reset_on_exhaustion,
+                        )
+                    });
+                    match update {
+                        Ok(_) => (),
+                        Err(PipelineInterruption::Exhaustion(exhaustion)) => {
+                            finished.push(beam.finish("exhaustion", Some(exhaustion), 0));
+                            continue;
+                        }
+                        Err(PipelineInterruption::Error(e)) => return Err(e),
+                    }
+                }
+                extended.push(beam);
+            }
+
+            // Pruned beams are dropped here, along with their copies
+            live = extended;
+            if live.is_empty() {
+                break;
+            }
+
+            let states: Vec<_> = live
+                .iter()
+                .map(|x| x.forked.pipeline.states[0].clone())
+                .collect();
+            let permits = model.batch_request.request(live.len())?;
+            let inferred = app_state
+                .infer_snapshot(
+                    states.clone(),
+                    live.iter()
+                        .map(|x| vec![*x.tokens.last().unwrap()])
+                        .collect(),
+                )
+                .await?;
+            drop(permits);
+            // The beams are inferred together, so they wait for the same batch
+            decode_queued += app_state.take_queued(&states);
+            for (beam, (logits, state)) in live.iter_mut().zip(inferred.into_iter()) {
+                beam.logits = logits;
+                beam.state = state;
+            }
+
+            if handle.is_cancelled() {
+                finished.extend(live.drain(..).map(|x| x.finish("cancelled", None, 0)));
+                break;
+            }
+            if self.deadline.is_some_and(|x| Instant::now() >= x) {
+                finished.extend(live.drain(..).map(|x| x.finish("timeout", None, 0)));
+                break;
+            }
+
+            let best_finished = finished.iter().map(|x| x.score).fold(f32::MIN, f32::max);
+            let best_live = live.iter().map(|x| x.score).fold(f32::MIN, f32::max);
+            if !finished.is_empty() && best_finished >= best_live {
+                break;
+            }
+        }
+
+        finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+        finished.truncate(self.beams);
+        Ok(Search {
+            sequences: finished,
+            prefilled,
+            prefill_queued,
+            decode_queued,
+        })
+    }
+}
+
+/// Indices of the `n` largest log probabilities.
+fn top_tokens(logprobs: &[f32], n: usize) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..logprobs.len())
+        .filter(|&x| logprobs[x].is_finite())
+        .collect();
+    let descending = |a: &usize, b: &usize| logprobs[*b].total_cmp(&logprobs[*a]);
+    if n < indices.len() {
+        indices.select_nth_unstable_by(n, descending);
+        indices.truncate(n);
+    }
+    indices
+}
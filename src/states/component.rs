@@ -0,0 +1,43 @@
+use std::ops::{Deref, DerefMut};
+
+use serde_json::Value;
+
+/// A component in a registry, along with the JSON definition (`type_id` and `params`) it's
+/// created from, so it can be exported and created again.
+#[derive(Debug)]
+pub struct Component<T> {
+    pub inner: T,
+    pub definition: Value,
+    /// The connection owning the component, which deletes it on disconnect. `None` for
+    /// components which live until deleted.
+    pub owner: Option<usize>,
+}
+
+impl<T> Component<T> {
+    pub fn new(inner: T, definition: Value) -> Self {
+        Self {
+            inner,
+            definition,
+            owner: None,
+        }
+    }
+
+    /// Ties the component to a connection, if any.
+    pub fn owned_by(self, owner: Option<usize>) -> Self {
+        Self { owner, ..self }
+    }
+}
+
+impl<T> Deref for Component<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for Component<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
@@ -0,0 +1,24 @@
+use serde::Deserialize;
+
+/// Classifier-free guidance, which steers the logits of a state away from those of a
+/// negative state by `uncond + scale * (cond - uncond)`.
+///
+/// The negative state is fed the same tokens as the state in the same batch, so both
+/// advance in lockstep. It should differ from the state by its earlier context, e.g. a
+/// negative prompt fed with `update_state` beforehand.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Guidance {
+    pub negative_state: String,
+    /// 1 leaves the logits of the state unchanged, and larger values push them further
+    /// from the negative state.
+    pub scale: f32,
+}
+
+impl Guidance {
+    /// Blends the logits of the negative state into those of the state.
+    pub fn blend(&self, cond: &mut [f32], uncond: &[f32]) {
+        for (cond, &uncond) in cond.iter_mut().zip(uncond) {
+            *cond = uncond + self.scale * (*cond - uncond);
+        }
+    }
+}
@@ -1,3 +1,8 @@
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
 use anyhow::{Ok, Result};
 use tokio::sync::{mpsc, oneshot};
 
@@ -7,11 +12,96 @@ use crate::helper::{Logits, State};
 pub struct InferContext {
     pub state: Option<State>,
     pub tokens: Vec<u16>,
+    /// Sends back the state after the tokens are inferred in `InferResult`.
+    pub snapshot: bool,
+    /// Loads `state` even if the pipeline still holds the state, which is outdated.
+    pub reload: bool,
 }
 
 #[derive(Debug)]
 pub struct InferResult {
     pub logits: Logits,
+    /// The state after the tokens are inferred, if a snapshot is requested.
+    pub state: Option<State>,
+    /// How long the request waited for a batch slot.
+    pub queued: Duration,
+}
+
+/// A run of the model which failed even after retries. The slot of the state is cleared,
+/// and the state is sent back as it was before the run if it's `restored`, or reset
+/// otherwise.
+#[derive(Debug, Clone)]
+pub struct RunError {
+    pub state: String,
+    pub restored: bool,
+    pub error: String,
+}
+
+impl Display for RunError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Failed to infer state {}: {}. ", self.state, self.error)?;
+        match self.restored {
+            true => write!(f, "The state is restored to before the failed step."),
+            false => write!(f, "The state is lost and reset to the initial state."),
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
+
+/// Asks the pipeline for the data of a state held in a slot, which is newer than the data
+/// in the app state once the state is inferred. `None` is sent back if no slot holds it.
+#[derive(Debug)]
+pub struct SyncRequest {
+    pub state_id: String,
+    pub callback: oneshot::Sender<Option<State>>,
+}
+
+impl SyncRequest {
+    pub async fn send(
+        state_id: String,
+        sender: mpsc::Sender<PipelineRequest>,
+    ) -> Result<Option<State>> {
+        let (callback, receiver) = oneshot::channel();
+        sender
+            .send(PipelineRequest::Sync(SyncRequest { state_id, callback }))
+            .await?;
+        Ok(receiver.await?)
+    }
+}
+
+/// Pins or unpins the slot of a state in the pipeline. A slot holding a pinned state is
+/// never given to another state, so the state stays loaded between its infers.
+#[derive(Debug)]
+pub struct PinRequest {
+    pub state_id: String,
+    pub pin: bool,
+    pub callback: oneshot::Sender<Result<()>>,
+}
+
+impl PinRequest {
+    pub async fn send(
+        state_id: String,
+        pin: bool,
+        sender: mpsc::Sender<PipelineRequest>,
+    ) -> Result<()> {
+        let (callback, receiver) = oneshot::channel();
+        sender
+            .send(PipelineRequest::Pin(PinRequest {
+                state_id,
+                pin,
+                callback,
+            }))
+            .await?;
+        receiver.await?
+    }
+}
+
+#[derive(Debug)]
+pub enum PipelineRequest {
+    Infer(Vec<InferRequest>),
+    Sync(SyncRequest),
+    Pin(PinRequest),
 }
 
 #[derive(Debug)]
@@ -20,42 +110,48 @@ pub struct InferResult {
 /// Use `InferRequest::send` instead.
 pub struct InferRequest {
     pub context: InferContext,
-    pub callback: oneshot::Sender<InferResult>,
+    pub callback: oneshot::Sender<Result<InferResult, RunError>>,
     pub state_id: String,
     pub state_callback: oneshot::Sender<Option<State>>,
+    /// When the request is sent to the pipeline.
+    pub sent: Instant,
 }
 
 impl InferRequest {
     /// Queue an infer request to the pipeline.
     pub async fn send(
         contexts: Vec<InferContext>,
-        sender: mpsc::Sender<Vec<InferRequest>>,
+        sender: mpsc::Sender<PipelineRequest>,
         state_ids: Vec<String>,
         state_callbacks: Vec<oneshot::Sender<Option<State>>>,
     ) -> Result<Vec<InferResult>> {
-        let (receivers, requests): (Vec<oneshot::Receiver<InferResult>>, Vec<InferRequest>) =
-            contexts
-                .into_iter()
-                .zip(state_ids.into_iter())
-                .zip(state_callbacks.into_iter())
-                .map(|((context, id), state_callback)| {
-                    let (callback, receiver) = oneshot::channel();
-                    (
-                        receiver,
-                        InferRequest {
-                            context,
-                            callback,
-                            state_id: id,
-                            state_callback,
-                        },
-                    )
-                })
-                .unzip();
-
-        sender.send(requests).await?;
+        let sent = Instant::now();
+        let (receivers, requests): (
+            Vec<oneshot::Receiver<Result<InferResult, RunError>>>,
+            Vec<InferRequest>,
+        ) = contexts
+            .into_iter()
+            .zip(state_ids.into_iter())
+            .zip(state_callbacks.into_iter())
+            .map(|((context, id), state_callback)| {
+                let (callback, receiver) = oneshot::channel();
+                (
+                    receiver,
+                    InferRequest {
+                        context,
+                        callback,
+                        state_id: id,
+                        state_callback,
+                        sent,
+                    },
+                )
+            })
+            .unzip();
+
+        sender.send(PipelineRequest::Infer(requests)).await?;
         let mut results = Vec::new();
         for receiver in receivers {
-            results.push(receiver.await?);
+            results.push(receiver.await??);
         }
         Ok(results)
     }
@@ -0,0 +1,84 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::{error::CommandErrorKind, helper::State};
+
+use super::state_file::StateLayout;
+
+/// Rows of each layer in a V4 state, of `num_emb` floats each.
+const ROWS_PER_LAYER: usize = 5;
+/// The rows holding the numerator (`aa`), the denominator (`bb`) and their shared
+/// exponent (`pp`) of the WKV of a layer, which are scaled by `exp(pp)`.
+const AA: usize = 1;
+const BB: usize = 2;
+const PP: usize = 3;
+
+/// Blends states of the same layout by weights, which must be finite and non-negative
+/// with a positive sum, and are normalized to sum up to 1.
+///
+/// Only the V4 layout is supported. The token shift rows are averaged by weights. The
+/// WKV numerator and denominator are averaged in the linear domain, i.e. as
+/// `aa * exp(pp)`, and rescaled to the largest `pp` of the states, so a state with
+/// a much larger exponent dominates as it would in the model.
+pub fn merge_states(layout: &StateLayout, states: &[(State, f32)]) -> Result<State> {
+    if layout.version != "V4" {
+        return Err(CommandErrorKind::BadRequest.error(format!(
+            "Merging states of layout {} is not supported, only V4 is!",
+            layout.version
+        )));
+    }
+    if states.is_empty() {
+        return Err(CommandErrorKind::BadRequest.error("No state to merge!"));
+    }
+    if states.iter().any(|(_, w)| !w.is_finite() || *w < 0.0) {
+        return Err(CommandErrorKind::BadRequest.error("Weights must be finite and non-negative!"));
+    }
+    let sum: f32 = states.iter().map(|(_, w)| w).sum();
+    if sum <= 0.0 || !sum.is_finite() {
+        return Err(CommandErrorKind::BadRequest.error("Weights must have a positive sum!"));
+    }
+    if states.iter().any(|(x, _)| x.len() != layout.data_len()) {
+        return Err(CommandErrorKind::Internal.error("State doesn't match its model!"));
+    }
+
+    let num_emb = layout.num_emb;
+    let mut data = vec![0.0; layout.data_len()];
+    for layer in 0..layout.num_layers {
+        let offset = layer * ROWS_PER_LAYER * num_emb;
+        for index in (0..ROWS_PER_LAYER).filter(|x| ![AA, BB, PP].contains(x)) {
+            let start = offset + index * num_emb;
+            for (state, weight) in states {
+                let weight = weight / sum;
+                for (x, y) in data[start..start + num_emb]
+                    .iter_mut()
+                    .zip(row(state, num_emb, layer, index))
+                {
+                    *x += weight * y;
+                }
+            }
+        }
+        for emb in 0..num_emb {
+            let pp = states
+                .iter()
+                .map(|(state, _)| row(state, num_emb, layer, PP)[emb])
+                .fold(f32::MIN, f32::max);
+            let (mut aa, mut bb) = (0.0, 0.0);
+            for (state, weight) in states {
+                let scale = weight / sum * (row(state, num_emb, layer, PP)[emb] - pp).exp();
+                aa += scale * row(state, num_emb, layer, AA)[emb];
+                bb += scale * row(state, num_emb, layer, BB)[emb];
+            }
+            data[offset + AA * num_emb + emb] = aa;
+            data[offset + BB * num_emb + emb] = bb;
+            data[offset + PP * num_emb + emb] = pp;
+        }
+    }
+    Ok(State(Arc::new(data)))
+}
+
+/// A row of a layer in a V4 state.
+fn row(state: &State, num_emb: usize, layer: usize, index: usize) -> &[f32] {
+    let start = (layer * ROWS_PER_LAYER + index) * num_emb;
+    &state.0[start..start + num_emb]
+}
@@ -1,13 +1,45 @@
 use anyhow::Error;
 
+use crate::error::CommandErrorKind;
+
+pub mod batch_controller;
+pub mod beam_search;
+pub mod component;
+pub mod guidance;
 pub mod infer;
+pub mod merge;
+pub mod model;
+pub mod normalizer;
 pub mod permit;
 pub mod pipeline;
+pub mod prefix_cache;
+pub mod sample_pipeline;
 pub mod sampler;
 pub mod softmax;
+pub mod speculative;
+pub mod spill;
+pub mod state_file;
+pub mod template;
+pub mod terminal;
 pub mod transformer;
 
 pub enum InferenceInterruption {
-    Exhaustion,
+    /// The component can't go on, with an optional reason for the client, e.g. whether a
+    /// grammar is completed or no token is admissible anymore.
+    Exhaustion(Option<String>),
     Error(Error),
 }
+
+impl InferenceInterruption {
+    /// An error for commands which update a component directly, e.g. `Transformer`.
+    pub fn into_error(self, component: &str) -> Error {
+        match self {
+            Self::Exhaustion(Some(reason)) => CommandErrorKind::Interrupted
+                .error(format!("{} is exhausted: {}", component, reason)),
+            Self::Exhaustion(None) => {
+                CommandErrorKind::Interrupted.error(format!("{} is exhausted!", component))
+            }
+            Self::Error(e) => e,
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use tokio::{
+    sync::{mpsc::Sender, oneshot},
+    task::JoinHandle,
+};
+use web_rwkv::{
+    context::Context,
+    model::{Model, ModelInfo},
+};
+
+use crate::{
+    cli::{BatchConfig, DeviceConfig, RetryConfig, SoftmaxConfig},
+    config::ModelSpec,
+    helper::State,
+};
+
+use super::{
+    batch_controller::{BatchController, BatchStats},
+    infer::{InferContext, InferRequest, InferResult, PinRequest, PipelineRequest, SyncRequest},
+    permit::BatchRequest,
+    pipeline::Pipeline,
+    softmax::Softmax,
+};
+
+/// A loaded model, along with the infer pipeline and softmax worker running on it.
+pub struct AxumModel {
+    pub name: String,
+    pub spec: ModelSpec,
+    pub context: Context,
+    pub model: Arc<Model<'static>>,
+    pub batch_request: BatchRequest,
+    /// Slots allocated for the batch, the upper bound of the batch size.
+    pub max_batch: usize,
+    /// States which may pin their slots at once, always leaving a slot for the others.
+    pub max_pinned: usize,
+    /// Runs of the pipeline and the slots inferred in them.
+    pub batch_stats: Arc<BatchStats>,
+    infer_queue: Sender<PipelineRequest>,
+    softmax_queue: Sender<Vec<(Vec<f32>, oneshot::Sender<Vec<f32>>)>>,
+}
+
+impl AxumModel {
+    /// Loads the model and starts its infer pipeline and softmax worker.
+    ///
+    /// `batch_config` overrides the batch size of the spec, and enables the adaptive batch
+    /// size if it has a lower bound. `retry_config` sets how failed runs are retried, and
+    /// `device_config` overrides the adapter the model is loaded on.
+    ///
+    /// The returned handles finish once the `AxumModel` is dropped.
+    pub async fn load(
+        name: String,
+        spec: ModelSpec,
+        softmax_config: SoftmaxConfig,
+        batch_config: BatchConfig,
+        retry_config: RetryConfig,
+        device_config: DeviceConfig,
+    ) -> Result<(Self, Vec<JoinHandle<()>>)> {
+        let context = spec.create_context(device_config).await?;
+        let model = Arc::new(spec.load_model(&context).await?);
+        let (min_batch, max_batch) = batch_config.bounds(spec.get_batch_size());
+        let batch_request = BatchRequest::new(max_batch);
+        let max_pinned = spec.get_max_pinned_slots().min(max_batch - 1);
+
+        let softmax = Softmax::new(
+            model.clone(),
+            softmax_config.batch_size.unwrap_or(max_batch),
+            softmax_config.batch_timeout,
+        )
+        .await;
+        let (softmax_queue, softmax_handle) = softmax.run().await;
+        let controller =
+            BatchController::new(min_batch, max_batch).with_window(spec.get_batch_window());
+        let batch_stats = controller.stats();
+        let (infer_queue, infer_handle) = Pipeline::start(
+            max_batch,
+            context.clone(),
+            model.clone(),
+            batch_request.clone(),
+            controller,
+            retry_config,
+            max_pinned,
+        )
+        .await;
+
+        Ok((
+            Self {
+                name,
+                spec,
+                context,
+                model,
+                batch_request,
+                max_batch,
+                max_pinned,
+                batch_stats,
+                infer_queue,
+                softmax_queue,
+            },
+            vec![infer_handle, softmax_handle],
+        ))
+    }
+
+    #[inline(always)]
+    pub fn info(&self) -> &ModelInfo {
+        self.model.info()
+    }
+
+    /// The state layout version of the model.
+    #[inline(always)]
+    pub fn version(&self) -> &'static str {
+        // The pipeline only handles the V4 state layout for now
+        "V4"
+    }
+
+    /// Queue infer requests to the pipeline of this model.
+    pub async fn infer(
+        &self,
+        contexts: Vec<InferContext>,
+        state_ids: Vec<String>,
+        state_callbacks: Vec<oneshot::Sender<Option<State>>>,
+    ) -> Result<Vec<InferResult>> {
+        InferRequest::send(
+            contexts,
+            self.infer_queue.clone(),
+            state_ids,
+            state_callbacks,
+        )
+        .await
+    }
+
+    /// Downloads the data of a state from the pipeline, `None` if no slot holds it.
+    pub async fn sync(&self, state_id: String) -> Result<Option<State>> {
+        SyncRequest::send(state_id, self.infer_queue.clone()).await
+    }
+
+    /// Pins the slot of a state so it stays loaded, or unpins it. Fails if `max_pinned`
+    /// states are already pinned.
+    pub async fn pin(&self, state_id: String, pin: bool) -> Result<()> {
+        PinRequest::send(state_id, pin, self.infer_queue.clone()).await
+    }
+
+    /// This must not fail, or the implementation is severly bugged
+    pub async fn softmax(&self, logits: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
+        Softmax::softmax(logits, self.softmax_queue.clone()).await
+    }
+}
@@ -0,0 +1,86 @@
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{app::AppState, error::CommandErrorKind, states::InferenceInterruption};
+
+use super::{
+    softmax::{default_temperature, softmax},
+    types::Normalizer,
+};
+
+/// Softmax followed by epsilon cutoff, which zeroes out every probability below `epsilon`
+/// and renormalizes, so no downstream sampler can pick those tokens.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EpsilonNormalizer {
+    epsilon: f32,
+    #[serde(default = "default_temperature")]
+    temperature: f32,
+}
+
+impl EpsilonNormalizer {
+    pub fn new(epsilon: f32, temperature: f32) -> Self {
+        Self {
+            epsilon,
+            temperature,
+        }
+    }
+}
+
+/// Zeroes out probabilities below `epsilon` and renormalizes. The most probable token
+/// always survives, even if it is below `epsilon` itself.
+pub fn epsilon_cutoff(probs: &mut [f32], epsilon: f32) {
+    let max = probs
+        .iter()
+        .enumerate()
+        .max_by(|(_, x), (_, y)| x.total_cmp(y))
+        .map(|(index, _)| index);
+    for (index, prob) in probs.iter_mut().enumerate() {
+        if *prob < epsilon && Some(index) != max {
+            *prob = 0.0;
+        }
+    }
+    let sum: f32 = probs.iter().sum();
+    if sum > 0.0 {
+        probs.iter_mut().for_each(|x| *x /= sum);
+    } else if let Some(max) = max {
+        // Only when all probs are 0, which means the logits are broken
+        probs[max] = 1.0;
+    }
+}
+
+impl Normalizer for EpsilonNormalizer {
+    fn update(&mut self, _tokens: &Vec<Vec<u16>>) -> Result<(), InferenceInterruption> {
+        Ok(())
+    }
+
+    fn normalize(&self, logits: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
+        logits
+            .iter()
+            .map(|x| {
+                let mut probs = softmax(x, self.temperature);
+                epsilon_cutoff(&mut probs, self.epsilon);
+                probs
+            })
+            .collect()
+    }
+
+    fn clear(&mut self) {}
+
+    fn clone(&self) -> Box<dyn Normalizer> {
+        Box::new(Clone::clone(self))
+    }
+}
+
+pub fn initialize_epsilon(_state: AppState, data: Option<Value>) -> Result<Box<dyn Normalizer>> {
+    let normalizer: EpsilonNormalizer = serde_json::from_value(
+        data.ok_or(CommandErrorKind::BadRequest.error("Field must present to specify epsilon!"))?,
+    )?;
+    if !(0.0..1.0).contains(&normalizer.epsilon) {
+        return Err(CommandErrorKind::BadRequest.error("epsilon must be in [0, 1)!"));
+    }
+    if !(normalizer.temperature > 0.0) {
+        return Err(CommandErrorKind::BadRequest.error("temperature must be positive!"));
+    }
+    Ok(Box::new(normalizer))
+}
@@ -0,0 +1,76 @@
+use std::{fmt::Debug, sync::Arc};
+
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::runtime::Handle;
+
+use crate::{
+    app::AppState,
+    error::CommandErrorKind,
+    states::{model::AxumModel, InferenceInterruption},
+};
+
+use super::{softmax::default_temperature, types::Normalizer};
+
+#[derive(Debug, Deserialize)]
+struct GpuSoftmaxData {
+    #[serde(default = "default_temperature")]
+    temperature: f32,
+    #[serde(default)]
+    model: Option<String>,
+}
+
+/// Softmax with temperature on GPU, through the softmax worker of a model.
+///
+/// The worker coalesces softmax requests from concurrent infer requests into one submit,
+/// so prefer this over `softmax` when many clients are generating at once.
+#[derive(Clone)]
+pub struct GpuSoftmaxNormalizer {
+    temperature: f32,
+    model: Arc<AxumModel>,
+}
+
+impl Debug for GpuSoftmaxNormalizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GpuSoftmaxNormalizer")
+            .field("temperature", &self.temperature)
+            .field("model", &self.model.name)
+            .finish()
+    }
+}
+
+impl Normalizer for GpuSoftmaxNormalizer {
+    fn update(&mut self, _tokens: &Vec<Vec<u16>>) -> Result<(), InferenceInterruption> {
+        Ok(())
+    }
+
+    fn normalize(&self, mut logits: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
+        if self.temperature != 1.0 {
+            logits
+                .iter_mut()
+                .flatten()
+                .for_each(|x| *x /= self.temperature);
+        }
+        // Normalizers are called in `block_in_place`, so it's fine to block on the worker
+        Handle::current().block_on(self.model.softmax(logits))
+    }
+
+    fn clear(&mut self) {}
+
+    fn clone(&self) -> Box<dyn Normalizer> {
+        Box::new(Clone::clone(self))
+    }
+}
+
+pub fn initialize_gpu_softmax(state: AppState, data: Option<Value>) -> Result<Box<dyn Normalizer>> {
+    let GpuSoftmaxData { temperature, model } =
+        serde_json::from_value(data.unwrap_or(Value::Object(Default::default())))?;
+    if !(temperature > 0.0) {
+        return Err(CommandErrorKind::BadRequest.error("temperature must be positive!"));
+    }
+    Ok(Box::new(GpuSoftmaxNormalizer {
+        temperature,
+        model: state.model(model.as_deref())?,
+    }))
+}
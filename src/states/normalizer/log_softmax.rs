@@ -0,0 +1,69 @@
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{app::AppState, error::CommandErrorKind, states::InferenceInterruption};
+
+use super::{
+    softmax::default_temperature,
+    types::{Domain, Normalizer},
+};
+
+/// Log-softmax on CPU with temperature, which hands log probabilities to the sampler.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LogSoftmaxNormalizer {
+    #[serde(default = "default_temperature")]
+    temperature: f32,
+}
+
+impl LogSoftmaxNormalizer {
+    pub fn new(temperature: f32) -> Self {
+        Self { temperature }
+    }
+}
+
+/// Computes `log_softmax(logits / temperature)`, subtracting the max logit for stability.
+pub fn log_softmax(logits: &[f32], temperature: f32) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let scaled = logits
+        .iter()
+        .map(|x| (x - max) / temperature)
+        .collect::<Vec<_>>();
+    let log_sum = scaled.iter().map(|x| x.exp()).sum::<f32>().ln();
+    scaled.into_iter().map(|x| x - log_sum).collect()
+}
+
+impl Normalizer for LogSoftmaxNormalizer {
+    fn update(&mut self, _tokens: &Vec<Vec<u16>>) -> Result<(), InferenceInterruption> {
+        Ok(())
+    }
+
+    fn normalize(&self, logits: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
+        logits
+            .iter()
+            .map(|x| log_softmax(x, self.temperature))
+            .collect()
+    }
+
+    fn domain(&self) -> Domain {
+        Domain::LogProbs
+    }
+
+    fn clear(&mut self) {}
+
+    fn clone(&self) -> Box<dyn Normalizer> {
+        Box::new(Clone::clone(self))
+    }
+}
+
+pub fn initialize_log_softmax(
+    _state: AppState,
+    data: Option<Value>,
+) -> Result<Box<dyn Normalizer>> {
+    let normalizer: LogSoftmaxNormalizer =
+        serde_json::from_value(data.unwrap_or(Value::Object(Default::default())))?;
+    if !(normalizer.temperature > 0.0) {
+        return Err(CommandErrorKind::BadRequest.error("temperature must be positive!"));
+    }
+    Ok(Box::new(normalizer))
+}
@@ -0,0 +1,142 @@
+use self::types::{Domain, Normalizer};
+use crate::{app::AppState, error::CommandErrorKind, hashmap_ex};
+use anyhow::{Ok, Result};
+use dashmap::DashMap;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+use super::{component::Component, InferenceInterruption};
+
+pub mod epsilon;
+pub mod gpu_softmax;
+pub mod log_softmax;
+pub mod softmax;
+pub mod types;
+
+#[derive(Debug, Deserialize)]
+struct NormalizerJson {
+    type_id: String,
+    params: Option<Value>,
+}
+
+pub struct Normalizers {
+    registry: HashMap<&'static str, fn(AppState, Option<Value>) -> Result<Box<dyn Normalizer>>>,
+    map: DashMap<String, Component<Box<dyn Normalizer>>>,
+}
+
+impl Normalizers {
+    pub fn new() -> Self {
+        Self {
+            registry: hashmap_ex! {
+                HashMap<&'static str, fn(AppState, Option<Value>) -> Result<Box<dyn Normalizer>>>,
+                    {
+                        "softmax" => softmax::initialize_softmax,
+                        "log_softmax" => log_softmax::initialize_log_softmax,
+                        "gpu_softmax" => gpu_softmax::initialize_gpu_softmax,
+                        "epsilon" => epsilon::initialize_epsilon,
+                    }
+            },
+            map: DashMap::with_capacity(128),
+        }
+    }
+
+    fn create(
+        &self,
+        key: &str,
+        state: AppState,
+        data: Option<Value>,
+    ) -> Result<Box<dyn Normalizer>> {
+        let constructor = self.registry.get(key);
+        if let Some(constructor) = constructor {
+            Ok(constructor(state, data)?)
+        } else {
+            Err(CommandErrorKind::NormalizerNotFound.error("Normalizer not found!"))
+        }
+    }
+
+    pub fn create_normalizer(&self, id: String, state: AppState, data: Value) -> Result<()> {
+        if self.map.contains_key(&id) {
+            return Err(CommandErrorKind::AlreadyExists.error("Normalizer already existed!"));
+        }
+        let NormalizerJson { type_id, params } =
+            serde_json::from_value::<NormalizerJson>(data.clone())?;
+        let normalizer = self.create(&type_id, state, params)?;
+        self.map.insert(id, Component::new(normalizer, data));
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn has_normalizer(&self, id: &str) -> bool {
+        self.map.contains_key(id)
+    }
+
+    pub fn delete_normalizer(&self, id: &str) -> Result<()> {
+        self.map
+            .remove(id)
+            .ok_or(CommandErrorKind::NormalizerNotFound.error("Normalizer id doesn't exist!"))
+            .map(|_| ())
+    }
+
+    pub fn reset_normalizer(&self, id: &str) -> Result<()> {
+        if let Some(mut normalizer) = self.map.get_mut(id) {
+            normalizer.clear();
+            Ok(())
+        } else {
+            Err(CommandErrorKind::NormalizerNotFound.error("Normalizer id doesn't exist!"))
+        }
+    }
+
+    pub fn update_normalizer(
+        &self,
+        id: &str,
+        content: &Vec<Vec<u16>>,
+    ) -> Result<(), InferenceInterruption> {
+        if let Some(mut normalizer) = self.map.get_mut(id) {
+            normalizer.update(content)
+        } else {
+            Err(InferenceInterruption::Error(
+                CommandErrorKind::NormalizerNotFound.error("Normalizer id doesn't exist!"),
+            ))
+        }
+    }
+
+    pub fn copy_normalizer(&self, src: String, dst: String) -> Result<()> {
+        if self.map.contains_key(&dst) {
+            return Err(
+                CommandErrorKind::AlreadyExists.error("Destination normalizer id already exists!")
+            );
+        }
+        let src = self
+            .map
+            .get(&src)
+            .map(|x| Component::new(x.inner.clone(), x.definition.clone()))
+            .ok_or(CommandErrorKind::NormalizerNotFound.error("Normalizer doesn't exist!"))?;
+        self.map.insert(dst, src);
+        Ok(())
+    }
+
+    /// The definitions of all normalizers by id, which create them again.
+    pub fn definitions(&self) -> Map<String, Value> {
+        self.map
+            .iter()
+            .map(|x| (x.key().clone(), x.definition.clone()))
+            .collect()
+    }
+
+    /// Ids of all normalizers, sorted.
+    pub fn ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.map.iter().map(|x| x.key().clone()).collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Normalizes the logits, and returns the domain of the normalized values.
+    pub fn normalize(&self, id: &str, logits: Vec<Vec<f32>>) -> Result<(Vec<Vec<f32>>, Domain)> {
+        if let Some(normalizer) = self.map.get(id) {
+            Ok((normalizer.normalize(logits), normalizer.domain()))
+        } else {
+            Err(CommandErrorKind::NormalizerNotFound.error("Normalizer id doesn't exist!"))
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{app::AppState, error::CommandErrorKind, states::InferenceInterruption};
+
+use super::types::Normalizer;
+
+pub(crate) fn default_temperature() -> f32 {
+    1.0
+}
+
+/// Softmax on CPU with temperature applied to the logits before exponentiation.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SoftmaxNormalizer {
+    #[serde(default = "default_temperature")]
+    temperature: f32,
+}
+
+impl SoftmaxNormalizer {
+    pub fn new(temperature: f32) -> Self {
+        Self { temperature }
+    }
+}
+
+/// Computes `softmax(logits / temperature)`, subtracting the max logit for stability.
+pub fn softmax(logits: &[f32], temperature: f32) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exp = logits
+        .iter()
+        .map(|x| ((x - max) / temperature).exp())
+        .collect::<Vec<_>>();
+    let sum: f32 = exp.iter().sum();
+    exp.into_iter().map(|x| x / sum).collect()
+}
+
+impl Normalizer for SoftmaxNormalizer {
+    fn update(&mut self, _tokens: &Vec<Vec<u16>>) -> Result<(), InferenceInterruption> {
+        Ok(())
+    }
+
+    fn normalize(&self, logits: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
+        logits
+            .iter()
+            .map(|x| softmax(x, self.temperature))
+            .collect()
+    }
+
+    fn clear(&mut self) {}
+
+    fn clone(&self) -> Box<dyn Normalizer> {
+        Box::new(Clone::clone(self))
+    }
+}
+
+pub fn initialize_softmax(_state: AppState, data: Option<Value>) -> Result<Box<dyn Normalizer>> {
+    let normalizer: SoftmaxNormalizer =
+        serde_json::from_value(data.unwrap_or(Value::Object(Default::default())))?;
+    if !(normalizer.temperature > 0.0) {
+        return Err(CommandErrorKind::BadRequest.error("temperature must be positive!"));
+    }
+    Ok(Box::new(normalizer))
+}
@@ -0,0 +1,56 @@
+use anyhow::Result;
+use std::fmt::Debug;
+
+use crate::states::InferenceInterruption;
+
+/// The domain of normalized values handed over to the `Sampler`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Domain {
+    /// Probabilities which sum up to 1.
+    #[default]
+    Probs,
+    /// Natural log of probabilities.
+    LogProbs,
+}
+
+/// Normalizes logits distributions into probabilities, in place of the default `softmax`.
+///
+/// This happens after all `Transformer`s are applied, and the result is handed over to
+/// the `Sampler`.
+///
+/// #### Registration
+///
+/// A normalizer type needs to be registered before it can be constructed by the Websocket
+/// API.
+///
+/// To register a normalizer, put the type_id (a literal string) with the constructor (which
+/// is a `Fn(SharedState, Option<Value>)->Result<Box<dyn Normalizer>>`) in the `new()` of
+/// `Normalizers`.
+pub trait Normalizer: Send + Sync + Debug {
+    /// Updates the internal state of the normalizer by accepting a list of tokens, one list
+    /// for each state in the infer request.
+    ///
+    /// Like `Sampler::update`, a normalizer must perceive if it can or can not accept any
+    /// further input, and interrupt the generation by returning
+    /// `Err(InferenceInterruption::Exhaustion(reason))`.
+    fn update(&mut self, tokens: &Vec<Vec<u16>>) -> Result<(), InferenceInterruption>;
+
+    /// Normalizes one logits distribution for each state into probabilities, each of which
+    /// must sum up to 1, or into log probabilities if `domain` is `Domain::LogProbs`.
+    ///
+    /// This function must be **infallible**, as any interruption is checked when updated.
+    fn normalize(&self, logits: Vec<Vec<f32>>) -> Vec<Vec<f32>>;
+
+    /// The domain of the values returned by `normalize`. Defaults to `Domain::Probs`.
+    fn domain(&self) -> Domain {
+        Domain::Probs
+    }
+
+    /// Clears the `Normalizer`. This will reset the internal state of the normalizer to
+    /// *when it is just constructed from params*.
+    fn clear(&mut self);
+
+    /// Copies the internal state (no matter if it's from construction or temporal calculation),
+    /// and construct a new `Normalizer` from the state.
+    fn clone(&self) -> Box<dyn Normalizer>;
+}
@@ -3,6 +3,10 @@ use std::sync::{
     Arc,
 };
 
+use anyhow::Result;
+
+use crate::error::CommandErrorKind;
+
 #[derive(Debug, Clone)]
 /// A `BatchRequest` is for locking the GPU infer loop so the run can be more batched.
 ///
@@ -15,27 +19,62 @@ use std::sync::{
 /// be requested to ensure run is not stalled.
 ///
 /// But usually that won't happen, probably.
-pub struct BatchRequest(Arc<AtomicUsize>);
+///
+/// #### Sizing
+///
+/// Taking permits never blocks, but the infer loop doesn't start a run until it has as many
+/// jobs as the permits held, unless the slots are full, the batch size of the controller
+/// is reached or the batch window is over. So a request must only hold permits for the jobs
+/// it has in flight or is about to send: holding more than it sends (e.g. `beams` permits
+/// while feeding the prompt once) stalls the loop until the permits are dropped. Requests
+/// whose job count changes between steps should take permits per step.
+///
+/// #### Fairness
+///
+/// A request can hold at most `max_batch_size` permits, since its states are inferred
+/// together and can't take more slots than the batch has. Jobs which don't fit into the batch are queued and
+/// loaded into slots in FIFO order, so a request with many states can't starve requests
+/// with a single state, or the other way around.
+pub struct BatchRequest {
+    requested: Arc<AtomicUsize>,
+    max_batch_size: usize,
+}
 
 #[derive(Debug)]
 pub struct Permit(usize, BatchRequest);
 
 impl BatchRequest {
-    pub fn new() -> Self {
-        BatchRequest(Arc::new(AtomicUsize::new(0)))
+    pub fn new(max_batch_size: usize) -> Self {
+        BatchRequest {
+            requested: Arc::new(AtomicUsize::new(0)),
+            max_batch_size,
+        }
     }
 
     pub fn get(&self) -> usize {
-        self.0.load(Ordering::Acquire)
+        self.requested.load(Ordering::Acquire)
+    }
+
+    /// Fails if `amount` states can't be inferred together, e.g. to reject a request up front
+    /// which takes permits step by step.
+    pub fn check(&self, amount: usize) -> Result<()> {
+        if amount > self.max_batch_size {
+            return Err(CommandErrorKind::BadRequest.error(format!(
+                "{} states are requested in one infer, but at most {} states can be inferred together!",
+                amount, self.max_batch_size
+            )));
+        }
+        Ok(())
     }
 
-    pub fn request(&self, amount: usize) -> Permit {
-        self.0.fetch_add(amount, Ordering::Release);
-        Permit(amount, self.clone())
+    pub fn request(&self, amount: usize) -> Result<Permit> {
+        self.check(amount)?;
+        self.requested.fetch_add(amount, Ordering::Release);
+        Ok(Permit(amount, self.clone()))
     }
 
     fn release(&self, permit: &Permit) {
-        self.0.fetch_sub(permit.0, Ordering::Release);
+        self.requested.fetch_sub(permit.0, Ordering::Release);
     }
 }
 
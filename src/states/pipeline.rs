@@ -1,6 +1,10 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use anyhow::{Error, Result};
+use anyhow::Result;
 use tokio::{
     sync::{
         mpsc::{self, error::TryRecvError},
@@ -14,10 +18,17 @@ use web_rwkv::{
     tensor::shape::Shape,
 };
 
-use crate::helper::{Logits, State};
+use crate::{
+    cli::RetryConfig,
+    error::CommandErrorKind,
+    helper::{Logits, State},
+};
 
 use super::{
-    infer::{InferContext, InferRequest, InferResult},
+    batch_controller::{BatchController, BatchStats},
+    infer::{
+        InferContext, InferRequest, InferResult, PinRequest, PipelineRequest, RunError, SyncRequest,
+    },
     permit::BatchRequest,
 };
 
@@ -26,10 +37,26 @@ struct Slots {
     batch_tokens: Vec<Vec<u16>>,
     batch_state_callbacks: Vec<Option<oneshot::Sender<Option<State>>>>,
     batch_state_ids: Vec<Option<String>>,
+    /// Whether to send back the state along with the logits
+    batch_snapshots: Vec<bool>,
+    /// How long each request waited before it's loaded into the slot
+    batch_waits: Vec<Duration>,
     batch_request: BatchRequest,
     batch_count: usize,
     batch: ModelState,
     model: Arc<Model<'static>>,
+    /// Limits how many slots are inferred in one run
+    controller: BatchController,
+    retry: RetryConfig,
+    /// When each slot got its request, so the oldest requests are inferred first
+    batch_order: Vec<u64>,
+    next_order: u64,
+    /// When the batch window of the next run opened, if any request is waiting
+    window_start: Option<Instant>,
+    /// States whose slots are never given to other states
+    pinned: HashSet<String>,
+    max_pinned: usize,
+    stats: Arc<BatchStats>,
 }
 
 impl Slots {
@@ -38,16 +65,29 @@ impl Slots {
         context: &Context,
         model: Arc<Model<'static>>,
         batch_request: BatchRequest,
+        controller: BatchController,
+        retry: RetryConfig,
+        max_pinned: usize,
     ) -> Slots {
         Slots {
             slots: (0..batch_count).map(|_| None).collect(),
             batch_tokens: (0..batch_count).map(|_| Vec::new()).collect(),
             batch_state_callbacks: (0..batch_count).map(|_| None).collect(),
             batch_state_ids: vec![None; batch_count],
+            batch_snapshots: vec![false; batch_count],
+            batch_waits: vec![Duration::ZERO; batch_count],
             batch: ModelState::new(&context, model.info(), batch_count),
             model,
             batch_count,
             batch_request,
+            stats: controller.stats(),
+            controller,
+            retry,
+            batch_order: vec![0; batch_count],
+            next_order: 0,
+            window_start: None,
+            pinned: HashSet::new(),
+            max_pinned,
         }
     }
 
@@ -59,17 +99,63 @@ impl Slots {
     #[inline(always)]
     /// Can the slots start infer or not
     ///
-    /// The infer will be started if requested slots are full
-    /// or the slots are full
+    /// The infer will be started if requested slots are full,
+    /// the slots are full, there are enough requests for the current batch size
+    /// or the batch window is over
     fn can_start_infer(&self) -> bool {
-        self.is_full() || self.batch_request.get() <= self.get_requests_count()
+        let count = self.get_requests_count();
+        self.is_full()
+            || self.batch_request.get() <= count
+            || self.controller.current() <= count
+            || self.is_window_over()
+    }
+
+    fn is_window_over(&self) -> bool {
+        match (self.controller.window(), self.window_start) {
+            (Some(window), Some(start)) => start.elapsed() >= window,
+            _ => false,
+        }
     }
 
+    /// Whether the next run should wait for more requests within the batch window
+    fn is_waiting(&self) -> bool {
+        self.controller.window().is_some() && !self.is_clear() && !self.can_start_infer()
+    }
+
+    /// Whether no slot is left for a state which is not pinned
     fn is_full(&self) -> bool {
-        self.slots.iter().all(|c| c.is_some())
+        (0..self.batch_count).all(|idx| self.slots[idx].is_some() || self.is_pinned(idx))
+    }
+
+    fn is_pinned(&self, index: usize) -> bool {
+        self.batch_state_ids[index]
+            .as_ref()
+            .is_some_and(|id| self.pinned.contains(id))
+    }
+
+    /// The slot to load the state into, if any is free for it
+    ///
+    /// Idle slots of pinned states are only taken by the states themselves
+    fn find_slot(&self, state_id: &String) -> Option<usize> {
+        // Try to reuse the slot
+        let reused = (0..self.batch_count).find(|&idx| {
+            self.slots[idx].is_none() && self.batch_state_ids[idx].as_ref() == Some(state_id)
+        });
+        // Try to find the empty slot with empty state id (not occupied)
+        let unoccupied = || {
+            (0..self.batch_count)
+                .find(|&idx| self.slots[idx].is_none() && self.batch_state_ids[idx].is_none())
+        };
+        // Find the first index being empty
+        // Assertion for performance: last state should have a long cooldown
+        let empty = || {
+            (0..self.batch_count)
+                .rev()
+                .find(|&idx| self.slots[idx].is_none() && !self.is_pinned(idx))
+        };
+        reused.or_else(unoccupied).or_else(empty)
     }
 
-    #[allow(dead_code)]
     fn is_clear(&self) -> bool {
         self.slots.iter().all(|c| c.is_none())
     }
@@ -77,15 +163,26 @@ impl Slots {
     /// Load requests into the slot
     ///
     /// If the slot is full, load to queue instead (which will be loaded to slot when available)
+    ///
+    /// Sync requests are answered right away
     #[inline(always)]
     fn load_or_queue(
         &mut self,
-        requests: Vec<InferRequest>,
-        queue: &mut Vec<InferRequest>,
+        requests: PipelineRequest,
+        queue: &mut VecDeque<InferRequest>,
     ) -> Result<()> {
+        let requests = match requests {
+            PipelineRequest::Infer(requests) => requests,
+            PipelineRequest::Sync(request) => return self.sync(request),
+            PipelineRequest::Pin(request) => {
+                self.pin(request);
+                return Ok(());
+            }
+        };
         for request in requests {
-            if self.is_full() {
-                queue.push(request);
+            // Requests never skip the queue, so they are served in FIFO order
+            if self.find_slot(&request.state_id).is_none() || !queue.is_empty() {
+                queue.push_back(request);
             } else {
                 self.insert(request)?;
             }
@@ -93,39 +190,88 @@ impl Slots {
         Ok(())
     }
 
+    /// Sends back the data of a state if a slot holds it
+    fn sync(&self, request: SyncRequest) -> Result<()> {
+        let SyncRequest { state_id, callback } = request;
+        let state = match self
+            .batch_state_ids
+            .iter()
+            .position(|id| id.as_ref() == Some(&state_id))
+        {
+            Some(index) => Some(State(Arc::new(self.batch.back_batch(index)?.data))),
+            None => None,
+        };
+        callback.send(state).ok();
+        Ok(())
+    }
+
+    /// Pins or unpins the slot of a state, up to `max_pinned` states at once
+    ///
+    /// A state which doesn't hold a slot yet keeps the one it gets on its next infer
+    fn pin(&mut self, request: PinRequest) {
+        let PinRequest {
+            state_id,
+            pin,
+            callback,
+        } = request;
+        let result = if !pin {
+            self.pinned.remove(&state_id);
+            Ok(())
+        } else if self.pinned.contains(&state_id) {
+            Ok(())
+        } else if self.pinned.len() >= self.max_pinned {
+            Err(CommandErrorKind::BadRequest.error(format!(
+                "Can't pin state {}, {} of {} pinned slots are taken!",
+                state_id,
+                self.pinned.len(),
+                self.max_pinned
+            )))
+        } else {
+            self.pinned.insert(state_id);
+            Ok(())
+        };
+        self.stats.set_pinned(self.pinned.len());
+        callback.send(result).ok();
+    }
+
     /// Swaps a state to a (potentially different) state in slot
+    ///
+    /// The state is loaded even if the slot holds the same state id when `reload` is set
     fn swap(
         &mut self,
         index: usize,
         state: Option<State>,
         state_id: Option<String>,
         state_callback: Option<oneshot::Sender<Option<State>>>,
+        reload: bool,
     ) -> Result<()> {
         if &self.batch_state_ids[index] == &state_id {
-            // State id matches, no need to update state
+            // State id matches, no need to send back the state
             let callback =
                 std::mem::replace(&mut self.batch_state_callbacks[index], state_callback).unwrap();
-            callback
-                .send(None)
-                .map_err(|_| Error::msg("Error when sending state!"))?;
-            return Ok(());
-        }
-
-        // Update the state since mismatch or empty slot
-        self.batch_state_ids[index] = state_id;
-        if let Some(callback) =
-            std::mem::replace(&mut self.batch_state_callbacks[index], state_callback)
-        {
-            callback
-                .send(Some(State(self.batch.back_batch(index)?.data)))
-                .map_err(|_| Error::msg("Error when sending state!"))?;
+            // The request may be dropped if its command is aborted, which must not stop
+            // the pipeline
+            callback.send(None).ok();
+            if !reload {
+                return Ok(());
+            }
+        } else {
+            // Update the state since mismatch or empty slot
+            self.batch_state_ids[index] = state_id;
+            if let Some(callback) =
+                std::mem::replace(&mut self.batch_state_callbacks[index], state_callback)
+            {
+                callback
+                    .send(Some(State(Arc::new(self.batch.back_batch(index)?.data))))
+                    .ok();
+            }
         }
         let info = self.model.info();
         let state = if let Some(state) = state {
             let shape = Shape::new(info.num_emb, 5 * info.num_layers, 1);
             BackedState {
                 shape,
-                data: state.0,
+                data: state.into_data(),
             }
         } else {
             BackedState::new(info, 1)
@@ -137,83 +283,199 @@ impl Slots {
     /// Inserts a request into the batch
     /// Also uploads the state to the buffer
     fn insert(&mut self, request: InferRequest) -> Result<()> {
-        if self.is_full() {
-            panic!("Batch is full while inserting new requests!")
-        }
-
         let InferRequest {
-            context: InferContext { state, tokens },
+            context:
+                InferContext {
+                    state,
+                    tokens,
+                    snapshot,
+                    reload,
+                },
             callback,
             state_id,
             state_callback,
+            sent,
         } = request;
-        let callback = Some(callback);
 
-        // Try to reuse the slot
-        for idx in 0..self.batch_count {
-            if self.slots[idx].is_none() {
-                if let Some(slot_id) = &self.batch_state_ids[idx] {
-                    if slot_id == &state_id {
-                        self.slots[idx] = callback;
-                        self.batch_tokens[idx] = tokens;
-                        return self.swap(idx, state, Some(state_id), Some(state_callback));
-                    }
-                }
-            }
-        }
-
-        // Try to find the empty slot with empty state id (not occupied)
-        for idx in 0..self.batch_count {
-            if self.slots[idx].is_none() && self.batch_state_ids[idx].is_none() {
-                self.slots[idx] = callback;
+        match self.find_slot(&state_id) {
+            Some(idx) => {
+                self.window_start.get_or_insert_with(Instant::now);
+                self.slots[idx] = Some(callback);
                 self.batch_tokens[idx] = tokens;
-                return self.swap(idx, state, Some(state_id), Some(state_callback));
+                self.batch_snapshots[idx] = snapshot;
+                self.batch_waits[idx] = sent.elapsed();
+                self.batch_order[idx] = self.next_order;
+                self.next_order += 1;
+                self.swap(idx, state, Some(state_id), Some(state_callback), reload)
             }
+            None => panic!("Batch is full while inserting new requests!"),
         }
+    }
 
-        // Find the first index being empty
-        // Assertion for performance: last state should have a long cooldown
-        for idx in (0..self.batch_count).rev() {
-            if self.slots[idx].is_none() {
-                self.slots[idx] = callback;
-                self.batch_tokens[idx] = tokens;
-                return self.swap(idx, state, Some(state_id), Some(state_callback));
-            }
-        }
-        Ok(())
+    /// Tokens of the slots which don't fit into the current batch size, which are held
+    /// back from the run. The oldest requests are kept in the run.
+    fn hold_back(&mut self) -> Vec<(usize, Vec<u16>)> {
+        let mut occupied: Vec<usize> = (0..self.batch_count)
+            .filter(|&idx| self.slots[idx].is_some())
+            .collect();
+        occupied.sort_unstable_by_key(|&idx| self.batch_order[idx]);
+        occupied
+            .into_iter()
+            .skip(self.controller.current())
+            .map(|idx| (idx, std::mem::take(&mut self.batch_tokens[idx])))
+            .collect()
     }
 
     /// Infer until any of the batch is completed.
-    fn infer(&mut self) -> Result<()> {
+    ///
+    /// If the run fails, e.g. web-rwkv fails to allocate buffers for the batch, the batch
+    /// size is lowered and the run is retried. Once it can't be lowered, the run is retried
+    /// up to `retry.retries` times with backoff before the requests in it fail.
+    ///
+    /// With retries, the slots in each run are backed up before the run and restored after
+    /// a failure, so a retry starts from the same states and tokens. The backoff only
+    /// suspends the pipeline task, and the requests which fail at last get a single error
+    /// telling how the run was retried.
+    async fn infer(&mut self) -> Result<()> {
+        let mut attempts = 0;
+        // The batch size of the first failed run, if it's lowered since
+        let mut lowered_from = None;
         let logits = loop {
-            let logits_internal = self
-                .model
-                .run(&mut self.batch_tokens, &self.batch)
-                .expect("Failed to run infer!");
-            if logits_internal.iter().any(|l| !l.is_empty()) {
-                break logits_internal;
+            let held = self.hold_back();
+            let count = self.get_requests_count() - held.len();
+            let running: Vec<usize> = (0..self.batch_count)
+                .filter(|&idx| self.slots[idx].is_some() && !held.iter().any(|(x, _)| *x == idx))
+                .collect();
+            let snapshots = match self.retry.retries {
+                0 => Vec::new(),
+                _ => running
+                    .iter()
+                    .map(|&idx| {
+                        let state = self.batch.back_batch(idx)?;
+                        Ok((idx, state, self.batch_tokens[idx].clone()))
+                    })
+                    .collect::<Result<Vec<_>>>()?,
+            };
+            let start = Instant::now();
+            let result = loop {
+                match self.model.run(&mut self.batch_tokens, &self.batch) {
+                    Ok(logits) if logits.iter().all(|l| l.is_empty()) => continue,
+                    result => break result,
+                }
+            };
+            for (idx, tokens) in held {
+                self.batch_tokens[idx] = tokens;
+            }
+            match result {
+                Ok(logits) => {
+                    self.controller.on_success(start.elapsed(), count);
+                    break logits;
+                }
+                Err(error) => {
+                    // If the slots can't even be restored, retrying is hopeless
+                    let restored = snapshots
+                        .iter()
+                        .try_for_each(|(idx, state, tokens)| {
+                            self.batch_tokens[*idx] = tokens.clone();
+                            self.batch.load_batch(state, *idx)
+                        })
+                        .is_ok();
+                    if restored && self.controller.on_failure() {
+                        lowered_from.get_or_insert(count);
+                        continue;
+                    }
+                    if restored && attempts < self.retry.retries {
+                        attempts += 1;
+                        let backoff = self
+                            .retry
+                            .backoff
+                            .saturating_mul(2u32.saturating_pow(attempts as u32 - 1));
+                        tokio::time::sleep(backoff).await;
+                        continue;
+                    }
+                    let mut error = error.to_string();
+                    if let Some(from) = lowered_from {
+                        error += &format!(
+                            ", after lowering the batch size from {} to {}",
+                            from,
+                            self.controller.current()
+                        );
+                    }
+                    if attempts > 0 {
+                        error += &format!(", after {} retries", attempts);
+                    }
+                    self.fail(&running, snapshots, error);
+                    self.reopen_window();
+                    return Ok(());
+                }
             }
         };
 
         for idx in 0..self.batch_count {
             if !logits[idx].is_empty() {
+                let state = if self.batch_snapshots[idx] {
+                    Some(State(Arc::new(self.batch.back_batch(idx)?.data)))
+                } else {
+                    None
+                };
                 let result = InferResult {
                     logits: Logits(logits[idx].clone()),
+                    state,
+                    queued: self.batch_waits[idx],
                 };
                 self.finish(idx, result)?
             }
         }
+        self.reopen_window();
         Ok(())
     }
 
+    /// Opens the batch window of the next run, for the slots still feeding their tokens
+    fn reopen_window(&mut self) {
+        self.window_start = (!self.is_clear()).then(Instant::now);
+    }
+
+    /// Fails the requests in `running` after their run can't be retried any more.
+    ///
+    /// Their slots are cleared so later requests load their states anew, and each state is
+    /// sent back as backed up before the run, or reset if there is no backup.
+    fn fail(
+        &mut self,
+        running: &[usize],
+        snapshots: Vec<(usize, BackedState, Vec<u16>)>,
+        error: String,
+    ) {
+        let info = self.model.info();
+        for &idx in running {
+            let snapshot = snapshots.iter().find(|(x, _, _)| *x == idx);
+            let state = match snapshot {
+                Some((_, state, _)) => state.data.clone(),
+                None => BackedState::new(info, 1).data,
+            };
+            if let Some(callback) = self.batch_state_callbacks[idx].take() {
+                callback.send(Some(State(Arc::new(state)))).ok();
+            }
+            let state = self.batch_state_ids[idx].take().unwrap_or_default();
+            self.batch_tokens[idx].clear();
+            if let Some(channel) = self.slots[idx].take() {
+                channel
+                    .send(Err(RunError {
+                        state,
+                        restored: snapshot.is_some(),
+                        error: error.clone(),
+                    }))
+                    .ok();
+            }
+        }
+    }
+
     /// Finishs a request by sending back the result.
     fn finish(&mut self, index: usize, result: InferResult) -> Result<()> {
         let channel = std::mem::replace(&mut self.slots[index], None)
-            .ok_or(Error::msg("Called finish on empty channel!"))?;
+            .ok_or(CommandErrorKind::Internal.error("Called finish on empty channel!"))?;
         self.batch_tokens[index].clear();
-        channel
-            .send(result)
-            .map_err(|_| Error::msg("Can't send result to channel!"))
+        channel.send(Ok(result)).ok();
+        Ok(())
     }
 }
 
@@ -230,17 +492,33 @@ impl Pipeline {
         context: Context,
         model: Arc<Model<'static>>,
         request_lock: BatchRequest,
-    ) -> (mpsc::Sender<Vec<InferRequest>>, JoinHandle<()>) {
-        let (sender, mut receiver) = mpsc::channel::<Vec<InferRequest>>(batch_size);
+        controller: BatchController,
+        retry: RetryConfig,
+        max_pinned: usize,
+    ) -> (mpsc::Sender<PipelineRequest>, JoinHandle<()>) {
+        let (sender, mut receiver) = mpsc::channel::<PipelineRequest>(batch_size);
         let handle = tokio::spawn(async move {
-            let mut slots = Slots::new(batch_size, &context, model, request_lock).await;
-            let mut queued_requests: Vec<InferRequest> = Vec::new();
+            let mut slots = Slots::new(
+                batch_size,
+                &context,
+                model,
+                request_lock,
+                controller,
+                retry,
+                max_pinned,
+            )
+            .await;
+            let mut queued_requests: VecDeque<InferRequest> = VecDeque::new();
 
             // When something arrives in the channel.
             // This has an assumption that the batch is empty. (just initialized/ finished all inference)
             while let Some(requests) = receiver.recv().await {
                 // Load the request
                 slots.load_or_queue(requests, &mut queued_requests).unwrap();
+                // Nothing to infer if only syncs and pins arrived
+                if slots.is_clear() {
+                    continue;
+                }
 
                 // Insert until slots full or no more requests
                 if !slots.is_full() {
@@ -268,14 +546,15 @@ impl Pipeline {
                 }
                 loop {
                     // Infer till at least 1 slot is done
-                    slots.infer().unwrap();
+                    slots.infer().await.unwrap();
 
                     // Release queued requests into the slots
-                    while let Some(queued) = queued_requests.pop() {
-                        slots.insert(queued).unwrap();
-                        if slots.is_full() {
+                    while let Some(queued) = queued_requests.pop_front() {
+                        if slots.find_slot(&queued.state_id).is_none() {
+                            queued_requests.push_front(queued);
                             break;
                         }
+                        slots.insert(queued).unwrap();
                     }
 
                     // If there're empty slot, try to load from receiver
@@ -286,6 +565,19 @@ impl Pipeline {
                                 break;
                             }
                         }
+                        // With a batch window, waits for the requests of the other running
+                        // infers, so they are inferred together
+                        while slots.is_waiting() {
+                            match receiver.try_recv() {
+                                Ok(requests) => {
+                                    slots.load_or_queue(requests, &mut queued_requests).unwrap()
+                                }
+                                Err(TryRecvError::Empty) => {
+                                    tokio::time::sleep(Duration::from_micros(100)).await
+                                }
+                                _ => return,
+                            }
+                        }
                         // No requests anymore, release the lock
                         if slots.is_clear() {
                             break;
@@ -0,0 +1,145 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+use crate::helper::{Logits, State};
+
+use super::{model::AxumModel, state_file::StateLayout};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct PrefixKey {
+    model: String,
+    /// The layout and vocab of the loaded model, so a state is never loaded into a model
+    /// it doesn't fit
+    layout: StateLayout,
+    num_vocab: usize,
+    tokens: Vec<u16>,
+}
+
+impl PrefixKey {
+    fn new(model: &AxumModel, tokens: Vec<u16>) -> Self {
+        Self {
+            model: model.name.clone(),
+            layout: StateLayout::of(model),
+            num_vocab: model.info().num_vocab,
+            tokens,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PrefixEntry {
+    state: State,
+    logits: Logits,
+    last_used: u64,
+}
+
+#[derive(Debug, Default)]
+struct PrefixMap {
+    entries: HashMap<PrefixKey, PrefixEntry>,
+    /// Count of entries of each prefix length, so lookups only try lengths which are cached
+    lengths: BTreeMap<usize, usize>,
+    clock: u64,
+}
+
+impl PrefixMap {
+    fn remove(&mut self, key: &PrefixKey) {
+        if self.entries.remove(key).is_none() {
+            return;
+        }
+        let len = key.tokens.len();
+        if let Some(count) = self.lengths.get_mut(&len) {
+            *count -= 1;
+            if *count == 0 {
+                self.lengths.remove(&len);
+            }
+        }
+    }
+}
+
+/// Caches the state and logits after prefixes of the prompts fed to fresh states, so later
+/// fresh states fed with a prompt starting with a cached prefix on the same model only
+/// infer the rest of it.
+///
+/// A prompt is cached at every chunk boundary it's fed through (see `token_chunk_size`)
+/// and at its end, so prompts sharing a long prefix, e.g. rendered from the same template,
+/// share its states up to the last common boundary.
+///
+/// The least recently used prefix is evicted once the cache is full.
+#[derive(Debug)]
+pub struct PrefixCache {
+    capacity: usize,
+    map: Mutex<PrefixMap>,
+}
+
+impl PrefixCache {
+    /// Creates a cache holding at most `capacity` prefixes, 0 to disable it.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            map: Mutex::new(PrefixMap::default()),
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// The longest cached prefix of `tokens`, with its length, state and logits.
+    ///
+    /// The state is shared with the cache, which is fine since states are copy-on-write.
+    pub fn get(&self, model: &AxumModel, tokens: &[u16]) -> Option<(usize, State, Logits)> {
+        if !self.is_enabled() || tokens.is_empty() {
+            return None;
+        }
+        let mut map = self.map.lock().unwrap();
+        map.clock += 1;
+        let clock = map.clock;
+        let lengths: Vec<usize> = map
+            .lengths
+            .range(1..=tokens.len())
+            .rev()
+            .map(|(len, _)| *len)
+            .collect();
+        let mut key = PrefixKey::new(model, tokens.to_vec());
+        for len in lengths {
+            key.tokens.truncate(len);
+            if let Some(entry) = map.entries.get_mut(&key) {
+                entry.last_used = clock;
+                return Some((len, entry.state.clone(), entry.logits.clone()));
+            }
+        }
+        None
+    }
+
+    pub fn insert(&self, model: &AxumModel, tokens: Vec<u16>, state: State, logits: Logits) {
+        if !self.is_enabled() || tokens.is_empty() {
+            return;
+        }
+        let mut map = self.map.lock().unwrap();
+        let key = PrefixKey::new(model, tokens);
+        if !map.entries.contains_key(&key) && map.entries.len() >= self.capacity {
+            let evicted = map
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(evicted) = evicted {
+                map.remove(&evicted);
+            }
+        }
+        map.clock += 1;
+        let last_used = map.clock;
+        let entry = PrefixEntry {
+            state,
+            logits,
+            last_used,
+        };
+        let len = key.tokens.len();
+        if map.entries.insert(key, entry).is_none() {
+            *map.lengths.entry(len).or_default() += 1;
+        }
+    }
+}
@@ -0,0 +1,592 @@
+use anyhow::{Error, Result};
+use fastrand::Rng;
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    app::{AppState, TemporaryState},
+    error::CommandErrorKind,
+    helper::{Logits, State},
+};
+
+use super::{
+    guidance::Guidance,
+    normalizer::types::Domain,
+    sampler::{types::Sampled, utils},
+    speculative::Drafter,
+    terminal::{stop_string::StopMatcher, types::Termination},
+    InferenceInterruption,
+};
+
+/// The component which is exhausted in a `SamplePipeline`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Exhaustion {
+    /// One of `transformer`, `sampler` or `normalizer`.
+    pub kind: &'static str,
+    pub id: String,
+    /// Why the component is exhausted, if it tells.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+pub enum PipelineInterruption {
+    Exhaustion(Exhaustion),
+    Error(Error),
+}
+
+impl PipelineInterruption {
+    fn from_component<'a>(
+        kind: &'static str,
+        id: &'a str,
+    ) -> impl Fn(InferenceInterruption) -> Self + 'a {
+        move |interruption| match interruption {
+            InferenceInterruption::Exhaustion(reason) => Self::Exhaustion(Exhaustion {
+                kind,
+                id: id.to_string(),
+                reason,
+            }),
+            InferenceInterruption::Error(e) => Self::Error(e),
+        }
+    }
+}
+
+impl PipelineInterruption {
+    /// The error of an infer whose pipeline is exhausted before anything is generated.
+    pub fn into_start_error(self) -> Error {
+        match self {
+            Self::Exhaustion(Exhaustion { kind, id, reason }) => CommandErrorKind::Interrupted
+                .error(match reason {
+                    Some(reason) => format!(
+                        "The {} {} is exhausted at the start ({}), inference won't continue.",
+                        kind, id, reason
+                    ),
+                    None => format!(
+                        "The {} {} is exhausted at the start, inference won't continue.",
+                        kind, id
+                    ),
+                }),
+            Self::Error(e) => e,
+        }
+    }
+}
+
+impl From<Error> for PipelineInterruption {
+    fn from(value: Error) -> Self {
+        Self::Error(value)
+    }
+}
+
+/// All components needed to sample tokens from a list of states.
+///
+/// A pipeline holds ids only, the components are looked up from the registries whenever
+/// they are used.
+#[derive(Debug, Clone)]
+pub struct SamplePipeline {
+    pub states: Vec<String>,
+    /// Transformers for each state.
+    pub transformers: Vec<Vec<String>>,
+    pub sampler: String,
+    pub normalizer: Option<String>,
+    pub terminal: Option<String>,
+    /// Stop strings given with the infer request, checked along with the terminal.
+    pub stop: Option<StopMatcher>,
+    /// Tokens masked out of the logits before the transformers, e.g. the end of text token
+    /// until `min_tokens` are generated.
+    pub suppressed: Vec<u16>,
+    /// Classifier-free guidance of the only state, if any.
+    pub guidance: Option<Guidance>,
+    /// Divides the logits after the transformers, before the normalizer and sampler.
+    pub temperature: Option<f32>,
+}
+
+/// The last generation of a pipeline, which `continue` resumes without the ids being sent
+/// again.
+#[derive(Debug, Clone)]
+pub struct GenerationRecord {
+    pub pipeline: SamplePipeline,
+    pub drafter: Option<Drafter>,
+    /// The last sampled token of each state, which is not fed to the states yet.
+    pub last_tokens: Vec<u16>,
+    /// Tokens sampled since the generation is started by `infer`.
+    pub steps: usize,
+    pub update_prompt: bool,
+    pub reset_on_exhaustion: bool,
+}
+
+/// A copy of a `SamplePipeline` with its own states and components, which are deleted
+/// once it's dropped.
+pub struct ForkedPipeline {
+    app_state: AppState,
+    pub pipeline: SamplePipeline,
+    _states: Vec<TemporaryState>,
+}
+
+impl Drop for ForkedPipeline {
+    fn drop(&mut self) {
+        let app_state = &self.app_state.0;
+        for transformer in self.pipeline.transformers.iter().flatten() {
+            app_state.transformers.delete_transformer(transformer).ok();
+        }
+        app_state
+            .samplers
+            .delete_sampler(&self.pipeline.sampler)
+            .ok();
+        if let Some(normalizer) = &self.pipeline.normalizer {
+            app_state.normalizers.delete_normalizer(normalizer).ok();
+        }
+        if let Some(terminal) = &self.pipeline.terminal {
+            app_state.terminals.delete_terminal(terminal).ok();
+        }
+    }
+}
+
+impl SamplePipeline {
+    /// Copies the pipeline, where the states start from `states` (shallow copied), and
+    /// every component is cloned from the one in this pipeline.
+    pub fn fork(&self, app_state: &AppState, states: &[State]) -> Result<ForkedPipeline> {
+        if self.guidance.is_some() {
+            return Err(
+                CommandErrorKind::BadRequest.error("A pipeline with guidance can't be forked!")
+            );
+        }
+        let model = app_state.state_model(&self.states)?.name.clone();
+        let states = states
+            .iter()
+            .map(|x| app_state.create_temporary_state(&model, Some(x.clone())))
+            .collect::<Result<Vec<_>>>()?;
+
+        // Components are added once copied, so they are deleted even if a later copy fails
+        let mut forked = ForkedPipeline {
+            app_state: app_state.clone(),
+            pipeline: SamplePipeline {
+                states: states.iter().map(|x| x.id.clone()).collect(),
+                transformers: Vec::with_capacity(self.transformers.len()),
+                sampler: String::new(),
+                normalizer: None,
+                terminal: None,
+                stop: self.stop.clone(),
+                suppressed: self.suppressed.clone(),
+                guidance: None,
+                temperature: self.temperature,
+            },
+            _states: states,
+        };
+        let components = &app_state.0;
+        for t_ids in &self.transformers {
+            forked
+                .pipeline
+                .transformers
+                .push(Vec::with_capacity(t_ids.len()));
+            for t_id in t_ids {
+                let id = app_state.temporary_id();
+                components
+                    .transformers
+                    .copy_transformer(t_id.clone(), id.clone())?;
+                forked.pipeline.transformers.last_mut().unwrap().push(id);
+            }
+        }
+        let id = app_state.temporary_id();
+        components
+            .samplers
+            .copy_sampler(self.sampler.clone(), id.clone())?;
+        forked.pipeline.sampler = id;
+        if let Some(normalizer) = &self.normalizer {
+            let id = app_state.temporary_id();
+            components
+                .normalizers
+                .copy_normalizer(normalizer.clone(), id.clone())?;
+            forked.pipeline.normalizer = Some(id);
+        }
+        if let Some(terminal) = &self.terminal {
+            let id = app_state.temporary_id();
+            components
+                .terminals
+                .copy_terminal(terminal.clone(), id.clone())?;
+            forked.pipeline.terminal = Some(id);
+        }
+        Ok(forked)
+    }
+
+    /// Checks if all states and components exist.
+    pub fn validate(&self, app_state: &AppState) -> Result<()> {
+        if self.states.len() != self.transformers.len() {
+            return Err(
+                CommandErrorKind::BadRequest.error("State and transformer length must be matched!")
+            );
+        }
+
+        if let Some(id) = self.states.iter().find(|x| !app_state.has_state(x)) {
+            return Err(app_state.missing_state(id));
+        }
+
+        if let Some(guidance) = &self.guidance {
+            if self.states.len() != 1 {
+                return Err(
+                    CommandErrorKind::BadRequest.error("Guidance only works with a single state!")
+                );
+            }
+            if self.states.contains(&guidance.negative_state) {
+                return Err(CommandErrorKind::BadRequest
+                    .error("The negative state must differ from the state!"));
+            }
+            // Also checks that both states belong to the same model
+            app_state.state_model(&self.inferred_states())?;
+        }
+
+        if self
+            .transformers
+            .iter()
+            .flatten()
+            .any(|x| !app_state.0.transformers.has_transformer(x))
+        {
+            return Err(CommandErrorKind::TransformerNotFound
+                .error("One or more transformer ids not exist!"));
+        }
+
+        if !app_state.0.samplers.has_sampler(&self.sampler) {
+            return Err(CommandErrorKind::SamplerNotFound.error("Sampler id does not exist!"));
+        }
+
+        if let Some(terminal) = &self.terminal {
+            if !app_state.0.terminals.has_terminal(terminal) {
+                return Err(CommandErrorKind::TerminalNotFound.error("Terminal id does not exist!"));
+            }
+        }
+
+        if let Some(normalizer) = &self.normalizer {
+            if !app_state.0.normalizers.has_normalizer(normalizer) {
+                return Err(
+                    CommandErrorKind::NormalizerNotFound.error("Normalizer id does not exist!")
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// The states fed in each step, which are the states and the negative state of the
+    /// guidance if any.
+    pub fn inferred_states(&self) -> Vec<String> {
+        self.states
+            .iter()
+            .chain(self.guidance.as_ref().map(|x| &x.negative_state))
+            .cloned()
+            .collect()
+    }
+
+    /// Updates transformers, sampler and normalizer with the tokens fed to each state.
+    pub fn update(
+        &self,
+        app_state: &AppState,
+        tokens: &Vec<Vec<u16>>,
+        reset_on_exhaustion: bool,
+    ) -> Result<(), PipelineInterruption> {
+        // This is the last place anything can stop the infer, if you want
+        // to stop the infer in case of additional termination from
+        // transformer/sampler, you must do it from updates, or the state
+        // will be polluted by the token input.
+
+        // Transformer and sampler should be aware of the exhaustion, where
+        // it should know it will fail no matter what logits/probs are
+        // given at sample/transformation time. and if it knows, it must
+        // throw an error.
+        let transformer_update = self
+            .transformers
+            .par_iter()
+            .zip(tokens.par_iter())
+            .map(|(t_ids, tokens)| {
+                for t_id in t_ids {
+                    let result = app_state.0.transformers.update_transformer(t_id, tokens);
+                    if let Err(InferenceInterruption::Exhaustion(_)) = result {
+                        if reset_on_exhaustion {
+                            app_state.0.transformers.reset_transformer(t_id).unwrap();
+                        }
+                    }
+                    result.map_err(PipelineInterruption::from_component("transformer", t_id))?
+                }
+                Ok(())
+            })
+            .collect::<Result<Vec<()>, PipelineInterruption>>();
+
+        let sampler_update = app_state.0.samplers.update_sampler(&self.sampler, tokens);
+        if let Err(InferenceInterruption::Exhaustion(_)) = sampler_update {
+            if reset_on_exhaustion {
+                app_state.0.samplers.reset_sampler(&self.sampler).unwrap();
+            }
+        }
+        let sampler_update = sampler_update.map_err(PipelineInterruption::from_component(
+            "sampler",
+            &self.sampler,
+        ));
+
+        let normalizer_update = match &self.normalizer {
+            Some(normalizer) => {
+                let result = app_state
+                    .0
+                    .normalizers
+                    .update_normalizer(normalizer, tokens);
+                if let Err(InferenceInterruption::Exhaustion(_)) = result {
+                    if reset_on_exhaustion {
+                        app_state
+                            .0
+                            .normalizers
+                            .reset_normalizer(normalizer)
+                            .unwrap();
+                    }
+                }
+                result.map_err(PipelineInterruption::from_component(
+                    "normalizer",
+                    normalizer,
+                ))
+            }
+            None => Ok(()),
+        };
+        transformer_update
+            .and(sampler_update)
+            .and(normalizer_update)
+    }
+
+    fn transform_logits(
+        &self,
+        app_state: &AppState,
+        mut logits: Vec<f32>,
+        transformers: &Vec<String>,
+    ) -> Result<Vec<f32>> {
+        for transformer in transformers {
+            logits = app_state
+                .0
+                .transformers
+                .transform_logits(transformer, logits)?
+        }
+        Ok(logits)
+    }
+
+    /// Feeds tokens to the states, and samples the next token of each state from the
+    /// logits.
+    pub async fn infer_and_sample(
+        &self,
+        app_state: &AppState,
+        tokens: Vec<Vec<u16>>,
+        update_prompts: bool,
+        reset_on_exhaustion: bool,
+        rng: &mut Rng,
+    ) -> Result<Vec<u16>, PipelineInterruption> {
+        self.infer_and_inspect(
+            app_state,
+            tokens,
+            update_prompts,
+            reset_on_exhaustion,
+            false,
+            rng,
+        )
+        .await
+        .map(|(sampled, _)| sampled.into_iter().map(|x| x.token).collect())
+    }
+
+    /// Same as `infer_and_sample`, but also returns the log probability of each token,
+    /// and the distribution of each state which its token is sampled from in log
+    /// probabilities if `keep_logprobs`.
+    pub async fn infer_and_inspect(
+        &self,
+        app_state: &AppState,
+        tokens: Vec<Vec<u16>>,
+        update_prompts: bool,
+        reset_on_exhaustion: bool,
+        keep_logprobs: bool,
+        rng: &mut Rng,
+    ) -> Result<(Vec<Sampled>, Option<Vec<Vec<f32>>>), PipelineInterruption> {
+        if update_prompts {
+            tokio::task::block_in_place(|| self.update(app_state, &tokens, reset_on_exhaustion))?;
+        }
+
+        let logits = match &self.guidance {
+            Some(guidance) => {
+                // The negative state is fed the same tokens in the same batch
+                let tokens = tokens.iter().chain(tokens.first()).cloned().collect();
+                let mut logits = app_state.infer(self.inferred_states(), tokens).await?;
+                let uncond = logits.pop().unwrap();
+                guidance.blend(&mut logits[0].0, &uncond.0);
+                logits
+            }
+            None => app_state.infer(self.states.clone(), tokens).await?,
+        };
+        self.sample_logits(app_state, logits, keep_logprobs, rng)
+            .await
+    }
+
+    /// Transforms and normalizes the logits of each state, giving the distributions which
+    /// the sampler sees.
+    async fn normalize(
+        &self,
+        app_state: &AppState,
+        logits: Vec<Logits>,
+    ) -> Result<(Vec<Vec<f32>>, Domain)> {
+        let mut logits: Vec<Vec<f32>> = logits.into_iter().map(|x| x.0).collect();
+        for logits in logits.iter_mut() {
+            for &token in &self.suppressed {
+                if let Some(x) = logits.get_mut(token as usize) {
+                    *x = f32::NEG_INFINITY;
+                }
+            }
+        }
+
+        // A state whose logits end up all masked or invalid falls back to its most likely
+        // token before the transformers
+        let fallbacks: Vec<usize> = logits
+            .iter_mut()
+            .map(|x| {
+                utils::sanitize_logits(x);
+                utils::argmax(x)
+            })
+            .collect();
+
+        // In case if transformation is needed, we block the current thread and use rayon to
+        // transform each logits
+        let mut logits = if self.transformers.iter().any(|x| !x.is_empty()) {
+            tokio::task::block_in_place(|| {
+                logits
+                    .into_par_iter()
+                    .zip(self.transformers.par_iter())
+                    .map(|(logits, t_ids)| self.transform_logits(app_state, logits, t_ids))
+                    .collect::<Result<Vec<_>>>()
+            })?
+        } else {
+            logits
+        };
+        for ((logits, fallback), id) in logits.iter_mut().zip(fallbacks).zip(&self.states) {
+            if !utils::sanitize_logits(logits) {
+                println!(
+                    "All logits of state {} are masked or invalid, falling back to token {}",
+                    id, fallback
+                );
+                *logits = utils::one_hot_logits(logits.len(), fallback);
+            }
+            if let Some(temperature) = self.temperature {
+                logits.iter_mut().for_each(|x| *x /= temperature);
+            }
+        }
+
+        Ok(match &self.normalizer {
+            Some(normalizer) => tokio::task::block_in_place(|| {
+                app_state.0.normalizers.normalize(normalizer, logits)
+            })?,
+            None => (
+                app_state.state_model(&self.states)?.softmax(logits).await,
+                Domain::Probs,
+            ),
+        })
+    }
+
+    /// Samples the next token of each state from logits already inferred from the states,
+    /// like `infer_and_inspect` does.
+    pub async fn sample_logits(
+        &self,
+        app_state: &AppState,
+        logits: Vec<Logits>,
+        keep_logprobs: bool,
+        rng: &mut Rng,
+    ) -> Result<(Vec<Sampled>, Option<Vec<Vec<f32>>>), PipelineInterruption> {
+        let (probs, domain) = self.normalize(app_state, logits).await?;
+        Ok(tokio::task::block_in_place(|| {
+            // After transformers and normalizer, so it's exactly what the sampler sees
+            let logprobs: Option<Vec<Vec<f32>>> = keep_logprobs.then(|| match domain {
+                Domain::Probs => probs
+                    .par_iter()
+                    .map(|x| x.iter().map(|p| p.ln()).collect())
+                    .collect(),
+                Domain::LogProbs => probs.clone(),
+            });
+            app_state
+                .0
+                .samplers
+                .sample_tokens(&self.sampler, probs, domain, rng)
+                .map(|sampled| (sampled, logprobs))
+        })?)
+    }
+
+    /// Log probabilities of each state after transformers and the normalizer, from logits
+    /// already inferred from the states.
+    pub async fn logprobs(
+        &self,
+        app_state: &AppState,
+        logits: Vec<Logits>,
+    ) -> Result<Vec<Vec<f32>>> {
+        let (probs, domain) = self.normalize(app_state, logits).await?;
+        Ok(match domain {
+            Domain::Probs => tokio::task::block_in_place(|| {
+                probs
+                    .into_par_iter()
+                    .map(|x| x.into_iter().map(f32::ln).collect())
+                    .collect()
+            }),
+            Domain::LogProbs => probs,
+        })
+    }
+
+    /// Computes the distributions of each state which the sampler draws from, from logits
+    /// already inferred from the states. Returns the probs before and after the sampler
+    /// truncates them.
+    ///
+    /// The sampler must be able to truncate probs.
+    pub async fn distribution(
+        &self,
+        app_state: &AppState,
+        logits: Vec<Logits>,
+    ) -> Result<(Vec<Vec<f32>>, Vec<Vec<f32>>)> {
+        let (probs, domain) = self.normalize(app_state, logits).await?;
+        tokio::task::block_in_place(|| {
+            let probs = match domain {
+                Domain::Probs => probs,
+                Domain::LogProbs => utils::exp(probs),
+            };
+            let mut truncated = probs.clone();
+            app_state
+                .0
+                .samplers
+                .truncate(&self.sampler, &mut truncated)?;
+            Ok((probs, truncated))
+        })
+    }
+
+    /// Arms the terminal for a new infer request.
+    pub fn arm(&self, app_state: &AppState) -> Result<()> {
+        match &self.terminal {
+            Some(terminal) => app_state.0.terminals.arm_terminal(terminal),
+            None => Ok(()),
+        }
+    }
+
+    /// Bytes at the end of the decoded `result` which should not be streamed yet.
+    pub fn holdback(&self, app_state: &AppState, result: &Vec<u16>) -> Result<usize> {
+        let holdback = match &self.terminal {
+            Some(terminal) => app_state.0.terminals.holdback(terminal)?,
+            None => 0,
+        };
+        Ok(match &self.stop {
+            Some(stop) => holdback.max(stop.holdback(&app_state.0.tokenizer.decode(result)?)),
+            None => holdback,
+        })
+    }
+
+    /// Checks if the generation should stop after `result` is generated. A stop string
+    /// is reported over the terminal if both fire, so its text is trimmed.
+    pub fn terminate(
+        &self,
+        app_state: &AppState,
+        result: &Vec<u16>,
+    ) -> Result<Option<Termination>> {
+        let fired = match &self.terminal {
+            Some(terminal) => app_state.0.terminals.terminate(terminal, result)?,
+            None => None,
+        };
+        let stopped = match &self.stop {
+            Some(stop) => stop
+                .trim(&app_state.0.tokenizer.decode(result)?)
+                .map(|trim| Termination {
+                    reason: "stop",
+                    trim,
+                }),
+            None => None,
+        };
+        Ok(stopped.or(fired))
+    }
+}
@@ -0,0 +1,99 @@
+use super::types::{Sampled, Sampler};
+use crate::{app::AppState, error::CommandErrorKind, states::InferenceInterruption};
+use anyhow::Result;
+use fastrand::Rng;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+#[derive(Debug, Deserialize)]
+struct ChainData {
+    stages: Vec<Value>,
+}
+
+/// Chains samplers, where every stage but the last truncates the probs in order, and the
+/// last stage draws the token.
+///
+/// The stages are constructed and owned by the chain, so they are not visible by their
+/// own ids.
+#[derive(Debug)]
+pub struct ChainSampler {
+    stages: Vec<Box<dyn Sampler>>,
+}
+
+impl ChainSampler {
+    pub fn new(stages: Vec<Box<dyn Sampler>>) -> Result<Self> {
+        match stages.split_last() {
+            Some((_, truncators)) if truncators.iter().all(|x| x.can_truncate()) => {
+                Ok(Self { stages })
+            }
+            Some(_) => Err(CommandErrorKind::BadRequest.error(
+                "Every stage but the last in a chain sampler must be able to truncate probs!",
+            )),
+            None => {
+                Err(CommandErrorKind::BadRequest.error("A chain sampler needs at least one stage!"))
+            }
+        }
+    }
+}
+
+impl Sampler for ChainSampler {
+    fn sample(&self, mut probs: Vec<Vec<f32>>, rng: &mut Rng) -> Vec<Sampled> {
+        let (last, truncators) = self.stages.split_last().unwrap();
+        if truncators.is_empty() {
+            return last.sample(probs, rng);
+        }
+        // The log probabilities are reported before any truncation
+        let original = probs.clone();
+        for stage in truncators {
+            stage.truncate(&mut probs);
+        }
+        last.sample(probs, rng)
+            .into_iter()
+            .zip(original.iter())
+            .map(|(sampled, probs)| Sampled::from_probs(probs, sampled.token as usize))
+            .collect()
+    }
+
+    fn can_truncate(&self) -> bool {
+        self.stages.iter().all(|x| x.can_truncate())
+    }
+
+    fn truncate(&self, probs: &mut Vec<Vec<f32>>) {
+        for stage in &self.stages {
+            stage.truncate(probs);
+        }
+    }
+
+    fn describe(&self) -> Value {
+        json!({ "stages": self.stages.iter().map(|x| x.describe()).collect::<Vec<_>>() })
+    }
+
+    fn clear(&mut self) {
+        self.stages.iter_mut().for_each(|x| x.clear());
+    }
+
+    fn update(&mut self, tokens: &Vec<Vec<u16>>) -> Result<(), InferenceInterruption> {
+        // Every stage is updated even if an earlier one is exhausted, so they stay in sync
+        self.stages
+            .iter_mut()
+            .map(|x| x.update(tokens))
+            .fold(Ok(()), |result, x| result.and(x))
+    }
+
+    fn clone(&self) -> Box<dyn Sampler> {
+        Box::new(Self {
+            stages: self.stages.iter().map(|x| x.as_ref().clone()).collect(),
+        })
+    }
+}
+
+pub fn initialize_chain(state: AppState, data: Option<Value>) -> Result<Box<dyn Sampler>> {
+    let ChainData { stages } = serde_json::from_value(
+        data.ok_or(CommandErrorKind::BadRequest.error("Field must present to specify stages!"))?,
+    )?;
+    let stages = stages
+        .into_iter()
+        .map(|stage| state.0.samplers.construct(state.clone(), stage))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Box::new(ChainSampler::new(stages)?))
+}
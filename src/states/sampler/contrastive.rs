@@ -0,0 +1,118 @@
+use std::collections::VecDeque;
+
+use super::{
+    types::{Sampled, Sampler},
+    utils,
+};
+use crate::{app::AppState, error::CommandErrorKind, states::InferenceInterruption};
+use anyhow::Result;
+use fastrand::Rng;
+use itertools::Itertools;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+fn default_window() -> usize {
+    64
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ContrastiveData {
+    k: usize,
+    alpha: f32,
+    #[serde(default = "default_window")]
+    window: usize,
+}
+
+/// Contrastive search, which picks from the top `k` tokens the one maximizing
+/// `(1 - alpha) * prob - alpha * degeneration_penalty`.
+///
+/// The original method measures the degeneration penalty by the similarity of hidden
+/// states, which are not available to samplers. It is approximated by token identity
+/// instead: a candidate is fully penalized if it occurs in the last `window` tokens
+/// of its state seen in `update`, with the penalty decaying linearly by distance.
+#[derive(Debug, Clone)]
+pub struct ContrastiveSampler {
+    data: ContrastiveData,
+    /// Recent tokens of each state.
+    history: Vec<VecDeque<u16>>,
+}
+
+impl ContrastiveSampler {
+    fn penalty(&self, history: &VecDeque<u16>, token: u16) -> f32 {
+        // The most recent occurrence decides the penalty
+        history
+            .iter()
+            .rev()
+            .position(|&x| x == token)
+            .map(|distance| 1.0 - distance as f32 / self.data.window.max(1) as f32)
+            .unwrap_or(0.0)
+    }
+}
+
+impl Sampler for ContrastiveSampler {
+    fn sample(&self, probs: Vec<Vec<f32>>, _rng: &mut Rng) -> Vec<Sampled> {
+        let alpha = self.data.alpha;
+        let empty = VecDeque::new();
+        probs
+            .iter()
+            .enumerate()
+            .map(|(index, probs)| {
+                let history = self.history.get(index).unwrap_or(&empty);
+                let token = probs
+                    .iter()
+                    .copied()
+                    .enumerate()
+                    .sorted_unstable_by(|(_, x), (_, y)| x.total_cmp(y).reverse())
+                    .take(self.data.k.max(1))
+                    .max_by(|&(a, pa), &(b, pb)| {
+                        let score_a = (1.0 - alpha) * pa - alpha * self.penalty(history, a as u16);
+                        let score_b = (1.0 - alpha) * pb - alpha * self.penalty(history, b as u16);
+                        score_a.total_cmp(&score_b)
+                    })
+                    .map(|(id, _)| id)
+                    .unwrap_or_else(|| utils::argmax(probs));
+                Sampled::from_probs(probs, token)
+            })
+            .collect()
+    }
+
+    fn describe(&self) -> Value {
+        json!({ "history": self.history })
+    }
+
+    fn clear(&mut self) {
+        self.history.clear();
+    }
+
+    fn update(&mut self, tokens: &Vec<Vec<u16>>) -> Result<(), InferenceInterruption> {
+        if self.history.len() < tokens.len() {
+            let window = self.data.window;
+            self.history
+                .resize_with(tokens.len(), || VecDeque::with_capacity(window));
+        }
+        for (history, tokens) in self.history.iter_mut().zip(tokens.iter()) {
+            history.extend(tokens);
+            while history.len() > self.data.window {
+                history.pop_front();
+            }
+        }
+        Ok(())
+    }
+
+    fn clone(&self) -> Box<dyn Sampler> {
+        Box::new(Clone::clone(self))
+    }
+}
+
+pub fn initialize_contrastive(_state: AppState, data: Option<Value>) -> Result<Box<dyn Sampler>> {
+    let data: ContrastiveData = serde_json::from_value(data.ok_or(
+        CommandErrorKind::BadRequest.error("Field must present to specify k and alpha!"),
+    )?)?;
+    if !(0.0..=1.0).contains(&data.alpha) {
+        return Err(CommandErrorKind::BadRequest.error("alpha must be between 0 and 1!"));
+    }
+    Ok(Box::new(ContrastiveSampler {
+        history: Vec::new(),
+        data,
+    }))
+}
@@ -0,0 +1,73 @@
+use super::{
+    types::{Sampled, Sampler},
+    utils,
+};
+use crate::{app::AppState, error::CommandErrorKind, states::InferenceInterruption};
+use anyhow::Result;
+use fastrand::Rng;
+use itertools::Itertools;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Epsilon sampler, drops every token below a fixed probability.
+#[derive(Debug, Clone, Deserialize)]
+pub struct EpsilonSampler {
+    epsilon: f32,
+    temp: f32,
+}
+
+impl EpsilonSampler {
+    fn candidates(&self, probs: &[f32]) -> Vec<(usize, f32)> {
+        let mut candidates = probs
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|&(_, x)| x >= self.epsilon)
+            .collect_vec();
+        utils::apply_temperature(&mut candidates, self.temp);
+        candidates
+    }
+}
+
+impl Sampler for EpsilonSampler {
+    fn sample(&self, probs: Vec<Vec<f32>>, rng: &mut Rng) -> Vec<Sampled> {
+        probs
+            .iter()
+            .map(|probs| {
+                // Every token falls below epsilon, so just pick the most probable one.
+                let token = utils::sample_weighted(&self.candidates(probs), rng)
+                    .unwrap_or_else(|| utils::argmax(probs));
+                Sampled::from_probs(probs, token)
+            })
+            .collect()
+    }
+
+    fn can_truncate(&self) -> bool {
+        true
+    }
+
+    fn truncate(&self, probs: &mut Vec<Vec<f32>>) {
+        for probs in probs.iter_mut() {
+            let candidates = self.candidates(probs);
+            utils::truncate(probs, &candidates);
+        }
+    }
+
+    fn clear(&mut self) {}
+
+    fn update(&mut self, _tokens: &Vec<Vec<u16>>) -> Result<(), InferenceInterruption> {
+        Ok(())
+    }
+
+    fn clone(&self) -> Box<dyn Sampler> {
+        Box::new(Clone::clone(self))
+    }
+}
+
+pub fn initialize_epsilon(_state: AppState, data: Option<Value>) -> Result<Box<dyn Sampler>> {
+    Ok(Box::new(serde_json::from_value::<EpsilonSampler>(
+        data.ok_or(
+            CommandErrorKind::BadRequest.error("Field must present to specify epsilon and temp!"),
+        )?,
+    )?))
+}
@@ -0,0 +1,70 @@
+use super::{
+    types::{Sampled, Sampler},
+    utils,
+};
+use crate::{app::AppState, error::CommandErrorKind, states::InferenceInterruption};
+use anyhow::Result;
+use fastrand::Rng;
+use serde::Deserialize;
+use serde_json::Value;
+
+fn default_temp() -> f32 {
+    1.0
+}
+
+/// Gumbel-max sampler, which draws from the whole distribution by adding Gumbel noise to
+/// the log probabilities and taking the argmax.
+///
+/// Tokens truncated to 0 (e.g. by earlier stages in a `ChainSampler`) are never drawn.
+#[derive(Debug, Clone, Deserialize)]
+pub struct GumbelSampler {
+    #[serde(default = "default_temp")]
+    temp: f32,
+}
+
+impl GumbelSampler {
+    pub fn new(temp: f32) -> Self {
+        Self { temp }
+    }
+}
+
+impl Sampler for GumbelSampler {
+    fn sample(&self, probs: Vec<Vec<f32>>, rng: &mut Rng) -> Vec<Sampled> {
+        probs
+            .iter()
+            .map(|probs| {
+                let token = utils::gumbel_max(probs.iter().map(|x| x.ln() / self.temp), rng);
+                Sampled::from_probs(probs, token)
+            })
+            .collect()
+    }
+
+    fn sample_logprobs(&self, logprobs: Vec<Vec<f32>>, rng: &mut Rng) -> Vec<Sampled> {
+        logprobs
+            .iter()
+            .map(|logprobs| {
+                let token = utils::gumbel_max(logprobs.iter().map(|x| x / self.temp), rng);
+                Sampled::from_logprobs(logprobs, token)
+            })
+            .collect()
+    }
+
+    fn clear(&mut self) {}
+
+    fn update(&mut self, _tokens: &Vec<Vec<u16>>) -> Result<(), InferenceInterruption> {
+        Ok(())
+    }
+
+    fn clone(&self) -> Box<dyn Sampler> {
+        Box::new(Clone::clone(self))
+    }
+}
+
+pub fn initialize_gumbel(_state: AppState, data: Option<Value>) -> Result<Box<dyn Sampler>> {
+    let sampler: GumbelSampler =
+        serde_json::from_value(data.unwrap_or(Value::Object(Default::default())))?;
+    if !(sampler.temp > 0.0) {
+        return Err(CommandErrorKind::BadRequest.error("temp must be positive!"));
+    }
+    Ok(Box::new(sampler))
+}
@@ -1,15 +1,23 @@
-use self::types::Sampler;
-use crate::{app::AppState, hashmap_ex};
-use anyhow::{Error, Ok, Result};
+use self::types::{Sampled, Sampler};
+use crate::{app::AppState, error::CommandErrorKind, hashmap_ex};
+use anyhow::{Ok, Result};
 use dashmap::{mapref::one::RefMut, DashMap};
+use fastrand::Rng;
 use serde::Deserialize;
-use serde_json::Value;
+use serde_json::{Map, Value};
 use std::collections::HashMap;
 
-use super::InferenceInterruption;
+use super::{component::Component, normalizer::types::Domain, InferenceInterruption};
 
+pub mod chain;
+pub mod contrastive;
+pub mod epsilon;
+pub mod gumbel;
+pub mod top_a;
 pub mod types;
 pub mod typical;
+pub mod utils;
+pub mod xtc;
 
 #[derive(Debug, Deserialize)]
 struct SamplerJson {
@@ -20,7 +28,7 @@ struct SamplerJson {
 #[derive(Debug)]
 pub struct Samplers {
     registry: HashMap<&'static str, fn(AppState, Option<Value>) -> Result<Box<dyn Sampler>>>,
-    map: DashMap<String, Box<dyn Sampler>>,
+    map: DashMap<String, Component<Box<dyn Sampler>>>,
 }
 
 impl Samplers {
@@ -29,7 +37,13 @@ impl Samplers {
             registry: hashmap_ex! {
                 HashMap<&'static str, fn(AppState, Option<Value>) -> Result<Box<dyn Sampler>>>,
                     {
-                        "typical" => typical::initialize_typical
+                        "typical" => typical::initialize_typical,
+                        "epsilon" => epsilon::initialize_epsilon,
+                        "contrastive" => contrastive::initialize_contrastive,
+                        "top_a" => top_a::initialize_top_a,
+                        "chain" => chain::initialize_chain,
+                        "gumbel" => gumbel::initialize_gumbel,
+                        "xtc" => xtc::initialize_xtc,
                     }
             },
             map: DashMap::with_capacity(128),
@@ -41,21 +55,43 @@ impl Samplers {
         if let Some(constructor) = constructor {
             Ok(constructor(state, data)?)
         } else {
-            Err(Error::msg("Sampler not found!"))
+            Err(CommandErrorKind::SamplerNotFound.error("Sampler not found!"))
         }
     }
 
-    pub fn create_sampler(&self, id: String, state: AppState, data: Value) -> Result<()> {
+    /// Constructs a sampler from its `type_id` and `params` without registering it.
+    pub fn construct(&self, state: AppState, data: Value) -> Result<Box<dyn Sampler>> {
+        let SamplerJson { type_id, params } = serde_json::from_value::<SamplerJson>(data)?;
+        self.create(&type_id, state, params)
+    }
+
+    /// Creates a sampler, which is deleted once the connection `owner` is closed if given.
+    pub fn create_sampler(
+        &self,
+        id: String,
+        state: AppState,
+        data: Value,
+        owner: Option<usize>,
+    ) -> Result<()> {
         if self.map.contains_key(&id) {
-            return Err(Error::msg("Sampler already existed!"));
+            return Err(CommandErrorKind::AlreadyExists.error("Sampler already existed!"));
         }
-        let SamplerJson { type_id, params } = serde_json::from_value::<SamplerJson>(data)?;
-        self.map.insert(id, self.create(&type_id, state, params)?);
+        let sampler = self.construct(state, data.clone())?;
+        self.map
+            .insert(id, Component::new(sampler, data).owned_by(owner));
         Ok(())
     }
 
+    /// Deletes all samplers owned by the connection.
+    pub fn disconnect(&self, connection: usize) {
+        self.map.retain(|_, x| x.owner != Some(connection));
+    }
+
     #[inline(always)]
-    pub fn get_sampler<'a>(&'a self, id: &str) -> Option<RefMut<'_, String, Box<dyn Sampler>>> {
+    pub fn get_sampler<'a>(
+        &'a self,
+        id: &str,
+    ) -> Option<RefMut<'_, String, Component<Box<dyn Sampler>>>> {
         self.map.get_mut(id)
     }
 
@@ -67,7 +103,7 @@ impl Samplers {
     pub fn delete_sampler(&self, id: &str) -> Result<()> {
         self.map
             .remove(id)
-            .ok_or(Error::msg("Sampler id doesn't exist!"))
+            .ok_or(CommandErrorKind::SamplerNotFound.error("Sampler id doesn't exist!"))
             .map(|_| ())
     }
 
@@ -76,7 +112,7 @@ impl Samplers {
             sampler.clear();
             Ok(())
         } else {
-            Err(Error::msg("Sampler id doesn't exist!"))
+            Err(CommandErrorKind::SamplerNotFound.error("Sampler id doesn't exist!"))
         }
     }
 
@@ -88,30 +124,94 @@ impl Samplers {
         if let Some(mut sampler) = self.map.get_mut(id) {
             sampler.update(content)
         } else {
-            Err(InferenceInterruption::Error(Error::msg(
-                "Sampler id doesn't exist!",
-            )))
+            Err(InferenceInterruption::Error(
+                CommandErrorKind::SamplerNotFound.error("Sampler id doesn't exist!"),
+            ))
         }
     }
 
     pub fn copy_sampler(&self, src: String, dst: String) -> Result<()> {
         if self.map.contains_key(&dst) {
-            return Err(Error::msg("Destination sampler id already exists!"));
+            return Err(
+                CommandErrorKind::AlreadyExists.error("Destination sampler id already exists!")
+            );
         }
         let src = self
             .map
             .get(&src)
-            .ok_or(Error::msg("Sampler doesn't exist!"))?
-            .clone();
+            .map(|x| Component::new(x.inner.clone(), x.definition.clone()))
+            .ok_or(CommandErrorKind::SamplerNotFound.error("Sampler doesn't exist!"))?;
         self.map.insert(dst, src);
         Ok(())
     }
 
-    pub fn sample_token(&self, id: &String, probs: Vec<Vec<f32>>) -> Result<u16> {
+    /// The definitions of all samplers by id, which create them again.
+    pub fn definitions(&self) -> Map<String, Value> {
+        self.map
+            .iter()
+            .map(|x| (x.key().clone(), x.definition.clone()))
+            .collect()
+    }
+
+    /// Ids of all samplers, sorted.
+    pub fn ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.map.iter().map(|x| x.key().clone()).collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    pub fn describe_sampler(&self, id: &str) -> Result<Value> {
+        self.map
+            .get(id)
+            .map(|sampler| sampler.describe())
+            .ok_or(CommandErrorKind::SamplerNotFound.error("Sampler id doesn't exist!"))
+    }
+
+    pub fn can_truncate(&self, id: &str) -> Result<bool> {
+        self.map
+            .get(id)
+            .map(|sampler| sampler.can_truncate())
+            .ok_or(CommandErrorKind::SamplerNotFound.error("Sampler id doesn't exist!"))
+    }
+
+    /// Truncates probs to the distribution which the sampler draws from.
+    pub fn truncate(&self, id: &str, probs: &mut Vec<Vec<f32>>) -> Result<()> {
+        let sampler = self
+            .map
+            .get(id)
+            .ok_or(CommandErrorKind::SamplerNotFound.error("Sampler id doesn't exist!"))?;
+        if !sampler.can_truncate() {
+            return Err(CommandErrorKind::BadRequest.error("Sampler can't truncate probs!"));
+        }
+        sampler.truncate(probs);
+        Ok(())
+    }
+
+    /// Samples a token for each state.
+    pub fn sample_tokens(
+        &self,
+        id: &String,
+        probs: Vec<Vec<f32>>,
+        domain: Domain,
+        rng: &mut Rng,
+    ) -> Result<Vec<Sampled>> {
         if let Some(sampler) = self.map.get(id) {
-            Ok(sampler.sample(probs))
+            let batch = probs.len();
+            let sampled = match domain {
+                Domain::Probs => sampler.sample(probs, rng),
+                Domain::LogProbs => sampler.sample_logprobs(probs, rng),
+            };
+            if sampled.len() != batch {
+                return Err(CommandErrorKind::Internal.error(format!(
+                    "Sampler {} returned {} tokens for {} states!",
+                    id,
+                    sampled.len(),
+                    batch
+                )));
+            }
+            Ok(sampled)
         } else {
-            Err(Error::msg("Sampler id doesn't exist!"))
+            Err(CommandErrorKind::SamplerNotFound.error("Sampler id doesn't exist!"))
         }
     }
 }
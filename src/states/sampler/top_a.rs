@@ -0,0 +1,74 @@
+use super::{
+    types::{Sampled, Sampler},
+    utils,
+};
+use crate::{app::AppState, error::CommandErrorKind, states::InferenceInterruption};
+use anyhow::Result;
+use fastrand::Rng;
+use itertools::Itertools;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Top-a sampler, drops every token below `a * max_prob ^ 2`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TopASampler {
+    a: f32,
+    temp: f32,
+}
+
+impl TopASampler {
+    fn candidates(&self, probs: &[f32]) -> Vec<(usize, f32)> {
+        let max = probs.iter().copied().fold(0.0, f32::max);
+        let threshold = self.a * max * max;
+        let mut candidates = probs
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|&(_, x)| x >= threshold)
+            .collect_vec();
+        utils::apply_temperature(&mut candidates, self.temp);
+        candidates
+    }
+}
+
+impl Sampler for TopASampler {
+    fn sample(&self, probs: Vec<Vec<f32>>, rng: &mut Rng) -> Vec<Sampled> {
+        probs
+            .iter()
+            .map(|probs| {
+                let token = utils::sample_weighted(&self.candidates(probs), rng)
+                    .unwrap_or_else(|| utils::argmax(probs));
+                Sampled::from_probs(probs, token)
+            })
+            .collect()
+    }
+
+    fn can_truncate(&self) -> bool {
+        true
+    }
+
+    fn truncate(&self, probs: &mut Vec<Vec<f32>>) {
+        for probs in probs.iter_mut() {
+            let candidates = self.candidates(probs);
+            utils::truncate(probs, &candidates);
+        }
+    }
+
+    fn clear(&mut self) {}
+
+    fn update(&mut self, _tokens: &Vec<Vec<u16>>) -> Result<(), InferenceInterruption> {
+        Ok(())
+    }
+
+    fn clone(&self) -> Box<dyn Sampler> {
+        Box::new(Clone::clone(self))
+    }
+}
+
+pub fn initialize_top_a(_state: AppState, data: Option<Value>) -> Result<Box<dyn Sampler>> {
+    Ok(Box::new(serde_json::from_value::<TopASampler>(
+        data.ok_or(
+            CommandErrorKind::BadRequest.error("Field must present to specify a and temp!"),
+        )?,
+    )?))
+}
@@ -1,13 +1,42 @@
 use std::fmt::Debug;
 
 use anyhow::Result;
+use fastrand::Rng;
+use serde_json::Value;
 
 use crate::states::InferenceInterruption;
 
-/// Sample a token from probablities (after softmax).
+use super::utils;
+
+/// A token drawn by a `Sampler`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sampled {
+    pub token: u16,
+    /// Log probability of the token in the distribution handed to the sampler, which is
+    /// after all transformers and the normalizer.
+    pub logprob: f32,
+}
+
+impl Sampled {
+    /// Picks `token` from a probs distribution.
+    pub fn from_probs(probs: &[f32], token: usize) -> Self {
+        Self {
+            token: token as u16,
+            logprob: probs.get(token).copied().unwrap_or_default().ln(),
+        }
+    }
+
+    /// Picks `token` from a log probs distribution.
+    pub fn from_logprobs(logprobs: &[f32], token: usize) -> Self {
+        Self {
+            token: token as u16,
+            logprob: logprobs.get(token).copied().unwrap_or(f32::NEG_INFINITY),
+        }
+    }
+}
+
+/// Sample a token for each state from probablities (after softmax).
 ///
-/// Multiple logits might present (in case of CFG).
-/// 
 /// #### Registration
 /// 
 /// A sampler type needs to be registered before it can be constructed by the Websocket API.
@@ -27,17 +56,47 @@ pub trait Sampler: Send + Sync + Debug {
     ///
     /// Once the update is completed, the **infer** will start, so sampler must **preceive**
     /// if it can or can not accept any further input, and interrupt the generation by
-    /// returning `Err(InferenceInterruption::Exhaustion)`.
+    /// returning `Err(InferenceInterruption::Exhaustion(reason))`, where the optional reason
+    /// is reported to the client.
     fn update(&mut self, tokens: &Vec<Vec<u16>>) -> Result<(), InferenceInterruption>;
-    /// Samples a token from one or *more* probabilities, which is `softmax`ed from one
-    /// or more states. For each probs distribution, it is guaranteed to have a sum of 1.
+    /// Samples a token for each probs distribution, which is `softmax`ed from the logits
+    /// of a state in the batch. For each probs distribution, it is guaranteed to have a
+    /// sum of 1.
+    ///
+    /// The sampler must return exactly one token per distribution, in order, and the
+    /// token of a state is fed back to that state only. Usually each token is drawn from
+    /// its own distribution like `typical` does, but a sampler may also look at all of
+    /// them, e.g. `CFG Sampling` which returns the same token for every state.
+    ///
+    /// The sampler also reports the log probability of each token, which is taken from
+    /// its distribution before any truncation or temperature of the sampler.
+    ///
+    /// All randomness must be drawn from `rng`, so a generation with a seed is
+    /// reproducible.
+    fn sample(&self, probs: Vec<Vec<f32>>, rng: &mut Rng) -> Vec<Sampled>;
+    /// Samples a token for each log probs distribution, which come from a normalizer in
+    /// the `LogProbs` domain.
+    ///
+    /// Converts them to probabilities and calls `sample` by default. Override it if the
+    /// sampler works on log probabilities natively.
+    fn sample_logprobs(&self, logprobs: Vec<Vec<f32>>, rng: &mut Rng) -> Vec<Sampled> {
+        self.sample(utils::exp(logprobs), rng)
+    }
+    /// Whether the sampler can be used as a non-final stage of a `ChainSampler`. Defaults
+    /// to `false`.
+    fn can_truncate(&self) -> bool {
+        false
+    }
+    /// Removes the tokens this sampler would never select from each probs distribution
+    /// and applies temperature, leaving distributions which still sum to 1.
     ///
-    /// Usually, the sampler would only accept 1 probs `Vec` -> 1 token mapping
-    /// like `typical` or `nucleus` would do, but there are also sampling methods like
-    /// `CFG Sampling` which samples from multiple parallel states. Note that only 1
-    /// token will be sampled from the list and selected as the next token for *all states*.
-    // TODO: Change it to Vec<u16> to increase concurrency.
-    fn sample(&self, probs: Vec<Vec<f32>>) -> u16;
+    /// Only called if `can_truncate` returns `true`. Does nothing by default.
+    fn truncate(&self, _probs: &mut Vec<Vec<f32>>) {}
+    /// Describes the internal state of the sampler for debugging. Returns `Value::Null`
+    /// by default.
+    fn describe(&self) -> Value {
+        Value::Null
+    }
     /// Clears the `Sampler`. This will reset the internal state of the sampler to *when it 
     /// is just constructed from params*.
     fn clear(&mut self);
@@ -1,6 +1,10 @@
-use super::types::Sampler;
-use crate::{app::AppState, states::InferenceInterruption};
-use anyhow::{Error, Result};
+use super::{
+    types::{Sampled, Sampler},
+    utils,
+};
+use crate::{app::AppState, error::CommandErrorKind, states::InferenceInterruption};
+use anyhow::Result;
+use fastrand::Rng;
 use itertools::Itertools;
 use serde::Deserialize;
 use serde_json::Value;
@@ -12,10 +16,8 @@ pub struct TypicalSampler {
     temp: f32,
 }
 
-impl Sampler for TypicalSampler {
-    // TODO: Make it return a vec of u16
-    fn sample(&self, probs: Vec<Vec<f32>>) -> u16 {
-        let probs = &probs[0];
+impl TypicalSampler {
+    fn sample_one(&self, probs: &[f32], rng: &mut Rng) -> Sampled {
         let sorted = probs
             .into_iter()
             .enumerate()
@@ -32,13 +34,47 @@ impl Sampler for TypicalSampler {
         let sum: f32 = sorted.iter().map(|(_, x)| x).sum();
         let sorted = sorted.into_iter().map(|(id, x)| (id, x / sum));
 
-        let rand = fastrand::f32();
+        let rand = rng.f32();
         let token = sorted
             .into_iter()
             .find_or_first(|&(_, cum)| rand <= cum)
             .map(|(id, _)| id)
             .unwrap_or_default();
-        token as u16
+        Sampled::from_probs(probs, token)
+    }
+}
+
+impl Sampler for TypicalSampler {
+    fn sample(&self, probs: Vec<Vec<f32>>, rng: &mut Rng) -> Vec<Sampled> {
+        probs
+            .iter()
+            .map(|probs| self.sample_one(probs, rng))
+            .collect()
+    }
+
+    fn can_truncate(&self) -> bool {
+        true
+    }
+
+    fn truncate(&self, probs: &mut Vec<Vec<f32>>) {
+        for probs in probs.iter_mut() {
+            let mut candidates = probs
+                .iter()
+                .copied()
+                .enumerate()
+                .sorted_unstable_by(|(_, x), (_, y)| x.total_cmp(&y).reverse())
+                .scan(0.0, |cum, (id, x)| {
+                    if *cum > self.top_p {
+                        None
+                    } else {
+                        *cum += x;
+                        Some((id, x))
+                    }
+                })
+                .collect_vec();
+            utils::apply_temperature(&mut candidates, self.temp);
+            utils::truncate(probs, &candidates);
+        }
     }
 
     fn clear(&mut self) {}
@@ -57,6 +93,8 @@ impl Sampler for TypicalSampler {
 
 pub fn initialize_typical(_state: AppState, data: Option<Value>) -> Result<Box<dyn Sampler>> {
     Ok(Box::new(serde_json::from_value::<TypicalSampler>(
-        data.ok_or(Error::msg("Field must present to specify top_p and temp!"))?,
+        data.ok_or(
+            CommandErrorKind::BadRequest.error("Field must present to specify top_p and temp!"),
+        )?,
     )?))
 }
@@ -0,0 +1,109 @@
+use fastrand::Rng;
+use itertools::Itertools;
+
+/// Returns the index of the largest probability, or 0 if `probs` is empty.
+pub fn argmax(probs: &[f32]) -> usize {
+    probs
+        .iter()
+        .position_max_by(|x, y| x.total_cmp(y))
+        .unwrap_or_default()
+}
+
+/// Logits are clamped into `[-LOGIT_BOUND, LOGIT_BOUND]` before they are normalized, so
+/// masked (`-inf`) tokens still get a probability of 0 but nothing overflows.
+pub const LOGIT_BOUND: f32 = 1e4;
+
+/// Replaces NaN logits with `-LOGIT_BOUND` and clamps the rest into the bound, so a
+/// transformer producing NaN or infinities can't break the normalizer or the sampler.
+///
+/// Returns `false` if every logit ends up at the lower bound, where no token is left to
+/// sample.
+pub fn sanitize_logits(logits: &mut [f32]) -> bool {
+    for x in logits.iter_mut() {
+        *x = match x.is_nan() {
+            true => -LOGIT_BOUND,
+            false => x.clamp(-LOGIT_BOUND, LOGIT_BOUND),
+        };
+    }
+    logits.iter().any(|&x| x > -LOGIT_BOUND)
+}
+
+/// Logits which put all the probability on `token`.
+pub fn one_hot_logits(len: usize, token: usize) -> Vec<f32> {
+    let mut logits = vec![-LOGIT_BOUND; len];
+    if let Some(x) = logits.get_mut(token) {
+        *x = 0.0;
+    }
+    logits
+}
+
+/// Converts log probabilities back to probabilities.
+pub fn exp(logprobs: Vec<Vec<f32>>) -> Vec<Vec<f32>> {
+    logprobs
+        .into_iter()
+        .map(|x| x.into_iter().map(f32::exp).collect())
+        .collect()
+}
+
+/// Applies temperature to a list of `(token, prob)` by raising each prob to `1 / temp`.
+///
+/// The result is not normalized, use `sample_weighted` to draw from it directly.
+pub fn apply_temperature(candidates: &mut Vec<(usize, f32)>, temp: f32) {
+    if temp == 1.0 || temp <= 0.0 {
+        return;
+    }
+    let exponent = 1.0 / temp;
+    for (_, prob) in candidates.iter_mut() {
+        *prob = prob.powf(exponent);
+    }
+}
+
+/// Draws a token from a list of `(token, weight)`, weights need not sum to 1.
+///
+/// Returns `None` if the list is empty or all weights are zero.
+pub fn sample_weighted(candidates: &[(usize, f32)], rng: &mut Rng) -> Option<usize> {
+    let sum: f32 = candidates.iter().map(|(_, x)| x).sum();
+    if candidates.is_empty() || !(sum > 0.0) {
+        return None;
+    }
+    let rand = rng.f32() * sum;
+    let mut cum = 0.0;
+    for &(id, weight) in candidates {
+        cum += weight;
+        if rand < cum {
+            return Some(id);
+        }
+    }
+    candidates.last().map(|(id, _)| *id)
+}
+
+/// Replaces `probs` with a list of `(token, weight)`, normalized to sum to 1. Tokens not in
+/// the list get 0.
+///
+/// Keeps the most probable token only if the list is empty or all weights are zero.
+pub fn truncate(probs: &mut Vec<f32>, candidates: &[(usize, f32)]) {
+    let sum: f32 = candidates.iter().map(|(_, x)| x).sum();
+    let mut truncated = vec![0.0; probs.len()];
+    if sum > 0.0 {
+        for &(id, weight) in candidates {
+            truncated[id] = weight / sum;
+        }
+    } else if !probs.is_empty() {
+        truncated[argmax(probs)] = 1.0;
+    }
+    *probs = truncated;
+}
+
+/// Draws a token from log weights by Gumbel-max: the argmax of `log_weight + gumbel_noise`
+/// is distributed as sampling by `exp(log_weight)`, without any cumulative sum.
+///
+/// Tokens with `-inf` log weights are never drawn unless all of them are.
+pub fn gumbel_max(log_weights: impl Iterator<Item = f32>, rng: &mut Rng) -> usize {
+    log_weights
+        .map(|x| {
+            let uniform = rng.f32().max(f32::MIN_POSITIVE);
+            x - (-uniform.ln()).ln()
+        })
+        .position_max_by(|x, y| x.total_cmp(y))
+        .unwrap_or_default()
+}
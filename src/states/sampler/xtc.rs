@@ -0,0 +1,97 @@
+use super::{
+    types::{Sampled, Sampler},
+    utils,
+};
+use crate::{app::AppState, error::CommandErrorKind, states::InferenceInterruption};
+use anyhow::Result;
+use fastrand::Rng;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Exclude top choices sampler. With chance `probability`, drops every token above
+/// `threshold` but the least probable of them, which steers away from the most obvious
+/// tokens while keeping a viable one.
+#[derive(Debug, Clone, Deserialize)]
+pub struct XtcSampler {
+    threshold: f32,
+    probability: f32,
+}
+
+impl XtcSampler {
+    /// Tokens left after the top choices are excluded, or `None` if fewer than two tokens
+    /// are above the threshold, in which case nothing is excluded.
+    fn candidates(&self, probs: &[f32]) -> Option<Vec<(usize, f32)>> {
+        let top = probs.iter().filter(|&&x| x > self.threshold);
+        if top.clone().count() < 2 {
+            return None;
+        }
+        let kept = top.copied().fold(f32::INFINITY, f32::min);
+        Some(
+            probs
+                .iter()
+                .copied()
+                .enumerate()
+                .filter(|&(_, x)| x <= kept)
+                .collect(),
+        )
+    }
+}
+
+impl Sampler for XtcSampler {
+    fn sample(&self, probs: Vec<Vec<f32>>, rng: &mut Rng) -> Vec<Sampled> {
+        probs
+            .iter()
+            .map(|probs| {
+                let token = match self.candidates(probs) {
+                    Some(candidates) if rng.f32() < self.probability => {
+                        utils::sample_weighted(&candidates, rng)
+                    }
+                    _ => utils::sample_weighted(
+                        &probs.iter().copied().enumerate().collect::<Vec<_>>(),
+                        rng,
+                    ),
+                }
+                .unwrap_or_else(|| utils::argmax(probs));
+                Sampled::from_probs(probs, token)
+            })
+            .collect()
+    }
+
+    fn can_truncate(&self) -> bool {
+        true
+    }
+
+    /// Replaces each distribution with the one `sample` draws from, which mixes the
+    /// excluded and the original distributions by `probability`.
+    fn truncate(&self, probs: &mut Vec<Vec<f32>>) {
+        let probability = self.probability.clamp(0.0, 1.0);
+        for probs in probs.iter_mut() {
+            if let Some(candidates) = self.candidates(probs) {
+                let mut excluded = probs.clone();
+                utils::truncate(&mut excluded, &candidates);
+                for (p, q) in probs.iter_mut().zip(excluded) {
+                    *p = (1.0 - probability) * *p + probability * q;
+                }
+            }
+        }
+    }
+
+    fn clear(&mut self) {}
+
+    fn update(&mut self, _tokens: &Vec<Vec<u16>>) -> Result<(), InferenceInterruption> {
+        Ok(())
+    }
+
+    fn clone(&self) -> Box<dyn Sampler> {
+        Box::new(Clone::clone(self))
+    }
+}
+
+pub fn initialize_xtc(_state: AppState, data: Option<Value>) -> Result<Box<dyn Sampler>> {
+    Ok(Box::new(serde_json::from_value::<XtcSampler>(
+        data.ok_or(
+            CommandErrorKind::BadRequest
+                .error("Field must present to specify threshold and probability!"),
+        )?,
+    )?))
+}
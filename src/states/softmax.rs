@@ -1,22 +1,32 @@
-use anyhow::{Error, Result};
 use std::{sync::Arc, time::Duration};
 
 use tokio::{
     sync::{mpsc, oneshot},
     task::JoinHandle,
+    time::Instant,
 };
 use web_rwkv::model::Model;
 
+/// Softmax worker which batches requests into one GPU submit.
+///
+/// Once a request arrives, the worker waits up to `batch_timeout` for more requests until
+/// `max_batch_size` requests are queued, then computes the queue in batches.
 pub struct Softmax {
     model: Arc<Model<'static>>,
     max_batch_size: usize,
+    batch_timeout: Duration,
 }
 
 impl Softmax {
-    pub async fn new(model: Arc<Model<'static>>, max_batch_size: usize) -> Self {
+    pub async fn new(
+        model: Arc<Model<'static>>,
+        max_batch_size: usize,
+        batch_timeout: Duration,
+    ) -> Self {
         Self {
             model,
-            max_batch_size,
+            max_batch_size: max_batch_size.max(1),
+            batch_timeout,
         }
     }
 
@@ -28,36 +38,28 @@ impl Softmax {
         while let Some(requests) = receiver.recv().await {
             queue.extend(requests);
 
-            // Sleep for 5us to make things batched
-            tokio::time::sleep(Duration::from_micros(10)).await;
-
-            while let Ok(requests) = receiver.try_recv() {
-                queue.extend(requests);
-                if queue.len() >= self.max_batch_size {
-                    break;
+            // Requests already queued are always taken, even with a zero timeout
+            let deadline = Instant::now() + self.batch_timeout;
+            while queue.len() < self.max_batch_size {
+                match tokio::time::timeout_at(deadline, receiver.recv()).await {
+                    Ok(Some(requests)) => queue.extend(requests),
+                    // Timed out or all senders are gone, compute the partial batch
+                    Ok(None) | Err(_) => break,
                 }
             }
 
-            let (softmax_queue, sender_queue): (Vec<Vec<f32>>, Vec<oneshot::Sender<Vec<f32>>>) =
-                queue
-                    .split_off(if self.max_batch_size > queue.len() {
-                        0
-                    } else {
-                        queue.len() - self.max_batch_size
-                    })
-                    .into_iter()
-                    .unzip();
-            let softmax_queue = self.model.softmax(softmax_queue).unwrap();
-            softmax_queue
-                .into_iter()
-                .zip(sender_queue.into_iter())
-                .map(|(result, sender)| {
-                    sender
-                        .send(result)
-                        .map_err(|_| Error::msg("Can't send data due to channel is dropped!"))
-                })
-                .collect::<Result<Vec<_>>>()
-                .unwrap();
+            // Requests beyond the batch size are left in the queue, so keep going until
+            // the queue is drained, or they wait until the next request arrives.
+            while !queue.is_empty() {
+                let batch_size = queue.len().min(self.max_batch_size);
+                let (softmax_queue, sender_queue): (Vec<Vec<f32>>, Vec<oneshot::Sender<Vec<f32>>>) =
+                    queue.drain(..batch_size).unzip();
+                let softmax_queue = self.model.softmax(softmax_queue).unwrap();
+                for (result, sender) in softmax_queue.into_iter().zip(sender_queue.into_iter()) {
+                    // The requester is gone (e.g. the connection is closed), which is fine
+                    sender.send(result).ok();
+                }
+            }
         }
     }
 
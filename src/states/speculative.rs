@@ -0,0 +1,377 @@
+use anyhow::Result;
+use fastrand::Rng;
+
+use crate::{
+    app::{AppState, FedTokens},
+    config::DRAFT_MODEL,
+    error::CommandErrorKind,
+    helper::{Logits, State},
+};
+
+use super::{
+    sample_pipeline::{PipelineInterruption, SamplePipeline},
+    sampler::{types::Sampled, utils},
+};
+
+/// Where the draft tokens of a speculation come from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Drafter {
+    /// A state of the draft model, which proposes `--draft-tokens` tokens.
+    Model(String),
+    /// The tokens which followed the last occurrence of the latest n-gram (at most
+    /// `max_ngram` tokens) in the context, up to `draft_len` of them.
+    NGram { draft_len: usize, max_ngram: usize },
+}
+
+impl Drafter {
+    /// The state of the draft model, if drafting with one.
+    pub fn draft_state(&self) -> Option<&String> {
+        match self {
+            Drafter::Model(state) => Some(state),
+            Drafter::NGram { .. } => None,
+        }
+    }
+
+    /// Tokens proposed in each round at most.
+    pub fn draft_len(&self, app_state: &AppState) -> usize {
+        match self {
+            Drafter::Model(_) => app_state.0.draft_tokens,
+            Drafter::NGram { draft_len, .. } => *draft_len,
+        }
+    }
+}
+
+/// The drafter of a running speculation, with what it keeps between rounds.
+enum Drafting {
+    Model {
+        state: String,
+        /// States of the draft model after the last fed token and each draft token but
+        /// the last one.
+        states: Vec<State>,
+        /// Tokens to feed to the draft state before the next round.
+        pending: Vec<u16>,
+    },
+    NGram {
+        max_ngram: usize,
+        /// The prompt and every token fed since.
+        context: Vec<u16>,
+    },
+}
+
+/// Proposes the tokens which followed the last earlier occurrence of the longest suffix
+/// of `context` (at most `max_ngram` tokens), up to `draft_len` of them. Proposes nothing
+/// if no suffix occurred before.
+pub fn ngram_draft(context: &[u16], max_ngram: usize, draft_len: usize) -> Vec<u16> {
+    for n in (1..=max_ngram.min(context.len().saturating_sub(1))).rev() {
+        let suffix = &context[context.len() - n..];
+        if let Some(start) = (0..context.len() - n)
+            .rev()
+            .find(|&start| &context[start..start + n] == suffix)
+        {
+            return context[start + n..]
+                .iter()
+                .copied()
+                .take(draft_len)
+                .collect();
+        }
+    }
+    Vec::new()
+}
+
+/// Speculative decoding of a single state, with a state of the draft model or n-gram
+/// lookup in the context.
+///
+/// Each round, the drafter proposes up to `draft_len` tokens, then the main model infers
+/// the last token with every prefix of the proposal in one batch. The proposal is
+/// verified token by token as the generation goes: a draft token `d` drawn from the
+/// draft distribution `q` is accepted with probability `min(1, p(d) / q(d))`, where `p`
+/// is the distribution the sampler draws from. Once a token is rejected, the replacement
+/// is drawn from `max(0, p - q)` normalized, and the round ends. So every token is
+/// distributed exactly as if it were sampled from the main model. An n-gram draft is
+/// certain, so `q` puts all the probability on the draft token.
+///
+/// The main state is only inferred once for the prompt, and set to the right state by
+/// `finish`. Rounds infer temporary copies of it instead.
+pub struct Speculation {
+    pipeline: SamplePipeline,
+    drafting: Drafting,
+    draft_len: usize,
+    update_prompt: bool,
+    reset_on_exhaustion: bool,
+    /// Logits and state of the main model after the last fed token and each draft token.
+    lanes: Vec<(Logits, State)>,
+    /// Draft tokens of the round, with the draft distribution each is drawn from, which
+    /// is `None` if the draft is certain.
+    drafts: Vec<(u16, Option<Vec<f32>>)>,
+    /// The lane which the next token is verified against.
+    position: usize,
+    /// Whether the round is over, so the next token starts a new round.
+    finished: bool,
+    /// Tokens fed after the prompt, which the main state catches up with in `finish`.
+    fed: Vec<u16>,
+}
+
+impl Speculation {
+    pub fn new(
+        app_state: &AppState,
+        pipeline: &SamplePipeline,
+        drafter: Drafter,
+        update_prompt: bool,
+        reset_on_exhaustion: bool,
+    ) -> Self {
+        let draft_len = drafter.draft_len(app_state);
+        let drafting = match drafter {
+            Drafter::Model(state) => Drafting::Model {
+                state,
+                states: Vec::new(),
+                pending: Vec::new(),
+            },
+            Drafter::NGram { max_ngram, .. } => Drafting::NGram {
+                max_ngram,
+                context: Vec::new(),
+            },
+        };
+        Self {
+            pipeline: pipeline.clone(),
+            drafting,
+            draft_len,
+            update_prompt,
+            reset_on_exhaustion,
+            lanes: Vec::new(),
+            drafts: Vec::new(),
+            position: 0,
+            finished: true,
+            fed: Vec::new(),
+        }
+    }
+
+    /// Stops masking the suppressed tokens of the pipeline.
+    pub fn unsuppress(&mut self) {
+        self.pipeline.suppressed.clear();
+    }
+
+    /// Feeds the prompt to the main state (and the draft state), and samples the first
+    /// token.
+    pub async fn start(
+        &mut self,
+        app_state: &AppState,
+        prompt: Vec<u16>,
+        keep_logprobs: bool,
+        rng: &mut Rng,
+    ) -> Result<(Vec<Sampled>, Option<Vec<Vec<f32>>>), PipelineInterruption> {
+        if self.update_prompt {
+            tokio::task::block_in_place(|| {
+                self.pipeline
+                    .update(app_state, &vec![prompt.clone()], false)
+            })?;
+        }
+
+        let _permit = app_state
+            .state_model(&self.pipeline.states)?
+            .batch_request
+            .request(1)?;
+        let main = match &mut self.drafting {
+            Drafting::Model { state, .. } => {
+                let (main, draft) = tokio::join!(
+                    app_state.infer_snapshot(self.pipeline.states.clone(), vec![prompt.clone()]),
+                    app_state.infer(vec![state.clone()], vec![prompt])
+                );
+                draft?;
+                main
+            }
+            Drafting::NGram { context, .. } => {
+                context.extend_from_slice(&prompt);
+                app_state
+                    .infer_snapshot(self.pipeline.states.clone(), vec![prompt])
+                    .await
+            }
+        };
+        let (logits, state) = main?
+            .pop()
+            .ok_or(CommandErrorKind::Internal.error("Main state is not inferred!"))?;
+        self.lanes = vec![(logits.clone(), state)];
+        self.position = 0;
+        self.finished = true;
+
+        self.pipeline
+            .sample_logits(app_state, vec![logits], keep_logprobs, rng)
+            .await
+    }
+
+    /// Drafts tokens after `token`, and infers the main model with all of them.
+    async fn round(&mut self, app_state: &AppState, token: u16, rng: &mut Rng) -> Result<()> {
+        let drafts = match &mut self.drafting {
+            Drafting::Model {
+                state: draft,
+                states,
+                pending,
+            } => {
+                let draft_model = app_state.model(Some(DRAFT_MODEL))?;
+                let mut drafts = Vec::with_capacity(self.draft_len);
+                let mut draft_states = Vec::with_capacity(self.draft_len);
+                let mut tokens = std::mem::take(pending);
+                tokens.push(token);
+                for _ in 0..self.draft_len {
+                    let (logits, state) = app_state
+                        .infer_snapshot(vec![draft.clone()], vec![tokens])
+                        .await?
+                        .pop()
+                        .ok_or(CommandErrorKind::Internal.error("Draft state is not inferred!"))?;
+                    let probs = draft_model.softmax(vec![logits.0]).await.remove(0);
+                    let drafted = sample(&probs, rng) as u16;
+                    draft_states.push(state);
+                    drafts.push((drafted, Some(probs)));
+                    tokens = vec![drafted];
+                }
+                *states = draft_states;
+                drafts
+            }
+            // Without a match, the round infers `token` alone like a normal step
+            Drafting::NGram { max_ngram, context } => {
+                ngram_draft(context, *max_ngram, self.draft_len)
+                    .into_iter()
+                    .map(|x| (x, None))
+                    .collect()
+            }
+        };
+
+        // Every prefix of the drafts is inferred from the last verified state at once
+        let base = self.lanes[self.position].1.clone();
+        let model = app_state.state_model(&self.pipeline.states)?;
+        let lanes = (0..=drafts.len())
+            .map(|_| app_state.create_temporary_state(&model.name, Some(base.clone())))
+            .collect::<Result<Vec<_>>>()?;
+        let inputs = (0..=drafts.len())
+            .map(|len| {
+                std::iter::once(token)
+                    .chain(drafts[..len].iter().map(|(x, _)| *x))
+                    .collect()
+            })
+            .collect();
+        // Locks a slot for each lane only now, so drafting doesn't hold up the main model.
+        // An n-gram draft may be shorter than `draft_len`, or empty
+        let _permits = model.batch_request.request(drafts.len() + 1)?;
+        self.lanes = app_state
+            .infer_snapshot(lanes.iter().map(|x| x.id.clone()).collect(), inputs)
+            .await?;
+
+        self.drafts = drafts;
+        self.position = 0;
+        self.finished = false;
+        Ok(())
+    }
+
+    /// Rolls the draft state back to after the draft token at `position` is fed. Nothing
+    /// to roll back for n-gram drafts.
+    fn rollback(&mut self, app_state: &AppState, position: usize) -> Result<()> {
+        match &mut self.drafting {
+            Drafting::Model { state, states, .. } if position < self.drafts.len() => {
+                // The last draft token is never fed to the draft state
+                let unfed = self.drafts.len() - 1 - position;
+                app_state.set_state(state, states[position].clone(), FedTokens::Truncate(unfed))
+            }
+            // All drafts are accepted, but the last one is not fed to the draft state yet
+            Drafting::Model { pending, .. } => {
+                if let Some((token, _)) = self.drafts.last() {
+                    pending.push(*token);
+                }
+                Ok(())
+            }
+            Drafting::NGram { .. } => Ok(()),
+        }
+    }
+
+    /// Feeds `token` which is just sampled, and samples the next token.
+    pub async fn next(
+        &mut self,
+        app_state: &AppState,
+        token: u16,
+        keep_logprobs: bool,
+        rng: &mut Rng,
+    ) -> Result<(Vec<Sampled>, Option<Vec<Vec<f32>>>), PipelineInterruption> {
+        if self.update_prompt {
+            tokio::task::block_in_place(|| {
+                self.pipeline
+                    .update(app_state, &vec![vec![token]], self.reset_on_exhaustion)
+            })?;
+        }
+        if let Drafting::NGram { context, .. } = &mut self.drafting {
+            context.push(token);
+        }
+        self.fed.push(token);
+
+        if self.finished {
+            self.round(app_state, token, rng).await?;
+        } else {
+            self.position += 1;
+        }
+
+        let logits = self.lanes[self.position].0.clone();
+        let (mut probs, truncated) = self.pipeline.distribution(app_state, vec![logits]).await?;
+        let (probs, p) = (probs.remove(0), &truncated[0]);
+
+        let (token, accepted) =
+            tokio::task::block_in_place(|| match self.drafts.get(self.position) {
+                Some((drafted, q))
+                    if rng.f32() * draft_prob(q, *drafted, *drafted as usize)
+                        < p.get(*drafted as usize).copied().unwrap_or_default() =>
+                {
+                    (*drafted as usize, true)
+                }
+                Some((drafted, q)) => {
+                    let residual: Vec<(usize, f32)> = p
+                        .iter()
+                        .enumerate()
+                        .map(|(index, p)| (p - draft_prob(q, *drafted, index)).max(0.0))
+                        .enumerate()
+                        .filter(|(_, x)| *x > 0.0)
+                        .collect();
+                    let token =
+                        utils::sample_weighted(&residual, rng).unwrap_or_else(|| sample(p, rng));
+                    (token, false)
+                }
+                // All drafts are accepted, the token after them is drawn from the main model
+                None => (sample(p, rng), false),
+            });
+
+        if !accepted {
+            self.finished = true;
+            self.rollback(app_state, self.position)?;
+        }
+
+        let logprobs = keep_logprobs.then(|| vec![probs.iter().map(|x| x.ln()).collect()]);
+        Ok((vec![Sampled::from_probs(&probs, token)], logprobs))
+    }
+
+    /// Sets both states to after all tokens but the last sampled one are fed, like a
+    /// normal infer leaves them.
+    pub async fn finish(mut self, app_state: &AppState) -> Result<()> {
+        if !self.finished {
+            self.rollback(app_state, self.position)?;
+        }
+        if let Drafting::Model { state, pending, .. } = &mut self.drafting {
+            if !pending.is_empty() {
+                let pending = std::mem::take(pending);
+                app_state.infer(vec![state.clone()], vec![pending]).await?;
+            }
+        }
+        let (_, state) = self.lanes.swap_remove(self.position);
+        for id in &self.pipeline.states {
+            app_state.set_state(id, state.clone(), FedTokens::Extend(&self.fed))?;
+        }
+        Ok(())
+    }
+}
+
+/// Probability of `token` under the distribution which `drafted` is drawn from.
+fn draft_prob(q: &Option<Vec<f32>>, drafted: u16, token: usize) -> f32 {
+    match q {
+        Some(q) => q.get(token).copied().unwrap_or_default(),
+        None => (token == drafted as usize) as u8 as f32,
+    }
+}
+
+fn sample(probs: &[f32], rng: &mut Rng) -> usize {
+    let candidates: Vec<(usize, f32)> = probs.iter().copied().enumerate().collect();
+    utils::sample_weighted(&candidates, rng).unwrap_or_else(|| utils::argmax(probs))
+}
@@ -0,0 +1,89 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::Result;
+use serde::Serialize;
+
+/// Moves the least recently used states out of memory into files of a directory once more
+/// than `max_resident` states are held in memory.
+#[derive(Debug)]
+pub struct Spill {
+    dir: PathBuf,
+    pub max_resident: usize,
+    next_file: AtomicUsize,
+    spills: AtomicUsize,
+    reloads: AtomicUsize,
+}
+
+impl Spill {
+    pub fn new(dir: PathBuf, max_resident: usize) -> Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            max_resident,
+            next_file: AtomicUsize::new(0),
+            spills: AtomicUsize::new(0),
+            reloads: AtomicUsize::new(0),
+        })
+    }
+
+    /// A new file to spill a state to.
+    pub fn file(&self) -> SpillFile {
+        let index = self.next_file.fetch_add(1, Ordering::Relaxed);
+        SpillFile {
+            path: self.dir.join(format!("spill-{}.state", index)),
+        }
+    }
+
+    pub fn record_spill(&self) {
+        self.spills.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_reload(&self) {
+        self.reloads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self, resident: usize, spilled: usize) -> SpillStats {
+        SpillStats {
+            max_resident: self.max_resident,
+            resident,
+            spilled,
+            spills: self.spills.load(Ordering::Relaxed),
+            reloads: self.reloads.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A file holding the data of spilled states, which is deleted once no state refers to it.
+#[derive(Debug)]
+pub struct SpillFile {
+    path: PathBuf,
+}
+
+impl SpillFile {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.path).ok();
+    }
+}
+
+/// How many states are in memory or spilled, and how often they are moved.
+#[derive(Debug, Clone, Serialize)]
+pub struct SpillStats {
+    pub max_resident: usize,
+    /// States whose data is in memory.
+    pub resident: usize,
+    /// States whose data is in the spill directory.
+    pub spilled: usize,
+    /// States moved to the spill directory so far.
+    pub spills: usize,
+    /// States read back from the spill directory so far.
+    pub reloads: usize,
+}
@@ -0,0 +1,138 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::{error::CommandErrorKind, helper::State};
+
+use super::model::AxumModel;
+
+/// Marks the start of a saved state.
+const MAGIC: &[u8; 8] = b"WRAXSTAT";
+/// Bumped whenever the layout of a saved state changes.
+const FORMAT_VERSION: u32 = 1;
+
+/// What a saved state must match to be loaded into a model.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct StateLayout {
+    /// The state layout version of the model, e.g. `V4`.
+    pub version: String,
+    pub num_layers: usize,
+    pub num_emb: usize,
+}
+
+impl StateLayout {
+    pub fn of(model: &AxumModel) -> Self {
+        let info = model.info();
+        Self {
+            version: model.version().to_string(),
+            num_layers: info.num_layers,
+            num_emb: info.num_emb,
+        }
+    }
+
+    /// Floats in a state of this layout.
+    pub fn data_len(&self) -> usize {
+        self.num_emb * 5 * self.num_layers
+    }
+}
+
+/// Encodes a state with a header of its layout.
+///
+/// All numbers are little endian: the magic, the format version (`u32`), the layout
+/// version (length as `u8`, then UTF-8), `num_layers` and `num_emb` (`u32`), the count of
+/// floats (`u64`) and at last the floats (`f32`).
+pub fn encode_state(layout: &StateLayout, state: &State) -> Vec<u8> {
+    encode_state_chunks(layout, state, usize::MAX)
+        .flatten()
+        .collect()
+}
+
+/// Encodes a state like `encode_state`, but in pieces of at most `chunk_size` bytes of
+/// floats after the header, so the whole encoding is never held at once. The pieces
+/// joined are the same as `encode_state`.
+pub fn encode_state_chunks<'a>(
+    layout: &StateLayout,
+    state: &'a State,
+    chunk_size: usize,
+) -> impl Iterator<Item = Vec<u8>> + 'a {
+    let mut header = Vec::with_capacity(64);
+    header.extend_from_slice(MAGIC);
+    header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    header.push(layout.version.len() as u8);
+    header.extend_from_slice(layout.version.as_bytes());
+    header.extend_from_slice(&(layout.num_layers as u32).to_le_bytes());
+    header.extend_from_slice(&(layout.num_emb as u32).to_le_bytes());
+    header.extend_from_slice(&(state.len() as u64).to_le_bytes());
+    let floats = (chunk_size / 4).max(1);
+    std::iter::once(header).chain(
+        state
+            .0
+            .chunks(floats)
+            .map(|x| x.iter().flat_map(|x| x.to_le_bytes()).collect()),
+    )
+}
+
+/// Decodes a state saved by `encode_state`, which must match `layout`.
+pub fn decode_state(bytes: &[u8], layout: &StateLayout) -> Result<State> {
+    let mut reader = Reader(bytes);
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(CommandErrorKind::BadRequest.error("Not a saved state!"));
+    }
+    let format = u32::from_le_bytes(reader.take(4)?.try_into()?);
+    if format != FORMAT_VERSION {
+        return Err(CommandErrorKind::BadRequest.error(format!(
+            "Saved state has format version {}, but only version {} is supported!",
+            format, FORMAT_VERSION
+        )));
+    }
+    let version_len = reader.take(1)?[0] as usize;
+    let version = String::from_utf8_lossy(reader.take(version_len)?).into_owned();
+    let num_layers = u32::from_le_bytes(reader.take(4)?.try_into()?) as usize;
+    let num_emb = u32::from_le_bytes(reader.take(4)?.try_into()?) as usize;
+    let saved = StateLayout {
+        version,
+        num_layers,
+        num_emb,
+    };
+    if &saved != layout {
+        return Err(CommandErrorKind::BadRequest.error(format!(
+            "Saved state has layout {} with {} layers and embed size {}, but the model has layout {} with {} layers and embed size {}!",
+            saved.version,
+            saved.num_layers,
+            saved.num_emb,
+            layout.version,
+            layout.num_layers,
+            layout.num_emb
+        )));
+    }
+    let len = u64::from_le_bytes(reader.take(8)?.try_into()?) as usize;
+    if len != layout.data_len() {
+        return Err(CommandErrorKind::BadRequest.error(format!(
+            "Saved state has {} floats, but {} are expected!",
+            len,
+            layout.data_len()
+        )));
+    }
+    let data = reader
+        .take(len * 4)?
+        .chunks_exact(4)
+        .map(|x| f32::from_le_bytes([x[0], x[1], x[2], x[3]]))
+        .collect();
+    if !reader.0.is_empty() {
+        return Err(CommandErrorKind::BadRequest.error("Saved state has trailing bytes!"));
+    }
+    Ok(State(Arc::new(data)))
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.0.len() < len {
+            return Err(CommandErrorKind::BadRequest.error("Saved state is truncated!"));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+}
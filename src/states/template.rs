@@ -0,0 +1,133 @@
+use anyhow::Result;
+
+use crate::error::CommandErrorKind;
+use dashmap::DashMap;
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Variable(String),
+}
+
+/// A prompt template, where each `{{name}}` is replaced by the variable `name` when it's
+/// rendered. Spaces around the name are ignored.
+#[derive(Debug, Clone)]
+pub struct Template {
+    source: String,
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut rest = source;
+        while let Some(start) = rest.find("{{") {
+            if start > 0 {
+                parts.push(Part::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find("}}")
+                .ok_or(CommandErrorKind::BadRequest.error("Template has an unclosed {{!"))?;
+            let name = rest[start + 2..start + end].trim();
+            if name.is_empty() || !name.chars().all(|x| x.is_alphanumeric() || x == '_') {
+                return Err(CommandErrorKind::BadRequest.error(format!(
+                    "Template variable name \"{}\" should only contain letters, digits and _!",
+                    name
+                )));
+            }
+            parts.push(Part::Variable(name.to_string()));
+            rest = &rest[start + end + 2..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Text(rest.to_string()));
+        }
+        Ok(Self {
+            source: source.to_string(),
+            parts,
+        })
+    }
+
+    /// Names of the variables in the template, in order of first appearance.
+    pub fn variables(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = Vec::new();
+        for part in &self.parts {
+            if let Part::Variable(name) = part {
+                if !names.contains(&name.as_str()) {
+                    names.push(name);
+                }
+            }
+        }
+        names
+    }
+
+    /// Renders the template. Strings are inserted as they are, and other values as JSON.
+    /// Every variable in the template must be given, while extra ones are ignored.
+    pub fn render(&self, variables: &Map<String, Value>) -> Result<String> {
+        let mut text = String::with_capacity(self.source.len());
+        for part in &self.parts {
+            match part {
+                Part::Text(x) => text.push_str(x),
+                Part::Variable(name) => match variables.get(name) {
+                    Some(Value::String(x)) => text.push_str(x),
+                    Some(x) => text.push_str(&x.to_string()),
+                    None => {
+                        return Err(CommandErrorKind::BadRequest
+                            .error(format!("Template variable {} is not given!", name)))
+                    }
+                },
+            }
+        }
+        Ok(text)
+    }
+}
+
+pub struct Templates {
+    map: DashMap<String, Template>,
+}
+
+impl Templates {
+    pub fn new() -> Self {
+        Self {
+            map: DashMap::with_capacity(128),
+        }
+    }
+
+    pub fn create_template(&self, id: String, source: &str) -> Result<()> {
+        if self.map.contains_key(&id) {
+            return Err(CommandErrorKind::AlreadyExists.error("Template already existed!"));
+        }
+        self.map.insert(id, Template::parse(source)?);
+        Ok(())
+    }
+
+    pub fn delete_template(&self, id: &str) -> Result<()> {
+        self.map
+            .remove(id)
+            .ok_or(CommandErrorKind::TemplateNotFound.error("Template id doesn't exist!"))
+            .map(|_| ())
+    }
+
+    /// The source of a template and its variables.
+    pub fn describe_template(&self, id: &str) -> Result<(String, Vec<String>)> {
+        let template = self
+            .map
+            .get(id)
+            .ok_or(CommandErrorKind::TemplateNotFound.error("Template id doesn't exist!"))?;
+        Ok((
+            template.source.clone(),
+            template
+                .variables()
+                .into_iter()
+                .map(str::to_string)
+                .collect(),
+        ))
+    }
+
+    pub fn render(&self, id: &str, variables: &Map<String, Value>) -> Result<String> {
+        self.map
+            .get(id)
+            .ok_or(CommandErrorKind::TemplateNotFound.error("Template id doesn't exist!"))?
+            .render(variables)
+    }
+}
@@ -0,0 +1,222 @@
+use anyhow::Result;
+use serde_json::{Map, Value};
+
+use crate::{app::AppState, error::CommandErrorKind};
+
+use super::types::{Terminal, Termination};
+
+/// A node of the expression tree in a `CompositeTerminal`.
+#[derive(Debug)]
+pub enum TerminalNode {
+    /// Fires when any of the children fires.
+    Any(Vec<TerminalNode>),
+    /// Fires when all of the children fire.
+    All(Vec<TerminalNode>),
+    /// Fires when the child doesn't fire.
+    Not(Box<TerminalNode>),
+    Leaf(Box<dyn Terminal>),
+}
+
+impl TerminalNode {
+    /// Parses a node from JSON. A node is one of:
+    ///
+    /// - `{"any": [node, ...]}`
+    /// - `{"all": [node, ...]}`
+    /// - `{"not": node}`
+    /// - `{"type_id": ..., "params": ...}`, which is constructed as a child terminal.
+    fn parse(state: &AppState, data: Value) -> Result<Self> {
+        let mut object = match data {
+            Value::Object(object) => object,
+            _ => {
+                return Err(
+                    CommandErrorKind::BadRequest.error("Composite terminal nodes must be objects!")
+                )
+            }
+        };
+        if let Some(children) = take_only(&mut object, "any")? {
+            Ok(Self::Any(Self::parse_list(state, children)?))
+        } else if let Some(children) = take_only(&mut object, "all")? {
+            Ok(Self::All(Self::parse_list(state, children)?))
+        } else if let Some(child) = take_only(&mut object, "not")? {
+            Ok(Self::Not(Box::new(Self::parse(state, child)?)))
+        } else {
+            Ok(Self::Leaf(
+                state
+                    .0
+                    .terminals
+                    .construct(state.clone(), Value::Object(object))?,
+            ))
+        }
+    }
+
+    fn parse_list(state: &AppState, data: Value) -> Result<Vec<Self>> {
+        match data {
+            Value::Array(children) if !children.is_empty() => children
+                .into_iter()
+                .map(|child| Self::parse(state, child))
+                .collect(),
+            _ => Err(CommandErrorKind::BadRequest
+                .error("any/all in composite terminal must be a non-empty list!")),
+        }
+    }
+
+    /// Returns `Some` if the node fires.
+    fn terminate(&mut self, result: &Vec<u16>) -> Result<Option<Termination>> {
+        match self {
+            Self::Any(children) => {
+                for child in children {
+                    if let Some(termination) = child.terminate(result)? {
+                        return Ok(Some(termination));
+                    }
+                }
+                Ok(None)
+            }
+            Self::All(children) => {
+                // Reports the reason of the first child, with the longest trim
+                let mut fired: Option<Termination> = None;
+                for child in children {
+                    match child.terminate(result)? {
+                        Some(termination) => {
+                            let fired = fired.get_or_insert(termination);
+                            fired.trim = fired.trim.max(termination.trim);
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                Ok(fired)
+            }
+            Self::Not(child) => Ok(match child.terminate(result)? {
+                Some(_) => None,
+                None => Some(Termination {
+                    reason: "terminal",
+                    trim: 0,
+                }),
+            }),
+            Self::Leaf(terminal) => Ok(terminal.terminate(result)?.then(|| Termination {
+                reason: terminal.reason(),
+                trim: terminal.trim(),
+            })),
+        }
+    }
+
+    /// The longest holdback among all terminals in the node.
+    fn holdback(&self) -> usize {
+        match self {
+            Self::Any(children) | Self::All(children) => children
+                .iter()
+                .map(|child| child.holdback())
+                .max()
+                .unwrap_or_default(),
+            Self::Not(child) => child.holdback(),
+            Self::Leaf(terminal) => terminal.holdback(),
+        }
+    }
+
+    fn arm(&mut self) {
+        match self {
+            Self::Any(children) | Self::All(children) => {
+                children.iter_mut().for_each(|child| child.arm())
+            }
+            Self::Not(child) => child.arm(),
+            Self::Leaf(terminal) => terminal.arm(),
+        }
+    }
+
+    fn clear(&mut self) {
+        match self {
+            Self::Any(children) | Self::All(children) => {
+                children.iter_mut().for_each(|child| child.clear())
+            }
+            Self::Not(child) => child.clear(),
+            Self::Leaf(terminal) => terminal.clear(),
+        }
+    }
+}
+
+impl Clone for TerminalNode {
+    fn clone(&self) -> Self {
+        match self {
+            Self::Any(children) => Self::Any(children.clone()),
+            Self::All(children) => Self::All(children.clone()),
+            Self::Not(child) => Self::Not(child.clone()),
+            Self::Leaf(terminal) => Self::Leaf(terminal.as_ref().clone()),
+        }
+    }
+}
+
+/// Takes `key` out of `object` if present, in which case it must be the only key.
+fn take_only(object: &mut Map<String, Value>, key: &str) -> Result<Option<Value>> {
+    match object.remove(key) {
+        Some(value) if object.is_empty() => Ok(Some(value)),
+        Some(_) => Err(CommandErrorKind::BadRequest.error(format!(
+            "A composite terminal node with {} must not have other fields!",
+            key
+        ))),
+        None => Ok(None),
+    }
+}
+
+/// Combines child terminals with `any` / `all` / `not`.
+///
+/// The children are constructed and owned by the composite, so they are not visible
+/// by their own ids.
+///
+/// Children are checked in order and short-circuited, so a child may not be
+/// checked for every token.
+#[derive(Debug)]
+pub struct CompositeTerminal {
+    root: TerminalNode,
+    fired: Option<Termination>,
+}
+
+impl CompositeTerminal {
+    pub fn new(root: TerminalNode) -> Self {
+        Self { root, fired: None }
+    }
+}
+
+impl Terminal for CompositeTerminal {
+    fn terminate(&mut self, result: &Vec<u16>) -> Result<bool> {
+        self.fired = self.root.terminate(result)?;
+        Ok(self.fired.is_some())
+    }
+
+    fn trim(&self) -> usize {
+        self.fired.map(|x| x.trim).unwrap_or_default()
+    }
+
+    fn holdback(&self) -> usize {
+        self.root.holdback()
+    }
+
+    fn reason(&self) -> &'static str {
+        self.fired.map(|x| x.reason).unwrap_or("terminal")
+    }
+
+    fn arm(&mut self) {
+        self.fired = None;
+        self.root.arm()
+    }
+
+    fn clear(&mut self) {
+        self.fired = None;
+        self.root.clear()
+    }
+
+    fn clone(&self) -> Box<dyn Terminal> {
+        Box::new(Self {
+            root: self.root.clone(),
+            fired: self.fired,
+        })
+    }
+}
+
+pub fn initialize_composite(state: AppState, data: Option<Value>) -> Result<Box<dyn Terminal>> {
+    let data = data.ok_or(
+        CommandErrorKind::BadRequest
+            .error("Field must present to specify the terminal expression!"),
+    )?;
+    Ok(Box::new(CompositeTerminal::new(TerminalNode::parse(
+        &state, data,
+    )?)))
+}
@@ -0,0 +1,153 @@
+use self::types::{Terminal, Termination};
+use crate::{app::AppState, error::CommandErrorKind, hashmap_ex};
+use anyhow::{Ok, Result};
+use dashmap::DashMap;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+use super::component::Component;
+
+pub mod composite;
+pub mod newline;
+pub mod regex_stop;
+pub mod repetition;
+pub mod stop_string;
+pub mod timeout;
+pub mod token_set;
+pub mod types;
+
+#[derive(Debug, Deserialize)]
+struct TerminalJson {
+    type_id: String,
+    params: Option<Value>,
+}
+
+pub struct Terminals {
+    registry: HashMap<&'static str, fn(AppState, Option<Value>) -> Result<Box<dyn Terminal>>>,
+    map: DashMap<String, Component<Box<dyn Terminal>>>,
+}
+
+impl Terminals {
+    pub fn new() -> Self {
+        Self {
+            registry: hashmap_ex! {
+                HashMap<&'static str, fn(AppState, Option<Value>) -> Result<Box<dyn Terminal>>>,
+                    {
+                        "token_set" => token_set::initialize_token_set,
+                        "composite" => composite::initialize_composite,
+                        "timeout" => timeout::initialize_timeout,
+                        "newline" => newline::initialize_newline,
+                        "stop_string" => stop_string::initialize_stop_string,
+                        "repetition" => repetition::initialize_repetition,
+                        "regex_stop" => regex_stop::initialize_regex_stop,
+                    }
+            },
+            map: DashMap::with_capacity(128),
+        }
+    }
+
+    fn create(&self, key: &str, state: AppState, data: Option<Value>) -> Result<Box<dyn Terminal>> {
+        let constructor = self.registry.get(key);
+        if let Some(constructor) = constructor {
+            Ok(constructor(state, data)?)
+        } else {
+            Err(CommandErrorKind::TerminalNotFound.error("Terminal not found!"))
+        }
+    }
+
+    /// Constructs a terminal from its `type_id` and `params` without registering it.
+    pub fn construct(&self, state: AppState, data: Value) -> Result<Box<dyn Terminal>> {
+        let TerminalJson { type_id, params } = serde_json::from_value::<TerminalJson>(data)?;
+        self.create(&type_id, state, params)
+    }
+
+    pub fn create_terminal(&self, id: String, state: AppState, data: Value) -> Result<()> {
+        if self.map.contains_key(&id) {
+            return Err(CommandErrorKind::AlreadyExists.error("Terminal already existed!"));
+        }
+        let terminal = self.construct(state, data.clone())?;
+        self.map.insert(id, Component::new(terminal, data));
+        Ok(())
+    }
+
+    #[inline(always)]
+    pub fn has_terminal(&self, id: &str) -> bool {
+        self.map.contains_key(id)
+    }
+
+    pub fn delete_terminal(&self, id: &str) -> Result<()> {
+        self.map
+            .remove(id)
+            .ok_or(CommandErrorKind::TerminalNotFound.error("Terminal id doesn't exist!"))
+            .map(|_| ())
+    }
+
+    pub fn reset_terminal(&self, id: &str) -> Result<()> {
+        if let Some(mut terminal) = self.map.get_mut(id) {
+            terminal.clear();
+            Ok(())
+        } else {
+            Err(CommandErrorKind::TerminalNotFound.error("Terminal id doesn't exist!"))
+        }
+    }
+
+    pub fn copy_terminal(&self, src: String, dst: String) -> Result<()> {
+        if self.map.contains_key(&dst) {
+            return Err(
+                CommandErrorKind::AlreadyExists.error("Destination terminal id already exists!")
+            );
+        }
+        let src = self
+            .map
+            .get(&src)
+            .map(|x| Component::new(x.inner.clone(), x.definition.clone()))
+            .ok_or(CommandErrorKind::TerminalNotFound.error("Terminal doesn't exist!"))?;
+        self.map.insert(dst, src);
+        Ok(())
+    }
+
+    /// The definitions of all terminals by id, which create them again.
+    pub fn definitions(&self) -> Map<String, Value> {
+        self.map
+            .iter()
+            .map(|x| (x.key().clone(), x.definition.clone()))
+            .collect()
+    }
+
+    /// Ids of all terminals, sorted.
+    pub fn ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.map.iter().map(|x| x.key().clone()).collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    pub fn arm_terminal(&self, id: &str) -> Result<()> {
+        if let Some(mut terminal) = self.map.get_mut(id) {
+            terminal.arm();
+            Ok(())
+        } else {
+            Err(CommandErrorKind::TerminalNotFound.error("Terminal id doesn't exist!"))
+        }
+    }
+
+    pub fn holdback(&self, id: &str) -> Result<usize> {
+        if let Some(terminal) = self.map.get(id) {
+            Ok(terminal.holdback())
+        } else {
+            Err(CommandErrorKind::TerminalNotFound.error("Terminal id doesn't exist!"))
+        }
+    }
+
+    /// Returns `Some` if the terminal fires.
+    pub fn terminate(&self, id: &str, result: &Vec<u16>) -> Result<Option<Termination>> {
+        if let Some(mut terminal) = self.map.get_mut(id) {
+            Ok(terminal.terminate(result)?.then(|| Termination {
+                reason: terminal.reason(),
+                trim: terminal.trim(),
+            }))
+        } else {
+            Err(CommandErrorKind::TerminalNotFound.error("Terminal id doesn't exist!"))
+        }
+    }
+}
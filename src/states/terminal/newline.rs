@@ -0,0 +1,117 @@
+use std::{fmt::Debug, sync::Arc};
+
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::Value;
+use web_rwkv::tokenizer::Tokenizer;
+
+use crate::{app::AppState, error::CommandErrorKind};
+
+use super::types::Terminal;
+
+fn default_newlines() -> usize {
+    1
+}
+
+/// Counts newlines in decoded text.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewlineCounter {
+    /// How many newlines should be met before stopping.
+    #[serde(default = "default_newlines")]
+    pub newlines: usize,
+    /// Ignore newlines before any non-whitespace output.
+    #[serde(default)]
+    pub skip_leading: bool,
+    /// Keep the last newline in the output.
+    #[serde(default)]
+    pub include: bool,
+}
+
+impl NewlineCounter {
+    /// Finds the byte position of the newline which should stop the inference.
+    pub fn find(&self, text: &[u8]) -> Option<usize> {
+        let mut count = 0;
+        let mut leading = self.skip_leading;
+        for (index, byte) in text.iter().enumerate() {
+            match byte {
+                b'\n' if !leading => {
+                    count += 1;
+                    if count >= self.newlines {
+                        return Some(index);
+                    }
+                }
+                byte if !byte.is_ascii_whitespace() => leading = false,
+                _ => (),
+            }
+        }
+        None
+    }
+
+    /// Bytes to trim from the end of `text` if it should stop the inference.
+    pub fn trim(&self, text: &[u8]) -> Option<usize> {
+        self.find(text)
+            .map(|index| text.len() - index - self.include as usize)
+    }
+}
+
+/// Stops the inference after a number of newlines are generated.
+#[derive(Clone)]
+pub struct NewlineTerminal {
+    counter: NewlineCounter,
+    tokenizer: Arc<Tokenizer>,
+    trim: usize,
+}
+
+impl Debug for NewlineTerminal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NewlineTerminal")
+            .field("counter", &self.counter)
+            .field("trim", &self.trim)
+            .finish()
+    }
+}
+
+impl Terminal for NewlineTerminal {
+    fn terminate(&mut self, result: &Vec<u16>) -> Result<bool> {
+        let text = self.tokenizer.decode(result)?;
+        match self.counter.trim(&text) {
+            Some(trim) => {
+                self.trim = trim;
+                Ok(true)
+            }
+            None => {
+                self.trim = 0;
+                Ok(false)
+            }
+        }
+    }
+
+    fn trim(&self) -> usize {
+        self.trim
+    }
+
+    fn arm(&mut self) {
+        self.trim = 0;
+    }
+
+    fn clear(&mut self) {
+        self.trim = 0;
+    }
+
+    fn clone(&self) -> Box<dyn Terminal> {
+        Box::new(Clone::clone(self))
+    }
+}
+
+pub fn initialize_newline(state: AppState, data: Option<Value>) -> Result<Box<dyn Terminal>> {
+    let counter: NewlineCounter =
+        serde_json::from_value(data.unwrap_or(Value::Object(Default::default())))?;
+    if counter.newlines == 0 {
+        return Err(CommandErrorKind::BadRequest.error("newlines must be at least 1!"));
+    }
+    Ok(Box::new(NewlineTerminal {
+        counter,
+        tokenizer: state.0.tokenizer.clone(),
+        trim: 0,
+    }))
+}
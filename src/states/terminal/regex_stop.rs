@@ -0,0 +1,98 @@
+use std::{fmt::Debug, sync::Arc};
+
+use anyhow::Result;
+use regex::bytes::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use web_rwkv::tokenizer::Tokenizer;
+
+use crate::{app::AppState, error::CommandErrorKind};
+
+use super::types::Terminal;
+
+#[derive(Debug, Deserialize)]
+struct RegexData {
+    pattern: String,
+    #[serde(default)]
+    capture_only: bool,
+}
+
+/// Stops the inference once the decoded output matches a regex. The output is kept as it
+/// is, or cut right after the first match if `capture_only`.
+///
+/// The regex is matched against the decoded bytes after every token, so it stops at the
+/// first token completing a match, e.g. `\d+` stops at the first digit.
+#[derive(Clone)]
+pub struct RegexTerminal {
+    regex: Regex,
+    capture_only: bool,
+    tokenizer: Arc<Tokenizer>,
+    trim: usize,
+}
+
+impl Debug for RegexTerminal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RegexTerminal")
+            .field("regex", &self.regex)
+            .field("capture_only", &self.capture_only)
+            .field("trim", &self.trim)
+            .finish()
+    }
+}
+
+impl Terminal for RegexTerminal {
+    fn terminate(&mut self, result: &Vec<u16>) -> Result<bool> {
+        let text = self.tokenizer.decode(result)?;
+        match self.regex.find(&text) {
+            Some(found) => {
+                self.trim = match self.capture_only {
+                    true => text.len() - found.end(),
+                    false => 0,
+                };
+                Ok(true)
+            }
+            None => {
+                self.trim = 0;
+                Ok(false)
+            }
+        }
+    }
+
+    fn trim(&self) -> usize {
+        self.trim
+    }
+
+    fn reason(&self) -> &'static str {
+        "regex"
+    }
+
+    fn arm(&mut self) {
+        self.trim = 0;
+    }
+
+    fn clear(&mut self) {
+        self.trim = 0;
+    }
+
+    fn clone(&self) -> Box<dyn Terminal> {
+        Box::new(Clone::clone(self))
+    }
+}
+
+pub fn initialize_regex_stop(state: AppState, data: Option<Value>) -> Result<Box<dyn Terminal>> {
+    let RegexData {
+        pattern,
+        capture_only,
+    } = serde_json::from_value(
+        data.ok_or(CommandErrorKind::BadRequest.error("Field must present to specify pattern!"))?,
+    )?;
+    let regex = Regex::new(&pattern).map_err(|e| {
+        CommandErrorKind::BadRequest.error(format!("Invalid regex {:?}: {}", pattern, e))
+    })?;
+    Ok(Box::new(RegexTerminal {
+        regex,
+        capture_only,
+        tokenizer: state.0.tokenizer.clone(),
+        trim: 0,
+    }))
+}
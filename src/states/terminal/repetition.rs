@@ -0,0 +1,70 @@
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{app::AppState, error::CommandErrorKind};
+
+use super::types::Terminal;
+
+fn default_ngram() -> usize {
+    1
+}
+
+/// Stops the inference once the model loops on the same token or n-gram, as a safety net
+/// independent of the token limit.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RepetitionTerminal {
+    /// Fires once the last n-gram occurs more than this many times in a row.
+    token_repeats: usize,
+    #[serde(default = "default_ngram")]
+    ngram: usize,
+}
+
+impl RepetitionTerminal {
+    pub fn new(token_repeats: usize, ngram: usize) -> Self {
+        Self {
+            token_repeats,
+            ngram,
+        }
+    }
+
+    /// Counts how many times the last `ngram` tokens of `result` occur consecutively at
+    /// its end.
+    pub fn repeats(&self, result: &[u16]) -> usize {
+        let n = self.ngram;
+        if n == 0 || result.len() < n {
+            return 0;
+        }
+        let tail = &result[result.len() - n..];
+        result
+            .rchunks_exact(n)
+            .take_while(|chunk| *chunk == tail)
+            .count()
+    }
+}
+
+impl Terminal for RepetitionTerminal {
+    fn terminate(&mut self, result: &Vec<u16>) -> Result<bool> {
+        Ok(self.repeats(result) > self.token_repeats)
+    }
+
+    fn reason(&self) -> &'static str {
+        "repetition"
+    }
+
+    fn clear(&mut self) {}
+
+    fn clone(&self) -> Box<dyn Terminal> {
+        Box::new(Clone::clone(self))
+    }
+}
+
+pub fn initialize_repetition(_state: AppState, data: Option<Value>) -> Result<Box<dyn Terminal>> {
+    let terminal: RepetitionTerminal = serde_json::from_value(data.ok_or(
+        CommandErrorKind::BadRequest.error("Field must present to specify token_repeats!"),
+    )?)?;
+    if terminal.ngram == 0 {
+        return Err(CommandErrorKind::BadRequest.error("ngram must be at least 1!"));
+    }
+    Ok(Box::new(terminal))
+}
@@ -0,0 +1,131 @@
+use std::{fmt::Debug, sync::Arc};
+
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::Value;
+use web_rwkv::tokenizer::Tokenizer;
+
+use crate::{app::AppState, error::CommandErrorKind};
+
+use super::types::Terminal;
+
+#[derive(Debug, Deserialize)]
+struct StopStringData {
+    strings: Vec<String>,
+}
+
+/// Matches stop strings in decoded text at byte level, so stop strings split across
+/// tokens (or inside a multi-byte character) are still matched.
+#[derive(Debug, Clone)]
+pub struct StopMatcher {
+    strings: Vec<Vec<u8>>,
+}
+
+impl StopMatcher {
+    pub fn new(strings: Vec<String>) -> Result<Self> {
+        if strings.is_empty() || strings.iter().any(|x| x.is_empty()) {
+            return Err(CommandErrorKind::BadRequest
+                .error("Stop strings must be a non-empty list of non-empty strings!"));
+        }
+        Ok(Self {
+            strings: strings.into_iter().map(String::into_bytes).collect(),
+        })
+    }
+
+    /// Finds the byte position where the earliest complete stop string starts.
+    pub fn find(&self, text: &[u8]) -> Option<usize> {
+        self.strings
+            .iter()
+            .filter_map(|x| text.windows(x.len()).position(|window| window == x))
+            .min()
+    }
+
+    /// Bytes to trim from the end of `text` if it should stop the inference.
+    pub fn trim(&self, text: &[u8]) -> Option<usize> {
+        self.find(text).map(|index| text.len() - index)
+    }
+
+    /// Length of the longest suffix of `text` which is a proper prefix of any stop string.
+    ///
+    /// These bytes may become a stop string with more tokens, so they should be held back
+    /// instead of streamed to the client.
+    pub fn holdback(&self, text: &[u8]) -> usize {
+        self.strings
+            .iter()
+            .filter_map(|x| (1..x.len()).rev().find(|&len| text.ends_with(&x[..len])))
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+/// Stops the inference once any of the stop strings is generated, and excludes the stop
+/// string (with anything after it) from the output.
+#[derive(Clone)]
+pub struct StopStringTerminal {
+    matcher: StopMatcher,
+    tokenizer: Arc<Tokenizer>,
+    trim: usize,
+    holdback: usize,
+}
+
+impl Debug for StopStringTerminal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StopStringTerminal")
+            .field("matcher", &self.matcher)
+            .field("trim", &self.trim)
+            .field("holdback", &self.holdback)
+            .finish()
+    }
+}
+
+impl Terminal for StopStringTerminal {
+    fn terminate(&mut self, result: &Vec<u16>) -> Result<bool> {
+        let text = self.tokenizer.decode(result)?;
+        match self.matcher.trim(&text) {
+            Some(trim) => {
+                self.trim = trim;
+                self.holdback = 0;
+                Ok(true)
+            }
+            None => {
+                self.trim = 0;
+                self.holdback = self.matcher.holdback(&text);
+                Ok(false)
+            }
+        }
+    }
+
+    fn trim(&self) -> usize {
+        self.trim
+    }
+
+    fn holdback(&self) -> usize {
+        self.holdback
+    }
+
+    fn arm(&mut self) {
+        self.trim = 0;
+        self.holdback = 0;
+    }
+
+    fn clear(&mut self) {
+        self.trim = 0;
+        self.holdback = 0;
+    }
+
+    fn clone(&self) -> Box<dyn Terminal> {
+        Box::new(Clone::clone(self))
+    }
+}
+
+pub fn initialize_stop_string(state: AppState, data: Option<Value>) -> Result<Box<dyn Terminal>> {
+    let StopStringData { strings } = serde_json::from_value(
+        data.ok_or(CommandErrorKind::BadRequest.error("Field must present to specify strings!"))?,
+    )?;
+    Ok(Box::new(StopStringTerminal {
+        matcher: StopMatcher::new(strings)?,
+        tokenizer: state.0.tokenizer.clone(),
+        trim: 0,
+        holdback: 0,
+    }))
+}
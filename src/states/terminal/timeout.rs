@@ -0,0 +1,66 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{app::AppState, error::CommandErrorKind};
+
+use super::types::Terminal;
+
+#[derive(Debug, Deserialize)]
+struct TimeoutData {
+    timeout_ms: u64,
+}
+
+/// Stops the inference once it has run for longer than a time budget.
+///
+/// The clock starts when the terminal is armed at the start of an infer request, or
+/// at the first `terminate` if it is never armed.
+#[derive(Debug, Clone)]
+pub struct TimeoutTerminal {
+    timeout: Duration,
+    start: Option<Instant>,
+}
+
+impl TimeoutTerminal {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            start: None,
+        }
+    }
+}
+
+impl Terminal for TimeoutTerminal {
+    fn terminate(&mut self, _result: &Vec<u16>) -> Result<bool> {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        Ok(start.elapsed() >= self.timeout)
+    }
+
+    fn reason(&self) -> &'static str {
+        "timeout"
+    }
+
+    fn arm(&mut self) {
+        self.start = Some(Instant::now());
+    }
+
+    fn clear(&mut self) {
+        self.start = None;
+    }
+
+    fn clone(&self) -> Box<dyn Terminal> {
+        Box::new(Clone::clone(self))
+    }
+}
+
+pub fn initialize_timeout(_state: AppState, data: Option<Value>) -> Result<Box<dyn Terminal>> {
+    let TimeoutData { timeout_ms } =
+        serde_json::from_value(data.ok_or(
+            CommandErrorKind::BadRequest.error("Field must present to specify timeout_ms!"),
+        )?)?;
+    Ok(Box::new(TimeoutTerminal::new(Duration::from_millis(
+        timeout_ms,
+    ))))
+}
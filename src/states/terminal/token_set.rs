@@ -0,0 +1,82 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{app::AppState, error::CommandErrorKind};
+
+use super::types::Terminal;
+
+#[derive(Debug, Deserialize)]
+struct TokenSetData {
+    #[serde(default)]
+    tokens: Vec<u16>,
+    #[serde(default)]
+    strings: Vec<String>,
+    #[serde(default)]
+    at_least: usize,
+}
+
+/// Stops the inference once the last sampled token is in a set of tokens.
+///
+/// Usually used for the end-of-text token `0`.
+#[derive(Debug, Clone)]
+pub struct TokenSetTerminal {
+    tokens: HashSet<u16>,
+    at_least: usize,
+}
+
+impl TokenSetTerminal {
+    pub fn new(tokens: impl IntoIterator<Item = u16>, at_least: usize) -> Self {
+        Self {
+            tokens: tokens.into_iter().collect(),
+            at_least,
+        }
+    }
+}
+
+impl Terminal for TokenSetTerminal {
+    fn terminate(&mut self, result: &Vec<u16>) -> Result<bool> {
+        if result.len() <= self.at_least {
+            return Ok(false);
+        }
+        Ok(result
+            .last()
+            .map(|token| self.tokens.contains(token))
+            .unwrap_or(false))
+    }
+
+    fn clear(&mut self) {}
+
+    fn clone(&self) -> Box<dyn Terminal> {
+        Box::new(Clone::clone(self))
+    }
+}
+
+pub fn initialize_token_set(state: AppState, data: Option<Value>) -> Result<Box<dyn Terminal>> {
+    let TokenSetData {
+        mut tokens,
+        strings,
+        at_least,
+    } = serde_json::from_value(data.ok_or(
+        CommandErrorKind::BadRequest.error("Field must present to specify tokens or strings!"),
+    )?)?;
+    for string in strings {
+        let encoded = state.tokenize(&string.as_bytes().to_vec())?;
+        if encoded.len() != 1 {
+            return Err(CommandErrorKind::BadRequest.error(format!(
+                "{:?} is encoded to {} tokens, but only single-token strings are accepted! Use a stop-string terminal instead.",
+                string,
+                encoded.len()
+            )));
+        }
+        tokens.extend(encoded);
+    }
+    if tokens.is_empty() {
+        return Err(
+            CommandErrorKind::BadRequest.error("At least one token or string must be specified!")
+        );
+    }
+    Ok(Box::new(TokenSetTerminal::new(tokens, at_least)))
+}
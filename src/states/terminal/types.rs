@@ -0,0 +1,71 @@
+use anyhow::Result;
+use std::fmt::Debug;
+
+/// Decides whether an inference should stop.
+///
+/// A terminal is checked after every sampled token, and the generation stops once it
+/// returns `true`.
+///
+/// #### Registration
+///
+/// A terminal type needs to be registered before it can be constructed by the Websocket
+/// API.
+///
+/// To register a terminal, put the type_id (a literal string) with the constructor (which
+/// is a `Fn(SharedState, Option<Value>)->Result<Box<dyn Terminal>>`) in the `new()` of
+/// `Terminals`.
+///
+/// Refer to `TokenSetTerminal` for a complete example of terminal implementation.
+pub trait Terminal: Send + Sync + Debug {
+    /// Checks if the inference should be terminated.
+    ///
+    /// `result` contains all tokens sampled in the current infer request, the last one
+    /// being the most recently sampled token.
+    ///
+    /// Returning an `Err` aborts the inference with that error.
+    fn terminate(&mut self, result: &Vec<u16>) -> Result<bool>;
+
+    /// How many bytes should be trimmed from the end of the decoded result after the
+    /// terminal fires, so the text that triggers the terminal can be excluded from the
+    /// output. Defaults to 0.
+    fn trim(&self) -> usize {
+        0
+    }
+
+    /// How many bytes at the end of the decoded result should be held back from streaming
+    /// while the terminal hasn't fired, because they may turn out to be trimmed once more
+    /// tokens are generated. Held back bytes are flushed if the terminal doesn't fire after
+    /// all. Defaults to 0.
+    fn holdback(&self) -> usize {
+        0
+    }
+
+    /// The stop reason reported in the infer response after the terminal fires.
+    /// Defaults to `terminal`.
+    fn reason(&self) -> &'static str {
+        "terminal"
+    }
+
+    /// Arms the terminal at the start of every infer request.
+    ///
+    /// A terminal is reused by infer requests, so anything counted per infer request (like
+    /// elapsed time) should be reset here instead of in `clear`. Does nothing by default.
+    fn arm(&mut self) {}
+
+    /// Clears the `Terminal`. This will reset the internal state of the terminal to *when it*
+    /// *is just constructed from params*.
+    fn clear(&mut self);
+
+    /// Copies the internal state (no matter if it's from construction or temporal calculation),
+    /// and construct a new `Terminal` from the state.
+    fn clone(&self) -> Box<dyn Terminal>;
+}
+
+/// Describes how a terminal stopped the inference.
+#[derive(Debug, Clone, Copy)]
+pub struct Termination {
+    /// The stop reason, see `Terminal::reason`.
+    pub reason: &'static str,
+    /// Bytes to trim from the end of the decoded result, see `Terminal::trim`.
+    pub trim: usize,
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{app::AppState, error::CommandErrorKind, states::InferenceInterruption};
+
+use super::types::Transformer;
+
+/// Upper bound of the penalty, so long matches can't push logits to infinity.
+const MAX_PENALTY: f32 = 1e4;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct DryData {
+    multiplier: f32,
+    base: f32,
+    allowed_length: usize,
+}
+
+/// "Don't Repeat Yourself" penalty.
+///
+/// Penalizes the tokens which would extend a sequence repeated from the history, by
+/// `multiplier * base^(match_len - allowed_length)`, where `match_len` is the length of
+/// the repeated sequence.
+#[derive(Debug, Clone)]
+pub struct DryTransformer {
+    data: DryData,
+    history: Vec<u16>,
+}
+
+impl DryTransformer {
+    pub fn new(multiplier: f32, base: f32, allowed_length: usize) -> Self {
+        Self {
+            data: DryData {
+                multiplier,
+                base,
+                allowed_length,
+            },
+            history: Vec::new(),
+        }
+    }
+
+    /// Computes the penalty of each token which would continue a repeated sequence.
+    pub fn penalties(&self) -> HashMap<u16, f32> {
+        let mut penalties = HashMap::new();
+        let len = self.history.len();
+        if len < 2 {
+            return penalties;
+        }
+
+        // `z[k]` is the length of the longest common suffix of the whole history and the
+        // history ending at `len - 1 - k`, from the Z-function of the reversed history.
+        let reversed: Vec<u16> = self.history.iter().rev().copied().collect();
+        let z = z_function(&reversed);
+        for (k, &match_len) in z.iter().enumerate().skip(1) {
+            if match_len == 0 || match_len < self.data.allowed_length {
+                continue;
+            }
+            let token = self.history[len - k];
+            let exponent = (match_len - self.data.allowed_length) as f32;
+            let scale = self.data.base.powf(exponent).min(MAX_PENALTY);
+            let penalty = (self.data.multiplier * scale).min(MAX_PENALTY);
+            let entry = penalties.entry(token).or_insert(0.0);
+            *entry = f32::max(*entry, penalty);
+        }
+        penalties
+    }
+}
+
+fn z_function(tokens: &[u16]) -> Vec<usize> {
+    let n = tokens.len();
+    let mut z = vec![0; n];
+    let (mut left, mut right) = (0, 0);
+    for i in 1..n {
+        if i < right {
+            z[i] = usize::min(right - i, z[i - left]);
+        }
+        while i + z[i] < n && tokens[z[i]] == tokens[i + z[i]] {
+            z[i] += 1;
+        }
+        if i + z[i] > right {
+            left = i;
+            right = i + z[i];
+        }
+    }
+    z
+}
+
+impl Transformer for DryTransformer {
+    fn update(&mut self, prompt: &Vec<u16>) -> Result<(), InferenceInterruption> {
+        self.history.extend_from_slice(prompt);
+        Ok(())
+    }
+
+    fn transform(&self, mut logits: Vec<f32>) -> Vec<f32> {
+        for (token, penalty) in self.penalties() {
+            if let Some(logit) = logits.get_mut(token as usize) {
+                *logit -= penalty;
+            }
+        }
+        logits
+    }
+
+    fn describe(&self) -> Value {
+        json!({
+            "history_length": self.history.len(),
+            "penalties": self.penalties(),
+        })
+    }
+
+    fn clear(&mut self) {
+        self.history.clear();
+    }
+
+    fn clone(&self) -> Box<dyn Transformer> {
+        Box::new(Clone::clone(self))
+    }
+}
+
+pub fn initialize_dry(_state: AppState, data: Option<Value>) -> Result<Box<dyn Transformer>> {
+    let data: DryData = serde_json::from_value(
+        data.ok_or(
+            CommandErrorKind::BadRequest
+                .error("Field must present to specify multiplier, base and allowed_length!"),
+        )?,
+    )?;
+    if !data.multiplier.is_finite() || data.multiplier < 0.0 {
+        return Err(CommandErrorKind::BadRequest.error("multiplier must be a non-negative number!"));
+    }
+    if !data.base.is_finite() || data.base < 1.0 {
+        return Err(CommandErrorKind::BadRequest.error("base must be at least 1!"));
+    }
+    Ok(Box::new(DryTransformer {
+        data,
+        history: Vec::new(),
+    }))
+}
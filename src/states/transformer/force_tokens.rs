@@ -0,0 +1,84 @@
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{app::AppState, error::CommandErrorKind, states::InferenceInterruption};
+
+use super::types::Transformer;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ForceTokensData {
+    tokens: Vec<u16>,
+}
+
+/// Forces a fixed sequence of tokens, e.g. the opening of a JSON answer, before free
+/// generation.
+///
+/// While tokens are left, every logit except the one of the next forced token is disabled,
+/// and the token is popped once it's fed back. Afterwards the logits pass through.
+#[derive(Debug, Clone)]
+pub struct ForceTokensTransformer {
+    data: ForceTokensData,
+    queue: VecDeque<u16>,
+}
+
+impl ForceTokensTransformer {
+    pub fn new(tokens: Vec<u16>) -> Self {
+        Self {
+            queue: tokens.iter().copied().collect(),
+            data: ForceTokensData { tokens },
+        }
+    }
+
+    /// The tokens still to be forced, in order.
+    pub fn remaining(&self) -> &VecDeque<u16> {
+        &self.queue
+    }
+}
+
+impl Transformer for ForceTokensTransformer {
+    fn update(&mut self, prompt: &Vec<u16>) -> Result<(), InferenceInterruption> {
+        for token in prompt {
+            if self.queue.front() == Some(token) {
+                self.queue.pop_front();
+            }
+        }
+        Ok(())
+    }
+
+    fn transform(&self, mut logits: Vec<f32>) -> Vec<f32> {
+        if let Some(&forced) = self.queue.front() {
+            for (token, logit) in logits.iter_mut().enumerate() {
+                if token != forced as usize {
+                    *logit = f32::NEG_INFINITY;
+                }
+            }
+        }
+        logits
+    }
+
+    fn describe(&self) -> Value {
+        json!({ "remaining": self.queue })
+    }
+
+    fn clear(&mut self) {
+        self.queue = self.data.tokens.iter().copied().collect();
+    }
+
+    fn clone(&self) -> Box<dyn Transformer> {
+        Box::new(Clone::clone(self))
+    }
+}
+
+pub fn initialize_force_tokens(
+    state: AppState,
+    data: Option<Value>,
+) -> Result<Box<dyn Transformer>> {
+    let data: ForceTokensData = serde_json::from_value(data.ok_or(
+        CommandErrorKind::BadRequest.error("Field must present to specify tokens to force!"),
+    )?)?;
+    state.validate_tokens(&state.model(None)?, &[data.tokens.clone()])?;
+    Ok(Box::new(ForceTokensTransformer::new(data.tokens)))
+}
@@ -1,9 +1,9 @@
-use anyhow::{Error, Result};
+use anyhow::Result;
 use ndarray::Array1;
 use serde::Deserialize;
-use serde_json::Value;
+use serde_json::{json, Map, Value};
 
-use crate::{app::AppState, states::InferenceInterruption};
+use crate::{app::AppState, error::CommandErrorKind, states::InferenceInterruption};
 
 use super::types::Transformer;
 
@@ -33,6 +33,20 @@ impl Transformer for GlobalPenalty {
         (Array1::from_vec(logits) - &self.record - &self.presence).to_vec()
     }
 
+    fn describe(&self) -> Value {
+        let penalties = self
+            .record
+            .iter()
+            .zip(self.presence.iter())
+            .enumerate()
+            .filter(|(_, (occurrence, presence))| **occurrence != 0.0 || **presence != 0.0)
+            .map(|(token, (occurrence, presence))| {
+                (token.to_string(), json!(occurrence + presence))
+            })
+            .collect::<Map<_, _>>();
+        json!({ "penalties": penalties })
+    }
+
     fn clear(&mut self) {
         self.record = Array1::zeros(65536);
         self.presence = Array1::zeros(65536);
@@ -49,9 +63,12 @@ impl Transformer for GlobalPenalty {
 
 pub fn initialize_global(_state: AppState, data: Option<Value>) -> Result<Box<dyn Transformer>> {
     Ok(Box::new(GlobalPenalty {
-        data: serde_json::from_value(data.ok_or(Error::msg(
-            "Field must present to specify alpha presence and occurrence!",
-        ))?)?,
+        data: serde_json::from_value(
+            data.ok_or(
+                CommandErrorKind::BadRequest
+                    .error("Field must present to specify alpha presence and occurrence!"),
+            )?,
+        )?,
         presence: Array1::zeros(65536),
         record: Array1::zeros(65536),
     }))
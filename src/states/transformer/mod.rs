@@ -1,13 +1,15 @@
 use self::types::Transformer;
-use crate::{app::AppState, hashmap_ex};
-use anyhow::{Error, Ok, Result};
+use crate::{app::AppState, error::CommandErrorKind, hashmap_ex};
+use anyhow::{Ok, Result};
 use dashmap::{mapref::one::RefMut, DashMap};
 use serde::Deserialize;
-use serde_json::Value;
+use serde_json::{Map, Value};
 use std::collections::HashMap;
 
-use super::InferenceInterruption;
+use super::{component::Component, InferenceInterruption};
 
+pub mod dry;
+pub mod force_tokens;
 mod global_penalty;
 pub mod types;
 
@@ -19,7 +21,7 @@ struct TransformerJson {
 
 pub struct Transformers {
     registry: HashMap<&'static str, fn(AppState, Option<Value>) -> Result<Box<dyn Transformer>>>,
-    map: DashMap<String, Box<dyn Transformer>>,
+    map: DashMap<String, Component<Box<dyn Transformer>>>,
 }
 
 impl Transformers {
@@ -29,6 +31,8 @@ impl Transformers {
                 HashMap<&'static str, fn(AppState, Option<Value>) -> Result<Box<dyn Transformer>>>,
                     {
                         "global_penalty" => global_penalty::initialize_global,
+                        "dry" => dry::initialize_dry,
+                        "force_tokens" => force_tokens::initialize_force_tokens,
                     }
             },
             map: DashMap::with_capacity(128),
@@ -45,34 +49,44 @@ impl Transformers {
         if let Some(constructor) = constructor {
             Ok(constructor(state, data)?)
         } else {
-            Err(Error::msg("Transformer not found!"))
+            Err(CommandErrorKind::TransformerNotFound.error("Transformer not found!"))
         }
     }
 
+    /// Creates a transformer, which is deleted once the connection `owner` is closed if
+    /// given.
     pub fn create_transformer(
         &self,
         id: String,
         state: AppState,
         data: Option<Value>,
+        owner: Option<usize>,
     ) -> Result<()> {
         if self.map.contains_key(&id) {
-            return Err(Error::msg("Transformer already existed!"));
+            return Err(CommandErrorKind::AlreadyExists.error("Transformer already existed!"));
         }
         if let Some(data) = data {
             let TransformerJson { type_id, params } =
-                serde_json::from_value::<TransformerJson>(data)?;
-            self.map.insert(id, self.create(&type_id, state, params)?);
+                serde_json::from_value::<TransformerJson>(data.clone())?;
+            let transformer = self.create(&type_id, state, params)?;
+            self.map
+                .insert(id, Component::new(transformer, data).owned_by(owner));
             Ok(())
         } else {
-            Err(Error::msg("No data to construct transformer!"))
+            Err(CommandErrorKind::BadRequest.error("No data to construct transformer!"))
         }
     }
 
+    /// Deletes all transformers owned by the connection.
+    pub fn disconnect(&self, connection: usize) {
+        self.map.retain(|_, x| x.owner != Some(connection));
+    }
+
     #[inline(always)]
     pub fn get_transformer<'a>(
         &'a self,
         id: &String,
-    ) -> Option<RefMut<'_, String, Box<dyn Transformer>>> {
+    ) -> Option<RefMut<'_, String, Component<Box<dyn Transformer>>>> {
         self.map.get_mut(id)
     }
 
@@ -84,7 +98,7 @@ impl Transformers {
     pub fn delete_transformer(&self, id: &str) -> Result<()> {
         self.map
             .remove(id)
-            .ok_or(Error::msg("Transformer id doesn't exist!"))
+            .ok_or(CommandErrorKind::TransformerNotFound.error("Transformer id doesn't exist!"))
             .map(|_| ())
     }
 
@@ -93,7 +107,7 @@ impl Transformers {
             transformer.clear();
             Ok(())
         } else {
-            Err(Error::msg("Transformer id doesn't exist!"))
+            Err(CommandErrorKind::TransformerNotFound.error("Transformer id doesn't exist!"))
         }
     }
 
@@ -105,30 +119,54 @@ impl Transformers {
         if let Some(mut transformer) = self.map.get_mut(id) {
             transformer.update(content)
         } else {
-            Err(InferenceInterruption::Error(Error::msg(
-                "Transformer id doesn't exist!",
-            )))
+            Err(InferenceInterruption::Error(
+                CommandErrorKind::TransformerNotFound.error("Transformer id doesn't exist!"),
+            ))
         }
     }
 
     pub fn copy_transformer(&self, src: String, dst: String) -> Result<()> {
         if self.map.contains_key(&dst) {
-            return Err(Error::msg("Destination transformer id already exists!"));
+            return Err(
+                CommandErrorKind::AlreadyExists.error("Destination transformer id already exists!")
+            );
         }
         let src = self
             .map
             .get(&src)
-            .ok_or(Error::msg("Transformer doesn't exist!"))?
-            .clone();
+            .map(|x| Component::new(x.inner.clone(), x.definition.clone()))
+            .ok_or(CommandErrorKind::TransformerNotFound.error("Transformer doesn't exist!"))?;
         self.map.insert(dst, src);
         Ok(())
     }
 
+    /// The definitions of all transformers by id, which create them again.
+    pub fn definitions(&self) -> Map<String, Value> {
+        self.map
+            .iter()
+            .map(|x| (x.key().clone(), x.definition.clone()))
+            .collect()
+    }
+
+    /// Ids of all transformers, sorted.
+    pub fn ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.map.iter().map(|x| x.key().clone()).collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    pub fn describe_transformer(&self, id: &str) -> Result<Value> {
+        self.map
+            .get(id)
+            .map(|transformer| transformer.describe())
+            .ok_or(CommandErrorKind::TransformerNotFound.error("Transformer id doesn't exist!"))
+    }
+
     pub fn transform_logits(&self, id: &String, logits: Vec<f32>) -> Result<Vec<f32>> {
         if let Some(transformer) = self.map.get_mut(id) {
             Ok(transformer.transform(logits))
         } else {
-            Err(Error::msg("Transformer id doesn't exist!"))
+            Err(CommandErrorKind::TransformerNotFound.error("Transformer id doesn't exist!"))
         }
     }
 }
@@ -1,4 +1,5 @@
 use anyhow::Result;
+use serde_json::Value;
 use std::fmt::Debug;
 
 use crate::states::InferenceInterruption;
@@ -24,7 +25,9 @@ pub trait Transformer: Send + Sync + Debug {
     /// will be no way to know if the call is from a prompt or an autoregressive generation,
     /// there're other Websocket APIs or infer params which can bypass the update.
     ///
-    /// Once the update is completed, the **infer** will start **without any interrution**, so transformer must preceive if it can or can not accept any further input, and interrupt the generation by returning `Err(InferenceInterruption::Exhaustion)`.
+    /// Once the update is completed, the **infer** will start **without any interrution**, so transformer must preceive if it can or can not accept any further input, and interrupt the generation by returning `Err(InferenceInterruption::Exhaustion(reason))`.
+    ///
+    /// The reason is reported to the client along with the stop reason, so a transformer should tell apart situations which mean different things to the caller, e.g. `Some("grammar completed")` and `Some("no admissible tokens remain")`.
     fn update(&mut self, prompt: &Vec<u16>) -> Result<(), InferenceInterruption>;
 
     ///Transform a logits distribution to another by mutating the input mutable reference of logits. This occurss *before* `softmax` to ensure a probs sum of 1 at `sampling`.
@@ -34,6 +37,12 @@ pub trait Transformer: Send + Sync + Debug {
     /// This function must be **infallible**, as any interruption is checked when updated.
     fn transform(&self, logits: Vec<f32>) -> Vec<f32>;
 
+    /// Describes the internal state of the transformer for debugging, e.g. the counters
+    /// of a penalty. Returns `Value::Null` by default.
+    fn describe(&self) -> Value {
+        Value::Null
+    }
+
     /// Clears the `Transformer`. This will reset the internal state of the Transformer to *when it*
     /// *is just constructed from params*.
     fn clear(&mut self);
@@ -0,0 +1,42 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use web_rwkv_axum::states::batch_controller::BatchController;
+
+    #[test]
+    fn test_batch_controller() {
+        let mut controller = BatchController::new(2, 8);
+        assert_eq!(controller.current(), 2);
+
+        // Ramps up once the latency is stable with every slot used
+        for _ in 0..20 {
+            controller.on_success(Duration::from_millis(20), controller.current());
+        }
+        assert_eq!(controller.current(), 3);
+
+        // Backs off on a much slower run
+        controller.on_success(Duration::from_millis(200), 3);
+        assert_eq!(controller.current(), 2);
+
+        // Halves on failures, down to the lower bound
+        let mut controller = BatchController::new(1, 8);
+        for _ in 0..200 {
+            controller.on_success(Duration::from_millis(10), controller.current());
+        }
+        assert_eq!(controller.current(), 8);
+        assert!(controller.on_failure());
+        assert_eq!(controller.current(), 4);
+        assert!(controller.on_failure());
+        assert!(controller.on_failure());
+        assert_eq!(controller.current(), 1);
+        assert!(!controller.on_failure());
+
+        // Fixed batch size
+        let mut controller = BatchController::new(4, 4);
+        controller.on_success(Duration::from_millis(10), 4);
+        controller.on_success(Duration::from_millis(100), 4);
+        assert_eq!(controller.current(), 4);
+        assert!(!controller.on_failure());
+    }
+}
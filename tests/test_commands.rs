@@ -0,0 +1,26 @@
+#[cfg(test)]
+mod tests {
+    use web_rwkv_axum::commands::registry;
+
+    #[test]
+    fn test_unknown_command_suggestion() {
+        let error = registry().get("infr").err().unwrap().to_string();
+        assert_eq!(error, "Unknown command infr, did you mean infer?");
+
+        let error = registry().get("generate").err().unwrap().to_string();
+        assert!(!error.contains("did you mean"));
+    }
+
+    #[test]
+    fn test_registered_commands() {
+        assert!(registry().get("continue").is_ok());
+        assert!(registry().get("continue_infer").is_err());
+        for command in registry().list() {
+            assert!(
+                command.info(false).description.is_some(),
+                "{}",
+                command.name
+            );
+        }
+    }
+}
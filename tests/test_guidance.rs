@@ -0,0 +1,24 @@
+#[cfg(test)]
+mod tests {
+    use web_rwkv_axum::states::guidance::Guidance;
+
+    #[test]
+    fn test_guidance_blend() {
+        let guidance = |scale| Guidance {
+            negative_state: "negative".to_string(),
+            scale,
+        };
+        let uncond = [1.0, 0.0, -1.0];
+
+        // Scale 1 keeps the logits of the state, and 0 takes the negative ones
+        let mut cond = [2.0, 1.0, 0.0];
+        guidance(1.0).blend(&mut cond, &uncond);
+        assert_eq!(cond, [2.0, 1.0, 0.0]);
+        guidance(0.0).blend(&mut cond, &uncond);
+        assert_eq!(cond, uncond);
+
+        let mut cond = [2.0, 1.0, 0.0];
+        guidance(1.5).blend(&mut cond, &uncond);
+        assert_eq!(cond, [2.5, 1.5, 0.5]);
+    }
+}
@@ -0,0 +1,71 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use web_rwkv_axum::helper::{State, Utf8Decoder};
+
+    #[test]
+    fn test_utf8_split_across_tokens() {
+        let mut decoder = Utf8Decoder::default();
+        // "é" is split into 2 tokens
+        assert_eq!(decoder.push(b"caf\xC3"), "caf");
+        assert_eq!(decoder.pending(), b"\xC3");
+        assert_eq!(decoder.push(b"\xA9!"), "é!");
+        assert!(decoder.pending().is_empty());
+
+        // "😀" is split into 3 tokens
+        assert_eq!(decoder.push(b"\xF0\x9F"), "");
+        assert_eq!(decoder.push(b"\x98"), "");
+        assert_eq!(decoder.push(b"\x80"), "😀");
+    }
+
+    #[test]
+    fn test_utf8_invalid_bytes() {
+        let mut decoder = Utf8Decoder::default();
+        assert_eq!(decoder.push(b"a\xFFb"), "a\u{FFFD}b");
+        // A character interrupted by another one
+        assert_eq!(decoder.push(b"\xE4\xB8a"), "\u{FFFD}a");
+    }
+
+    #[test]
+    fn test_utf8_finish_incomplete_emoji() {
+        let mut decoder = Utf8Decoder::default();
+        // The generation stops after 2 of the 3 tokens of "😀"
+        let tokens: [&[u8]; 3] = [b"Hi ", b"\xF0\x9F", b"\x98"];
+        let mut text: String = tokens.iter().map(|x| decoder.push(x)).collect();
+        assert_eq!(text, "Hi ");
+        assert_eq!(decoder.pending(), b"\xF0\x9F\x98");
+
+        // The incomplete emoji is flushed instead of dropped
+        text.push_str(&decoder.finish());
+        assert_eq!(text, "Hi \u{FFFD}");
+    }
+
+    #[test]
+    fn test_utf8_finish() {
+        let mut decoder = Utf8Decoder::default();
+        assert_eq!(decoder.push(b"ok\xE4\xB8"), "ok");
+        assert_eq!(decoder.finish(), "\u{FFFD}");
+        assert!(decoder.pending().is_empty());
+        assert_eq!(decoder.finish(), "");
+    }
+
+    #[test]
+    fn test_shallow_copy_survives_source() {
+        let source = State(Arc::new(vec![1.0, 2.0]));
+        let copy = source.clone();
+        assert!(Arc::ptr_eq(&source.0, &copy.0));
+        assert!(!Arc::ptr_eq(&source.0, &source.deep_clone().0));
+
+        // Inferring the source takes its data out, which is copied while shared, so
+        // updating the source doesn't change the copy
+        let mut data = source.into_data();
+        data[0] = 3.0;
+        assert_eq!(copy.0.as_ref(), &vec![1.0, 2.0]);
+
+        // Deleting the source doesn't invalidate the copy, which owns the data from now on
+        drop(data);
+        assert_eq!(Arc::strong_count(&copy.0), 1);
+        assert_eq!(copy.into_data(), vec![1.0, 2.0]);
+    }
+}
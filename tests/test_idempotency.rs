@@ -0,0 +1,38 @@
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use web_rwkv_axum::{
+        commands::idempotency::{IdempotencyCache, Outcome},
+        error::CommandErrorKind,
+    };
+
+    #[tokio::test]
+    async fn test_idempotency_replay() {
+        let cache = IdempotencyCache::new(2);
+        let slot = cache.slot("a", "create_state").unwrap().unwrap();
+        *slot.lock().await = Some(Outcome::of(&Ok(json!("ok"))));
+
+        // A duplicate gets the stored response
+        let slot = cache.slot("a", "create_state").unwrap().unwrap();
+        let replay = slot.lock().await.as_ref().unwrap().replay().unwrap();
+        assert_eq!(replay, json!("ok"));
+
+        // Errors keep their code
+        let slot = cache.slot("b", "create_state").unwrap().unwrap();
+        let error = CommandErrorKind::AlreadyExists.error("taken");
+        *slot.lock().await = Some(Outcome::of(&Err(error)));
+        let slot = cache.slot("b", "create_state").unwrap().unwrap();
+        let replay = slot.lock().await.as_ref().unwrap().replay().unwrap_err();
+        assert_eq!(CommandErrorKind::of(&replay), CommandErrorKind::AlreadyExists);
+
+        // A key can't be reused by another command
+        assert!(cache.slot("b", "copy_state").is_err());
+
+        // The oldest key is forgotten beyond the capacity
+        cache.slot("c", "create_state").unwrap();
+        let slot = cache.slot("a", "create_state").unwrap().unwrap();
+        assert!(slot.lock().await.is_none());
+
+        assert!(IdempotencyCache::new(0).slot("a", "echo").unwrap().is_none());
+    }
+}
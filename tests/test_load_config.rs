@@ -21,7 +21,11 @@ mod tests {
     #[tokio::test]
     async fn test_model() {
         let config = get_config();
-        let context = config.model.create_context().await.unwrap();
+        let context = config
+            .model
+            .create_context(Default::default())
+            .await
+            .unwrap();
         let _ = config.model.load_model(&context).await.unwrap();
     }
 }
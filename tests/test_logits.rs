@@ -0,0 +1,88 @@
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use serde_json::{json, Value};
+    use tokio::sync::mpsc;
+    use web_rwkv_axum::{
+        app::{AppSettings, AppState},
+        cli::WsConfig,
+        commands::registry,
+        config::ModelConfig,
+        states::model::AxumModel,
+    };
+
+    async fn app_state() -> AppState {
+        let config: ModelConfig = toml::from_str(include_str!("./test_parse_config.toml")).unwrap();
+        let mut models = HashMap::new();
+        for (name, spec) in config.model_specs().unwrap() {
+            let (model, _) = AxumModel::load(
+                name.clone(),
+                spec,
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                Default::default(),
+            )
+            .await
+            .unwrap();
+            models.insert(name, Arc::new(model));
+        }
+        AppState::new(&config, WsConfig::default(), AppSettings::default(), models)
+            .await
+            .unwrap()
+    }
+
+    async fn call(state: &AppState, command: &str, data: Value) -> Value {
+        let (partial, _) = mpsc::unbounded_channel();
+        let (binary, _) = mpsc::unbounded_channel();
+        registry()
+            .get(command)
+            .unwrap()
+            .call("test", Some(data), state.clone(), partial, binary)
+            .await
+            .unwrap()
+    }
+
+    fn top_logits(result: &Value) -> Vec<(u64, f64)> {
+        result["top"][0]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|x| (x["token"].as_u64().unwrap(), x["logit"].as_f64().unwrap()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_logits_without_update_see_latest_state() {
+        let state = app_state().await.connect();
+        call(&state, "create_state", json!("a")).await;
+
+        let logits = |id: &str, update_state: bool| {
+            json!({
+                "states": [id],
+                "tokens": " world",
+                "top": 8,
+                "update_state": update_state,
+            })
+        };
+        // The latest data of the state is only kept in its slot after this
+        call(&state, "get_logits", logits("a", true)).await;
+
+        let peeked = call(&state, "get_logits", logits("a", false)).await;
+        call(
+            &state,
+            "copy_state",
+            json!({ "source": "a", "destination": "b" }),
+        )
+        .await;
+        let fed = call(&state, "get_logits", logits("b", true)).await;
+
+        let (peeked, fed) = (top_logits(&peeked), top_logits(&fed));
+        assert_eq!(peeked.len(), fed.len());
+        for ((token, logit), (expected_token, expected)) in peeked.into_iter().zip(fed) {
+            assert_eq!(token, expected_token);
+            assert!((logit - expected).abs() < 1e-3);
+        }
+    }
+}
@@ -0,0 +1,45 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use web_rwkv_axum::{
+        helper::State,
+        states::{merge::merge_states, state_file::StateLayout},
+    };
+
+    fn layout(version: &str) -> StateLayout {
+        StateLayout {
+            version: version.to_string(),
+            num_layers: 1,
+            num_emb: 2,
+        }
+    }
+
+    #[test]
+    fn test_merge_states() {
+        let layout = layout("V4");
+        // Rows: token shift of att, aa, bb, pp, token shift of ffn
+        let state = |rows: [[f32; 2]; 5]| State(Arc::new(rows.concat()));
+        let a = state([[1.0, 2.0], [2.0, 2.0], [1.0, 1.0], [0.0, 0.0], [4.0, 4.0]]);
+        let b = state([
+            [3.0, 4.0],
+            [4.0, 4.0],
+            [1.0, 1.0],
+            [f32::MIN; 2],
+            [0.0, 0.0],
+        ]);
+        let merged = merge_states(&layout, &[(a.clone(), 1.0), (b, 1.0)]).unwrap();
+        // Token shifts are averaged, while the WKV of `b` vanishes beside the larger `pp`
+        let expected = state([[2.0, 3.0], [1.0, 1.0], [0.5, 0.5], [0.0, 0.0], [2.0, 2.0]]);
+        assert_eq!(merged.0, expected.0);
+
+        // Weights are normalized
+        let merged = merge_states(&layout, &[(a.clone(), 3.0)]).unwrap();
+        assert_eq!(merged.0, a.0);
+
+        assert!(merge_states(&layout, &[(a.clone(), f32::NAN)]).is_err());
+        assert!(merge_states(&layout, &[(a.clone(), -1.0)]).is_err());
+        assert!(merge_states(&layout, &[(a.clone(), 0.0)]).is_err());
+        assert!(merge_states(&self::layout("V5"), &[(a, 1.0)]).is_err());
+    }
+}
@@ -0,0 +1,21 @@
+#[cfg(test)]
+mod tests {
+    use web_rwkv_axum::states::speculative::ngram_draft;
+
+    #[test]
+    fn test_ngram_draft() {
+        // The last occurrence of the longest suffix decides the draft
+        let context = [1, 2, 3, 9, 1, 2, 3, 4, 5, 1, 2, 3];
+        assert_eq!(ngram_draft(&context, 3, 2), vec![4, 5]);
+        assert_eq!(ngram_draft(&context, 3, 8), vec![4, 5, 1, 2, 3]);
+
+        // Falls back to shorter n-grams
+        let context = [7, 3, 8, 5, 3];
+        assert_eq!(ngram_draft(&context, 3, 4), vec![8, 5, 3]);
+
+        // Nothing to draft without a match
+        assert!(ngram_draft(&[1, 2, 3], 3, 4).is_empty());
+        assert!(ngram_draft(&[1], 3, 4).is_empty());
+        assert!(ngram_draft(&[], 3, 4).is_empty());
+    }
+}
@@ -0,0 +1,79 @@
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use web_rwkv_axum::{
+        app::{AppSettings, AppState},
+        cli::WsConfig,
+        config::ModelConfig,
+        states::permit::BatchRequest,
+    };
+
+    #[test]
+    fn test_permits_released_on_drop() {
+        let request = BatchRequest::new(4);
+        let permit = request.request(3).unwrap();
+        let other = request.clone().request(1).unwrap();
+        assert_eq!(request.get(), 4);
+
+        // A cancelled command drops its permits as soon as it returns
+        drop(permit);
+        assert_eq!(request.get(), 1);
+        drop(other);
+        assert_eq!(request.get(), 0);
+
+        assert!(request.request(5).is_err());
+        assert_eq!(request.get(), 0);
+    }
+
+    async fn app_state() -> AppState {
+        let config: ModelConfig = toml::from_str(include_str!("./test_parse_config.toml")).unwrap();
+        AppState::new(
+            &config,
+            WsConfig::default(),
+            AppSettings::default(),
+            HashMap::new(),
+        )
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_cancels_commands() {
+        let state = app_state().await;
+        let connection = state.connect();
+        let other = state.connect();
+        let request = BatchRequest::new(4);
+
+        // A long generation holding its slots until it's cancelled, like `infer` does
+        let generate = |state: AppState, echo_id: &str| {
+            let handle = state.register_command(echo_id.to_string());
+            let permits = request.request(2).unwrap();
+            tokio::spawn(async move {
+                while !handle.is_cancelled() {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+                drop(permits);
+            })
+        };
+        let cancelled = generate(connection.clone(), "infer");
+        let running = generate(other.clone(), "infer");
+        assert_eq!(request.get(), 4);
+
+        // Only the commands of the closed connection are cancelled
+        connection.disconnect();
+        tokio::time::timeout(Duration::from_secs(1), cancelled)
+            .await
+            .expect("the command is not cancelled in time")
+            .unwrap();
+        assert_eq!(request.get(), 2);
+        assert!(!running.is_finished());
+
+        other.disconnect();
+        tokio::time::timeout(Duration::from_secs(1), running)
+            .await
+            .expect("the command is not cancelled in time")
+            .unwrap();
+        assert_eq!(request.get(), 0);
+    }
+}
@@ -0,0 +1,43 @@
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use serde_json::json;
+    use web_rwkv_axum::commands::{idempotency::Outcome, resume::ResumeBuffers};
+
+    #[tokio::test]
+    async fn test_resume_replay() {
+        let buffers = ResumeBuffers::new(Duration::from_secs(60));
+        let progress = buffers.start("a", Some(0)).unwrap();
+        progress.push(json!(1));
+        progress.push(json!(2));
+        assert!(buffers.is_running(Some(0), "a"));
+        assert!(!buffers.is_running(Some(1), "a"));
+
+        // A running echo_id can't be reused
+        assert!(buffers.start("a", Some(1)).is_err());
+
+        // Partial results are replayed after the ones already got
+        let resumed = buffers.get("a").unwrap();
+        let (partials, outcome) = resumed.next(1).await;
+        assert_eq!(partials, vec![json!(2)]);
+        assert!(outcome.is_none());
+
+        // New partial results and the final response are followed
+        let follow = tokio::spawn(async move { resumed.next(2).await });
+        progress.push(json!(3));
+        let (partials, _) = follow.await.unwrap();
+        assert_eq!(partials, vec![json!(3)]);
+
+        buffers.finish("a", &progress, Outcome::of(&Ok(json!("done"))));
+        let (partials, outcome) = buffers.get("a").unwrap().next(3).await;
+        assert!(partials.is_empty());
+        assert_eq!(outcome.unwrap().replay().unwrap(), json!("done"));
+        assert!(!buffers.is_running(Some(0), "a"));
+
+        // A finished echo_id can be reused
+        assert!(buffers.start("a", Some(1)).is_ok());
+
+        assert!(ResumeBuffers::new(Duration::ZERO).start("b", None).is_err());
+    }
+}
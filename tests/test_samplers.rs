@@ -0,0 +1,208 @@
+#[cfg(test)]
+mod tests {
+    use fastrand::Rng;
+    use serde_json::json;
+    use web_rwkv_axum::states::{
+        normalizer::{epsilon::epsilon_cutoff, log_softmax::log_softmax, softmax::softmax},
+        sampler::{
+            gumbel::GumbelSampler, types::Sampler, typical::TypicalSampler, utils, xtc::XtcSampler,
+        },
+    };
+
+    const DRAWS: usize = 20000;
+
+    fn frequencies(mut draw: impl FnMut() -> u16, vocab: usize) -> Vec<f32> {
+        let mut counts = vec![0usize; vocab];
+        for _ in 0..DRAWS {
+            counts[draw() as usize] += 1;
+        }
+        counts
+            .into_iter()
+            .map(|x| x as f32 / DRAWS as f32)
+            .collect()
+    }
+
+    #[test]
+    fn test_nucleus_in_both_domains() {
+        let sampler: TypicalSampler =
+            serde_json::from_value(json!({ "top_p": 0.9, "temp": 1.0 })).unwrap();
+        let logits = [2.0, 1.5, 1.0, 0.0, -1.0, -4.0];
+        let probs = vec![softmax(&logits, 0.7)];
+        let logprobs = vec![log_softmax(&logits, 0.7)];
+
+        for (p, lp) in probs[0].iter().zip(logprobs[0].iter()) {
+            assert!((p - lp.exp()).abs() < 1e-6);
+        }
+
+        let mut rng = Rng::new();
+        let from_probs = frequencies(
+            || sampler.sample(probs.clone(), &mut rng)[0].token,
+            logits.len(),
+        );
+        let from_logprobs = frequencies(
+            || sampler.sample_logprobs(logprobs.clone(), &mut rng)[0].token,
+            logits.len(),
+        );
+        for (x, y) in from_probs.iter().zip(from_logprobs.iter()) {
+            assert!(
+                (x - y).abs() < 0.03,
+                "{:?} != {:?}",
+                from_probs,
+                from_logprobs
+            );
+        }
+    }
+
+    #[test]
+    fn test_gumbel_matches_weighted() {
+        let sampler = GumbelSampler::new(1.0);
+        // The last token is masked out, and must never be drawn
+        let probs = vec![vec![0.4, 0.3, 0.15, 0.1, 0.05, 0.0]];
+        let candidates = probs[0].iter().copied().enumerate().collect::<Vec<_>>();
+
+        let mut rng = Rng::new();
+        let weighted = frequencies(
+            || utils::sample_weighted(&candidates, &mut rng).unwrap() as u16,
+            6,
+        );
+        let gumbel = frequencies(|| sampler.sample(probs.clone(), &mut rng)[0].token, 6);
+        let gumbel_logprobs = frequencies(
+            || {
+                sampler.sample_logprobs(vec![probs[0].iter().map(|x| x.ln()).collect()], &mut rng)
+                    [0]
+                .token
+            },
+            6,
+        );
+        assert_eq!(gumbel[5], 0.0);
+        for ((x, y), z) in weighted
+            .iter()
+            .zip(gumbel.iter())
+            .zip(gumbel_logprobs.iter())
+        {
+            assert!((x - y).abs() < 0.03, "{:?} != {:?}", weighted, gumbel);
+            assert!(
+                (x - z).abs() < 0.03,
+                "{:?} != {:?}",
+                weighted,
+                gumbel_logprobs
+            );
+        }
+    }
+
+    #[test]
+    fn test_sampled_logprob() {
+        let sampler: TypicalSampler =
+            serde_json::from_value(json!({ "top_p": 0.5, "temp": 1.0 })).unwrap();
+        let probs = vec![vec![0.6, 0.3, 0.1]];
+        // Only the first token survives top-p, but the logprob is taken before truncation
+        let sampled = sampler.sample(probs.clone(), &mut Rng::new())[0];
+        assert_eq!(sampled.token, 0);
+        assert!((sampled.logprob - 0.6f32.ln()).abs() < 1e-6);
+
+        let sampler = GumbelSampler::new(1.0);
+        let logprobs = vec![vec![0.0, f32::NEG_INFINITY]];
+        let sampled = sampler.sample_logprobs(logprobs, &mut Rng::new())[0];
+        assert_eq!(sampled.token, 0);
+        assert_eq!(sampled.logprob, 0.0);
+    }
+
+    #[test]
+    fn test_sample_each_state() {
+        let sampler: TypicalSampler =
+            serde_json::from_value(json!({ "top_p": 0.5, "temp": 1.0 })).unwrap();
+        let probs = vec![vec![0.9, 0.05, 0.05], vec![0.05, 0.05, 0.9]];
+        let sampled = sampler.sample(probs.clone(), &mut Rng::new());
+        assert_eq!(
+            sampled.iter().map(|x| x.token).collect::<Vec<_>>(),
+            vec![0, 2]
+        );
+
+        let sampler = GumbelSampler::new(1.0);
+        let probs = vec![vec![1.0, 0.0], vec![0.0, 1.0], vec![1.0, 0.0]];
+        let sampled = sampler.sample(probs, &mut Rng::new());
+        assert_eq!(
+            sampled.iter().map(|x| x.token).collect::<Vec<_>>(),
+            vec![0, 1, 0]
+        );
+    }
+
+    #[test]
+    fn test_same_seed_same_tokens() {
+        let sampler: TypicalSampler =
+            serde_json::from_value(json!({ "top_p": 0.95, "temp": 1.0 })).unwrap();
+        let probs = vec![vec![0.3, 0.25, 0.2, 0.15, 0.1]];
+        let draw = |seed: u64| {
+            let mut rng = Rng::with_seed(seed);
+            (0..64)
+                .map(|_| sampler.sample(probs.clone(), &mut rng)[0].token)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(draw(42), draw(42));
+        assert_ne!(draw(42), draw(43));
+    }
+
+    #[test]
+    fn test_epsilon_cutoff() {
+        let mut probs = vec![0.5, 0.3, 0.15, 0.05];
+        epsilon_cutoff(&mut probs, 0.1);
+        assert_eq!(probs[3], 0.0);
+        assert!((probs.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+
+        // The argmax survives even if everything is below epsilon
+        let mut probs = vec![0.2, 0.3, 0.25, 0.25];
+        epsilon_cutoff(&mut probs, 0.5);
+        assert_eq!(probs, vec![0.0, 1.0, 0.0, 0.0]);
+
+        // No division by zero
+        let mut probs = vec![0.0; 4];
+        epsilon_cutoff(&mut probs, 0.5);
+        assert_eq!(probs.iter().sum::<f32>(), 1.0);
+    }
+
+    #[test]
+    fn test_xtc_excludes_top_choices() {
+        let probs = vec![vec![0.4, 0.3, 0.2, 0.1]];
+        let mut rng = Rng::with_seed(0);
+
+        // Tokens 0 and 1 are above the threshold, only the less probable one is kept
+        let sampler: XtcSampler =
+            serde_json::from_value(json!({ "threshold": 0.25, "probability": 1.0 })).unwrap();
+        let freqs = frequencies(|| sampler.sample(probs.clone(), &mut rng)[0].token, 4);
+        assert_eq!(freqs[0], 0.0);
+        assert!((freqs[1] - 0.5).abs() < 0.03, "{:?}", freqs);
+
+        // The truncated distribution is the mixture which `sample` draws from
+        let sampler: XtcSampler =
+            serde_json::from_value(json!({ "threshold": 0.25, "probability": 0.5 })).unwrap();
+        let mut truncated = probs.clone();
+        sampler.truncate(&mut truncated);
+        let expected = [0.2, 0.4, 0.266_666_7, 0.133_333_3];
+        for (x, y) in truncated[0].iter().zip(expected) {
+            assert!((x - y).abs() < 1e-5, "{:?}", truncated);
+        }
+
+        // Nothing is excluded with a single token above the threshold
+        let sampler: XtcSampler =
+            serde_json::from_value(json!({ "threshold": 0.35, "probability": 1.0 })).unwrap();
+        let mut truncated = probs.clone();
+        sampler.truncate(&mut truncated);
+        assert_eq!(truncated, probs);
+    }
+
+    #[test]
+    fn test_sanitize_logits() {
+        let mut logits = vec![f32::NAN, f32::INFINITY, f32::NEG_INFINITY, 1.0];
+        assert!(utils::sanitize_logits(&mut logits));
+        assert!(logits.iter().all(|x| x.is_finite()));
+        assert_eq!(utils::argmax(&logits), 1);
+        let probs = softmax(&logits, 1.0);
+        assert!(probs[0] == 0.0 && probs[2] == 0.0);
+        assert!((probs.iter().sum::<f32>() - 1.0).abs() < 1e-4);
+
+        // Nothing left to sample, so the caller falls back to a single token
+        let mut masked = vec![f32::NEG_INFINITY, f32::NAN];
+        assert!(!utils::sanitize_logits(&mut masked));
+        assert_eq!(utils::argmax(&utils::one_hot_logits(2, 1)), 1);
+    }
+}
@@ -0,0 +1,24 @@
+#[cfg(test)]
+mod tests {
+    use web_rwkv_axum::states::spill::Spill;
+
+    #[test]
+    fn test_spill_file_deleted_on_drop() {
+        let dir = std::env::temp_dir().join(format!("web-rwkv-axum-spill-{}", std::process::id()));
+        let spill = Spill::new(dir.clone(), 1).unwrap();
+        let (first, second) = (spill.file(), spill.file());
+        assert_ne!(first.path(), second.path());
+
+        std::fs::write(first.path(), b"state").unwrap();
+        let path = first.path().to_path_buf();
+        assert!(path.exists());
+        drop(first);
+        assert!(!path.exists());
+
+        spill.record_spill();
+        spill.record_reload();
+        let stats = spill.stats(1, 0);
+        assert_eq!((stats.spills, stats.reloads), (1, 1));
+        std::fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,41 @@
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use web_rwkv_axum::{
+        helper::State,
+        states::state_file::{decode_state, encode_state, encode_state_chunks, StateLayout},
+    };
+
+    fn layout(num_layers: usize) -> StateLayout {
+        StateLayout {
+            version: "V4".to_string(),
+            num_layers,
+            num_emb: 3,
+        }
+    }
+
+    #[test]
+    fn test_state_file_round_trip() {
+        let layout = layout(2);
+        let state = State(Arc::new((0..layout.data_len()).map(|x| x as f32).collect()));
+        let bytes = encode_state(&layout, &state);
+        let decoded = decode_state(&bytes, &layout).unwrap();
+        assert_eq!(decoded.0, state.0);
+
+        // Mismatched models, truncated and corrupt files are rejected
+        assert!(decode_state(&bytes, &self::layout(3)).is_err());
+        assert!(decode_state(&bytes[..bytes.len() - 1], &layout).is_err());
+        assert!(decode_state(&bytes[1..], &layout).is_err());
+        assert!(decode_state(&[], &layout).is_err());
+    }
+
+    #[test]
+    fn test_state_file_chunks() {
+        let layout = layout(2);
+        let state = State(Arc::new(vec![0.5; layout.data_len()]));
+        let chunks: Vec<Vec<u8>> = encode_state_chunks(&layout, &state, 8).collect();
+        assert!(chunks[1..].iter().all(|x| x.len() <= 8));
+        assert_eq!(chunks.concat(), encode_state(&layout, &state));
+    }
+}
@@ -0,0 +1,29 @@
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use web_rwkv_axum::states::template::Template;
+
+    #[test]
+    fn test_render_template() {
+        let template = Template::parse("User: {{ user }}\nTurn {{turn}}: {{user}}").unwrap();
+        assert_eq!(template.variables(), vec!["user", "turn"]);
+
+        let variables = json!({ "user": "Hi", "turn": 2, "extra": true });
+        let rendered = template.render(variables.as_object().unwrap()).unwrap();
+        assert_eq!(rendered, "User: Hi\nTurn 2: Hi");
+
+        let missing = json!({ "user": "Hi" });
+        assert!(template.render(missing.as_object().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_invalid_template() {
+        assert!(Template::parse("Hello {{user").is_err());
+        assert!(Template::parse("Hello {{}}").is_err());
+        assert!(Template::parse("Hello {{a b}}").is_err());
+        assert!(Template::parse("No variables")
+            .unwrap()
+            .variables()
+            .is_empty());
+    }
+}
@@ -0,0 +1,106 @@
+#[cfg(test)]
+mod tests {
+    use web_rwkv_axum::states::terminal::{
+        newline::NewlineCounter, repetition::RepetitionTerminal, stop_string::StopMatcher,
+    };
+
+    fn new_counter(newlines: usize, skip_leading: bool, include: bool) -> NewlineCounter {
+        NewlineCounter {
+            newlines,
+            skip_leading,
+            include,
+        }
+    }
+
+    #[test]
+    fn test_newline_single_line() {
+        let counter = new_counter(1, false, false);
+        assert_eq!(counter.trim(b"hello"), None);
+        assert_eq!(counter.trim(b"hello\n"), Some(1));
+        assert_eq!(counter.trim(b"hello\nworld"), Some(6));
+    }
+
+    #[test]
+    fn test_newline_multiple_in_one_token() {
+        // A single token such as "\n\n" decodes to multiple newlines at once
+        let counter = new_counter(2, false, false);
+        assert_eq!(counter.trim(b"first"), None);
+        assert_eq!(counter.trim(b"first\n\n"), Some(1));
+        assert_eq!(counter.trim(b"first\n\n\n"), Some(2));
+
+        let counter = new_counter(1, false, true);
+        assert_eq!(counter.trim(b"first\n\n"), Some(1));
+    }
+
+    #[test]
+    fn test_newline_skip_leading() {
+        let counter = new_counter(1, true, false);
+        assert_eq!(counter.trim(b"\n \n"), None);
+        assert_eq!(counter.trim(b"\n \nhello"), None);
+        assert_eq!(counter.trim(b"\n \nhello\n"), Some(1));
+
+        let counter = new_counter(1, false, false);
+        assert_eq!(counter.trim(b"\nhello"), Some(6));
+    }
+
+    fn new_matcher(strings: &[&str]) -> StopMatcher {
+        StopMatcher::new(strings.iter().map(|x| x.to_string()).collect()).unwrap()
+    }
+
+    #[test]
+    fn test_stop_string_split_across_tokens() {
+        // Simulates "User:" being generated as "Us", "er", ":"
+        let matcher = new_matcher(&["User:"]);
+        assert_eq!(matcher.trim(b"Hi\nUs"), None);
+        assert_eq!(matcher.holdback(b"Hi\nUs"), 2);
+        assert_eq!(matcher.holdback(b"Hi\nUser"), 4);
+        assert_eq!(matcher.trim(b"Hi\nUser:"), Some(5));
+        assert_eq!(matcher.trim(b"Hi\nUser: x"), Some(7));
+
+        // The match fails, so everything is flushed
+        assert_eq!(matcher.holdback(b"Hi\nUsed"), 0);
+        assert_eq!(matcher.trim(b"Hi\nUsed"), None);
+    }
+
+    #[test]
+    fn test_stop_string_overlapping() {
+        let matcher = new_matcher(&["User:", "er:", "\n\n"]);
+        // The earliest stop string wins
+        assert_eq!(matcher.trim(b"a User:"), Some(5));
+        assert_eq!(matcher.trim(b"a\n\nUser:"), Some(7));
+        // The longest possible prefix is held back
+        assert_eq!(matcher.holdback(b"a Use"), 3);
+        assert_eq!(matcher.holdback(b"a User"), 4);
+        assert_eq!(matcher.holdback(b"a\n"), 1);
+
+        // A stop string which is also a prefix of another one
+        let matcher = new_matcher(&["ab", "abc"]);
+        assert_eq!(matcher.trim(b"xab"), Some(2));
+        assert_eq!(matcher.holdback(b"xa"), 1);
+    }
+
+    #[test]
+    fn test_stop_string_multibyte() {
+        // A multi-byte character may be split across tokens
+        let matcher = new_matcher(&["。"]);
+        let text = "好。".as_bytes();
+        assert_eq!(matcher.holdback(&text[..4]), 1);
+        assert_eq!(matcher.holdback(&text[..5]), 2);
+        assert_eq!(matcher.trim(text), Some(3));
+        assert!(StopMatcher::new(vec![String::new()]).is_err());
+    }
+
+    #[test]
+    fn test_repetition() {
+        let terminal = RepetitionTerminal::new(2, 1);
+        assert_eq!(terminal.repeats(&[]), 0);
+        assert_eq!(terminal.repeats(&[1, 2, 2]), 2);
+        assert_eq!(terminal.repeats(&[2, 2, 2, 1]), 1);
+
+        let terminal = RepetitionTerminal::new(2, 2);
+        assert_eq!(terminal.repeats(&[1]), 0);
+        assert_eq!(terminal.repeats(&[5, 1, 2, 1, 2, 1, 2]), 3);
+        assert_eq!(terminal.repeats(&[1, 2, 1, 2, 1]), 2);
+        assert_eq!(terminal.repeats(&[1, 2, 2, 1, 2]), 1);
+    }
+}
@@ -0,0 +1,58 @@
+#[cfg(test)]
+mod tests {
+    use web_rwkv_axum::states::transformer::{
+        dry::DryTransformer, force_tokens::ForceTokensTransformer, types::Transformer,
+    };
+
+    #[test]
+    fn test_dry_penalizes_continuation() {
+        let mut dry = DryTransformer::new(1.0, 2.0, 2);
+        dry.update(&vec![1, 2, 3, 1, 2]).ok();
+        let penalties = dry.penalties();
+        assert_eq!(penalties.len(), 1);
+        assert_eq!(penalties[&3], 1.0);
+
+        // A longer match grows the penalty exponentially
+        dry.update(&vec![3, 1]).ok();
+        assert_eq!(dry.penalties()[&2], 4.0);
+    }
+
+    #[test]
+    fn test_dry_allowed_length() {
+        let mut dry = DryTransformer::new(1.0, 2.0, 3);
+        dry.update(&vec![1, 2, 3, 1, 2]).ok();
+        assert!(dry.penalties().is_empty());
+    }
+
+    #[test]
+    fn test_dry_penalty_capped() {
+        let mut dry = DryTransformer::new(1.0, 10.0, 1);
+        dry.update(&vec![7; 200]).ok();
+        let logits = dry.transform(vec![0.0; 8]);
+        assert!(logits[7].is_finite());
+        assert!(logits[7] < 0.0);
+        assert_eq!(logits[0], 0.0);
+    }
+
+    #[test]
+    fn test_force_tokens() {
+        let mut force = ForceTokensTransformer::new(vec![2, 0]);
+        let logits = force.transform(vec![1.0; 4]);
+        assert_eq!(logits[2], 1.0);
+        assert!(logits.iter().filter(|x| x.is_finite()).count() == 1);
+
+        // A token other than the forced one doesn't pop it
+        force.update(&vec![1]).ok();
+        assert_eq!(force.remaining().len(), 2);
+        force.update(&vec![2]).ok();
+        assert_eq!(force.transform(vec![1.0; 4])[0], 1.0);
+        force.update(&vec![0]).ok();
+
+        // Passes through once all tokens are forced
+        assert!(force.remaining().is_empty());
+        assert_eq!(force.transform(vec![1.0; 4]), vec![1.0; 4]);
+
+        force.clear();
+        assert_eq!(force.remaining().len(), 2);
+    }
+}